@@ -14,6 +14,8 @@ Function Install-JarvisBinary($BaseDirectory, $TargetArchitecture, $Source, $Nam
     }
 }
 
-Install-JarvisBinary -BaseDirectory $jarvisDirectory -TargetArchitecture $linuxArch -Source "agent-worker-x86_64-unknown-linux-gnu.tar" -Name "agent-worker"
+$latestBuild = Get-ChildItem -Path ".jarvis\out" -Directory | Sort-Object -Property LastWriteTime -Descending | Select-Object -First 1
 
-Install-JarvisBinary -BaseDirectory $pluginsDirectory -TargetArchitecture $linuxArch -Source "hello-world-plugin-x86_64-unknown-linux-gnu.tar" -Name "hello-world-plugin"
+Install-JarvisBinary -BaseDirectory $jarvisDirectory -TargetArchitecture $linuxArch -Source (Join-Path -Path $latestBuild.FullName -ChildPath "agent-worker-x86_64-unknown-linux-gnu.tar") -Name "agent-worker"
+
+Install-JarvisBinary -BaseDirectory $pluginsDirectory -TargetArchitecture $linuxArch -Source (Join-Path -Path $latestBuild.FullName -ChildPath "hello-world-plugin-x86_64-unknown-linux-gnu.tar") -Name "hello-world-plugin"
@@ -8,7 +8,8 @@ use futures::future::Ready;
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
 
-use jarvis_core::{build_project, RuntimeOption, validate_project, OutputFormatter, cleanup_resources, init_project, core_test};
+use jarvis_core::{build_project, BuildOptions, GitSource, WorkspaceMode, RuntimeOption, validate_project, OutputFormatter, cleanup_resources, init_project, core_test, list_artifacts, get_artifacts, prune_artifacts, ArtifactFilter, list_caches, inspect_cache, prune_caches, clear_caches, export_cache, import_cache, CacheInfo, add_secret, list_secrets, remove_secret, rotate_secrets_key, update_images};
+use jarvis_core::config::RetentionPolicy;
 use crate::cli_output_formatter::CliOutputFormatter;
 
 #[derive(StructOpt)]
@@ -42,6 +43,42 @@ enum SubCommands {
 
         #[structopt(long, default_value = "")]
         runtime: RuntimeOption,
+
+        #[structopt(long)]
+        /// Keep the workspace between builds and only upload the files which changed
+        reuse_workspace: bool,
+
+        #[structopt(long, default_value = "volume")]
+        /// How the project is made available to the agents, one of volume, bind or bind-ro
+        workspace_mode: WorkspaceMode,
+
+        #[structopt(long)]
+        /// Build a clean checkout of this repository URL or local path instead of the working tree
+        git_source: Option<String>,
+
+        #[structopt(long, requires = "git-source")]
+        /// The branch, tag or commit to check out, defaults to the repository's HEAD
+        git_ref: Option<String>,
+
+        #[structopt(long, requires = "git-source")]
+        /// Only fetch this many commits of history
+        git_depth: Option<u32>,
+
+        #[structopt(long, requires = "git-source")]
+        /// Also check out submodules
+        git_submodules: bool,
+
+        #[structopt(long, requires = "git-source")]
+        /// Only check out these paths, may be repeated
+        git_sparse: Vec<String>,
+
+        #[structopt(long)]
+        /// Allow steps to use this environment's secrets where it requires an explicit choice, may be repeated
+        environment: Vec<String>,
+
+        #[structopt(long)]
+        /// Fail if any agent image isn't pinned in .jarvis/images.lock
+        locked: bool,
     },
 
     Cleanup {
@@ -49,9 +86,233 @@ enum SubCommands {
         runtime: RuntimeOption,
     },
 
+    Artifacts {
+        #[structopt(subcommand)]
+        cmd: ArtifactCommands,
+    },
+
+    Cache {
+        #[structopt(subcommand)]
+        cmd: CacheCommands,
+    },
+
+    Secrets {
+        #[structopt(subcommand)]
+        cmd: SecretsCommands,
+    },
+
+    Images {
+        #[structopt(subcommand)]
+        cmd: ImagesCommands,
+    },
+
     Test {},
 }
 
+#[derive(StructOpt)]
+enum ArtifactCommands {
+    /// List the artifacts stored for a project
+    List {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        build: Option<String>,
+
+        #[structopt(long)]
+        module: Option<String>,
+
+        #[structopt(long)]
+        step: Option<String>,
+    },
+
+    /// Download artifacts from the store
+    Get {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        build: String,
+
+        #[structopt(long)]
+        module: Option<String>,
+
+        #[structopt(long)]
+        step: Option<String>,
+
+        #[structopt(long)]
+        name: Option<String>,
+
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        /// The directory to download to
+        output: std::path::PathBuf,
+    },
+
+    /// Remove artifacts which are outside the retention policy
+    Prune {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        /// Overrides the configured policy, keeping only this many of the most recent builds
+        keep_builds: Option<usize>,
+
+        #[structopt(long)]
+        /// Overrides the configured policy, removing builds older than this
+        max_age_days: Option<i64>,
+    },
+}
+
+#[derive(StructOpt)]
+enum CacheCommands {
+    /// List cache volumes with their size, last use and owning project
+    List {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        /// Include caches from every project
+        all: bool,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+
+    /// Show the details of a single cache volume
+    Inspect {
+        /// The cache volume name, as shown by `cache list`
+        volume: String,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+
+    /// Remove caches which haven't been used recently or don't fit in a size budget
+    Prune {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        /// Prune caches from every project
+        all: bool,
+
+        #[structopt(long)]
+        /// Remove caches which haven't been used for this many days
+        max_age_days: Option<i64>,
+
+        #[structopt(long)]
+        /// Remove the least recently used caches until the rest fit in this many megabytes
+        max_size_mb: Option<u64>,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+
+    /// Write a cache volume to an archive which can be imported on another machine
+    Export {
+        /// The cache volume name, as shown by `cache list`
+        volume: String,
+
+        #[structopt(long, parse(from_os_str))]
+        /// The archive to write, defaults to the volume name
+        output: Option<std::path::PathBuf>,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+
+    /// Load a cache archive written by `cache export`
+    Import {
+        #[structopt(parse(from_os_str))]
+        /// The archive to import
+        archive: std::path::PathBuf,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+
+    /// Remove all caches
+    Clear {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        /// Clear caches from every project
+        all: bool,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+}
+
+#[derive(StructOpt)]
+enum SecretsCommands {
+    /// Encrypt a secret into the project, replacing any existing value
+    Add {
+        /// The name steps refer to the secret by
+        name: String,
+
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long, parse(from_os_str))]
+        /// Read the value from this file instead of standard input
+        from_file: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        /// Add the secret to this environment instead of the project's shared secrets
+        environment: Option<String>,
+    },
+
+    /// List the secrets stored in the project
+    List {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+    },
+
+    /// Remove a secret from the project
+    Rm {
+        /// The secret to remove
+        name: String,
+
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long)]
+        /// Remove the secret from this environment instead of the project's shared secrets
+        environment: Option<String>,
+    },
+
+    /// Re-encrypt every secret in the project with a new key
+    RotateKey {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+    },
+}
+
+#[derive(StructOpt)]
+enum ImagesCommands {
+    /// Resolve every agent image to its current digest and write them to .jarvis/images.lock
+    Update {
+        #[structopt(long, parse(from_os_str))]
+        /// The project to use
+        project: Option<std::path::PathBuf>,
+
+        #[structopt(long, default_value = "")]
+        runtime: RuntimeOption,
+    },
+}
+
 fn main() {
     let args = Cli::from_args();
 
@@ -75,18 +336,47 @@ fn main() {
             };
             exit_code = block_on(rt.block_on(init(project_dir, runtime, cli_output_formatter))).unwrap();
         }
-        SubCommands::Build { project, runtime } => {
+        SubCommands::Build { project, runtime, reuse_workspace, workspace_mode, git_source, git_ref, git_depth, git_submodules, git_sparse, environment, locked } => {
             let cli_output_formatter = Box::new(CliOutputFormatter {});
             let project_dir = match project {
                 Some(project) => project,
                 None => current_dir().unwrap()
             };
-            exit_code = block_on(rt.block_on(build(project_dir, runtime, cli_output_formatter))).unwrap();
+            let build_options = BuildOptions {
+                reuse_workspace,
+                workspace_mode,
+                git_source: git_source.map(|repository| GitSource {
+                    repository,
+                    reference: git_ref,
+                    depth: git_depth,
+                    submodules: git_submodules,
+                    sparse_paths: git_sparse,
+                }),
+                environments: environment,
+                locked,
+            };
+            exit_code = block_on(rt.block_on(build(project_dir, runtime, build_options, cli_output_formatter))).unwrap();
         }
         SubCommands::Cleanup { runtime } => {
             let cli_output_formatter = Box::new(CliOutputFormatter {});
             exit_code = block_on(rt.block_on(cleanup(runtime, cli_output_formatter))).unwrap();
         }
+        SubCommands::Artifacts { cmd } => {
+            let cli_output_formatter = Box::new(CliOutputFormatter {});
+            exit_code = block_on(rt.block_on(artifacts(cmd, cli_output_formatter))).unwrap();
+        }
+        SubCommands::Cache { cmd } => {
+            let cli_output_formatter = Box::new(CliOutputFormatter {});
+            exit_code = block_on(rt.block_on(cache(cmd, cli_output_formatter))).unwrap();
+        }
+        SubCommands::Secrets { cmd } => {
+            let cli_output_formatter = Box::new(CliOutputFormatter {});
+            exit_code = block_on(rt.block_on(secrets(cmd, cli_output_formatter))).unwrap();
+        }
+        SubCommands::Images { cmd } => {
+            let cli_output_formatter = Box::new(CliOutputFormatter {});
+            exit_code = block_on(rt.block_on(images(cmd, cli_output_formatter))).unwrap();
+        }
         SubCommands::Test {} => {
             let cli_output_formatter = Box::new(CliOutputFormatter {});
             exit_code = block_on(rt.block_on(test(cli_output_formatter))).unwrap();
@@ -135,8 +425,8 @@ async fn init(project: std::path::PathBuf, runtime: RuntimeOption, output_format
     }
 }
 
-async fn build(project: std::path::PathBuf, runtime: RuntimeOption, output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
-    let result = build_project(project, runtime, &output_formatter).await;
+async fn build(project: std::path::PathBuf, runtime: RuntimeOption, build_options: BuildOptions, output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
+    let result = build_project(project, runtime, &build_options, &output_formatter).await;
 
     match result {
         Ok(_) => {
@@ -165,6 +455,273 @@ async fn cleanup(runtime: RuntimeOption, output_formatter: Box<dyn OutputFormatt
     }
 }
 
+async fn artifacts(cmd: ArtifactCommands, output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
+    match cmd {
+        ArtifactCommands::List { project, build, module, step } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            let result = list_artifacts(project_dir, ArtifactFilter {
+                build_id: build,
+                module_name: module,
+                step_name: step,
+                ..Default::default()
+            }).await;
+
+            match result {
+                Ok(artifacts) => {
+                    if artifacts.is_empty() {
+                        output_formatter.print("No artifacts found".to_string());
+                    }
+                    for artifact in artifacts {
+                        output_formatter.print(format!("{} [{}] {} / {} / {}", artifact.created, artifact.key.build_id, artifact.key.module_name, artifact.key.step_name, artifact.key.name));
+                        output_formatter.background(format!("{} - {} bytes, {} file(s), sha256 {}", artifact.file_name, artifact.size, artifact.manifest.files.len(), artifact.digest));
+                    }
+                    futures::future::ok(1)
+                }
+                Err(e) => {
+                    output_formatter.error(format!("Listing artifacts failed: {}", e));
+                    futures::future::ok(0)
+                }
+            }
+        }
+        ArtifactCommands::Get { project, build, module, step, name, output } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            let result = get_artifacts(project_dir, ArtifactFilter {
+                build_id: Some(build),
+                module_name: module,
+                step_name: step,
+                name,
+                ..Default::default()
+            }, output).await;
+
+            match result {
+                Ok(files) => {
+                    for file in files {
+                        output_formatter.print(format!("Downloaded {}", file.display()));
+                    }
+                    output_formatter.success("Artifacts downloaded".to_string());
+                    futures::future::ok(1)
+                }
+                Err(e) => {
+                    output_formatter.error(format!("Downloading artifacts failed: {}", e));
+                    futures::future::ok(0)
+                }
+            }
+        }
+        ArtifactCommands::Prune { project, keep_builds, max_age_days } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            let retention = if keep_builds.is_some() || max_age_days.is_some() {
+                Some(RetentionPolicy { keep_builds, max_age_days })
+            } else {
+                None
+            };
+
+            match prune_artifacts(project_dir, retention).await {
+                Ok(removed) => {
+                    for artifact in &removed {
+                        output_formatter.background(format!("Removed [{}] {}", artifact.key.build_id, artifact.key.name));
+                    }
+                    output_formatter.success(format!("Pruned {} artifact(s)", removed.len()));
+                    futures::future::ok(1)
+                }
+                Err(e) => {
+                    output_formatter.error(format!("Pruning artifacts failed: {}", e));
+                    futures::future::ok(0)
+                }
+            }
+        }
+    }
+}
+
+async fn cache(cmd: CacheCommands, output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
+    let project_scope = |project: Option<std::path::PathBuf>, all: bool| {
+        if all { None } else { Some(project.unwrap_or(current_dir().unwrap())) }
+    };
+
+    let (result, action) = match cmd {
+        CacheCommands::List { project, all, runtime } => {
+            match list_caches(project_scope(project, all), runtime).await {
+                Ok(caches) => {
+                    if caches.is_empty() {
+                        output_formatter.print("No caches found".to_string());
+                    }
+                    for cache in &caches {
+                        print_cache(cache, &output_formatter);
+                    }
+                    return futures::future::ok(1);
+                }
+                Err(e) => (Err(e), "Listing caches")
+            }
+        }
+        CacheCommands::Inspect { volume, runtime } => {
+            match inspect_cache(volume.as_str(), runtime).await {
+                Ok(cache) => {
+                    print_cache(&cache, &output_formatter);
+                    output_formatter.background(format!("key: {}", if cache.key.is_empty() { "<none>" } else { cache.key.as_str() }));
+                    output_formatter.background(format!("created: {}", cache.created));
+                    output_formatter.background(format!("in use: {}", cache.in_use));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (Err(e), "Inspecting cache")
+            }
+        }
+        CacheCommands::Export { volume, output, runtime } => {
+            let output = output.unwrap_or(std::path::PathBuf::from(format!("{}.tgz", volume)));
+            match export_cache(volume.as_str(), &output, runtime).await {
+                Ok(cache) => {
+                    output_formatter.success(format!("Exported cache {} to {}", cache.name, output.display()));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (Err(e), "Exporting cache")
+            }
+        }
+        CacheCommands::Import { archive, runtime } => {
+            match import_cache(&archive, runtime).await {
+                Ok(metadata) => {
+                    output_formatter.success(format!("Imported cache {} for [{}] {}", metadata.name, metadata.project_id, metadata.module_name));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (Err(e), "Importing cache")
+            }
+        }
+        CacheCommands::Prune { project, all, max_age_days, max_size_mb, runtime } => {
+            let max_size = max_size_mb.map(|mb| mb * 1024 * 1024);
+            (prune_caches(project_scope(project, all), max_age_days, max_size, runtime, &output_formatter).await, "Pruning caches")
+        }
+        CacheCommands::Clear { project, all, runtime } => {
+            (clear_caches(project_scope(project, all), runtime, &output_formatter).await, "Clearing caches")
+        }
+    };
+
+    match result {
+        Ok(removed) => {
+            for cache in &removed {
+                output_formatter.background(format!("Removed {}", cache.volume_name));
+            }
+            output_formatter.success(format!("Removed {} cache(s)", removed.len()));
+            futures::future::ok(1)
+        }
+        Err(e) => {
+            output_formatter.error(format!("{} failed: {}", action, e));
+            futures::future::ok(0)
+        }
+    }
+}
+
+fn print_cache(cache: &CacheInfo, output_formatter: &Box<dyn OutputFormatter>) {
+    output_formatter.print(format!("{} [{}] {} / {}", cache.volume_name, cache.project_id, cache.module_name, cache.name));
+
+    let size = cache.size.map_or("unknown size".to_string(), |size| format!("{} bytes", size));
+    let last_used = cache.last_used.as_ref().map_or("never used".to_string(), |time| format!("last used {}", time));
+    output_formatter.background(format!("{}, {}", size, last_used));
+}
+
+async fn secrets(cmd: SecretsCommands, output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
+    let (error, action) = match cmd {
+        SecretsCommands::Add { name, project, from_file, environment } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            let value = match from_file {
+                Some(file) => std::fs::read(&file),
+                None => {
+                    eprintln!("Enter the value for {}, then end the input with Ctrl-D:", name);
+                    let mut value = vec![];
+                    std::io::Read::read_to_end(&mut std::io::stdin(), &mut value).map(|_| value)
+                }
+            };
+
+            let value = match value {
+                Ok(value) => value,
+                Err(e) => {
+                    output_formatter.error(format!("Reading the secret value failed: {}", e));
+                    return futures::future::ok(0);
+                }
+            };
+
+            match add_secret(project_dir, environment.as_deref(), name.as_str(), value.as_slice()) {
+                Ok(created_key) => {
+                    if let Some(key_file) = created_key {
+                        output_formatter.print(format!("Created a new key at {}, keep a copy of it somewhere safe", key_file.display()));
+                    }
+                    output_formatter.success(format!("Added secret {}", name));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (e, "Adding the secret")
+            }
+        }
+        SecretsCommands::List { project } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            match list_secrets(project_dir) {
+                Ok(secrets) => {
+                    if secrets.is_empty() {
+                        output_formatter.print("No secrets found".to_string());
+                    }
+                    for secret in &secrets {
+                        let (name, option) = match &secret.environment {
+                            Some(environment) => (format!("[{}] {}", environment, secret.name), format!(" --environment {}", environment)),
+                            None => (secret.name.clone(), "".to_string())
+                        };
+                        if secret.encrypted {
+                            output_formatter.print(name);
+                        } else {
+                            output_formatter.print(format!("{} (plain text)", name));
+                            output_formatter.background(format!("Run `jarvis secrets add {}{}` to encrypt it", secret.name, option));
+                        }
+                    }
+                    return futures::future::ok(1);
+                }
+                Err(e) => (e, "Listing secrets")
+            }
+        }
+        SecretsCommands::Rm { name, project, environment } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            match remove_secret(project_dir, environment.as_deref(), name.as_str()) {
+                Ok(_) => {
+                    output_formatter.success(format!("Removed secret {}", name));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (e, "Removing the secret")
+            }
+        }
+        SecretsCommands::RotateKey { project } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            match rotate_secrets_key(project_dir) {
+                Ok(count) => {
+                    output_formatter.success(format!("Re-encrypted {} secret(s) with a new key", count));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (e, "Rotating the key")
+            }
+        }
+    };
+
+    output_formatter.error(format!("{} failed: {}", action, error));
+    futures::future::ok(0)
+}
+
+async fn images(cmd: ImagesCommands, output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
+    let (error, action) = match cmd {
+        ImagesCommands::Update { project, runtime } => {
+            let project_dir = project.unwrap_or(current_dir().unwrap());
+            match update_images(project_dir, runtime, &output_formatter).await {
+                Ok(images) => {
+                    for image in &images {
+                        match &image.previous_digest {
+                            Some(previous) if previous == &image.digest => output_formatter.background(format!("{} unchanged", image.image)),
+                            Some(previous) => output_formatter.print(format!("{} {} -> {}", image.image, previous, image.digest)),
+                            None => output_formatter.print(format!("{} {}", image.image, image.digest))
+                        }
+                    }
+                    output_formatter.success(format!("Locked {} image(s)", images.len()));
+                    return futures::future::ok(1);
+                }
+                Err(e) => (e, "Updating images")
+            }
+        }
+    };
+
+    output_formatter.error(format!("{} failed: {}", action, error));
+    futures::future::ok(0)
+}
+
 async fn test(output_formatter: Box<dyn OutputFormatter>) -> Ready<Result<i32, ()>> {
     let result = core_test().await;
 
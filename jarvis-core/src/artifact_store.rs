@@ -0,0 +1,442 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use crypto::digest::Digest;
+use serde::{Deserialize, Serialize};
+
+use crate::artifacts::{ArtifactManifest, HashingReader};
+use crate::artifact_store::local_store::LocalArtifactStore;
+use crate::artifact_store::s3_store::S3ArtifactStore;
+use crate::runtime::CheckoutReport;
+use crate::config::{ArtifactStoreConfig, BuildConfig, get_project_config, jarvis_home_directory, RetentionPolicy};
+
+pub mod local_store;
+pub mod s3_store;
+
+#[async_trait]
+pub trait ArtifactStore {
+    async fn publish(&self, key: &ArtifactKey, file: &PathBuf, manifest: &ArtifactManifest) -> Result<StoredArtifact, ArtifactStoreError>;
+
+    async fn list(&self, filter: &ArtifactFilter) -> Result<Vec<StoredArtifact>, ArtifactStoreError>;
+
+    async fn fetch(&self, artifact: &StoredArtifact, destination: &PathBuf) -> Result<(), ArtifactStoreError>;
+
+    async fn remove(&self, artifact: &StoredArtifact) -> Result<(), ArtifactStoreError>;
+
+    // Removes stored content which is no longer referenced by any artifact, returning the number of objects removed.
+    async fn collect_garbage(&self) -> Result<usize, ArtifactStoreError>;
+
+    async fn record_build(&self, record: &BuildRecord) -> Result<(), ArtifactStoreError>;
+
+    async fn list_builds(&self, project_id: &str) -> Result<Vec<BuildRecord>, ArtifactStoreError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArtifactKey {
+    pub project_id: String,
+
+    pub build_id: String,
+
+    pub module_name: String,
+
+    pub step_name: String,
+
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredArtifact {
+    pub key: ArtifactKey,
+
+    pub file_name: String,
+
+    pub digest: String,
+
+    pub size: u64,
+
+    pub created: String,
+
+    pub manifest: ArtifactManifest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub project_id: String,
+
+    pub build_id: String,
+
+    pub succeeded: bool,
+
+    pub finished: String,
+
+    // The commit the workspace was checked out at, when the build ran from a git source.
+    pub source_commit: Option<String>,
+
+    // Additional repositories checked out into module workspaces.
+    #[serde(default)]
+    pub checkouts: Vec<CheckoutReport>,
+}
+
+impl BuildRecord {
+    pub fn record_path(project_id: &str, build_id: &str) -> String {
+        format!("builds/{}/{}.json", to_path_segment(project_id), to_path_segment(build_id))
+    }
+
+    pub fn project_prefix(project_id: &str) -> String {
+        format!("builds/{}/", to_path_segment(project_id))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArtifactFilter {
+    pub project_id: String,
+
+    pub build_id: Option<String>,
+
+    pub module_name: Option<String>,
+
+    pub step_name: Option<String>,
+
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArtifactStoreError {
+    pub(crate) msg: String
+}
+
+impl fmt::Display for ArtifactStoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "artifact store error: {}", self.msg)
+    }
+}
+
+impl Error for ArtifactStoreError {}
+
+impl ArtifactFilter {
+    pub fn matches(&self, key: &ArtifactKey) -> bool {
+        key.project_id == self.project_id
+            && self.build_id.as_ref().map_or(true, |b| b == &key.build_id)
+            && self.module_name.as_ref().map_or(true, |m| m == &key.module_name)
+            && self.step_name.as_ref().map_or(true, |s| s == &key.step_name)
+            && self.name.as_ref().map_or(true, |n| n == &key.name)
+    }
+}
+
+impl StoredArtifact {
+    // Refs are laid out by project, build, module and step so that listing can be narrowed by prefix.
+    pub fn ref_path(key: &ArtifactKey) -> String {
+        format!("refs/{}/{}/{}/{}/{}.json",
+                to_path_segment(key.project_id.as_str()),
+                to_path_segment(key.build_id.as_str()),
+                to_path_segment(key.module_name.as_str()),
+                to_path_segment(key.step_name.as_str()),
+                to_path_segment(key.name.as_str()))
+    }
+
+    pub fn object_path(digest: &str) -> String {
+        format!("objects/{}/{}", &digest[..2], digest)
+    }
+}
+
+pub fn ref_prefix(filter: &ArtifactFilter) -> String {
+    let mut prefix = format!("refs/{}/", to_path_segment(filter.project_id.as_str()));
+    if let Some(build_id) = &filter.build_id {
+        prefix.push_str(format!("{}/", to_path_segment(build_id.as_str())).as_str());
+        if let Some(module_name) = &filter.module_name {
+            prefix.push_str(format!("{}/", to_path_segment(module_name.as_str())).as_str());
+        }
+    }
+
+    prefix
+}
+
+fn to_path_segment(value: &str) -> String {
+    value.replace(|c| c == '/' || c == '\\', "_")
+}
+
+// Returns `None` when the project doesn't configure a store, in which case artifacts are only written to the artifacts
+// directory.
+pub fn create_store(build_config: &BuildConfig) -> Result<Option<Box<dyn ArtifactStore + Send + Sync>>, ArtifactStoreError> {
+    match &build_config.artifact_store {
+        Some(config) => Ok(Some(create_store_from_config(config)?)),
+        None => Ok(None)
+    }
+}
+
+// Browsing artifacts always needs a store, so fall back to the default local store.
+pub fn create_store_or_default(build_config: &BuildConfig) -> Result<Box<dyn ArtifactStore + Send + Sync>, ArtifactStoreError> {
+    match &build_config.artifact_store {
+        Some(config) => create_store_from_config(config),
+        None => Ok(Box::new(LocalArtifactStore::new(default_local_store_path()?)))
+    }
+}
+
+pub fn retention_policy(build_config: &BuildConfig) -> Option<RetentionPolicy> {
+    match &build_config.artifact_store {
+        Some(ArtifactStoreConfig::Local { retention, .. }) => retention.clone(),
+        Some(ArtifactStoreConfig::S3 { retention, .. }) => retention.clone(),
+        None => None
+    }
+}
+
+fn create_store_from_config(config: &ArtifactStoreConfig) -> Result<Box<dyn ArtifactStore + Send + Sync>, ArtifactStoreError> {
+    match config {
+        ArtifactStoreConfig::Local { path, .. } => {
+            let path = match path {
+                Some(path) => PathBuf::from(path),
+                None => default_local_store_path()?
+            };
+
+            Ok(Box::new(LocalArtifactStore::new(path)))
+        },
+        ArtifactStoreConfig::S3 { endpoint, bucket, region, .. } => {
+            let store = S3ArtifactStore::new(endpoint.as_str(), bucket.as_str(), region.as_ref())?;
+
+            Ok(Box::new(store))
+        },
+    }
+}
+
+fn default_local_store_path() -> Result<PathBuf, ArtifactStoreError> {
+    jarvis_home_directory()
+        .map(|home| home.join("artifacts"))
+        .map_err(|e| ArtifactStoreError { msg: format!("{}", e) })
+}
+
+// Extracted archives are a directory on disk, they are packed into a tar so the store only ever deals with files.
+pub async fn publish_output(store: &Box<dyn ArtifactStore + Send + Sync>, key: &ArtifactKey, output_path: &PathBuf, manifest: &ArtifactManifest) -> Result<StoredArtifact, ArtifactStoreError> {
+    if !output_path.is_dir() {
+        return store.publish(key, output_path, manifest).await;
+    }
+
+    let packed_path = output_path.with_extension("tar");
+    {
+        let file = File::create(&packed_path)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to pack [{}]: {}", output_path.display(), e) })?;
+        let mut tar = tar::Builder::new(file);
+        tar.append_dir_all(".", output_path)
+            .and_then(|_| tar.finish())
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to pack [{}]: {}", output_path.display(), e) })?;
+    }
+
+    let result = store.publish(key, &packed_path, manifest).await;
+
+    std::fs::remove_file(&packed_path)
+        .map_err(|e| ArtifactStoreError { msg: format!("Failed to remove [{}]: {}", packed_path.display(), e) })?;
+
+    result
+}
+
+pub async fn prune(store: &Box<dyn ArtifactStore + Send + Sync>, project_id: &str, retention: &RetentionPolicy) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+    let artifacts = store.list(&ArtifactFilter {
+        project_id: project_id.to_string(),
+        ..Default::default()
+    }).await?;
+
+    // Newest build first, by the time its first artifact was stored.
+    let mut builds: Vec<(String, DateTime<Utc>)> = vec![];
+    for artifact in &artifacts {
+        let created = parse_created(artifact);
+        match builds.iter_mut().find(|b| b.0 == artifact.key.build_id) {
+            Some(build) => if created < build.1 { build.1 = created },
+            None => builds.push((artifact.key.build_id.clone(), created))
+        }
+    }
+    builds.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let now = Utc::now();
+    let expired_builds: Vec<String> = builds.iter().enumerate()
+        .filter(|(index, build)| {
+            let beyond_count = retention.keep_builds.map_or(false, |keep| *index >= keep);
+            let beyond_age = retention.max_age_days.map_or(false, |days| now - build.1 > Duration::days(days));
+            beyond_count || beyond_age
+        })
+        .map(|(_, build)| build.0.clone())
+        .collect();
+
+    let mut removed = vec![];
+    for artifact in artifacts {
+        if expired_builds.contains(&artifact.key.build_id) {
+            store.remove(&artifact).await?;
+            removed.push(artifact);
+        }
+    }
+
+    store.collect_garbage().await?;
+
+    Ok(removed)
+}
+
+fn parse_created(artifact: &StoredArtifact) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(artifact.created.as_str())
+        .map(|created| created.with_timezone(&Utc))
+        .unwrap_or(Utc::now())
+}
+
+pub async fn latest_successful_build(store: &Box<dyn ArtifactStore + Send + Sync>, project_id: &str) -> Result<Option<BuildRecord>, ArtifactStoreError> {
+    let mut builds: Vec<BuildRecord> = store.list_builds(project_id).await?
+        .into_iter()
+        .filter(|build| build.succeeded)
+        .collect();
+    builds.sort_by(|a, b| a.finished.cmp(&b.finished));
+
+    Ok(builds.pop())
+}
+
+pub fn file_digest(path: &PathBuf) -> Result<(String, u64), ArtifactStoreError> {
+    let file = File::open(path)
+        .map_err(|e| ArtifactStoreError { msg: format!("Failed to open [{}]: {}", path.display(), e) })?;
+
+    let mut reader = HashingReader::new(file);
+    io::copy(&mut reader, &mut io::sink())
+        .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+
+    Ok((reader.hasher.result_str(), reader.bytes))
+}
+
+pub fn new_stored_artifact(key: &ArtifactKey, file: &PathBuf, manifest: &ArtifactManifest) -> Result<StoredArtifact, ArtifactStoreError> {
+    let (digest, size) = file_digest(file)?;
+
+    Ok(StoredArtifact {
+        key: key.clone(),
+        file_name: file.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or(key.name.clone()),
+        digest,
+        size,
+        created: Utc::now().to_rfc3339(),
+        manifest: manifest.clone(),
+    })
+}
+
+pub async fn list_artifacts(project_path: PathBuf, mut filter: ArtifactFilter) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+    let project_config = get_project_config(project_path)
+        .map_err(|e| ArtifactStoreError { msg: format!("Project configuration error: {}", e) })?;
+    let store = create_store_or_default(&project_config.build_config)?;
+
+    filter.project_id = project_config.build_config.project_id.clone();
+
+    let mut artifacts = store.list(&filter).await?;
+    artifacts.sort_by(|a, b| a.created.cmp(&b.created));
+
+    Ok(artifacts)
+}
+
+pub async fn get_artifacts(project_path: PathBuf, filter: ArtifactFilter, output_directory: PathBuf) -> Result<Vec<PathBuf>, ArtifactStoreError> {
+    let project_config = get_project_config(project_path.clone())
+        .map_err(|e| ArtifactStoreError { msg: format!("Project configuration error: {}", e) })?;
+    let store = create_store_or_default(&project_config.build_config)?;
+
+    let artifacts = list_artifacts(project_path, filter).await?;
+    if artifacts.is_empty() {
+        return Err(ArtifactStoreError { msg: "No matching artifacts found".to_string() });
+    }
+
+    std::fs::create_dir_all(&output_directory)
+        .map_err(|e| ArtifactStoreError { msg: format!("Failed to create [{}]: {}", output_directory.display(), e) })?;
+
+    let destinations = artifact_destinations(&artifacts, &output_directory);
+    if let Some(existing) = destinations.iter().find(|destination| destination.exists()) {
+        return Err(ArtifactStoreError { msg: format!("Refusing to overwrite [{}]", existing.display()) });
+    }
+
+    let mut fetched = vec![];
+    for (artifact, destination) in artifacts.iter().zip(destinations) {
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to create [{}]: {}", parent.display(), e) })?;
+        }
+        store.fetch(artifact, &destination).await?;
+        fetched.push(destination);
+    }
+
+    Ok(fetched)
+}
+
+// Artifacts are written straight into the output directory by their file name. When more than one matching artifact has
+// the same name, each of those goes under its build, module and step instead so that none replaces another.
+fn artifact_destinations(artifacts: &Vec<StoredArtifact>, output_directory: &PathBuf) -> Vec<PathBuf> {
+    let file_name = |artifact: &StoredArtifact| std::path::Path::new(artifact.file_name.as_str()).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(to_path_segment(artifact.key.name.as_str()));
+
+    artifacts.iter()
+        .map(|artifact| {
+            let name = file_name(artifact);
+            if artifacts.iter().filter(|other| file_name(other) == name).count() > 1 {
+                output_directory
+                    .join(to_path_segment(artifact.key.build_id.as_str()))
+                    .join(to_path_segment(artifact.key.module_name.as_str()))
+                    .join(to_path_segment(artifact.key.step_name.as_str()))
+                    .join(name)
+            } else {
+                output_directory.join(name)
+            }
+        })
+        .collect()
+}
+
+pub async fn prune_artifacts(project_path: PathBuf, retention: Option<RetentionPolicy>) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+    let project_config = get_project_config(project_path)
+        .map_err(|e| ArtifactStoreError { msg: format!("Project configuration error: {}", e) })?;
+    let store = create_store_or_default(&project_config.build_config)?;
+
+    let retention = match retention.or(retention_policy(&project_config.build_config)) {
+        Some(retention) => retention,
+        None => return Err(ArtifactStoreError { msg: "No retention policy configured or provided".to_string() })
+    };
+
+    prune(&store, project_config.build_config.project_id.as_str(), &retention).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::artifacts::ArtifactManifest;
+    use super::{artifact_destinations, ArtifactKey, StoredArtifact};
+
+    fn artifact(build_id: &str, step_name: &str, file_name: &str) -> StoredArtifact {
+        StoredArtifact {
+            key: ArtifactKey {
+                project_id: "project".to_string(),
+                build_id: build_id.to_string(),
+                module_name: "app".to_string(),
+                step_name: step_name.to_string(),
+                name: "dist".to_string(),
+            },
+            file_name: file_name.to_string(),
+            digest: "abc".to_string(),
+            size: 0,
+            created: "2020-10-19T00:00:00Z".to_string(),
+            manifest: ArtifactManifest {
+                name: "dist".to_string(),
+                output: file_name.to_string(),
+                files: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn artifacts_with_the_same_name_are_kept_apart() {
+        let output = PathBuf::from("out");
+        let destinations = artifact_destinations(&vec![
+            artifact("1", "build", "dist.tar"),
+            artifact("2", "build", "dist.tar"),
+            artifact("2", "docs", "docs.tar"),
+            artifact("2", "evil", "../../escape.tar"),
+        ], &output);
+
+        assert_eq!(vec![
+            PathBuf::from("out/1/app/build/dist.tar"),
+            PathBuf::from("out/2/app/build/dist.tar"),
+            PathBuf::from("out/docs.tar"),
+            PathBuf::from("out/escape.tar"),
+        ], destinations);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use crate::artifact_store::{ArtifactFilter, ArtifactKey, ArtifactStore, ArtifactStoreError, BuildRecord, new_stored_artifact, ref_prefix, StoredArtifact};
+use crate::artifacts::ArtifactManifest;
+
+// Stores each distinct archive once under `objects/`, named by its SHA-256 digest, with a small JSON ref per artifact
+// under `refs/` pointing at it.
+pub struct LocalArtifactStore {
+    root: PathBuf,
+}
+
+impl LocalArtifactStore {
+    pub fn new(root: PathBuf) -> Self {
+        LocalArtifactStore {
+            root
+        }
+    }
+
+    fn read_refs(&self, directory: &PathBuf, refs: &mut Vec<StoredArtifact>) -> Result<(), ArtifactStoreError> {
+        if !directory.exists() {
+            return Ok(());
+        }
+
+        let entries = fs::read_dir(directory)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", directory.display(), e) })?;
+
+        for entry in entries {
+            let path = entry
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", directory.display(), e) })?
+                .path();
+
+            if path.is_dir() {
+                self.read_refs(&path, refs)?;
+            } else if path.extension().map_or(false, |e| e == "json") {
+                let content = fs::read_to_string(&path)
+                    .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+                let artifact: StoredArtifact = serde_json::from_str(content.as_str())
+                    .map_err(|e| ArtifactStoreError { msg: format!("Invalid artifact ref [{}]: {}", path.display(), e) })?;
+                refs.push(artifact);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for LocalArtifactStore {
+    async fn publish(&self, key: &ArtifactKey, file: &PathBuf, manifest: &ArtifactManifest) -> Result<StoredArtifact, ArtifactStoreError> {
+        let artifact = new_stored_artifact(key, file, manifest)?;
+
+        let object_path = self.root.join(StoredArtifact::object_path(artifact.digest.as_str()));
+        if !object_path.exists() {
+            fs::create_dir_all(object_path.parent().unwrap())
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to create [{}]: {}", object_path.display(), e) })?;
+
+            // Copy then rename so a partially written object is never visible under its digest.
+            let partial_path = object_path.with_extension("partial");
+            fs::copy(file, &partial_path)
+                .and_then(|_| fs::rename(&partial_path, &object_path))
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to store [{}]: {}", file.display(), e) })?;
+        }
+
+        let ref_path = self.root.join(StoredArtifact::ref_path(key));
+        fs::create_dir_all(ref_path.parent().unwrap())
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to create [{}]: {}", ref_path.display(), e) })?;
+        let content = serde_json::to_string_pretty(&artifact)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to serialize artifact ref: {}", e) })?;
+        fs::write(&ref_path, content)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to write [{}]: {}", ref_path.display(), e) })?;
+
+        Ok(artifact)
+    }
+
+    async fn list(&self, filter: &ArtifactFilter) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+        let mut refs = vec![];
+        self.read_refs(&self.root.join(ref_prefix(filter)), &mut refs)?;
+
+        Ok(refs.into_iter().filter(|artifact| filter.matches(&artifact.key)).collect())
+    }
+
+    async fn fetch(&self, artifact: &StoredArtifact, destination: &PathBuf) -> Result<(), ArtifactStoreError> {
+        let object_path = self.root.join(StoredArtifact::object_path(artifact.digest.as_str()));
+
+        fs::copy(&object_path, destination)
+            .map(|_| ())
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to copy [{}] to [{}]: {}", object_path.display(), destination.display(), e) })
+    }
+
+    async fn remove(&self, artifact: &StoredArtifact) -> Result<(), ArtifactStoreError> {
+        let ref_path = self.root.join(StoredArtifact::ref_path(&artifact.key));
+
+        fs::remove_file(&ref_path)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to remove [{}]: {}", ref_path.display(), e) })?;
+
+        // Tidy up now empty build directories, stopping at the first one which still has content.
+        let refs_root = self.root.join("refs");
+        let mut parent = ref_path.parent().map(|p| p.to_path_buf());
+        while let Some(directory) = parent {
+            if directory == refs_root || fs::remove_dir(&directory).is_err() {
+                break;
+            }
+            parent = directory.parent().map(|p| p.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    async fn collect_garbage(&self) -> Result<usize, ArtifactStoreError> {
+        let mut refs = vec![];
+        self.read_refs(&self.root.join("refs"), &mut refs)?;
+        let referenced: HashSet<String> = refs.into_iter().map(|artifact| artifact.digest).collect();
+
+        let objects_root = self.root.join("objects");
+        if !objects_root.exists() {
+            return Ok(0);
+        }
+
+        let mut removed = 0;
+        let shards = fs::read_dir(&objects_root)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", objects_root.display(), e) })?;
+        for shard in shards.filter_map(|s| s.ok()) {
+            let objects = fs::read_dir(shard.path())
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", shard.path().display(), e) })?;
+            for object in objects.filter_map(|o| o.ok()) {
+                let digest = object.file_name().to_string_lossy().to_string();
+                if !referenced.contains(&digest) {
+                    fs::remove_file(object.path())
+                        .map_err(|e| ArtifactStoreError { msg: format!("Failed to remove [{}]: {}", object.path().display(), e) })?;
+                    removed += 1;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn record_build(&self, record: &BuildRecord) -> Result<(), ArtifactStoreError> {
+        let record_path = self.root.join(BuildRecord::record_path(record.project_id.as_str(), record.build_id.as_str()));
+        fs::create_dir_all(record_path.parent().unwrap())
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to create [{}]: {}", record_path.display(), e) })?;
+
+        let content = serde_json::to_string_pretty(record)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to serialize build record: {}", e) })?;
+        fs::write(&record_path, content)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to write [{}]: {}", record_path.display(), e) })
+    }
+
+    async fn list_builds(&self, project_id: &str) -> Result<Vec<BuildRecord>, ArtifactStoreError> {
+        let directory = self.root.join(BuildRecord::project_prefix(project_id));
+        if !directory.exists() {
+            return Ok(vec![]);
+        }
+
+        let entries = fs::read_dir(&directory)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", directory.display(), e) })?;
+
+        let mut records = vec![];
+        for entry in entries.filter_map(|e| e.ok()) {
+            let content = fs::read_to_string(entry.path())
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", entry.path().display(), e) })?;
+            let record: BuildRecord = serde_json::from_str(content.as_str())
+                .map_err(|e| ArtifactStoreError { msg: format!("Invalid build record [{}]: {}", entry.path().display(), e) })?;
+            records.push(record);
+        }
+
+        Ok(records)
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use crate::artifact_store::{ArtifactFilter, ArtifactKey, ArtifactStore, ArtifactStoreError, BuildRecord, new_stored_artifact, ref_prefix, StoredArtifact};
+use crate::artifacts::ArtifactManifest;
+use crate::s3::{S3Client, S3Error};
+
+// Uses the same content addressed layout as the local store, with objects and refs stored as keys in a single bucket.
+pub struct S3ArtifactStore {
+    client: S3Client,
+}
+
+impl S3ArtifactStore {
+    pub fn new(endpoint: &str, bucket: &str, region: Option<&String>) -> Result<Self, ArtifactStoreError> {
+        let client = S3Client::new(endpoint, bucket, region)
+            .map_err(to_store_error)?;
+
+        Ok(S3ArtifactStore {
+            client
+        })
+    }
+
+    async fn read_ref(&self, key: &str) -> Result<Option<StoredArtifact>, ArtifactStoreError> {
+        let content = self.client.get_object(key).await
+            .map_err(to_store_error)?;
+
+        match content {
+            Some(content) => serde_json::from_slice(content.as_slice())
+                .map(|artifact| Some(artifact))
+                .map_err(|e| ArtifactStoreError { msg: format!("Invalid artifact ref [{}]: {}", key, e) }),
+            None => Ok(None)
+        }
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for S3ArtifactStore {
+    async fn publish(&self, key: &ArtifactKey, file: &PathBuf, manifest: &ArtifactManifest) -> Result<StoredArtifact, ArtifactStoreError> {
+        let artifact = new_stored_artifact(key, file, manifest)?;
+
+        let object_key = StoredArtifact::object_path(artifact.digest.as_str());
+        let exists = self.client.head_object(object_key.as_str()).await
+            .map_err(to_store_error)?;
+        if !exists {
+            let content = tokio::fs::read(file).await
+                .map_err(|e| ArtifactStoreError { msg: format!("Failed to read [{}]: {}", file.display(), e) })?;
+            self.client.put_object(object_key.as_str(), content).await
+                .map_err(to_store_error)?;
+        }
+
+        let content = serde_json::to_vec_pretty(&artifact)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to serialize artifact ref: {}", e) })?;
+        self.client.put_object(StoredArtifact::ref_path(key).as_str(), content).await
+            .map_err(to_store_error)?;
+
+        Ok(artifact)
+    }
+
+    async fn list(&self, filter: &ArtifactFilter) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+        let keys = self.client.list_objects(ref_prefix(filter).as_str()).await
+            .map_err(to_store_error)?;
+
+        let mut artifacts = vec![];
+        for key in keys {
+            if let Some(artifact) = self.read_ref(key.as_str()).await? {
+                if filter.matches(&artifact.key) {
+                    artifacts.push(artifact);
+                }
+            }
+        }
+
+        Ok(artifacts)
+    }
+
+    async fn fetch(&self, artifact: &StoredArtifact, destination: &PathBuf) -> Result<(), ArtifactStoreError> {
+        let object_key = StoredArtifact::object_path(artifact.digest.as_str());
+        let content = self.client.get_object(object_key.as_str()).await
+            .map_err(to_store_error)?
+            .ok_or(ArtifactStoreError { msg: format!("Content for artifact [{}] is missing from the store", artifact.key.name) })?;
+
+        tokio::fs::write(destination, content).await
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to write [{}]: {}", destination.display(), e) })
+    }
+
+    async fn remove(&self, artifact: &StoredArtifact) -> Result<(), ArtifactStoreError> {
+        self.client.delete_object(StoredArtifact::ref_path(&artifact.key).as_str()).await
+            .map_err(to_store_error)
+    }
+
+    async fn collect_garbage(&self) -> Result<usize, ArtifactStoreError> {
+        let mut referenced = HashSet::new();
+        for key in self.client.list_objects("refs/").await.map_err(to_store_error)? {
+            if let Some(artifact) = self.read_ref(key.as_str()).await? {
+                referenced.insert(artifact.digest);
+            }
+        }
+
+        let mut removed = 0;
+        for key in self.client.list_objects("objects/").await.map_err(to_store_error)? {
+            let digest = key.rsplit('/').next().unwrap_or("").to_string();
+            if !referenced.contains(&digest) {
+                self.client.delete_object(key.as_str()).await
+                    .map_err(to_store_error)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn record_build(&self, record: &BuildRecord) -> Result<(), ArtifactStoreError> {
+        let content = serde_json::to_vec_pretty(record)
+            .map_err(|e| ArtifactStoreError { msg: format!("Failed to serialize build record: {}", e) })?;
+
+        self.client.put_object(BuildRecord::record_path(record.project_id.as_str(), record.build_id.as_str()).as_str(), content).await
+            .map_err(to_store_error)
+    }
+
+    async fn list_builds(&self, project_id: &str) -> Result<Vec<BuildRecord>, ArtifactStoreError> {
+        let keys = self.client.list_objects(BuildRecord::project_prefix(project_id).as_str()).await
+            .map_err(to_store_error)?;
+
+        let mut records = vec![];
+        for key in keys {
+            if let Some(content) = self.client.get_object(key.as_str()).await.map_err(to_store_error)? {
+                let record: BuildRecord = serde_json::from_slice(content.as_slice())
+                    .map_err(|e| ArtifactStoreError { msg: format!("Invalid build record [{}]: {}", key, e) })?;
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+fn to_store_error(e: S3Error) -> ArtifactStoreError {
+    ArtifactStoreError { msg: format!("{}", e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::artifact_store::{ArtifactFilter, ArtifactKey, ArtifactStore, BuildRecord};
+    use crate::artifacts::ArtifactManifest;
+    use crate::cache;
+    use super::S3ArtifactStore;
+
+    // Needs an S3 compatible store with an existing bucket, for example
+    // `docker run -p 9000:9000 -e MINIO_ACCESS_KEY=jarvis -e MINIO_SECRET_KEY=jarvis-secret minio/minio server /data`
+    // and a bucket made with `mc mb`. Run with JARVIS_TEST_S3_ENDPOINT, JARVIS_TEST_S3_BUCKET, AWS_ACCESS_KEY_ID and
+    // AWS_SECRET_ACCESS_KEY set and `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn round_trips_artifacts_through_an_s3_compatible_store() {
+        let endpoint = std::env::var("JARVIS_TEST_S3_ENDPOINT").expect("JARVIS_TEST_S3_ENDPOINT must be set");
+        let bucket = std::env::var("JARVIS_TEST_S3_BUCKET").expect("JARVIS_TEST_S3_BUCKET must be set");
+        let store = S3ArtifactStore::new(endpoint.as_str(), bucket.as_str(), None).unwrap();
+
+        let project_id = format!("jarvis-test-{}", chrono::Utc::now().timestamp_nanos());
+        let key = ArtifactKey {
+            project_id: project_id.clone(),
+            build_id: "1".to_string(),
+            module_name: "app".to_string(),
+            step_name: "build".to_string(),
+            name: "dist".to_string(),
+        };
+        let manifest = ArtifactManifest {
+            name: "dist".to_string(),
+            output: "dist.tar".to_string(),
+            files: vec![],
+        };
+
+        let file = cache::temp_path("jarvis-s3-store-test", "tar");
+        std::fs::write(&file, b"artifact content").unwrap();
+        let stored = store.publish(&key, &file, &manifest).await.unwrap();
+
+        let filter = ArtifactFilter {
+            project_id: project_id.clone(),
+            ..Default::default()
+        };
+        let listed = store.list(&filter).await.unwrap();
+        assert_eq!(1, listed.len());
+        assert_eq!(stored.digest, listed[0].digest);
+
+        let download = cache::temp_path("jarvis-s3-store-test", "tar");
+        store.fetch(&listed[0], &download).await.unwrap();
+        assert_eq!(b"artifact content".to_vec(), std::fs::read(&download).unwrap());
+
+        store.record_build(&BuildRecord {
+            project_id: project_id.clone(),
+            build_id: "1".to_string(),
+            succeeded: true,
+            finished: "2020-10-19T00:00:00Z".to_string(),
+            source_commit: None,
+            checkouts: vec![],
+        }).await.unwrap();
+        assert_eq!(1, store.list_builds(project_id.as_str()).await.unwrap().len());
+
+        store.remove(&stored).await.unwrap();
+        assert!(store.list(&filter).await.unwrap().is_empty());
+        assert!(store.collect_garbage().await.unwrap() >= 1);
+
+        std::fs::remove_file(file).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ArchiveCompression, ArchiveRule};
+
+pub const WORKSPACE_DIRECTORY: &str = "/build/workspace";
+
+#[derive(Debug, Clone)]
+pub struct ArtifactError {
+    msg: String
+}
+
+impl fmt::Display for ArtifactError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "artifact error: {}", self.msg)
+    }
+}
+
+impl Error for ArtifactError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactManifest {
+    pub name: String,
+
+    pub output: String,
+
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+
+    pub size: u64,
+
+    pub sha256: String,
+}
+
+impl ArtifactManifest {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+// The paths that have to be downloaded from the agent to satisfy an archive rule. Each root is the longest leading part of
+// a pattern which doesn't contain any glob syntax.
+pub fn archive_roots(archive_rule: &ArchiveRule) -> Result<Vec<String>, ArtifactError> {
+    let mut roots: Vec<String> = vec![];
+
+    for pattern in include_patterns(archive_rule)? {
+        let mut root = vec![];
+        for component in pattern.split('/') {
+            if component.contains(|c| c == '*' || c == '?' || c == '[' || c == '{') {
+                break;
+            }
+            root.push(component);
+        }
+
+        let root = root.join("/");
+        let root = if root.is_empty() { "/".to_string() } else { root };
+
+        // Anything under an existing root will be downloaded anyway.
+        if roots.iter().any(|r| is_within(root.as_str(), r.as_str())) {
+            continue;
+        }
+        roots.retain(|r| !is_within(r.as_str(), root.as_str()));
+        roots.push(root);
+    }
+
+    Ok(roots)
+}
+
+// Takes the tar files downloaded for each of the `archive_roots` and writes the matching entries to the configured output.
+pub fn package_archive(archive_rule: &ArchiveRule, downloads: &Vec<(String, PathBuf)>, output_directory: &PathBuf) -> Result<ArtifactManifest, ArtifactError> {
+    let includes = build_glob_set(&include_patterns(archive_rule)?)?;
+    let excludes = match &archive_rule.exclude {
+        Some(exclude) => Some(build_glob_set(&exclude.iter().map(|p| to_absolute_pattern(p)).collect())?),
+        None => None
+    };
+
+    std::fs::create_dir_all(output_directory)
+        .map_err(|e| ArtifactError { msg: format!("Failed to create artifacts directory [{}]: {}", output_directory.display(), e) })?;
+
+    let output_name = match &archive_rule.output {
+        Some(output) => output.clone(),
+        None => if archive_rule.extract.unwrap_or(false) {
+            archive_rule.name.clone()
+        } else {
+            format!("{}.{}", archive_rule.name, archive_rule.compression.as_ref().unwrap_or(&ArchiveCompression::Tar).extension())
+        }
+    };
+    let output_path = output_directory.join(output_name.as_str());
+
+    // A plain `location` keeps the layout of the original single path archives, where entries are named relative to the
+    // parent of the location. Glob paths are named relative to the workspace.
+    let location = archive_rule.location.as_ref().map(|l| to_absolute_pattern(l));
+    let location_parent = location.as_ref()
+        .and_then(|l| Path::new(l.as_str()).parent().map(|p| p.to_str().unwrap_or("/").to_string()));
+
+    let mut writer = ArchiveWriter::create(archive_rule, &output_path)?;
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+
+    for (root, download) in downloads {
+        // Downloaded entries are named relative to the parent of the root that was requested.
+        let root_parent = Path::new(root.as_str()).parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::from("/"));
+
+        let download_file = File::open(download)
+            .map_err(|e| ArtifactError { msg: format!("Failed to open downloaded archive: {}", e) })?;
+        let mut archive = tar::Archive::new(download_file);
+        let entries = archive.entries()
+            .map_err(|e| ArtifactError { msg: format!("Failed to read downloaded archive: {}", e) })?;
+
+        for entry in entries {
+            let mut entry = entry.map_err(|e| ArtifactError { msg: format!("Failed to read downloaded archive: {}", e) })?;
+            let entry_path = entry.path()
+                .map_err(|e| ArtifactError { msg: format!("Invalid path in downloaded archive: {}", e) })?
+                .into_owned();
+
+            if entry_path.components().any(|c| c == Component::ParentDir) {
+                return Err(ArtifactError { msg: format!("Refusing to collect path [{}] which escapes the archive", entry_path.display()) });
+            }
+
+            let absolute_path = root_parent.join(&entry_path);
+            let absolute_path = absolute_path.to_str().unwrap_or("").trim_end_matches('/').to_string();
+            if !includes.is_match(absolute_path.as_str()) {
+                continue;
+            }
+            if let Some(excludes) = &excludes {
+                if excludes.is_match(absolute_path.as_str()) {
+                    continue;
+                }
+            }
+            if !seen.insert(absolute_path.clone()) {
+                continue;
+            }
+
+            let relative_path = match (&location, &location_parent) {
+                (Some(location), Some(location_parent)) if is_within(absolute_path.as_str(), location.as_str()) => {
+                    absolute_path[location_parent.len()..].trim_start_matches('/').to_string()
+                },
+                _ => to_output_path(absolute_path.as_str())
+            };
+            if relative_path.is_empty() {
+                continue;
+            }
+
+            if let Some(manifest_entry) = writer.append(&mut entry, relative_path.as_str())? {
+                files.push(manifest_entry);
+            }
+        }
+    }
+
+    writer.finish()?;
+
+    let manifest = ArtifactManifest {
+        name: archive_rule.name.clone(),
+        output: output_name.clone(),
+        files,
+    };
+
+    let manifest_file = File::create(output_directory.join(format!("{}.manifest.json", archive_rule.name)))
+        .map_err(|e| ArtifactError { msg: format!("Failed to create manifest for archive {}: {}", archive_rule.name, e) })?;
+    serde_json::to_writer_pretty(manifest_file, &manifest)
+        .map_err(|e| ArtifactError { msg: format!("Failed to write manifest for archive {}: {}", archive_rule.name, e) })?;
+
+    Ok(manifest)
+}
+
+fn include_patterns(archive_rule: &ArchiveRule) -> Result<Vec<String>, ArtifactError> {
+    let mut patterns = vec![];
+    if let Some(location) = &archive_rule.location {
+        patterns.push(to_absolute_pattern(location));
+    }
+    if let Some(paths) = &archive_rule.paths {
+        patterns.extend(paths.iter().map(|p| to_absolute_pattern(p)));
+    }
+
+    if patterns.is_empty() {
+        return Err(ArtifactError { msg: format!("Archive [{}] must specify a location or paths", archive_rule.name) });
+    }
+
+    Ok(patterns)
+}
+
+fn to_absolute_pattern(pattern: &str) -> String {
+    let pattern = pattern.trim_end_matches('/');
+    if pattern.starts_with('/') {
+        pattern.to_string()
+    } else {
+        format!("{}/{}", WORKSPACE_DIRECTORY, pattern.trim_start_matches("./"))
+    }
+}
+
+fn to_output_path(absolute_path: &str) -> String {
+    match absolute_path.strip_prefix(WORKSPACE_DIRECTORY) {
+        Some(relative) => relative.trim_start_matches('/').to_string(),
+        None => absolute_path.trim_start_matches('/').to_string()
+    }
+}
+
+fn is_within(path: &str, root: &str) -> bool {
+    root == "/" || path == root || path.starts_with(format!("{}/", root).as_str())
+}
+
+// Matching a directory should collect everything inside it, so each pattern also matches its own descendants.
+fn build_glob_set(patterns: &Vec<String>) -> Result<GlobSet, ArtifactError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        for variant in vec![pattern.clone(), format!("{}/**", pattern)] {
+            let glob = GlobBuilder::new(variant.as_str())
+                .literal_separator(true)
+                .build()
+                .map_err(|e| ArtifactError { msg: format!("Invalid pattern [{}]: {}", pattern, e) })?;
+            builder.add(glob);
+        }
+    }
+
+    builder.build()
+        .map_err(|e| ArtifactError { msg: format!("Invalid patterns: {}", e) })
+}
+
+enum ArchiveWriter {
+    Tar(tar::Builder<File>),
+    TarGz(tar::Builder<GzEncoder<File>>),
+    Zip(zip::ZipWriter<File>),
+    Directory(PathBuf),
+}
+
+impl ArchiveWriter {
+    fn create(archive_rule: &ArchiveRule, output_path: &PathBuf) -> Result<Self, ArtifactError> {
+        if archive_rule.extract.unwrap_or(false) {
+            std::fs::create_dir_all(output_path)
+                .map_err(|e| ArtifactError { msg: format!("Failed to create directory [{}]: {}", output_path.display(), e) })?;
+            return Ok(ArchiveWriter::Directory(output_path.clone()));
+        }
+
+        let file = File::create(output_path)
+            .map_err(|e| ArtifactError { msg: format!("Failed to create file for archive {}, due to {}", archive_rule.name, e) })?;
+
+        Ok(match archive_rule.compression.as_ref().unwrap_or(&ArchiveCompression::Tar) {
+            ArchiveCompression::Tar => ArchiveWriter::Tar(tar::Builder::new(file)),
+            ArchiveCompression::Tgz => ArchiveWriter::TarGz(tar::Builder::new(GzEncoder::new(file, Compression::default()))),
+            ArchiveCompression::Zip => ArchiveWriter::Zip(zip::ZipWriter::new(file)),
+        })
+    }
+
+    fn append<R: Read>(&mut self, entry: &mut tar::Entry<R>, path: &str) -> Result<Option<ManifestEntry>, ArtifactError> {
+        let entry_type = entry.header().entry_type();
+        let mode = entry.header().mode().unwrap_or(0o644);
+        let is_file = entry_type.is_file();
+
+        let mut reader = HashingReader::new(entry);
+
+        let write_result = match self {
+            ArchiveWriter::Tar(builder) => {
+                let mut header = reader.inner.header().clone();
+                builder.append_data(&mut header, path, &mut reader)
+            },
+            ArchiveWriter::TarGz(builder) => {
+                let mut header = reader.inner.header().clone();
+                builder.append_data(&mut header, path, &mut reader)
+            },
+            ArchiveWriter::Zip(writer) => {
+                let options = zip::write::FileOptions::default().unix_permissions(mode);
+                if entry_type.is_dir() {
+                    writer.add_directory(path, options).map_err(to_io_error)
+                } else if is_file {
+                    writer.start_file(path, options).map_err(to_io_error)
+                        .and_then(|_| io::copy(&mut reader, writer).map(|_| ()))
+                } else {
+                    // Links and special files have no representation here.
+                    Ok(())
+                }
+            },
+            // Links are never recreated on the host, the agent controls where they point and a later entry written through
+            // one would land outside the artifacts directory. Files are created new so nothing already there is followed.
+            ArchiveWriter::Directory(directory) => {
+                let target = directory.join(path);
+                let parent_result = match target.parent() {
+                    Some(parent) => std::fs::create_dir_all(parent),
+                    None => Ok(())
+                };
+
+                parent_result.and_then(|_| {
+                    if is_file {
+                        std::fs::OpenOptions::new().write(true).create_new(true).open(&target)
+                            .and_then(|mut file| io::copy(&mut reader, &mut file))
+                            .map(|_| ())
+                    } else if entry_type.is_dir() {
+                        std::fs::create_dir_all(&target)
+                    } else {
+                        Ok(())
+                    }
+                })
+            },
+        };
+
+        write_result.map_err(|e| ArtifactError { msg: format!("Failed to write [{}] to archive: {}", path, e) })?;
+
+        if is_file {
+            Ok(Some(ManifestEntry {
+                path: path.to_string(),
+                size: reader.bytes,
+                sha256: reader.hasher.result_str(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn finish(self) -> Result<(), ArtifactError> {
+        let result = match self {
+            ArchiveWriter::Tar(builder) => builder.into_inner().map(|_| ()),
+            ArchiveWriter::TarGz(builder) => builder.into_inner().and_then(|enc| enc.finish()).map(|_| ()),
+            ArchiveWriter::Zip(mut writer) => writer.finish().map(|_| ()).map_err(to_io_error),
+            ArchiveWriter::Directory(_) => Ok(()),
+        };
+
+        result.map_err(|e| ArtifactError { msg: format!("Failed to finish archive: {}", e) })
+    }
+}
+
+fn to_io_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+pub struct HashingReader<R: Read> {
+    pub inner: R,
+
+    pub hasher: Sha256,
+
+    pub bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.input(&buf[..read]);
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+// Produces an archive which can be uploaded into an agent's workspace. Docker accepts tar and compressed tar directly,
+// directories and zip files are repacked as a tar.
+pub fn to_upload_archive(path: &PathBuf) -> Result<Vec<u8>, ArtifactError> {
+    if path.is_dir() {
+        let mut tar = tar::Builder::new(Vec::new());
+        tar.append_dir_all(".", path)
+            .map_err(|e| ArtifactError { msg: format!("Failed to pack [{}]: {}", path.display(), e) })?;
+        return tar.into_inner()
+            .map_err(|e| ArtifactError { msg: format!("Failed to pack [{}]: {}", path.display(), e) });
+    }
+
+    if path.extension().map_or(false, |e| e == "zip") {
+        let file = File::open(path)
+            .map_err(|e| ArtifactError { msg: format!("Failed to open [{}]: {}", path.display(), e) })?;
+        let mut zip = zip::ZipArchive::new(file)
+            .map_err(|e| ArtifactError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+
+        let mut tar = tar::Builder::new(Vec::new());
+        for index in 0..zip.len() {
+            let mut zip_entry = zip.by_index(index)
+                .map_err(|e| ArtifactError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(if zip_entry.is_dir() { 0 } else { zip_entry.size() });
+            header.set_mode(zip_entry.unix_mode().unwrap_or(if zip_entry.is_dir() { 0o755 } else { 0o644 }));
+            header.set_entry_type(if zip_entry.is_dir() { tar::EntryType::Directory } else { tar::EntryType::Regular });
+
+            let name = zip_entry.name().to_string();
+            tar.append_data(&mut header, name.as_str(), &mut zip_entry)
+                .map_err(|e| ArtifactError { msg: format!("Failed to repack [{}]: {}", name, e) })?;
+        }
+
+        return tar.into_inner()
+            .map_err(|e| ArtifactError { msg: format!("Failed to repack [{}]: {}", path.display(), e) });
+    }
+
+    std::fs::read(path)
+        .map_err(|e| ArtifactError { msg: format!("Failed to read [{}]: {}", path.display(), e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::cache;
+    use crate::config::ArchiveRule;
+    use super::{package_archive, to_upload_archive};
+
+    fn directory_rule() -> ArchiveRule {
+        ArchiveRule {
+            name: "site".to_string(),
+            location: Some("dist".to_string()),
+            paths: None,
+            exclude: None,
+            extract: Some(true),
+            compression: None,
+            output: None,
+        }
+    }
+
+    fn write_download(entries: Vec<(&str, tar::EntryType, &str)>) -> PathBuf {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, entry_type, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o644);
+            if entry_type.is_symlink() {
+                header.set_size(0);
+                header.set_link_name(content).unwrap();
+                builder.append_data(&mut header, path, "".as_bytes()).unwrap();
+            } else {
+                header.set_size(content.len() as u64);
+                builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+            }
+        }
+
+        let download = cache::temp_path("jarvis-artifacts-test", "tar");
+        std::fs::write(&download, builder.into_inner().unwrap()).unwrap();
+        download
+    }
+
+    #[test]
+    fn extracted_archives_keep_matching_files() {
+        let output_directory = cache::temp_path("jarvis-artifacts-test", "out");
+        let download = write_download(vec![
+            ("dist", tar::EntryType::Directory, ""),
+            ("dist/index.html", tar::EntryType::Regular, "<html/>"),
+            ("dist/css/site.css", tar::EntryType::Regular, "body {}"),
+        ]);
+
+        let manifest = package_archive(&directory_rule(), &vec![("/build/workspace/dist".to_string(), download.clone())], &output_directory).unwrap();
+
+        let paths: Vec<&str> = manifest.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(vec!["dist/index.html", "dist/css/site.css"], paths);
+        assert_eq!("body {}", std::fs::read_to_string(output_directory.join("site/dist/css/site.css")).unwrap());
+
+        std::fs::remove_file(download).unwrap();
+        std::fs::remove_dir_all(output_directory).unwrap();
+    }
+
+    #[test]
+    fn links_from_the_agent_cannot_write_outside_the_artifacts_directory() {
+        let outside = cache::temp_path("jarvis-artifacts-test", "outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        let output_directory = cache::temp_path("jarvis-artifacts-test", "out");
+        let download = write_download(vec![
+            ("dist", tar::EntryType::Directory, ""),
+            ("dist/escape", tar::EntryType::Symlink, outside.to_str().unwrap()),
+            ("dist/escape/evil.sh", tar::EntryType::Regular, "rm -rf ~"),
+            ("dist/hosts", tar::EntryType::Symlink, "/etc/hosts"),
+        ]);
+
+        package_archive(&directory_rule(), &vec![("/build/workspace/dist".to_string(), download.clone())], &output_directory).unwrap();
+
+        assert!(!outside.join("evil.sh").exists());
+        assert!(!std::fs::symlink_metadata(output_directory.join("site/dist/escape")).unwrap().file_type().is_symlink());
+        assert!(output_directory.join("site/dist/escape/evil.sh").is_file());
+        assert!(std::fs::symlink_metadata(output_directory.join("site/dist/hosts")).is_err());
+
+        std::fs::remove_file(download).unwrap();
+        std::fs::remove_dir_all(output_directory).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn extracted_inputs_are_packed_for_upload() {
+        let directory = cache::temp_path("jarvis-artifacts-test", "input");
+        std::fs::create_dir_all(directory.join("css")).unwrap();
+        std::fs::write(directory.join("css/site.css"), "body {}").unwrap();
+
+        let archive = to_upload_archive(&directory).unwrap();
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let paths: Vec<String> = archive.entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().trim_end_matches('/').to_string())
+            .collect();
+        assert!(paths.contains(&"css/site.css".to_string()));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,11 +1,28 @@
-use crate::config::{get_project_config, ProjectConfig, Module, Agent, Step, ShellConfig};
+use crate::config::{get_project_config, ProjectConfig, Module, Agent, Step, ShellConfig, StepInput, InputSource, BuildImageRule, PushImageRule, BuildImageRetention};
 use std::collections::HashMap;
-use crate::runtime::{BuildRuntime, BuildRuntimeError};
+use crate::runtime::{BuildRuntime, BuildRuntimeError, StepReport};
 use std::fmt;
 use std::fmt::Formatter;
 use std::error::Error;
 use crate::OutputFormatter;
 use serde::{Deserialize, Serialize};
+use crate::summary::{BuildSummary, StepSummary};
+use crate::artifact_store;
+use crate::artifact_store::{ArtifactFilter, ArtifactKey, ArtifactStore, BuildRecord};
+use crate::artifacts;
+use crate::artifacts::ArtifactManifest;
+use crate::environments;
+use crate::git;
+use crate::git::GitSource;
+use crate::image_lock::ImageLock;
+use crate::masking::{MaskedOutputFormatter, SecretMasker};
+use crate::validate;
+use std::path::PathBuf;
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+const DEFAULT_ARTIFACTS_DIRECTORY: &str = ".jarvis/out";
 
 #[derive(Deserialize, Serialize)]
 pub struct AgentInitialization {
@@ -76,6 +93,69 @@ pub async fn core_test() -> Result<(), BuildError> {
     Ok(())
 }
 
+// Options chosen for a single build, as opposed to the project configuration which is shared by every build.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    // Keep the workspace volume between builds and only send the files which changed since the last one.
+    pub reuse_workspace: bool,
+
+    pub workspace_mode: WorkspaceMode,
+
+    // Populate the workspace from a clean checkout instead of the working tree.
+    pub git_source: Option<GitSource>,
+
+    // Environments named on the command line, which those requiring an explicit choice need.
+    pub environments: Vec<String>,
+
+    // Refuse to run agents whose images aren't pinned in the project's image lock.
+    pub locked: bool,
+}
+
+// How the project reaches `/build/workspace` in the agents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WorkspaceMode {
+    // The project is copied into a volume, so nothing the build does is visible on the host.
+    Volume,
+
+    // The project directory is mounted directly and build outputs appear on the host as they are written. Agents run as
+    // their configured user, and the files they write are handed back to the owner of the project directory after each
+    // step.
+    Bind,
+
+    // The project directory is the read-only lower layer of an overlay, writes stay in the overlay and are discarded.
+    // Only available with a local Linux daemon.
+    BindReadOnly,
+}
+
+impl Default for WorkspaceMode {
+    fn default() -> Self {
+        WorkspaceMode::Volume
+    }
+}
+
+impl std::str::FromStr for WorkspaceMode {
+    type Err = BuildError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "volume" => Ok(WorkspaceMode::Volume),
+            "bind" => Ok(WorkspaceMode::Bind),
+            "bind-ro" => Ok(WorkspaceMode::BindReadOnly),
+            _ => Err(BuildError { msg: format!("Unknown workspace mode [{}], expected one of [volume, bind, bind-ro]", s) })
+        }
+    }
+}
+
+struct BuildContext<'a> {
+    project_config: &'a ProjectConfig,
+
+    build_options: &'a BuildOptions,
+
+    artifacts_directory: PathBuf,
+
+    artifact_store: Option<Box<dyn ArtifactStore + Send + Sync>>,
+}
+
 struct BuildAgentConfig<'a> {
     agents: HashMap<String, &'a Agent>,
 
@@ -95,16 +175,192 @@ impl fmt::Display for BuildError {
 
 impl Error for BuildError {}
 
-pub async fn build_project(project_path: std::path::PathBuf, mut runtime: Box<dyn BuildRuntime>, output_formatter: &Box<dyn OutputFormatter>) -> Result<(), BuildError> {
-    let project_config = get_project_config(project_path)
+pub async fn build_project(project_path: std::path::PathBuf, runtime: Box<dyn BuildRuntime>, build_options: &BuildOptions, secret_masker: SecretMasker, output_formatter: &Box<dyn OutputFormatter>) -> Result<(), BuildError> {
+    // Secrets are registered with `secret_masker` as they're read, so they're hidden from everything the build reports.
+    let output_formatter = &MaskedOutputFormatter::new(secret_masker.clone(), &**output_formatter);
+
+    // Everything about a build from a git source comes from the checked out commit, including its configuration and
+    // secrets. Only the artifacts are written to the local project directory.
+    let checkout = match &build_options.git_source {
+        Some(git_source) => {
+            let checkout_directory = std::env::temp_dir().join(format!("jarvis-checkout-{}", new_build_id()));
+            output_formatter.print(format!("Checking out [{}] at [{}]", git_source.repository, git_source.reference.as_ref().map(|r| r.as_str()).unwrap_or("HEAD")));
+
+            let commit = git::checkout(git_source, &checkout_directory)
+                .map_err(|e| {
+                    remove_checkout(&checkout_directory);
+                    BuildError { msg: format!("Failed to check out source: {}", e) }
+                })?;
+            output_formatter.background(format!("Checked out commit [{}]", commit));
+
+            // Environments restricted to branches are checked against the branch the commit actually came from, the
+            // requested ref could as well be a tag or a commit.
+            let branch = git::checked_out_branch(git_source, &checkout_directory, commit.as_str());
+
+            Some((checkout_directory, commit, branch))
+        },
+        None => None
+    };
+
+    let build_result = match &checkout {
+        Some((checkout_directory, commit, branch)) => build_source(checkout_directory.clone(), &project_path, runtime, build_options, Some(commit.clone()), branch.clone(), output_formatter).await,
+        None => build_source(project_path.clone(), &project_path, runtime, build_options, None, git::current_branch(&project_path), output_formatter).await
+    };
+
+    if let Some((checkout_directory, _, _)) = &checkout {
+        remove_checkout(checkout_directory);
+    }
+
+    build_result.map_err(|e| BuildError { msg: secret_masker.mask(e.msg.as_str()) })
+}
+
+async fn build_source(source_directory: PathBuf, output_directory: &PathBuf, mut runtime: Box<dyn BuildRuntime>, build_options: &BuildOptions, source_commit: Option<String>, branch: Option<String>, output_formatter: &dyn OutputFormatter) -> Result<(), BuildError> {
+    let project_config = get_project_config(source_directory)
         .map_err(|e| BuildError { msg: format!("Project configuration error: {}", e) })?;
 
+    if build_options.reuse_workspace && build_options.workspace_mode != WorkspaceMode::Volume {
+        return Err(BuildError { msg: "A reused workspace can only be combined with the volume workspace mode".to_string() });
+    }
+
+    let sensitive_workspace = project_config.build_config.workspace.as_ref().and_then(|workspace| workspace.sensitive).unwrap_or(false);
+    if build_options.reuse_workspace && sensitive_workspace {
+        return Err(BuildError { msg: "A sensitive workspace is destroyed at the end of every build and can't be reused".to_string() });
+    }
+
+    // Checked before anything runs, so a build which isn't allowed a secret or would write outside the workspace fails
+    // without doing any work.
+    let validation = validate::validate_project_config(&project_config);
+    if !validation.errors.is_empty() {
+        return Err(BuildError { msg: format!("Project configuration error: {}", validation.errors.join(", ")) });
+    }
+
+    if build_options.locked {
+        let image_lock = ImageLock::load(&project_config.jarvis_directory)
+            .map_err(|e| BuildError { msg: format!("{}", e) })?;
+        let unpinned = image_lock.unpinned_images(&project_config.build_config);
+        if !unpinned.is_empty() {
+            return Err(BuildError { msg: format!("Images [{}] aren't pinned, run `jarvis images update` to lock them", unpinned.join(", ")) });
+        }
+    }
+
+    environments::check_environment_access(&project_config.build_config, &build_options.environments, branch.as_ref().map(|branch| branch.as_str()))
+        .map_err(|e| BuildError { msg: format!("{}", e) })?;
+
     runtime.connect();
 
-    build_project_with_config(project_config, &mut runtime, output_formatter).await
+    build_project_with_config(project_config, output_directory, &mut runtime, build_options, source_commit, output_formatter).await
 }
 
-async fn build_project_with_config(project_config: ProjectConfig, runtime: &mut Box<dyn BuildRuntime>, output_formatter: &Box<dyn OutputFormatter>) -> Result<(), BuildError> {
+// Whether the build-scoped names of the images a build made outlive it, they're removed unless the project says otherwise.
+fn keep_build_images(retention: Option<BuildImageRetention>, succeeded: bool) -> bool {
+    match retention.unwrap_or(BuildImageRetention::Remove) {
+        BuildImageRetention::Remove => false,
+        BuildImageRetention::Keep => true,
+        BuildImageRetention::KeepOnFailure => !succeeded,
+    }
+}
+
+fn remove_checkout(checkout_directory: &PathBuf) {
+    if checkout_directory.exists() {
+        std::fs::remove_dir_all(checkout_directory)
+            .unwrap_or_else(|e| println!("Failed to remove checkout [{}]: {}", checkout_directory.display(), e));
+    }
+}
+
+async fn build_project_with_config(project_config: ProjectConfig, output_directory: &PathBuf, runtime: &mut Box<dyn BuildRuntime>, build_options: &BuildOptions, source_commit: Option<String>, output_formatter: &dyn OutputFormatter) -> Result<(), BuildError> {
+    let build_id = new_build_id();
+    let artifacts_directory = output_directory
+        .join(project_config.build_config.artifacts_dir.as_ref().map(|d| d.as_str()).unwrap_or(DEFAULT_ARTIFACTS_DIRECTORY))
+        .join(build_id.as_str());
+
+    let artifact_store = artifact_store::create_store(&project_config.build_config)
+        .map_err(|e| BuildError { msg: format!("Failed to configure artifact store: {}", e) })?;
+
+    let context = BuildContext {
+        project_config: &project_config,
+        build_options,
+        artifacts_directory,
+        artifact_store,
+    };
+
+    let mut summary = BuildSummary::new(build_id);
+    summary.source_commit = source_commit;
+
+    let build_result = build_modules(&context, runtime, &mut summary, output_formatter).await;
+
+    if !keep_build_images(project_config.build_config.build_image_retention, build_result.is_ok()) {
+        runtime.remove_build_images().await
+            .unwrap_or_else(|e| output_formatter.error(format!("Failed to remove build images: {}", e)));
+    }
+
+    summary.print(output_formatter);
+
+    // Artifacts from a failed build are left in the artifacts directory for inspection but never published, where later
+    // builds could restore them as inputs. A failure to publish never hides why the build failed.
+    if let Some(store) = &context.artifact_store {
+        let publish_result = match &build_result {
+            Ok(_) => publish_artifacts(store, &context, &summary, output_formatter).await,
+            Err(_) => Ok(())
+        };
+
+        let record_result = store.record_build(&BuildRecord {
+            project_id: project_config.build_config.project_id.clone(),
+            build_id: summary.build_id.clone(),
+            succeeded: build_result.is_ok() && publish_result.is_ok(),
+            finished: Utc::now().to_rfc3339(),
+            source_commit: summary.source_commit.clone(),
+            checkouts: summary.checkouts.clone(),
+        }).await
+            .map_err(|e| BuildError { msg: format!("Failed to record build: {}", e) });
+
+        if build_result.is_err() {
+            for e in publish_result.err().iter().chain(record_result.err().iter()) {
+                output_formatter.error(format!("{}", e));
+            }
+            return build_result;
+        }
+
+        publish_result?;
+        record_result?;
+    }
+
+    build_result
+}
+
+async fn publish_artifacts<'a>(store: &Box<dyn ArtifactStore + Send + Sync>, context: &BuildContext<'a>, summary: &BuildSummary, output_formatter: &dyn OutputFormatter) -> Result<(), BuildError> {
+    let project_config = context.project_config;
+    let artifacts_directory = &context.artifacts_directory;
+    let project_id = project_config.build_config.project_id.as_str();
+
+    for step in &summary.steps {
+        for manifest in &step.artifacts {
+            let key = ArtifactKey {
+                project_id: project_id.to_string(),
+                build_id: summary.build_id.clone(),
+                module_name: step.module_name.clone(),
+                step_name: step.step_name.clone(),
+                name: manifest.name.clone(),
+            };
+
+            let stored = artifact_store::publish_output(store, &key, &artifacts_directory.join(manifest.output.as_str()), manifest).await
+                .map_err(|e| BuildError { msg: format!("Failed to publish artifact [{}]: {}", manifest.name, e) })?;
+            output_formatter.background(format!("Published artifact {} [{}]", manifest.name, stored.digest));
+        }
+    }
+
+    if let Some(retention) = artifact_store::retention_policy(&project_config.build_config) {
+        let removed = artifact_store::prune(store, project_id, &retention).await
+            .map_err(|e| BuildError { msg: format!("Failed to apply artifact retention: {}", e) })?;
+        if !removed.is_empty() {
+            output_formatter.background(format!("Removed {} artifact(s) beyond the retention policy", removed.len()));
+        }
+    }
+
+    Ok(())
+}
+
+async fn build_modules<'a>(context: &BuildContext<'a>, runtime: &mut Box<dyn BuildRuntime>, summary: &mut BuildSummary, output_formatter: &dyn OutputFormatter) -> Result<(), BuildError> {
+    let project_config = context.project_config;
     for module in &project_config.build_config.modules {
         output_formatter.print(format!("Building module: {}", module.name));
 
@@ -114,14 +370,26 @@ async fn build_project_with_config(project_config: ProjectConfig, runtime: &mut
             })?;
 
         output_formatter.print("Starting module build initialisation".to_string());
-        runtime.init_for_module(&module.name, &project_config).await.map_err(build_project_error)?;
-        output_formatter.print("Module build initialised, ready to run steps".to_string());
-        let module_build_result = build_module(&module, &agent_config, runtime).await;
+        let module_build_result = match runtime.init_for_module(&module.name, project_config, context.build_options).await {
+            Ok(module_report) => {
+                summary.checkouts.extend(module_report.checkouts);
+                output_formatter.print("Module build initialised, ready to run steps".to_string());
+                build_module(&module, &agent_config, runtime, context, summary).await
+            },
+            Err(e) => Err(build_project_error(e))
+        };
+
+        // Whatever the module managed to create is torn down, including after a failed initialisation. A failure to clean
+        // up never hides why the module failed.
         output_formatter.print("Cleaning up".to_string());
-        runtime.tear_down_for_module(&module.name).await.map_err(build_project_error)?;
-
-        if module_build_result.is_err() {
-            return module_build_result;
+        let tear_down_result = runtime.tear_down_for_module(&module.name, module_build_result.is_ok()).await.map_err(build_project_error);
+        match (module_build_result, tear_down_result) {
+            (Err(e), Err(tear_down_error)) => {
+                output_formatter.error(format!("{}", tear_down_error));
+                return Err(e);
+            },
+            (Err(e), Ok(_)) | (Ok(_), Err(e)) => return Err(e),
+            (Ok(_), Ok(_)) => {}
         }
     }
 
@@ -132,19 +400,30 @@ fn build_project_error(bre: BuildRuntimeError) -> BuildError {
     BuildError { msg: format!("Failed to build project: {}", bre) }
 }
 
-async fn build_module<'a>(module: &Module, agent_config: &'a BuildAgentConfig<'a>, runtime: &mut Box<dyn BuildRuntime>) -> Result<(), BuildError> {
+async fn build_module<'a>(module: &Module, agent_config: &'a BuildAgentConfig<'a>, runtime: &mut Box<dyn BuildRuntime>, context: &BuildContext<'a>, summary: &mut BuildSummary) -> Result<(), BuildError> {
     if module.steps.is_empty() {
         return Err(BuildError { msg: "No build steps provided.".to_string() });
     }
 
     for step in &module.steps {
-        run_step(step, &module.name, agent_config, runtime).await?;
+        run_step(step, &module.name, agent_config, runtime, context, summary).await?;
     }
 
     Ok(())
 }
 
-async fn run_step<'a>(step: &Step, module_name:&String, agent_config: &'a BuildAgentConfig<'a>, runtime: &mut Box<dyn BuildRuntime>) -> Result<(), BuildError> {
+async fn run_step<'a>(step: &Step, module_name:&String, agent_config: &'a BuildAgentConfig<'a>, runtime: &mut Box<dyn BuildRuntime>, context: &BuildContext<'a>, summary: &mut BuildSummary) -> Result<(), BuildError> {
+    if let Some(rule) = &step.build_image {
+        return run_build_image_step(step, rule, module_name, runtime, summary).await;
+    }
+
+    if let Some(rule) = &step.push_image {
+        return run_push_image_step(step, rule, module_name, runtime, summary).await;
+    }
+
+    let command = step.command.as_ref()
+        .ok_or(BuildError { msg: format!("Step [{}] needs a command, an image to build or an image to push", step.name) })?;
+
     let agent = if let Some(ref agent) = step.agent {
         if agent_config.agents.contains_key(agent) {
             agent_config.agents[agent]
@@ -162,6 +441,21 @@ async fn run_step<'a>(step: &Step, module_name:&String, agent_config: &'a BuildA
     let agent_id = runtime.create_agent(module_name, agent, Some(&step)).await
         .map_err(|e| run_step_error(step.name.as_str(), e))?;
 
+    let step_result = run_agent_step(step, command, module_name, agent_id.as_str(), runtime, context, summary).await;
+
+    // The agent goes whatever happened to the step, it holds the step's secrets, services and proxy configuration.
+    let destroy_result = runtime.destroy_agent(agent_id.as_str()).await
+        .map_err(|e| run_step_error(step.name.as_str(), e));
+    match (step_result, destroy_result) {
+        (Err(e), Err(destroy_error)) => {
+            println!("Failed to destroy agent [{}]: {}", agent_id, destroy_error);
+            Err(e)
+        },
+        (step_result, destroy_result) => step_result.and(destroy_result)
+    }
+}
+
+async fn run_agent_step<'a>(step: &Step, command: &str, module_name: &String, agent_id: &str, runtime: &mut Box<dyn BuildRuntime>, context: &BuildContext<'a>, summary: &mut BuildSummary) -> Result<(), BuildError> {
     let shell_default = ShellConfig {
         executable: "/bin/sh".to_string()
     };
@@ -171,25 +465,212 @@ async fn run_step<'a>(step: &Step, module_name:&String, agent_config: &'a BuildA
         None => &shell_default
     };
 
-    core_test().await?;
+    // The plugin agent only listens when the step uses plugins.
+    if step.plugins.is_some() {
+        core_test().await?;
+    }
+
+    if let Some(inputs) = &step.inputs {
+        for input in inputs {
+            restore_input(input, module_name, agent_id, runtime, context, summary).await?;
+        }
+    }
 
-    let command_result = runtime.execute_command(agent_id.as_str(), shell_config, &step.command).await
+    let command_result = runtime.execute_command(agent_id, shell_config, command).await
         .map_err(|e| run_step_error(step.name.as_str(), e));
 
+    // Images are only registered from a command which succeeded, a failed one may have left a partial image behind.
+    let mut images = vec![];
+    let command_result = match (command_result, &step.register_images) {
+        (Ok(_), Some(rules)) => {
+            let mut register_result = Ok(());
+            for rule in rules {
+                match runtime.register_image(module_name, agent_id, rule).await {
+                    Ok(image) => images.push(image),
+                    Err(e) => {
+                        register_result = Err(run_step_error(step.name.as_str(), e));
+                        break;
+                    }
+                }
+            }
+            register_result
+        },
+        (command_result, _) => command_result
+    };
+
+    let mut artifacts = vec![];
     if let Some(archives) = &step.archives {
         for archive in archives {
             println!("Getting archive: {}", archive.name);
-            runtime.get_archive(agent_id.as_str(), archive).await
-                .map_err(|e| run_step_error(step.name.as_str(), e))?
+            let manifest = runtime.get_archive(agent_id, archive, &context.artifacts_directory).await
+                .map_err(|e| run_step_error(step.name.as_str(), e))?;
+            artifacts.push(manifest);
         }
     }
 
-    runtime.destroy_agent(agent_id.as_str()).await
+    let report = runtime.collect_step_report(module_name, agent_id).await
         .map_err(|e| run_step_error(step.name.as_str(), e))?;
 
+    // A denied connection usually surfaces as an obscure network error in the command output, so report the cause directly.
+    let command_result = match &report.egress {
+        Some(egress) => {
+            let denied: Vec<String> = egress.iter()
+                .filter(|record| record.denied_connections > 0)
+                .map(|record| format!("{}:{}", record.host, record.port))
+                .collect();
+
+            if denied.is_empty() {
+                command_result
+            } else {
+                Err(BuildError { msg: format!("Failed to run step [{}]: egress denied to [{}]", step.name, denied.join(", ")) })
+            }
+        },
+        None => command_result
+    };
+
+    summary.steps.push(StepSummary {
+        module_name: module_name.clone(),
+        step_name: step.name.clone(),
+        succeeded: command_result.is_ok(),
+        report,
+        artifacts,
+        images,
+        pushed: vec![],
+    });
+
     command_result
 }
 
+// Image builds run through the runtime's own engine, there is no agent to create.
+async fn run_build_image_step(step: &Step, rule: &BuildImageRule, module_name: &String, runtime: &mut Box<dyn BuildRuntime>, summary: &mut BuildSummary) -> Result<(), BuildError> {
+    println!("Building image {}", rule.name);
+    let build_result = runtime.build_image(module_name, rule).await
+        .map_err(|e| run_step_error(step.name.as_str(), e));
+
+    summary.steps.push(StepSummary {
+        module_name: module_name.clone(),
+        step_name: step.name.clone(),
+        succeeded: build_result.is_ok(),
+        report: StepReport::default(),
+        artifacts: vec![],
+        images: build_result.iter().cloned().collect(),
+        pushed: vec![],
+    });
+
+    build_result.map(|_| ())
+}
+
+async fn run_push_image_step(step: &Step, rule: &PushImageRule, module_name: &String, runtime: &mut Box<dyn BuildRuntime>, summary: &mut BuildSummary) -> Result<(), BuildError> {
+    let environment = step.environment.as_ref().map(|environment| environment.as_str());
+    let push_result = runtime.push_image(module_name, rule, environment).await
+        .map_err(|e| run_step_error(step.name.as_str(), e));
+
+    summary.steps.push(StepSummary {
+        module_name: module_name.clone(),
+        step_name: step.name.clone(),
+        succeeded: push_result.is_ok(),
+        report: StepReport::default(),
+        artifacts: vec![],
+        images: vec![],
+        pushed: push_result.as_ref().map(|pushed| pushed.clone()).unwrap_or_default(),
+    });
+
+    push_result.map(|_| ())
+}
+
+async fn restore_input<'a>(input: &StepInput, module_name: &String, agent_id: &str, runtime: &mut Box<dyn BuildRuntime>, context: &BuildContext<'a>, summary: &BuildSummary) -> Result<(), BuildError> {
+    let target_path = input.path.as_ref().map(|p| p.as_str()).unwrap_or(".");
+
+    let archive_path = match input.from.as_ref().unwrap_or(&InputSource::CurrentBuild) {
+        InputSource::CurrentBuild => {
+            let manifest = find_current_build_input(input, module_name.as_str(), summary)?;
+
+            context.artifacts_directory.join(manifest.output.as_str())
+        },
+        InputSource::PreviousBuild => {
+            let store = context.artifact_store.as_ref()
+                .ok_or(BuildError { msg: format!("Input [{}] needs an artifact store to restore from a previous build", input.artifact) })?;
+            let project_id = context.project_config.build_config.project_id.as_str();
+
+            let build = artifact_store::latest_successful_build(store, project_id).await
+                .map_err(|e| BuildError { msg: format!("Failed to find a previous build for input [{}]: {}", input.artifact, e) })?
+                .ok_or(BuildError { msg: format!("Input [{}] needs a previous successful build of [{}]", input.artifact, project_id) })?;
+
+            let artifacts = store.list(&ArtifactFilter {
+                project_id: project_id.to_string(),
+                build_id: Some(build.build_id.clone()),
+                module_name: Some(input.module.clone().unwrap_or(module_name.clone())),
+                step_name: input.step.clone(),
+                name: Some(input.artifact.clone()),
+            }).await
+                .map_err(|e| BuildError { msg: format!("Failed to find input [{}]: {}", input.artifact, e) })?;
+
+            let artifact = artifacts.first()
+                .ok_or(BuildError { msg: format!("Input [{}] not found in build [{}]", input.artifact, build.build_id) })?;
+
+            let inputs_directory = context.artifacts_directory.join("inputs");
+            std::fs::create_dir_all(&inputs_directory)
+                .map_err(|e| BuildError { msg: format!("Failed to create [{}]: {}", inputs_directory.display(), e) })?;
+
+            let file_name = std::path::Path::new(artifact.file_name.as_str()).file_name()
+                .ok_or(BuildError { msg: format!("Input [{}] has no file name in the artifact store", input.artifact) })?;
+            let destination = inputs_directory.join(file_name);
+            store.fetch(artifact, &destination).await
+                .map_err(|e| BuildError { msg: format!("Failed to fetch input [{}]: {}", input.artifact, e) })?;
+
+            destination
+        },
+    };
+
+    println!("Restoring input {} to {}", input.artifact, target_path);
+
+    let archive = artifacts::to_upload_archive(&archive_path)
+        .map_err(|e| BuildError { msg: format!("Failed to prepare input [{}]: {}", input.artifact, e) })?;
+
+    runtime.restore_input(agent_id, archive, target_path).await
+        .map_err(|e| BuildError { msg: format!("Failed to restore input [{}]: {}", input.artifact, e) })
+}
+
+fn new_build_id() -> String {
+    let suffix: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(6)
+        .collect();
+
+    format!("{}-{}", Utc::now().format("%Y%m%d%H%M%S"), suffix.to_lowercase())
+}
+
+// Without an explicit module, an artifact from this module is preferred over one with the same name elsewhere. Within a
+// module the most recent step which produced the artifact wins.
+fn find_current_build_input<'a>(input: &StepInput, module_name: &str, summary: &'a BuildSummary) -> Result<&'a ArtifactManifest, BuildError> {
+    let matches_input = |step: &&StepSummary, module: &str| {
+        step.module_name == module && input.step.as_ref().map_or(true, |s| s == &step.step_name)
+    };
+
+    let candidate_modules = match &input.module {
+        Some(module) => vec![module.clone()],
+        None => {
+            let mut modules = vec![module_name.to_string()];
+            for step in &summary.steps {
+                if !modules.contains(&step.module_name) {
+                    modules.push(step.module_name.clone());
+                }
+            }
+            modules
+        }
+    };
+
+    candidate_modules.iter()
+        .filter_map(|module| {
+            summary.steps.iter().rev()
+                .filter(|step| matches_input(step, module.as_str()))
+                .flat_map(|step| step.artifacts.iter())
+                .find(|artifact| artifact.name == input.artifact)
+        })
+        .next()
+        .ok_or(BuildError { msg: format!("Input [{}] was not produced earlier in this build", input.artifact) })
+}
+
 fn run_step_error(step_name: &str, bre: BuildRuntimeError) -> BuildError {
     BuildError { msg: format!("Failed to run step [{}]: {}", step_name, bre) }
 }
@@ -232,3 +713,178 @@ fn get_default_agent(agents: &Vec<Agent>) -> Result<Option<String>, &'static str
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::artifacts::ArtifactManifest;
+    use crate::config::StepInput;
+    use crate::runtime::StepReport;
+    use crate::summary::{BuildSummary, StepSummary};
+    use crate::OutputFormatter;
+    use crate::config::{BuildConfig, BuildImageRetention, ProjectConfig};
+    use crate::runtime::BuildRuntime;
+    use crate::runtime::fake_runtime::FakeRuntime;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use super::{build_modules, find_current_build_input, keep_build_images, BuildContext, BuildOptions};
+
+    fn step(module_name: &str, step_name: &str, output: &str) -> StepSummary {
+        StepSummary {
+            module_name: module_name.to_string(),
+            step_name: step_name.to_string(),
+            succeeded: true,
+            report: StepReport::default(),
+            artifacts: vec![ArtifactManifest {
+                name: "dist".to_string(),
+                output: output.to_string(),
+                files: vec![],
+            }],
+            images: vec![],
+            pushed: vec![],
+        }
+    }
+
+    fn input(module: Option<&str>, step: Option<&str>) -> StepInput {
+        StepInput {
+            artifact: "dist".to_string(),
+            module: module.map(|module| module.to_string()),
+            step: step.map(|step| step.to_string()),
+            from: None,
+            path: None,
+        }
+    }
+
+    #[test]
+    fn inputs_prefer_the_latest_artifact_from_the_same_module() {
+        let mut summary = BuildSummary::new("1".to_string());
+        summary.steps.push(step("web", "build", "web-first.tar"));
+        summary.steps.push(step("api", "build", "api.tar"));
+        summary.steps.push(step("web", "package", "web-second.tar"));
+
+        assert_eq!("web-second.tar", find_current_build_input(&input(None, None), "web", &summary).unwrap().output);
+        assert_eq!("api.tar", find_current_build_input(&input(None, None), "api", &summary).unwrap().output);
+        assert_eq!("web-first.tar", find_current_build_input(&input(None, Some("build")), "web", &summary).unwrap().output);
+        assert_eq!("api.tar", find_current_build_input(&input(Some("api"), None), "web", &summary).unwrap().output);
+        assert_eq!("web-second.tar", find_current_build_input(&input(None, None), "docs", &summary).unwrap().output);
+        assert!(find_current_build_input(&input(Some("docs"), None), "web", &summary).is_err());
+    }
+
+    #[test]
+    fn build_images_are_removed_unless_the_project_keeps_them() {
+        for succeeded in vec![true, false] {
+            assert!(!keep_build_images(None, succeeded));
+            assert!(!keep_build_images(Some(BuildImageRetention::Remove), succeeded));
+            assert!(keep_build_images(Some(BuildImageRetention::Keep), succeeded));
+        }
+
+        assert!(!keep_build_images(Some(BuildImageRetention::KeepOnFailure), true));
+        assert!(keep_build_images(Some(BuildImageRetention::KeepOnFailure), false));
+    }
+
+    const PROJECT: &str = "api_version: 0.1
+project_id: test
+modules:
+  - name: web
+    agents:
+      - name: builder
+        default: true
+        image: alpine
+    steps:
+      - name: build
+        command: make
+        archives:
+          - name: dist
+            paths: [dist]
+";
+
+    struct QuietOutputFormatter;
+
+    impl OutputFormatter for QuietOutputFormatter {
+        fn print(&self, _msg: String) {}
+
+        fn success(&self, _msg: String) {}
+
+        fn error(&self, _msg: String) {}
+
+        fn background(&self, _msg: String) {}
+    }
+
+    // Builds the test project against a runtime which fails the calls named in `failing`, returning how the build ended
+    // and every call it made.
+    async fn run_failing_build(failing: Vec<&'static str>) -> (Result<(), String>, Vec<String>) {
+        let project_config = ProjectConfig {
+            project_directory: PathBuf::from("project"),
+            workspace_directory: PathBuf::from("project"),
+            jarvis_directory: PathBuf::from("project/.jarvis"),
+            build_config: serde_yaml::from_str::<BuildConfig>(PROJECT).unwrap(),
+        };
+        let build_options = BuildOptions::default();
+        let context = BuildContext {
+            project_config: &project_config,
+            build_options: &build_options,
+            artifacts_directory: PathBuf::from("project/.jarvis/artifacts"),
+            artifact_store: None,
+        };
+
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut runtime: Box<dyn BuildRuntime> = Box::new(FakeRuntime { calls: calls.clone(), failing });
+        let mut summary = BuildSummary::new("1".to_string());
+
+        let result = build_modules(&context, &mut runtime, &mut summary, &QuietOutputFormatter).await;
+        let calls = calls.lock().unwrap().clone();
+        (result.map_err(|e| e.msg), calls)
+    }
+
+    fn calls(names: Vec<&str>) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn agents_are_destroyed_when_their_command_fails() {
+        let (result, made) = run_failing_build(vec!["execute_command"]).await;
+
+        assert!(result.unwrap_err().contains("[execute_command] failed"));
+        assert_eq!(calls(vec!["init_for_module", "create_agent", "execute_command", "get_archive", "collect_step_report", "destroy_agent", "tear_down_for_module"]), made);
+    }
+
+    #[tokio::test]
+    async fn agents_are_destroyed_when_their_archives_cannot_be_collected() {
+        let (result, made) = run_failing_build(vec!["get_archive"]).await;
+
+        assert!(result.unwrap_err().contains("[get_archive] failed"));
+        assert_eq!(calls(vec!["init_for_module", "create_agent", "execute_command", "get_archive", "destroy_agent", "tear_down_for_module"]), made);
+    }
+
+    #[tokio::test]
+    async fn cleanup_failures_never_hide_why_the_build_failed() {
+        let (result, made) = run_failing_build(vec!["get_archive", "destroy_agent", "tear_down_for_module"]).await;
+
+        assert!(result.unwrap_err().contains("[get_archive] failed"));
+        assert_eq!(calls(vec!["init_for_module", "create_agent", "execute_command", "get_archive", "destroy_agent", "tear_down_for_module"]), made);
+    }
+
+    #[tokio::test]
+    async fn cleanup_failures_fail_an_otherwise_successful_build() {
+        let (result, _) = run_failing_build(vec!["destroy_agent"]).await;
+        assert!(result.unwrap_err().contains("[destroy_agent] failed"));
+
+        let (result, _) = run_failing_build(vec!["tear_down_for_module"]).await;
+        assert!(result.unwrap_err().contains("[tear_down_for_module] failed"));
+    }
+
+    #[tokio::test]
+    async fn no_agent_is_destroyed_when_none_was_created() {
+        let (result, made) = run_failing_build(vec!["create_agent"]).await;
+
+        assert!(result.unwrap_err().contains("[create_agent] failed"));
+        assert_eq!(calls(vec!["init_for_module", "create_agent", "tear_down_for_module"]), made);
+    }
+
+    #[tokio::test]
+    async fn modules_are_torn_down_when_they_fail_to_initialise() {
+        let (result, made) = run_failing_build(vec!["init_for_module", "tear_down_for_module"]).await;
+
+        assert!(result.unwrap_err().contains("[init_for_module] failed"));
+        assert_eq!(calls(vec!["init_for_module", "tear_down_for_module"]), made);
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::http_cache::HttpRemoteCache;
+use crate::cache::s3_cache::S3RemoteCache;
+use crate::config::{BuildConfig, get_project_config, jarvis_home_directory, RemoteCacheConfig};
+use crate::runtime::BuildRuntime;
+use crate::OutputFormatter;
+
+pub mod http_cache;
+pub mod s3_cache;
+
+const METADATA_ENTRY: &str = "cache.json";
+
+// Shares cache archives between machines. Archives are addressed by the cache's identity and key, so a pull only ever
+// returns content for exactly the key being asked for.
+#[async_trait]
+pub trait RemoteCache {
+    // Returns `false` when the remote doesn't have an archive with this name.
+    async fn pull(&self, object_name: &str, destination: &PathBuf) -> Result<bool, CacheError>;
+
+    async fn push(&self, object_name: &str, archive: &PathBuf) -> Result<(), CacheError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheError {
+    msg: String
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "cache error: {}", self.msg)
+    }
+}
+
+impl Error for CacheError {}
+
+#[derive(Debug, Clone)]
+pub struct CacheInfo {
+    pub volume_name: String,
+
+    pub project_id: String,
+
+    pub module_name: String,
+
+    pub name: String,
+
+    pub key: String,
+
+    pub size: Option<u64>,
+
+    pub created: String,
+
+    pub last_used: Option<String>,
+
+    pub in_use: bool,
+}
+
+// Identifies a cache independently of the volume holding it, this is written into exported archives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    pub project_id: String,
+
+    pub module_name: String,
+
+    pub name: String,
+
+    pub key: String,
+}
+
+impl CacheMetadata {
+    pub fn volume_name(&self) -> String {
+        volume_name(format!("{}_{}", self.project_id, self.module_name).as_str(), self.name.as_str(), self.key.as_str())
+    }
+
+    pub fn remote_object_name(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input_str(self.key.as_str());
+
+        let segment = |value: &str| value.replace(|c| c == '/' || c == '\\', "_");
+        format!("{}/{}/{}/{}.tgz", segment(self.project_id.as_str()), segment(self.module_name.as_str()), segment(self.name.as_str()), hasher.result_str())
+    }
+}
+
+impl CacheInfo {
+    pub fn metadata(&self) -> CacheMetadata {
+        CacheMetadata {
+            project_id: self.project_id.clone(),
+            module_name: self.module_name.clone(),
+            name: self.name.clone(),
+            key: self.key.clone(),
+        }
+    }
+
+    fn last_activity(&self) -> DateTime<Utc> {
+        let time = self.last_used.as_ref().unwrap_or(&self.created);
+        DateTime::parse_from_rfc3339(time.as_str())
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or(Utc::now())
+    }
+}
+
+// Expands the `{{ ... }}` expressions in a cache key template. The only supported expression is `hashFiles('pattern', ...)`
+// which is replaced by a digest of every file in the project matching any of the patterns.
+pub fn render_cache_key(template: &str, project_directory: &PathBuf) -> Result<String, CacheError> {
+    let expression_pattern = Regex::new(r"\{\{\s*(.*?)\s*\}\}").unwrap();
+    let hash_files_pattern = Regex::new(r"^hashFiles\((.*)\)$").unwrap();
+
+    let mut key = String::new();
+    let mut last_end = 0;
+    for expression in expression_pattern.captures_iter(template) {
+        let whole = expression.get(0).unwrap();
+        key.push_str(&template[last_end..whole.start()]);
+        last_end = whole.end();
+
+        let arguments = hash_files_pattern.captures(&expression[1])
+            .ok_or(CacheError { msg: format!("Unsupported expression [{}] in cache key [{}]", &expression[1], template) })?;
+
+        let patterns = parse_arguments(&arguments[1])
+            .ok_or(CacheError { msg: format!("Invalid arguments to hashFiles in cache key [{}]", template) })?;
+
+        key.push_str(hash_files(&patterns, project_directory)?.as_str());
+    }
+    key.push_str(&template[last_end..]);
+
+    Ok(key)
+}
+
+// Volume names only allow a limited character set, so keys are identified by a digest and the full key is kept in a label.
+pub fn volume_name(identifier_base: &str, cache_name: &str, key: &str) -> String {
+    let name = if key.is_empty() {
+        format!("cache_{}_{}", identifier_base, cache_name)
+    } else {
+        let mut hasher = Sha256::new();
+        hasher.input_str(key);
+        format!("cache_{}_{}_{}", identifier_base, cache_name, &hasher.result_str()[..16])
+    };
+
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' { c } else { '-' })
+        .collect()
+}
+
+// Volume labels can't be changed after creation, so the time each cache was last mounted is tracked in the Jarvis home
+// directory instead.
+pub fn record_cache_use(volume_names: &Vec<String>) -> Result<(), CacheError> {
+    if volume_names.is_empty() {
+        return Ok(());
+    }
+
+    let mut usage = read_usage()?;
+    let now = Utc::now().to_rfc3339();
+    for volume_name in volume_names {
+        usage.insert(volume_name.clone(), now.clone());
+    }
+
+    write_usage(&usage)
+}
+
+fn usage_path() -> Result<PathBuf, CacheError> {
+    jarvis_home_directory()
+        .map(|home| home.join("caches").join("usage.json"))
+        .map_err(|e| CacheError { msg: format!("{}", e) })
+}
+
+fn read_usage() -> Result<HashMap<String, String>, CacheError> {
+    let path = usage_path()?;
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = fs::read_to_string(&path)
+        .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+    serde_json::from_str(content.as_str())
+        .map_err(|e| CacheError { msg: format!("Invalid cache usage file [{}]: {}", path.display(), e) })
+}
+
+fn write_usage(usage: &HashMap<String, String>) -> Result<(), CacheError> {
+    let path = usage_path()?;
+    fs::create_dir_all(path.parent().unwrap())
+        .map_err(|e| CacheError { msg: format!("Failed to create [{}]: {}", path.display(), e) })?;
+
+    let content = serde_json::to_string_pretty(usage)
+        .map_err(|e| CacheError { msg: format!("Failed to serialize cache usage: {}", e) })?;
+    fs::write(&path, content)
+        .map_err(|e| CacheError { msg: format!("Failed to write [{}]: {}", path.display(), e) })
+}
+
+// Lists the caches owned by the project at `project_path`, or every cache Jarvis has created when no project is given.
+pub async fn list_caches(mut runtime: Box<dyn BuildRuntime>, project_path: Option<PathBuf>) -> Result<Vec<CacheInfo>, CacheError> {
+    runtime.connect();
+
+    find_caches(&runtime, project_path).await
+}
+
+pub async fn inspect_cache(mut runtime: Box<dyn BuildRuntime>, volume_name: &str) -> Result<CacheInfo, CacheError> {
+    runtime.connect();
+
+    find_caches(&runtime, None).await?
+        .into_iter()
+        .find(|cache| cache.volume_name == volume_name)
+        .ok_or(CacheError { msg: format!("No cache volume named [{}]", volume_name) })
+}
+
+pub async fn prune_caches(mut runtime: Box<dyn BuildRuntime>, project_path: Option<PathBuf>, max_age_days: Option<i64>, max_size: Option<u64>, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<CacheInfo>, CacheError> {
+    if max_age_days.is_none() && max_size.is_none() {
+        return Err(CacheError { msg: "Either a maximum age or a maximum size is required to prune caches".to_string() });
+    }
+
+    runtime.connect();
+
+    let caches = find_caches(&runtime, project_path).await?;
+    let selected = select_for_pruning(&caches, max_age_days, max_size, Utc::now());
+
+    remove_caches(&runtime, selected, output_formatter).await
+}
+
+pub async fn clear_caches(mut runtime: Box<dyn BuildRuntime>, project_path: Option<PathBuf>, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<CacheInfo>, CacheError> {
+    runtime.connect();
+
+    let caches = find_caches(&runtime, project_path).await?;
+
+    remove_caches(&runtime, caches, output_formatter).await
+}
+
+async fn find_caches(runtime: &Box<dyn BuildRuntime>, project_path: Option<PathBuf>) -> Result<Vec<CacheInfo>, CacheError> {
+    let project_id = match project_path {
+        Some(project_path) => {
+            let project_config = get_project_config(project_path)
+                .map_err(|e| CacheError { msg: format!("Project configuration error: {}", e) })?;
+            Some(project_config.build_config.project_id)
+        },
+        None => None
+    };
+
+    let usage = read_usage()?;
+
+    let mut caches: Vec<CacheInfo> = runtime.list_caches().await
+        .map_err(|e| CacheError { msg: format!("{}", e) })?
+        .into_iter()
+        .filter(|cache| project_id.as_ref().map_or(true, |id| &cache.project_id == id))
+        .map(|mut cache| {
+            cache.last_used = usage.get(&cache.volume_name).cloned();
+            cache
+        })
+        .collect();
+    caches.sort_by(|a, b| (&a.project_id, &a.module_name, &a.name).cmp(&(&b.project_id, &b.module_name, &b.name)));
+
+    Ok(caches)
+}
+
+// Removal is best effort, a cache which is mounted by a running build is reported and left in place.
+async fn remove_caches(runtime: &Box<dyn BuildRuntime>, caches: Vec<CacheInfo>, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<CacheInfo>, CacheError> {
+    let mut removed = vec![];
+    for cache in caches {
+        match runtime.remove_cache(cache.volume_name.as_str()).await {
+            Ok(_) => removed.push(cache),
+            Err(e) => output_formatter.error(format!("Failed to remove cache [{}]: {}", cache.volume_name, e))
+        }
+    }
+
+    let mut usage = read_usage()?;
+    for cache in &removed {
+        usage.remove(&cache.volume_name);
+    }
+    write_usage(&usage)?;
+
+    Ok(removed)
+}
+
+pub async fn export_cache(mut runtime: Box<dyn BuildRuntime>, volume_name: &str, output: &PathBuf) -> Result<CacheInfo, CacheError> {
+    runtime.connect();
+
+    let cache = find_caches(&runtime, None).await?
+        .into_iter()
+        .find(|cache| cache.volume_name == volume_name)
+        .ok_or(CacheError { msg: format!("No cache volume named [{}]", volume_name) })?;
+
+    let volume_tar = temp_path("jarvis-cache-export", "tar");
+    let export_result = runtime.export_cache(volume_name, &volume_tar).await
+        .map_err(|e| CacheError { msg: format!("{}", e) })
+        .and_then(|_| pack_cache_archive(&cache.metadata(), &volume_tar, output));
+
+    if volume_tar.exists() {
+        fs::remove_file(&volume_tar)
+            .map_err(|e| CacheError { msg: format!("Failed to remove [{}]: {}", volume_tar.display(), e) })?;
+    }
+
+    export_result.map(|_| cache)
+}
+
+// Imports into the volume the cache would have had on the machine it was exported from, replacing matching files.
+pub async fn import_cache(mut runtime: Box<dyn BuildRuntime>, archive: &PathBuf) -> Result<CacheMetadata, CacheError> {
+    runtime.connect();
+
+    let metadata = read_cache_metadata(archive)?;
+
+    runtime.import_cache(metadata.volume_name().as_str(), &metadata, archive).await
+        .map_err(|e| CacheError { msg: format!("{}", e) })?;
+
+    Ok(metadata)
+}
+
+// Cache archives are a gzipped tar holding the metadata followed by the volume content under `cache/`, which is the layout
+// the runtime produces when downloading a volume mounted at `/cache`.
+pub fn pack_cache_archive(metadata: &CacheMetadata, volume_tar: &PathBuf, output: &PathBuf) -> Result<(), CacheError> {
+    let file = File::create(output)
+        .map_err(|e| CacheError { msg: format!("Failed to create [{}]: {}", output.display(), e) })?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    let content = serde_json::to_vec_pretty(metadata)
+        .map_err(|e| CacheError { msg: format!("Failed to serialize cache metadata: {}", e) })?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    builder.append_data(&mut header, METADATA_ENTRY, content.as_slice())
+        .map_err(|e| CacheError { msg: format!("Failed to write cache metadata: {}", e) })?;
+
+    let volume_file = File::open(volume_tar)
+        .map_err(|e| CacheError { msg: format!("Failed to open [{}]: {}", volume_tar.display(), e) })?;
+    let mut volume_archive = tar::Archive::new(volume_file);
+    let entries = volume_archive.entries()
+        .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", volume_tar.display(), e) })?;
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", volume_tar.display(), e) })?;
+        let path = entry.path()
+            .map(|p| p.to_path_buf())
+            .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", volume_tar.display(), e) })?;
+
+        let mut header = entry.header().clone();
+        builder.append_data(&mut header, &path, &mut entry)
+            .map_err(|e| CacheError { msg: format!("Failed to write [{}] to cache archive: {}", path.display(), e) })?;
+    }
+
+    builder.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map(|_| ())
+        .map_err(|e| CacheError { msg: format!("Failed to finish [{}]: {}", output.display(), e) })
+}
+
+pub fn read_cache_metadata(archive: &PathBuf) -> Result<CacheMetadata, CacheError> {
+    let file = File::open(archive)
+        .map_err(|e| CacheError { msg: format!("Failed to open [{}]: {}", archive.display(), e) })?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+
+    let mut entries = tar.entries()
+        .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", archive.display(), e) })?;
+
+    // The metadata is always written first, so there's no need to read through the content.
+    let mut entry = entries.next()
+        .ok_or(CacheError { msg: format!("[{}] is empty", archive.display()) })?
+        .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", archive.display(), e) })?;
+
+    let is_metadata = entry.path().map_or(false, |p| p.to_string_lossy() == METADATA_ENTRY);
+    if !is_metadata {
+        return Err(CacheError { msg: format!("[{}] is not a cache archive", archive.display()) });
+    }
+
+    let mut content = String::new();
+    entry.read_to_string(&mut content)
+        .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", archive.display(), e) })?;
+
+    serde_json::from_str(content.as_str())
+        .map_err(|e| CacheError { msg: format!("Invalid cache metadata in [{}]: {}", archive.display(), e) })
+}
+
+pub fn create_remote_cache(build_config: &BuildConfig) -> Result<Option<Box<dyn RemoteCache + Send + Sync>>, CacheError> {
+    match &build_config.remote_cache {
+        Some(RemoteCacheConfig::Http { url, token_env }) => {
+            let token = match token_env {
+                Some(token_env) => Some(std::env::var(token_env)
+                    .map_err(|_| CacheError { msg: format!("[{}] must be set to use the remote cache", token_env) })?),
+                None => None
+            };
+
+            Ok(Some(Box::new(HttpRemoteCache::new(url.as_str(), token))))
+        },
+        Some(RemoteCacheConfig::S3 { endpoint, bucket, region, prefix }) => {
+            let cache = S3RemoteCache::new(endpoint.as_str(), bucket.as_str(), region.as_ref(), prefix.clone())?;
+
+            Ok(Some(Box::new(cache)))
+        },
+        None => Ok(None)
+    }
+}
+
+pub fn temp_path(prefix: &str, extension: &str) -> PathBuf {
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(30)
+        .collect();
+
+    std::env::temp_dir().join(format!("{}-{}.{}", prefix, id, extension))
+}
+
+// Removes anything not used within the age limit, then the least recently used caches until the rest fit the size budget.
+fn select_for_pruning(caches: &Vec<CacheInfo>, max_age_days: Option<i64>, max_size: Option<u64>, now: DateTime<Utc>) -> Vec<CacheInfo> {
+    let mut candidates: Vec<&CacheInfo> = caches.iter().filter(|cache| !cache.in_use).collect();
+    candidates.sort_by(|a, b| a.last_activity().cmp(&b.last_activity()));
+
+    let mut selected = vec![];
+    let mut total_size: u64 = caches.iter().map(|cache| cache.size.unwrap_or(0)).sum();
+    for cache in candidates {
+        let expired = max_age_days.map_or(false, |days| now - cache.last_activity() > Duration::days(days));
+        let over_budget = max_size.map_or(false, |max_size| total_size > max_size);
+
+        if expired || over_budget {
+            total_size -= cache.size.unwrap_or(0);
+            selected.push(cache.clone());
+        }
+    }
+
+    selected
+}
+
+fn parse_arguments(arguments: &str) -> Option<Vec<String>> {
+    let mut patterns = vec![];
+    for argument in arguments.split(',') {
+        let argument = argument.trim();
+        let quoted = argument.len() >= 2
+            && ((argument.starts_with('\'') && argument.ends_with('\'')) || (argument.starts_with('"') && argument.ends_with('"')));
+        if !quoted {
+            return None;
+        }
+        patterns.push(argument[1..argument.len() - 1].to_string());
+    }
+
+    Some(patterns)
+}
+
+fn hash_files(patterns: &Vec<String>, project_directory: &PathBuf) -> Result<String, CacheError> {
+    let glob_set = build_glob_set(patterns)?;
+
+    let mut files = vec![];
+    find_files(project_directory, "", &glob_set, &mut files)?;
+    if files.is_empty() {
+        return Err(CacheError { msg: format!("hashFiles({}) did not match any files", patterns.join(", ")) });
+    }
+
+    // Sorted so the digest doesn't depend on the order the file system lists entries in.
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        let content = fs::read(project_directory.join(file.as_str()))
+            .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", file, e) })?;
+        hasher.input_str(file.as_str());
+        hasher.input(content.as_slice());
+    }
+
+    Ok(hasher.result_str())
+}
+
+fn find_files(directory: &PathBuf, relative_path: &str, glob_set: &GlobSet, files: &mut Vec<String>) -> Result<(), CacheError> {
+    let entries = fs::read_dir(directory)
+        .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", directory.display(), e) })?;
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let path = if relative_path.is_empty() {
+            file_name.clone()
+        } else {
+            format!("{}/{}", relative_path, file_name)
+        };
+
+        if entry.path().is_dir() {
+            // Build outputs and version control metadata shouldn't affect cache keys.
+            if relative_path.is_empty() && (file_name == ".git" || file_name == ".jarvis") {
+                continue;
+            }
+            find_files(&entry.path(), path.as_str(), glob_set, files)?;
+        } else if glob_set.is_match(path.as_str()) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn build_glob_set(patterns: &Vec<String>) -> Result<GlobSet, CacheError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern.trim_start_matches("./"))
+            .literal_separator(true)
+            .build()
+            .map_err(|e| CacheError { msg: format!("Invalid pattern [{}]: {}", pattern, e) })?;
+        builder.add(glob);
+    }
+
+    builder.build()
+        .map_err(|e| CacheError { msg: format!("Invalid patterns: {}", e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::{CacheInfo, CacheMetadata, pack_cache_archive, parse_arguments, read_cache_metadata, select_for_pruning, temp_path, volume_name};
+
+    #[test]
+    fn cache_archives_round_trip() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "cache/registry/index", "index".as_bytes()).unwrap();
+        let volume_tar = temp_path("jarvis-cache-test", "tar");
+        std::fs::write(&volume_tar, builder.into_inner().unwrap()).unwrap();
+
+        let metadata = CacheMetadata {
+            project_id: "project".to_string(),
+            module_name: "module".to_string(),
+            name: "cargo".to_string(),
+            key: "abc".to_string(),
+        };
+        let archive = temp_path("jarvis-cache-test", "tgz");
+        pack_cache_archive(&metadata, &volume_tar, &archive).unwrap();
+
+        let read = read_cache_metadata(&archive).unwrap();
+        assert_eq!(metadata.volume_name(), read.volume_name());
+        assert_eq!(metadata.remote_object_name(), read.remote_object_name());
+
+        let mut packed = tar::Archive::new(GzDecoder::new(std::fs::File::open(&archive).unwrap()));
+        let mut content = String::new();
+        packed.entries().unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap().to_string_lossy() == "cache/registry/index")
+            .unwrap()
+            .read_to_string(&mut content).unwrap();
+        assert_eq!("index", content);
+
+        std::fs::remove_file(volume_tar).unwrap();
+        std::fs::remove_file(archive).unwrap();
+    }
+
+    #[test]
+    fn parses_quoted_arguments() {
+        assert_eq!(Some(vec!["Cargo.lock".to_string(), "**/Cargo.toml".to_string()]), parse_arguments("'Cargo.lock', \"**/Cargo.toml\""));
+        assert_eq!(None, parse_arguments("Cargo.lock"));
+    }
+
+    #[test]
+    fn unkeyed_caches_keep_their_volume_name() {
+        assert_eq!("cache_module_cargo", volume_name("module", "cargo", ""));
+        assert_ne!(volume_name("module", "cargo", "a"), volume_name("module", "cargo", "b"));
+        assert_eq!("cache_my-project_module_cargo", volume_name("my/project_module", "cargo", ""));
+    }
+
+    #[test]
+    fn prunes_least_recently_used_caches_first() {
+        let now = Utc::now();
+        let cache = |name: &str, days_ago: i64, size: u64, in_use: bool| CacheInfo {
+            volume_name: name.to_string(),
+            project_id: "project".to_string(),
+            module_name: "module".to_string(),
+            name: name.to_string(),
+            key: "".to_string(),
+            size: Some(size),
+            created: (now - Duration::days(100)).to_rfc3339(),
+            last_used: Some((now - Duration::days(days_ago)).to_rfc3339()),
+            in_use,
+        };
+        let caches = vec![cache("recent", 1, 300, false), cache("old", 30, 200, false), cache("busy", 60, 500, true)];
+
+        let names = |selected: Vec<CacheInfo>| selected.into_iter().map(|c| c.volume_name).collect::<Vec<String>>();
+
+        assert_eq!(vec!["old"], names(select_for_pruning(&caches, Some(7), None, now)));
+        assert_eq!(vec!["old"], names(select_for_pruning(&caches, None, Some(850), now)));
+        assert_eq!(vec!["old", "recent"], names(select_for_pruning(&caches, None, Some(100), now)));
+    }
+}
@@ -0,0 +1,148 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+
+use crate::cache::{CacheError, RemoteCache};
+
+// Stores archives with plain GET and PUT requests under a base URL, which most artifact servers and simple file servers
+// support without any extra configuration.
+pub struct HttpRemoteCache {
+    base_url: String,
+
+    token: Option<String>,
+
+    client: reqwest::Client,
+}
+
+impl HttpRemoteCache {
+    pub fn new(base_url: &str, token: Option<String>) -> Self {
+        HttpRemoteCache {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, object_name: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}/{}", self.base_url, object_name).as_str());
+
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteCache for HttpRemoteCache {
+    async fn pull(&self, object_name: &str, destination: &PathBuf) -> Result<bool, CacheError> {
+        let response = self.request(reqwest::Method::GET, object_name).send().await
+            .map_err(|e| CacheError { msg: format!("Request for [{}] failed: {}", object_name, e) })?;
+
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !status.is_success() {
+            return Err(CacheError { msg: format!("Request for [{}] failed with status [{}]", object_name, status) });
+        }
+
+        let content = response.bytes().await
+            .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", object_name, e) })?;
+
+        tokio::fs::write(destination, content).await
+            .map(|_| true)
+            .map_err(|e| CacheError { msg: format!("Failed to write [{}]: {}", destination.display(), e) })
+    }
+
+    async fn push(&self, object_name: &str, archive: &PathBuf) -> Result<(), CacheError> {
+        let content = tokio::fs::read(archive).await
+            .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", archive.display(), e) })?;
+
+        let response = self.request(reqwest::Method::PUT, object_name).body(content).send().await
+            .map_err(|e| CacheError { msg: format!("Upload of [{}] failed: {}", object_name, e) })?;
+
+        if !response.status().is_success() {
+            return Err(CacheError { msg: format!("Upload of [{}] failed with status [{}]", object_name, response.status()) });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::cache::RemoteCache;
+    use super::HttpRemoteCache;
+
+    // A stand-in for a remote cache server which keeps uploaded objects in memory.
+    fn start_stand_in_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut objects: HashMap<String, Vec<u8>> = HashMap::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let parts: Vec<&str> = request_line.split_whitespace().collect();
+
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if header.to_ascii_lowercase().starts_with("content-length:") {
+                        content_length = header[15..].trim().parse().unwrap();
+                    }
+                }
+
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let response = if parts[0] == "PUT" {
+                    objects.insert(parts[1].to_string(), body);
+                    b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                } else if let Some(object) = objects.get(parts[1]) {
+                    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", object.len()).into_bytes();
+                    response.extend(object);
+                    response
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                };
+                stream.write_all(response.as_slice()).unwrap();
+            }
+        });
+
+        format!("http://{}/cache", address)
+    }
+
+    #[tokio::test]
+    async fn round_trips_archives_through_a_stand_in_server() {
+        let cache = HttpRemoteCache::new(start_stand_in_server().as_str(), None);
+
+        let archive = std::env::temp_dir().join("jarvis-http-cache-test-upload.tgz");
+        let download = std::env::temp_dir().join("jarvis-http-cache-test-download.tgz");
+        std::fs::write(&archive, b"cache content").unwrap();
+
+        assert!(!cache.pull("project/module/cargo/abc.tgz", &download).await.unwrap());
+
+        cache.push("project/module/cargo/abc.tgz", &archive).await.unwrap();
+        assert!(cache.pull("project/module/cargo/abc.tgz", &download).await.unwrap());
+        assert_eq!(b"cache content".to_vec(), std::fs::read(&download).unwrap());
+
+        std::fs::remove_file(archive).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+}
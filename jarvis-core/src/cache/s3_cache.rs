@@ -0,0 +1,56 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use crate::cache::{CacheError, RemoteCache};
+use crate::s3::{S3Client, S3Error};
+
+pub struct S3RemoteCache {
+    client: S3Client,
+
+    prefix: String,
+}
+
+impl S3RemoteCache {
+    pub fn new(endpoint: &str, bucket: &str, region: Option<&String>, prefix: Option<String>) -> Result<Self, CacheError> {
+        let client = S3Client::new(endpoint, bucket, region)
+            .map_err(to_cache_error)?;
+
+        let prefix = match prefix {
+            Some(prefix) => format!("{}/", prefix.trim_end_matches('/')),
+            None => "caches/".to_string()
+        };
+
+        Ok(S3RemoteCache {
+            client,
+            prefix,
+        })
+    }
+}
+
+#[async_trait]
+impl RemoteCache for S3RemoteCache {
+    async fn pull(&self, object_name: &str, destination: &PathBuf) -> Result<bool, CacheError> {
+        let content = self.client.get_object(format!("{}{}", self.prefix, object_name).as_str()).await
+            .map_err(to_cache_error)?;
+
+        match content {
+            Some(content) => tokio::fs::write(destination, content).await
+                .map(|_| true)
+                .map_err(|e| CacheError { msg: format!("Failed to write [{}]: {}", destination.display(), e) }),
+            None => Ok(false)
+        }
+    }
+
+    async fn push(&self, object_name: &str, archive: &PathBuf) -> Result<(), CacheError> {
+        let content = tokio::fs::read(archive).await
+            .map_err(|e| CacheError { msg: format!("Failed to read [{}]: {}", archive.display(), e) })?;
+
+        self.client.put_object(format!("{}{}", self.prefix, object_name).as_str(), content).await
+            .map_err(to_cache_error)
+    }
+}
+
+fn to_cache_error(e: S3Error) -> CacheError {
+    CacheError { msg: format!("{}", e) }
+}
@@ -7,6 +7,10 @@ use std::error::Error;
 use serde::export::Formatter;
 use std::collections::HashMap;
 
+// Agents run an image built earlier in the same build by naming it `build://<name>` in place of an image reference.
+// `://` can never appear in an image reference, so no registry image is mistaken for a build output.
+pub const BUILD_IMAGE_PREFIX: &str = "build://";
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Agent {
     pub name: String,
@@ -20,6 +24,29 @@ pub struct Agent {
     pub cache: Option<Vec<CacheRule>>,
 
     pub container: Option<ContainerConfiguration>,
+
+    pub pull_policy: Option<PullPolicy>,
+}
+
+impl Agent {
+    // The name of the build output this agent runs, if it doesn't run an image from a registry.
+    pub fn built_image(&self) -> Option<&str> {
+        if self.image.starts_with(BUILD_IMAGE_PREFIX) {
+            Some(&self.image[BUILD_IMAGE_PREFIX.len()..])
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullPolicy {
+    // Pull once per build, so moving tags such as `latest` are refreshed.
+    Always,
+    IfNotPresent,
+    // Only use images which are already present, for offline builds.
+    Never,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -27,6 +54,22 @@ pub struct CacheRule {
     pub name: String,
 
     pub location: String,
+
+    pub key: Option<String>,
+
+    pub restore_keys: Option<Vec<String>>,
+
+    pub mode: Option<CacheMode>,
+
+    // A sensitive cache is overwritten and removed when the module finishes rather than kept for later builds.
+    pub sensitive: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheMode {
+    ReadWrite,
+    ReadOnly,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -35,15 +78,188 @@ pub struct Step {
 
     pub shell: Option<ShellConfig>,
 
-    pub command: String,
+    // One of a command to run in an agent, an image to build or an image to push is required.
+    pub command: Option<String>,
+
+    pub build_image: Option<BuildImageRule>,
+
+    pub push_image: Option<PushImageRule>,
+
+    // Images the step's command leaves in the container engine, registered under build-scoped names for later steps.
+    pub register_images: Option<Vec<RegisterImageRule>>,
+
+    // Containers started before the step's agent and reachable from it by name, such as the application under test.
+    pub services: Option<Vec<ServiceRule>>,
 
     pub agent: Option<String>,
 
-    pub secrets: Option<Vec<String>>,
+    pub secrets: Option<Vec<SecretRule>>,
+
+    // The environment whose secrets the step uses, steps without one can only use secrets outside any environment.
+    pub environment: Option<String>,
 
     pub archives: Option<Vec<ArchiveRule>>,
 
-    pub plugins: Option<Vec<PluginSpecification>>
+    pub plugins: Option<Vec<PluginSpecification>>,
+
+    // Replaces the module's allow list for this step.
+    pub allowed_hosts: Option<Vec<String>>,
+
+    pub inputs: Option<Vec<StepInput>>,
+}
+
+// Builds an image through the container engine from a directory in the workspace, rather than inside an agent.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildImageRule {
+    // The build output name, which agents in later steps refer to as `build://<name>`.
+    pub name: String,
+
+    // Relative to the workspace, defaults to the workspace itself.
+    pub context: Option<String>,
+
+    // Relative to the context, defaults to `Dockerfile`.
+    pub dockerfile: Option<String>,
+
+    pub build_args: Option<HashMap<String, String>>,
+
+    pub target: Option<String>,
+
+    pub tags: Option<Vec<String>>,
+
+    pub cache_from: Option<Vec<String>>,
+}
+
+// Pushes an image built earlier in the build under one or more references.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PushImageRule {
+    // The name given to the image by its `build_image` step.
+    pub image: String,
+
+    // Full references including the registry, such as `ghcr.io/org/app:1.0`. The tag defaults to `latest`.
+    pub to: Vec<String>,
+
+    // A secret with `username` and `password` keys, from the step's environment when it has one. Without it the project's
+    // registries and the user's docker configuration are used.
+    pub secret: Option<String>,
+}
+
+// Exactly one of `image` or `archive` says where the image comes from.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RegisterImageRule {
+    // The name agents and services in later steps refer to the image by, as `build://<name>`.
+    pub name: String,
+
+    // An image the command put into the container engine, such as one it loaded or tagged itself.
+    pub image: Option<String>,
+
+    // An image archive in the workspace, such as one written by buildkit's `--output type=docker,dest=<path>`.
+    pub archive: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceRule {
+    // The host name the agent reaches the service by.
+    pub name: String,
+
+    // An image reference, or `build://<name>` for an image built earlier in the build.
+    pub image: String,
+
+    pub environment: Option<HashMap<String, String>>,
+
+    // Replaces the image's entrypoint, for images built to run something other than a service.
+    pub entrypoint: Option<Vec<String>>,
+
+    // Replaces the image's default command.
+    pub command: Option<Vec<String>>,
+
+    pub ready: Option<ReadinessCheck>,
+}
+
+// The step only starts once every check passes. A port is checked from the service's network, a command runs inside
+// the service.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReadinessCheck {
+    pub port: Option<u16>,
+
+    pub command: Option<String>,
+
+    // In seconds, defaults to 60.
+    pub timeout: Option<u64>,
+}
+
+// A secret is either just its name, which delivers it as a file, or a description of how it should be exposed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SecretRule {
+    Name(String),
+    Detailed(SecretDetails),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SecretDetails {
+    pub name: String,
+
+    // Defaults to a file, unless keys are given in which case only the keys are exposed.
+    #[serde(rename = "as")]
+    pub expose_as: Option<SecretExposure>,
+
+    // Relative paths are inside `/build/secrets`, an absolute path elsewhere becomes a link to the file in there.
+    pub path: Option<String>,
+
+    // The variable holding the value, a file is announced in `<variable>_FILE`.
+    pub variable: Option<String>,
+
+    // Parses the secret as a JSON or YAML object and exposes these keys from it.
+    pub keys: Option<Vec<SecretKeyRule>>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SecretKeyRule {
+    pub key: String,
+
+    #[serde(rename = "as")]
+    pub expose_as: Option<SecretExposure>,
+
+    pub path: Option<String>,
+
+    pub variable: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretExposure {
+    Env,
+    File,
+    Both,
+}
+
+impl SecretRule {
+    pub fn name(&self) -> &str {
+        match self {
+            SecretRule::Name(name) => name.as_str(),
+            SecretRule::Detailed(details) => details.name.as_str()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct StepInput {
+    pub artifact: String,
+
+    pub module: Option<String>,
+
+    pub step: Option<String>,
+
+    pub from: Option<InputSource>,
+
+    pub path: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputSource {
+    CurrentBuild,
+    PreviousBuild,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -64,11 +280,37 @@ pub struct PluginSpecification {
 pub struct ArchiveRule {
     pub name: String,
 
-    pub location: String,
+    pub location: Option<String>,
+
+    pub paths: Option<Vec<String>>,
+
+    pub exclude: Option<Vec<String>>,
+
+    pub extract: Option<bool>,
+
+    pub compression: Option<ArchiveCompression>,
 
     pub output: Option<String>,
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveCompression {
+    Tar,
+    Tgz,
+    Zip,
+}
+
+impl ArchiveCompression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveCompression::Tar => "tar",
+            ArchiveCompression::Tgz => "tgz",
+            ArchiveCompression::Zip => "zip",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Module {
     pub name: String,
@@ -77,7 +319,35 @@ pub struct Module {
 
     pub agents: Option<Vec<Agent>>,
 
-    pub steps: Vec<Step>
+    pub steps: Vec<Step>,
+
+    pub monitor_egress: Option<bool>,
+
+    // Hosts the module's agents may reach, as host names, IP addresses or `*.example.com` for a domain and its
+    // subdomains. Agents are only attached to an internal network whose sole way out is the egress proxy, so HTTP and
+    // HTTPS through the proxy are allowed or denied and logged, while any other connection has no route out at all.
+    pub allowed_hosts: Option<Vec<String>>,
+
+    pub checkouts: Option<Vec<CheckoutRule>>,
+}
+
+// An additional repository cloned into the workspace, such as shared protobuf definitions or test fixtures.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CheckoutRule {
+    // A URL or a path to a local repository.
+    pub repository: String,
+
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+
+    // Where the checkout goes, relative to the workspace.
+    pub path: String,
+
+    pub depth: Option<u32>,
+
+    pub submodules: Option<bool>,
+
+    pub sparse: Option<Vec<String>>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -86,9 +356,162 @@ pub struct BuildConfig {
 
     pub project_id: String,
 
+    pub artifacts_dir: Option<String>,
+
+    pub artifact_store: Option<ArtifactStoreConfig>,
+
+    pub remote_cache: Option<RemoteCacheConfig>,
+
+    pub workspace: Option<WorkspaceConfig>,
+
+    pub secrets: Option<SecretsConfig>,
+
+    pub environments: Option<Vec<EnvironmentConfig>>,
+
+    pub registries: Option<Vec<RegistryConfig>>,
+
+    // What happens to the build-scoped names of images built or registered during the build, they're removed by default.
+    pub build_image_retention: Option<BuildImageRetention>,
+
     pub modules: Vec<Module>,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildImageRetention {
+    Remove,
+    Keep,
+    // Keep the images for debugging when the build fails.
+    KeepOnFailure,
+}
+
+// Credentials for a private registry kept in a Jarvis secret, which must have `username` and `password` keys. Registries
+// which aren't listed use the credentials in the user's docker configuration.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct RegistryConfig {
+    pub host: String,
+
+    pub secret: String,
+}
+
+// A named set of secrets, such as those for deploying to production, and the rules for which builds may use them.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnvironmentConfig {
+    pub name: String,
+
+    // Read from the secrets provider as `<environment>/<secret>`, so each environment can have its own values.
+    pub secrets: Vec<String>,
+
+    // Patterns for the git branches allowed to use the environment, any branch when not set.
+    pub branches: Option<Vec<String>>,
+
+    // Only allow the environment when the build is started with `--environment <name>`.
+    pub require_explicit: Option<bool>,
+}
+
+// Settings which apply to every project the user builds, read from `config.yaml` in the user config directory.
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct UserConfig {
+    pub secrets: Option<SecretsConfig>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum SecretsConfig {
+    // Plain text `<name>.secret.txt` files, in `.jarvis/secrets` unless another directory is given.
+    File {
+        directory: Option<String>,
+    },
+    // Environment variables on the host named after the secret, `JARVIS_SECRET_<NAME>` unless another prefix is given.
+    Env {
+        prefix: Option<String>,
+    },
+    // AES-GCM encrypted `<name>.secret.enc` files, in `.jarvis/secrets` unless another directory is given.
+    EncryptedFile {
+        directory: Option<String>,
+
+        key_file: Option<String>,
+    },
+    // A HashiCorp Vault compatible KV version 2 secrets engine.
+    Vault {
+        address: String,
+
+        mount: Option<String>,
+
+        path: Option<String>,
+
+        field: Option<String>,
+
+        // The name of an environment variable holding the token, rather than the token itself.
+        token_env: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkspaceConfig {
+    pub respect_gitignore: Option<bool>,
+
+    pub compression: Option<WorkspaceCompression>,
+
+    pub compression_threads: Option<usize>,
+
+    // Overwrite the workspace before it is deleted at the end of the build.
+    pub sensitive: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceCompression {
+    None,
+    Gzip,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ArtifactStoreConfig {
+    Local {
+        path: Option<String>,
+
+        retention: Option<RetentionPolicy>,
+    },
+    S3 {
+        endpoint: String,
+
+        bucket: String,
+
+        region: Option<String>,
+
+        retention: Option<RetentionPolicy>,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RemoteCacheConfig {
+    Http {
+        url: String,
+
+        // The name of an environment variable holding a bearer token, rather than the token itself.
+        token_env: Option<String>,
+    },
+    S3 {
+        endpoint: String,
+
+        bucket: String,
+
+        region: Option<String>,
+
+        prefix: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
+pub struct RetentionPolicy {
+    pub keep_builds: Option<usize>,
+
+    pub max_age_days: Option<i64>,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
 pub struct ContainerConfiguration {
     pub user: Option<String>,
@@ -101,6 +524,9 @@ pub struct ContainerConfiguration {
 pub struct ProjectConfig {
     pub project_directory: PathBuf,
 
+    // What gets copied into the workspace, the project directory itself unless the build checks out a clean tree.
+    pub workspace_directory: PathBuf,
+
     pub jarvis_directory: PathBuf,
 
     pub build_config: BuildConfig,
@@ -149,11 +575,46 @@ pub fn get_project_config(project_directory: std::path::PathBuf) -> Result<Proje
 
     return Ok(ProjectConfig {
         jarvis_directory: project_dir,
+        workspace_directory: project_directory.clone(),
         project_directory,
         build_config
     });
 }
 
+// Holds state which belongs to the user rather than to a project, such as the local artifact store.
+pub fn jarvis_home_directory() -> Result<PathBuf, ConfigError> {
+    if let Ok(home) = std::env::var("JARVIS_HOME") {
+        return Ok(PathBuf::from(home));
+    }
+
+    dirs::home_dir()
+        .map(|home| home.join(".jarvis"))
+        .ok_or(ConfigError { msg: "Cannot find the user's home directory, set JARVIS_HOME instead".to_string() })
+}
+
+// Holds configuration which belongs to the user, as opposed to state which goes in the Jarvis home directory.
+pub fn user_config_directory() -> Result<PathBuf, ConfigError> {
+    if let Ok(config) = std::env::var("JARVIS_CONFIG_HOME") {
+        return Ok(PathBuf::from(config));
+    }
+
+    dirs::config_dir()
+        .map(|config| config.join("jarvis"))
+        .ok_or(ConfigError { msg: "Cannot find the user's config directory, set JARVIS_CONFIG_HOME instead".to_string() })
+}
+
+pub fn get_user_config() -> Result<UserConfig, ConfigError> {
+    let config_file = user_config_directory()?.join("config.yaml");
+    if !config_file.exists() {
+        return Ok(UserConfig::default());
+    }
+
+    let content = read_to_string(&config_file)
+        .map_err(|e| ConfigError { msg: format!("Cannot read [{}]: {}", config_file.display(), e) })?;
+    serde_yaml::from_str(content.as_str())
+        .map_err(|e| ConfigError { msg: format!("[{}] is not valid: {}", config_file.display(), e) })
+}
+
 fn find_project_dir(project_path: &std::path::PathBuf) -> Option<PathBuf> {
     let dir = fs::read_dir(project_path);
     match dir {
@@ -0,0 +1,209 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+
+use globset::GlobBuilder;
+
+use crate::config::{BuildConfig, EnvironmentConfig};
+
+#[derive(Debug, Clone)]
+pub struct EnvironmentError {
+    msg: String
+}
+
+impl fmt::Display for EnvironmentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "environment error: {}", self.msg)
+    }
+}
+
+impl Error for EnvironmentError {}
+
+// The name a secret is looked up by, environments keep their secrets apart from everyone else's.
+pub fn secret_lookup_name(environment: Option<&str>, secret: &str) -> String {
+    match environment {
+        Some(environment) => format!("{}/{}", environment, secret),
+        None => secret.to_string()
+    }
+}
+
+// Problems which can be found from the configuration alone, each step may only ask for the secrets its environment has.
+// Secrets are only ever named without their environment, otherwise a step could ask for `production/password` directly
+// and get around the environment's branch rules.
+pub fn check_step_environments(build_config: &BuildConfig) -> Vec<String> {
+    let mut errors = vec![];
+    for environment in build_config.environments.iter().flatten() {
+        if environment.name.is_empty() || !environment.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            errors.push(format!("Environment [{}] must be named with letters, digits, '-' and '_'", environment.name));
+        }
+        for secret in environment.secrets.iter().filter(|secret| secret.contains('/')) {
+            errors.push(format!("Environment [{}] has secret [{}], secret names can't contain '/'", environment.name, secret));
+        }
+    }
+
+    for registry in build_config.registries.iter().flatten().filter(|registry| registry.secret.contains('/')) {
+        errors.push(format!("Registry [{}] uses secret [{}], registries can't use secrets from an environment", registry.host, registry.secret));
+    }
+
+    for module in &build_config.modules {
+        for step in &module.steps {
+            let environment = match &step.environment {
+                Some(name) => match find_environment(build_config, name.as_str()) {
+                    Some(environment) => Some(environment),
+                    None => {
+                        errors.push(format!("Step [{}] in module [{}] uses environment [{}], which isn't defined", step.name, module.name, name));
+                        continue;
+                    }
+                },
+                None => None
+            };
+
+            let push_secret = step.push_image.as_ref().and_then(|rule| rule.secret.as_ref()).map(|secret| secret.as_str());
+            for name in step.secrets.iter().flatten().map(|rule| rule.name()).chain(push_secret) {
+                if name.contains('/') {
+                    errors.push(format!("Step [{}] in module [{}] asks for secret [{}], secrets from an environment are used by setting the step's environment", step.name, module.name, name));
+                    continue;
+                }
+
+                if let Some(environment) = environment {
+                    if !environment.secrets.iter().any(|secret| secret == name) {
+                        errors.push(format!("Step [{}] in module [{}] asks for secret [{}], which isn't part of environment [{}]", step.name, module.name, name, environment.name));
+                    }
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+// Checks that this build may use every environment its steps ask for, given the branch being built and the environments
+// named on the command line.
+pub fn check_environment_access(build_config: &BuildConfig, selected: &Vec<String>, branch: Option<&str>) -> Result<(), EnvironmentError> {
+    for name in selected {
+        if find_environment(build_config, name.as_str()).is_none() {
+            return Err(EnvironmentError { msg: format!("Environment [{}] isn't defined", name) });
+        }
+    }
+
+    let mut used: Vec<&str> = build_config.modules.iter()
+        .flat_map(|module| module.steps.iter())
+        .filter_map(|step| step.environment.as_ref().map(|environment| environment.as_str()))
+        .collect();
+    used.sort();
+    used.dedup();
+
+    for name in used {
+        let environment = match find_environment(build_config, name) {
+            Some(environment) => environment,
+            None => continue
+        };
+
+        if environment.require_explicit.unwrap_or(false) && !selected.iter().any(|selected| selected == name) {
+            return Err(EnvironmentError { msg: format!("Environment [{}] can only be used when the build is run with --environment {}", name, name) });
+        }
+
+        if let Some(patterns) = &environment.branches {
+            let allowed = match branch {
+                Some(branch) => branch_matches(patterns, branch)?,
+                None => false
+            };
+            if !allowed {
+                return Err(EnvironmentError { msg: format!("Environment [{}] is restricted to branches [{}] but the build is on [{}]", name, patterns.join(", "), branch.unwrap_or("no branch")) });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn find_environment<'a>(build_config: &'a BuildConfig, name: &str) -> Option<&'a EnvironmentConfig> {
+    build_config.environments.iter().flatten().find(|environment| environment.name == name)
+}
+
+// A `*` stays within one part of the branch name, so `release/*` doesn't match `release/1.x/hotfix`.
+fn branch_matches(patterns: &Vec<String>, branch: &str) -> Result<bool, EnvironmentError> {
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern.as_str())
+            .literal_separator(true)
+            .build()
+            .map_err(|e| EnvironmentError { msg: format!("Invalid branch pattern [{}]: {}", pattern, e) })?;
+
+        if glob.compile_matcher().is_match(branch) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::BuildConfig;
+    use super::{branch_matches, check_step_environments};
+
+    fn build_config(steps_yaml: &str) -> BuildConfig {
+        serde_yaml::from_str(format!("api_version: 0.1
+project_id: test
+environments:
+  - name: production
+    secrets: [db-password]
+    branches: [main]
+registries:
+  - host: registry.example.com
+    secret: registry
+modules:
+  - name: app
+    steps:
+{}", steps_yaml).as_str()).unwrap()
+    }
+
+    #[test]
+    fn steps_only_use_their_environment_secrets() {
+        let errors = check_step_environments(&build_config("      - name: deploy
+        command: deploy
+        environment: production
+        secrets: [db-password, api-key]
+"));
+
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("[api-key]"));
+    }
+
+    #[test]
+    fn steps_without_an_environment_cannot_name_environment_secrets() {
+        let errors = check_step_environments(&build_config("      - name: test
+        command: test
+        secrets: [api-key, production/db-password]
+      - name: deploy
+        command: deploy
+        environment: production
+        secrets: [production/db-password]
+"));
+
+        assert_eq!(2, errors.len());
+        assert!(errors.iter().all(|error| error.contains("[production/db-password]")));
+    }
+
+    #[test]
+    fn registries_cannot_use_environment_secrets() {
+        let mut config = build_config("      - name: test
+        command: test
+");
+        config.registries.as_mut().unwrap()[0].secret = "production/registry".to_string();
+
+        let errors = check_step_environments(&config);
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("[production/registry]"));
+    }
+
+    #[test]
+    fn branch_patterns_match_within_one_level() {
+        let patterns = vec!["main".to_string(), "release/*".to_string()];
+
+        assert!(branch_matches(&patterns, "main").unwrap());
+        assert!(branch_matches(&patterns, "release/1.2").unwrap());
+        assert!(!branch_matches(&patterns, "release/1.2/hotfix").unwrap());
+        assert!(!branch_matches(&patterns, "feature/main").unwrap());
+    }
+}
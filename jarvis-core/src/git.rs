@@ -0,0 +1,270 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use path_absolutize::Absolutize;
+
+// Where the workspace comes from when it is cloned from a repository rather than uploaded from the project directory.
+#[derive(Debug, Clone)]
+pub struct GitSource {
+    // A URL or a path to a local repository.
+    pub repository: String,
+
+    // A branch, tag or commit, the remote's HEAD when not set.
+    pub reference: Option<String>,
+
+    pub depth: Option<u32>,
+
+    pub submodules: bool,
+
+    // Paths to check out, the whole tree when empty.
+    pub sparse_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GitError {
+    msg: String
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "git error: {}", self.msg)
+    }
+}
+
+impl Error for GitError {}
+
+// Clones `source` into `destination`, which must not exist yet, and returns the SHA of the commit which was checked out.
+// Only the requested ref is fetched so the checkout is no bigger than it needs to be.
+pub fn checkout(source: &GitSource, destination: &PathBuf) -> Result<String, GitError> {
+    fs::create_dir_all(destination)
+        .map_err(|e| GitError { msg: format!("Failed to create [{}]: {}", destination.display(), e) })?;
+
+    run_git(destination, &["init", "--quiet"])?;
+    run_git(destination, &["remote", "add", "--", "origin", repository_url(source.repository.as_str()).as_str()])?;
+
+    if !source.sparse_paths.is_empty() {
+        run_git(destination, &["config", "core.sparseCheckout", "true"])?;
+
+        let patterns_file = destination.join(".git").join("info").join("sparse-checkout");
+        fs::create_dir_all(patterns_file.parent().unwrap())
+            .and_then(|_| fs::write(&patterns_file, sparse_checkout_patterns(&source.sparse_paths)))
+            .map_err(|e| GitError { msg: format!("Failed to write [{}]: {}", patterns_file.display(), e) })?;
+    }
+
+    let reference = source.reference.clone().unwrap_or("HEAD".to_string());
+    let depth = source.depth.map(|depth| format!("--depth={}", depth));
+
+    let mut fetch = vec!["fetch", "--quiet"];
+    if let Some(depth) = &depth {
+        fetch.push(depth.as_str());
+    }
+    // Whatever follows `--` is never read as an option, so a ref can't pass options such as `--upload-pack` to git.
+    fetch.extend(vec!["--", "origin", reference.as_str()]);
+    run_git(destination, &fetch)?;
+
+    run_git(destination, &["checkout", "--quiet", "--detach", "FETCH_HEAD"])?;
+
+    if source.submodules {
+        let mut update = vec!["submodule", "update", "--quiet", "--init", "--recursive"];
+        if let Some(depth) = &depth {
+            update.push(depth.as_str());
+        }
+        run_git(destination, &update)?;
+    }
+
+    run_git(destination, &["rev-parse", "HEAD"])
+        .map(|sha| sha.trim().to_string())
+}
+
+// The branch a checkout of `source` into `destination` was taken from, if `commit` is still the tip of that branch on the
+// remote. A tag or a commit is never a branch, even when a branch points at the same commit.
+pub fn checked_out_branch(source: &GitSource, destination: &PathBuf, commit: &str) -> Option<String> {
+    let branch = match &source.reference {
+        Some(reference) => reference.trim_start_matches("refs/heads/").to_string(),
+        None => {
+            let head = run_git(destination, &["ls-remote", "--symref", "--", "origin", "HEAD"]).ok()?;
+            head.lines()
+                .filter(|line| line.starts_with("ref: refs/heads/"))
+                .filter_map(|line| line["ref: refs/heads/".len()..].split('\t').next())
+                .next()?
+                .to_string()
+        }
+    };
+
+    let full_name = format!("refs/heads/{}", branch);
+    let heads = run_git(destination, &["ls-remote", "--heads", "--", "origin", full_name.as_str()]).ok()?;
+    let tip = heads.lines()
+        .map(|line| line.split('\t').collect::<Vec<&str>>())
+        .find(|fields| fields.len() == 2 && fields[1] == full_name)
+        .map(|fields| fields[0].to_string())?;
+
+    if tip == commit {
+        Some(branch)
+    } else {
+        None
+    }
+}
+
+// The branch checked out in `directory`, if it is a repository which isn't on a detached HEAD.
+pub fn current_branch(directory: &PathBuf) -> Option<String> {
+    run_git(directory, &["rev-parse", "--abbrev-ref", "HEAD"]).ok()
+        .map(|branch| branch.trim().to_string())
+        .filter(|branch| !branch.is_empty() && branch != "HEAD")
+}
+
+fn run_git(directory: &PathBuf, args: &[&str]) -> Result<String, GitError> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(directory)
+        .output()
+        .map_err(|e| GitError { msg: format!("Failed to run git, is it installed? {}", e) })?;
+
+    if !output.status.success() {
+        return Err(GitError { msg: format!("[git {}] failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()) });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Local repositories are fetched through a file URL, otherwise git ignores the depth and copies the whole history.
+fn repository_url(repository: &str) -> String {
+    let path = Path::new(repository);
+    if repository.contains("://") || !path.exists() {
+        return repository.to_string();
+    }
+
+    let absolute = path.absolutize()
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .unwrap_or(repository.to_string());
+    if absolute.starts_with('/') {
+        format!("file://{}", absolute)
+    } else {
+        format!("file:///{}", absolute)
+    }
+}
+
+fn sparse_checkout_patterns(paths: &Vec<String>) -> String {
+    paths.iter()
+        .map(|path| format!("/{}\n", path.trim_start_matches("./").trim_start_matches('/')))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::cache::temp_path;
+    use crate::config::get_project_config;
+    use super::{checked_out_branch, checkout, current_branch, GitSource, repository_url, run_git, sparse_checkout_patterns};
+
+    // A repository with a release branch, whose build configuration has uncommitted changes in the working tree.
+    fn create_repository() -> PathBuf {
+        let repository = temp_path("jarvis-git-test", "repository");
+        fs::create_dir_all(repository.join(".jarvis")).unwrap();
+        run_git(&repository, &["init", "--quiet"]).unwrap();
+
+        let commit = |project_id: &str| {
+            fs::write(repository.join(".jarvis/build.yaml"), format!("api_version: 0.1\nproject_id: {}\nmodules: []\n", project_id)).unwrap();
+            run_git(&repository, &["add", "--all"]).unwrap();
+            run_git(&repository, &["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "--quiet", "-m", project_id]).unwrap();
+        };
+        commit("release");
+        run_git(&repository, &["branch", "release"]).unwrap();
+        commit("committed");
+
+        fs::write(repository.join(".jarvis/build.yaml"), "api_version: 0.1\nproject_id: local\nmodules: []\n").unwrap();
+        repository
+    }
+
+    fn source(repository: &PathBuf, reference: Option<&str>) -> GitSource {
+        GitSource {
+            repository: repository.to_string_lossy().to_string(),
+            reference: reference.map(|reference| reference.to_string()),
+            depth: Some(1),
+            submodules: false,
+            sparse_paths: vec![],
+        }
+    }
+
+    #[test]
+    fn checkouts_contain_the_committed_configuration() {
+        let repository = create_repository();
+
+        for (reference, project_id) in vec![(None, "committed"), (Some("release"), "release")] {
+            let destination = temp_path("jarvis-git-test", "checkout");
+            let commit = checkout(&source(&repository, reference), &destination).unwrap();
+
+            let expected = run_git(&repository, &["rev-parse", reference.unwrap_or("HEAD")]).unwrap();
+            assert_eq!(expected.trim(), commit);
+            assert_eq!(project_id, get_project_config(destination.clone()).unwrap().build_config.project_id);
+
+            fs::remove_dir_all(destination).unwrap();
+        }
+
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn refs_are_never_read_as_options() {
+        let repository = create_repository();
+        let destination = temp_path("jarvis-git-test", "checkout");
+        let marker = temp_path("jarvis-git-test", "marker");
+
+        let reference = format!("--upload-pack=touch {}", marker.display());
+        assert!(checkout(&source(&repository, Some(reference.as_str())), &destination).is_err());
+        assert!(!marker.exists());
+
+        fs::remove_dir_all(destination).unwrap();
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn checkouts_know_the_branch_they_came_from() {
+        let repository = create_repository();
+        let default_branch = current_branch(&repository).unwrap();
+        let release = run_git(&repository, &["rev-parse", "release"]).unwrap().trim().to_string();
+        run_git(&repository, &["tag", "v1", "release"]).unwrap();
+
+        let references = vec![
+            (None, Some(default_branch.clone())),
+            (Some("release"), Some("release".to_string())),
+            (Some("refs/heads/release"), Some("release".to_string())),
+            (Some("v1"), None),
+            (Some(release.as_str()), None),
+        ];
+        for (reference, branch) in references {
+            let destination = temp_path("jarvis-git-test", "checkout");
+            let source = source(&repository, reference);
+            let commit = checkout(&source, &destination).unwrap();
+
+            assert_eq!(branch, checked_out_branch(&source, &destination, commit.as_str()), "{:?}", reference);
+            assert_eq!(None, checked_out_branch(&source, &destination, "0000000000000000000000000000000000000000"), "{:?}", reference);
+
+            fs::remove_dir_all(destination).unwrap();
+        }
+
+        fs::remove_dir_all(repository).unwrap();
+    }
+
+    #[test]
+    fn remote_repositories_are_used_as_given() {
+        assert_eq!("https://github.com/example/project.git", repository_url("https://github.com/example/project.git"));
+        assert_eq!("git@github.com:example/project.git", repository_url("git@github.com:example/project.git"));
+    }
+
+    #[test]
+    fn local_repositories_are_fetched_through_file_urls() {
+        let directory = std::env::temp_dir();
+        assert!(repository_url(directory.to_str().unwrap()).starts_with("file://"));
+    }
+
+    #[test]
+    fn sparse_paths_are_anchored_to_the_repository_root() {
+        assert_eq!("/src\n/docs/api\n", sparse_checkout_patterns(&vec!["./src".to_string(), "/docs/api".to_string()]));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{BuildConfig, get_project_config};
+use crate::runtime::BuildRuntime;
+use crate::OutputFormatter;
+
+const IMAGES_LOCK_FILE: &str = "images.lock";
+
+const LOCK_FILE_HEADER: &str = "# Generated by `jarvis images update`, commit this file to pin agent images to these digests.\n";
+
+#[derive(Debug, Clone)]
+pub struct ImageLockError {
+    msg: String
+}
+
+impl fmt::Display for ImageLockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "image lock error: {}", self.msg)
+    }
+}
+
+impl Error for ImageLockError {}
+
+// The digest each agent image resolved to when the lock was last updated, keyed by the image as it's written in build.yaml.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageLock {
+    pub images: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LockedImage {
+    pub image: String,
+
+    pub digest: String,
+
+    // The digest this image was locked to before the update, if it was locked at all.
+    pub previous_digest: Option<String>,
+}
+
+impl ImageLock {
+    // A project without a lock file has nothing pinned.
+    pub fn load(jarvis_directory: &PathBuf) -> Result<ImageLock, ImageLockError> {
+        let lock_file = jarvis_directory.join(IMAGES_LOCK_FILE);
+        if !lock_file.exists() {
+            return Ok(ImageLock::default());
+        }
+
+        let content = std::fs::read_to_string(&lock_file)
+            .map_err(|e| ImageLockError { msg: format!("Failed to read [{}]: {}", lock_file.display(), e) })?;
+        serde_yaml::from_str(content.as_str())
+            .map_err(|e| ImageLockError { msg: format!("[{}] is not valid: {}", lock_file.display(), e) })
+    }
+
+    pub fn save(&self, jarvis_directory: &PathBuf) -> Result<(), ImageLockError> {
+        let lock_file = jarvis_directory.join(IMAGES_LOCK_FILE);
+        let content = serde_yaml::to_string(self)
+            .map_err(|e| ImageLockError { msg: format!("Failed to serialise the image lock: {}", e) })?;
+
+        std::fs::write(&lock_file, format!("{}{}\n", LOCK_FILE_HEADER, content))
+            .map_err(|e| ImageLockError { msg: format!("Failed to write [{}]: {}", lock_file.display(), e) })
+    }
+
+    // The reference to run an image by, its repository at the locked digest. Images already given by digest need no lock.
+    pub fn pinned_reference(&self, image: &str) -> Option<String> {
+        if image.contains('@') {
+            return Some(image.to_string());
+        }
+
+        self.images.get(image).map(|digest| format!("{}@{}", image_repository(image), digest))
+    }
+
+    // Agent images which the lock doesn't cover, a `--locked` build refuses to run with any of these.
+    pub fn unpinned_images(&self, build_config: &BuildConfig) -> Vec<String> {
+        agent_images(build_config).into_iter()
+            .filter(|image| self.pinned_reference(image.as_str()).is_none())
+            .collect()
+    }
+}
+
+// Resolves every agent image in the project to its current digest and rewrites the lock. Images which are no longer used
+// by any agent are dropped from the lock.
+pub async fn update_images(mut runtime: Box<dyn BuildRuntime>, project_path: PathBuf, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<LockedImage>, ImageLockError> {
+    let project_config = get_project_config(project_path)
+        .map_err(|e| ImageLockError { msg: format!("Project configuration error: {}", e) })?;
+    let previous = ImageLock::load(&project_config.jarvis_directory)?;
+
+    runtime.connect();
+
+    let mut lock = ImageLock::default();
+    let mut locked_images = vec![];
+    for image in agent_images(&project_config.build_config) {
+        if image.contains('@') {
+            continue;
+        }
+
+        output_formatter.print(format!("Resolving [{}]", image));
+        let digest = runtime.resolve_image_digest(&project_config, image.as_str()).await
+            .map_err(|e| ImageLockError { msg: format!("Failed to resolve [{}]: {}", image, e) })?;
+
+        lock.images.insert(image.clone(), digest.clone());
+        locked_images.push(LockedImage {
+            previous_digest: previous.images.get(image.as_str()).cloned(),
+            image,
+            digest,
+        });
+    }
+
+    lock.save(&project_config.jarvis_directory)?;
+
+    Ok(locked_images)
+}
+
+// Images built during the build aren't pulled, so they're never locked.
+fn agent_images(build_config: &BuildConfig) -> Vec<String> {
+    let mut images: Vec<String> = build_config.modules.iter()
+        .flat_map(|module| module.agents.iter().flatten())
+        .filter(|agent| agent.built_image().is_none())
+        .map(|agent| agent.image.clone())
+        .collect();
+    images.sort();
+    images.dedup();
+    images
+}
+
+// The image without its tag or digest. A ':' only starts the tag after the last '/', before that it's a registry port.
+pub fn image_repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap();
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match image[name_start..].find(':') {
+        Some(i) => &image[..name_start + i],
+        None => image
+    }
+}
+
+// Pulling an image without a tag pulls every tag in the repository, so references without one are given `latest` the way
+// the docker CLI does.
+pub fn with_default_tag(image: &str) -> String {
+    if image.contains('@') || image_repository(image).len() < image.len() {
+        image.to_string()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{image_repository, with_default_tag, ImageLock};
+
+    #[test]
+    fn registry_ports_are_not_taken_for_tags() {
+        assert_eq!("python", image_repository("python:3-buster"));
+        assert_eq!("localhost:5000/app", image_repository("localhost:5000/app:1.0"));
+        assert_eq!("localhost:5000/app", image_repository("localhost:5000/app"));
+        assert_eq!("alpine", image_repository("alpine@sha256:abc"));
+
+        assert_eq!("alpine:latest", with_default_tag("alpine"));
+        assert_eq!("localhost:5000/app:latest", with_default_tag("localhost:5000/app"));
+        assert_eq!("alpine:3.12", with_default_tag("alpine:3.12"));
+    }
+
+    #[test]
+    fn locked_images_run_by_digest() {
+        let mut lock = ImageLock::default();
+        lock.images.insert("python:3-buster".to_string(), "sha256:abc".to_string());
+
+        assert_eq!(Some("python@sha256:abc".to_string()), lock.pinned_reference("python:3-buster"));
+        assert_eq!(Some("alpine@sha256:def".to_string()), lock.pinned_reference("alpine@sha256:def"));
+        assert_eq!(None, lock.pinned_reference("alpine:latest"));
+    }
+}
@@ -9,6 +9,16 @@ use crate::init::InitError;
 use crate::runtime::BuildRuntime;
 use crate::runtime::docker_runtime::DockerRuntime;
 use crate::runtime::k8s_runtime::KubernetesRuntime;
+use crate::config::RetentionPolicy;
+use crate::masking::SecretMasker;
+
+pub use crate::build::{BuildOptions, WorkspaceMode};
+pub use crate::git::GitSource;
+pub use crate::artifact_store::{ArtifactFilter, ArtifactStoreError, StoredArtifact};
+pub use crate::cache::{CacheError, CacheInfo, CacheMetadata};
+pub use crate::secrets::SecretsError;
+pub use crate::secrets::local_store::SecretInfo;
+pub use crate::image_lock::{ImageLockError, LockedImage};
 
 mod runtime;
 mod validate;
@@ -16,6 +26,18 @@ pub mod config;
 mod init;
 mod build;
 mod cleanup;
+mod summary;
+mod artifacts;
+mod artifact_store;
+mod s3;
+mod cache;
+mod workspace;
+mod git;
+mod secrets;
+mod masking;
+mod environments;
+mod registry;
+mod image_lock;
 
 pub trait OutputFormatter {
     fn print(&self, msg: String);
@@ -41,14 +63,15 @@ pub async fn init_project(project_path: std::path::PathBuf, runtime: RuntimeOpti
     init::init_project(project_path, runtime, output_formatter).await
 }
 
-pub async fn build_project(project_path: std::path::PathBuf, runtime: RuntimeOption, output_formatter: &Box<dyn OutputFormatter>) -> Result<(), BuildError> {
+pub async fn build_project(project_path: std::path::PathBuf, runtime: RuntimeOption, build_options: &BuildOptions, output_formatter: &Box<dyn OutputFormatter>) -> Result<(), BuildError> {
+    let secret_masker = SecretMasker::new();
     let runtime: Box<dyn BuildRuntime> = match runtime {
-        RuntimeOption::Docker => Box::new(DockerRuntime::new() ),
+        RuntimeOption::Docker => Box::new(DockerRuntime::with_secret_masker(secret_masker.clone())),
         RuntimeOption::Kubernetes => Box::new(KubernetesRuntime {}),
-        RuntimeOption::None => Box::new(DockerRuntime::new() )
+        RuntimeOption::None => Box::new(DockerRuntime::with_secret_masker(secret_masker.clone()))
     };
 
-    build::build_project(project_path, runtime, output_formatter).await
+    build::build_project(project_path, runtime, build_options, secret_masker, output_formatter).await
 }
 
 pub fn validate_project(project_path: std::path::PathBuf) -> Result<validate::ValidationMessages, validate::ValidationError> {
@@ -65,6 +88,70 @@ pub async fn cleanup_resources(runtime: RuntimeOption, output_formatter: &Box<dy
     return cleanup::cleanup_resources(runtime, output_formatter).await
 }
 
+pub async fn list_artifacts(project_path: std::path::PathBuf, filter: ArtifactFilter) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+    artifact_store::list_artifacts(project_path, filter).await
+}
+
+pub async fn get_artifacts(project_path: std::path::PathBuf, filter: ArtifactFilter, output_directory: std::path::PathBuf) -> Result<Vec<std::path::PathBuf>, ArtifactStoreError> {
+    artifact_store::get_artifacts(project_path, filter, output_directory).await
+}
+
+pub async fn prune_artifacts(project_path: std::path::PathBuf, retention: Option<RetentionPolicy>) -> Result<Vec<StoredArtifact>, ArtifactStoreError> {
+    artifact_store::prune_artifacts(project_path, retention).await
+}
+
+pub async fn list_caches(project_path: Option<std::path::PathBuf>, runtime: RuntimeOption) -> Result<Vec<CacheInfo>, CacheError> {
+    cache::list_caches(create_runtime(runtime), project_path).await
+}
+
+pub async fn inspect_cache(volume_name: &str, runtime: RuntimeOption) -> Result<CacheInfo, CacheError> {
+    cache::inspect_cache(create_runtime(runtime), volume_name).await
+}
+
+pub async fn prune_caches(project_path: Option<std::path::PathBuf>, max_age_days: Option<i64>, max_size: Option<u64>, runtime: RuntimeOption, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<CacheInfo>, CacheError> {
+    cache::prune_caches(create_runtime(runtime), project_path, max_age_days, max_size, output_formatter).await
+}
+
+pub async fn clear_caches(project_path: Option<std::path::PathBuf>, runtime: RuntimeOption, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<CacheInfo>, CacheError> {
+    cache::clear_caches(create_runtime(runtime), project_path, output_formatter).await
+}
+
+pub async fn export_cache(volume_name: &str, output: &std::path::PathBuf, runtime: RuntimeOption) -> Result<CacheInfo, CacheError> {
+    cache::export_cache(create_runtime(runtime), volume_name, output).await
+}
+
+pub async fn import_cache(archive: &std::path::PathBuf, runtime: RuntimeOption) -> Result<CacheMetadata, CacheError> {
+    cache::import_cache(create_runtime(runtime), archive).await
+}
+
+pub fn add_secret(project_path: std::path::PathBuf, environment: Option<&str>, name: &str, value: &[u8]) -> Result<Option<std::path::PathBuf>, SecretsError> {
+    secrets::local_store::add_secret(project_path, environment, name, value)
+}
+
+pub fn list_secrets(project_path: std::path::PathBuf) -> Result<Vec<SecretInfo>, SecretsError> {
+    secrets::local_store::list_secrets(project_path)
+}
+
+pub fn remove_secret(project_path: std::path::PathBuf, environment: Option<&str>, name: &str) -> Result<(), SecretsError> {
+    secrets::local_store::remove_secret(project_path, environment, name)
+}
+
+pub fn rotate_secrets_key(project_path: std::path::PathBuf) -> Result<usize, SecretsError> {
+    secrets::local_store::rotate_key(project_path)
+}
+
+pub async fn update_images(project_path: std::path::PathBuf, runtime: RuntimeOption, output_formatter: &Box<dyn OutputFormatter>) -> Result<Vec<LockedImage>, ImageLockError> {
+    image_lock::update_images(create_runtime(runtime), project_path, output_formatter).await
+}
+
+fn create_runtime(runtime: RuntimeOption) -> Box<dyn BuildRuntime> {
+    match runtime {
+        RuntimeOption::Docker => Box::new(DockerRuntime::new() ),
+        RuntimeOption::Kubernetes => Box::new(KubernetesRuntime {}),
+        RuntimeOption::None => Box::new(DockerRuntime::new() )
+    }
+}
+
 pub enum RuntimeOption {
     Docker,
     Kubernetes,
@@ -0,0 +1,238 @@
+use std::sync::{Arc, RwLock};
+
+use crate::OutputFormatter;
+
+const MASK: &str = "***";
+
+// Values shorter than this would mask ordinary output far more often than they'd hide a secret.
+const MIN_MASKED_LENGTH: usize = 4;
+
+// Replaces every registered secret value in build output with `***`. Values are also registered in the encodings they
+// are most likely to be printed in, base64 and URL encoding, since tools often log credentials that way. Clones share
+// the same set of values so a secret registered by the runtime is masked everywhere output goes.
+#[derive(Clone, Default)]
+pub struct SecretMasker {
+    values: Arc<RwLock<Vec<String>>>,
+}
+
+impl SecretMasker {
+    pub fn new() -> Self {
+        SecretMasker::default()
+    }
+
+    pub fn register(&self, secret: &[u8]) {
+        let mut variants = vec![];
+        for value in secret_values(secret) {
+            variants.push(base64::encode(value.as_bytes()));
+            variants.push(base64::encode_config(value.as_bytes(), base64::STANDARD_NO_PAD));
+            variants.push(base64::encode_config(value.as_bytes(), base64::URL_SAFE_NO_PAD));
+            variants.push(url_encode(value.as_str(), false));
+            variants.push(url_encode(value.as_str(), true));
+            variants.push(value);
+        }
+
+        let mut values = self.values.write().unwrap();
+        for variant in variants {
+            if variant.len() >= MIN_MASKED_LENGTH && !values.contains(&variant) {
+                values.push(variant);
+            }
+        }
+
+        // Longer values go first so that a value containing another is masked whole.
+        values.sort_by(|a, b| b.len().cmp(&a.len()));
+    }
+
+    pub fn mask(&self, text: &str) -> String {
+        let values = self.values.read().unwrap();
+
+        let mut masked = text.to_string();
+        for value in values.iter() {
+            if masked.contains(value.as_str()) {
+                masked = masked.replace(value.as_str(), MASK);
+            }
+        }
+
+        masked
+    }
+
+    pub fn stream(&self) -> MaskingStream {
+        MaskingStream {
+            masker: self.clone(),
+            pending: String::new(),
+        }
+    }
+
+    // The length of the longest suffix of `text` which could be the start of a secret, and so can't be printed until
+    // more output shows whether it is one.
+    fn partial_match_length(&self, text: &str) -> usize {
+        let values = self.values.read().unwrap();
+        let longest = values.first().map_or(0, |value| value.len());
+
+        text.char_indices()
+            .map(|(i, _)| &text[i..])
+            .filter(|suffix| suffix.len() < longest)
+            .find(|suffix| values.iter().any(|value| value.len() > suffix.len() && value.starts_with(suffix)))
+            .map_or(0, |suffix| suffix.len())
+    }
+}
+
+// Masks output which arrives in chunks, such as an exec stream, where a secret may be split between two chunks.
+pub struct MaskingStream {
+    masker: SecretMasker,
+
+    pending: String,
+}
+
+impl MaskingStream {
+    // Returns whatever can safely be printed now, holding back anything which might turn out to be part of a secret.
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.pending.push_str(chunk);
+
+        let held = self.masker.partial_match_length(self.pending.as_str());
+        let ready = self.pending.len() - held;
+        let masked = self.masker.mask(&self.pending[..ready]);
+        self.pending = self.pending[ready..].to_string();
+
+        masked
+    }
+
+    pub fn finish(&mut self) -> String {
+        let masked = self.masker.mask(self.pending.as_str());
+        self.pending.clear();
+
+        masked
+    }
+}
+
+// Masks everything a build writes through `output_formatter`, the summary and its reports included.
+pub struct MaskedOutputFormatter<'a> {
+    masker: SecretMasker,
+
+    output_formatter: &'a dyn OutputFormatter,
+}
+
+impl<'a> MaskedOutputFormatter<'a> {
+    pub fn new(masker: SecretMasker, output_formatter: &'a dyn OutputFormatter) -> Self {
+        MaskedOutputFormatter {
+            masker,
+            output_formatter,
+        }
+    }
+}
+
+impl<'a> OutputFormatter for MaskedOutputFormatter<'a> {
+    fn print(&self, msg: String) {
+        self.output_formatter.print(self.masker.mask(msg.as_str()));
+    }
+
+    fn success(&self, msg: String) {
+        self.output_formatter.success(self.masker.mask(msg.as_str()));
+    }
+
+    fn error(&self, msg: String) {
+        self.output_formatter.error(self.masker.mask(msg.as_str()));
+    }
+
+    fn background(&self, msg: String) {
+        self.output_formatter.background(self.masker.mask(msg.as_str()));
+    }
+}
+
+// Secret files usually end with a newline which won't be part of what gets printed, and multi-line secrets such as keys
+// tend to be printed a line at a time.
+fn secret_values(secret: &[u8]) -> Vec<String> {
+    let value = String::from_utf8_lossy(secret).to_string();
+
+    let mut values = vec![value.clone()];
+    let trimmed = value.trim();
+    if trimmed != value {
+        values.push(trimmed.to_string());
+    }
+    if trimmed.contains('\n') {
+        values.extend(trimmed.lines().map(|line| line.trim().to_string()));
+    }
+
+    values
+}
+
+fn url_encode(value: &str, space_as_plus: bool) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b' ' if space_as_plus => "+".to_string(),
+            _ => format!("%{:02X}", b)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::OutputFormatter;
+    use super::{MaskedOutputFormatter, SecretMasker};
+
+    #[derive(Default)]
+    struct RecordingOutputFormatter {
+        messages: RefCell<Vec<String>>,
+    }
+
+    impl OutputFormatter for RecordingOutputFormatter {
+        fn print(&self, msg: String) {
+            self.messages.borrow_mut().push(msg);
+        }
+
+        fn success(&self, msg: String) {
+            self.messages.borrow_mut().push(msg);
+        }
+
+        fn error(&self, msg: String) {
+            self.messages.borrow_mut().push(msg);
+        }
+
+        fn background(&self, msg: String) {
+            self.messages.borrow_mut().push(msg);
+        }
+    }
+
+    #[test]
+    fn masks_values_and_their_encodings() {
+        let masker = SecretMasker::new();
+        masker.register(b"p@ss word\n");
+
+        assert_eq!("token=*** and ***", masker.mask("token=p@ss word and cEBzcyB3b3Jk"));
+        assert_eq!("url=https://host/?p=***", masker.mask("url=https://host/?p=p%40ss+word"));
+        assert_eq!("short values like ss stay", masker.mask("short values like ss stay"));
+    }
+
+    #[test]
+    fn masks_values_split_across_chunks() {
+        let masker = SecretMasker::new();
+        masker.register(b"hunter22");
+
+        let mut stream = masker.stream();
+        let mut output = stream.push("password is hun");
+        assert_eq!("password is ", output);
+        output.push_str(stream.push("ter2"));
+        output.push_str(stream.push("2, done hu"));
+        output.push_str(stream.finish().as_str());
+
+        assert_eq!("password is ***, done hu", output);
+    }
+
+    #[test]
+    fn masks_everything_written_to_the_output() {
+        let masker = SecretMasker::new();
+        let recording = RecordingOutputFormatter::default();
+        let output = MaskedOutputFormatter::new(masker.clone(), &recording);
+
+        // Registered after the formatter was created, as the runtime does when it reads a secret.
+        masker.register(b"hunter22");
+        output.print("summary: hunter22".to_string());
+        output.success("ok".to_string());
+        output.error("egress denied to hunter22.example.com:443".to_string());
+        output.background("aHVudGVyMjI=".to_string());
+
+        assert_eq!(vec!["summary: ***", "ok", "egress denied to ***.example.com:443", "***"], *recording.messages.borrow());
+    }
+}
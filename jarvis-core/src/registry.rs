@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use serde_json::Value;
+
+const DOCKER_HUB: &str = "docker.io";
+
+// Docker records credentials for Docker Hub under its old index address rather than the registry's host name.
+const DOCKER_HUB_KEYS: &[&str] = &["https://index.docker.io/v1/", "index.docker.io", "docker.io", "registry-1.docker.io"];
+
+#[derive(Debug, Clone)]
+pub struct RegistryError {
+    msg: String
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "registry error: {}", self.msg)
+    }
+}
+
+impl Error for RegistryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryCredentials {
+    pub server: String,
+
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+
+    // Set instead of a password by registries which use OAuth, such as those logged in to through a cloud provider.
+    pub identity_token: Option<String>,
+}
+
+// The registry an image reference points at. The first part of the name is only a registry when it looks like a host,
+// otherwise the image is on Docker Hub.
+pub fn registry_host(image: &str) -> String {
+    match image.split_once_compat('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => first.to_string(),
+        _ => DOCKER_HUB.to_string()
+    }
+}
+
+trait SplitOnceCompat {
+    fn split_once_compat(&self, delimiter: char) -> Option<(&str, &str)>;
+}
+
+impl SplitOnceCompat for str {
+    fn split_once_compat(&self, delimiter: char) -> Option<(&str, &str)> {
+        self.find(delimiter).map(|i| (&self[..i], &self[i + 1..]))
+    }
+}
+
+// Looks up credentials the way the docker CLI does: a credential helper for the registry, then credentials stored in the
+// config file itself, then the default credential store.
+pub fn docker_config_credentials(host: &str) -> Result<Option<RegistryCredentials>, RegistryError> {
+    let config_file = docker_config_file()?;
+    if !config_file.exists() {
+        return Ok(None);
+    }
+
+    let content = std::fs::read(&config_file)
+        .map_err(|e| RegistryError { msg: format!("Failed to read [{}]: {}", config_file.display(), e) })?;
+    let config: Value = serde_json::from_slice(content.as_slice())
+        .map_err(|e| RegistryError { msg: format!("Invalid docker config [{}]: {}", config_file.display(), e) })?;
+
+    for key in config_keys(host) {
+        if let Some(helper) = config.pointer(format!("/credHelpers/{}", escape_pointer(key.as_str())).as_str()).and_then(|helper| helper.as_str()) {
+            return run_credential_helper(helper, key.as_str());
+        }
+    }
+
+    if let Some(credentials) = stored_credentials(&config, host)? {
+        return Ok(Some(credentials));
+    }
+
+    match config.get("credsStore").and_then(|store| store.as_str()) {
+        Some(store) => run_credential_helper(store, config_keys(host)[0].as_str()),
+        None => Ok(None)
+    }
+}
+
+fn docker_config_file() -> Result<PathBuf, RegistryError> {
+    if let Ok(directory) = std::env::var("DOCKER_CONFIG") {
+        return Ok(PathBuf::from(directory).join("config.json"));
+    }
+
+    dirs::home_dir()
+        .map(|home| home.join(".docker").join("config.json"))
+        .ok_or(RegistryError { msg: "Cannot find the user's home directory, set DOCKER_CONFIG instead".to_string() })
+}
+
+fn config_keys(host: &str) -> Vec<String> {
+    if host == DOCKER_HUB {
+        DOCKER_HUB_KEYS.iter().map(|key| key.to_string()).collect()
+    } else {
+        vec![host.to_string(), format!("https://{}", host), format!("http://{}", host)]
+    }
+}
+
+// Entries under `auths` hold `username:password` base64 encoded, or an identity token.
+fn stored_credentials(config: &Value, host: &str) -> Result<Option<RegistryCredentials>, RegistryError> {
+    for key in config_keys(host) {
+        let entry = match config.pointer(format!("/auths/{}", escape_pointer(key.as_str())).as_str()) {
+            Some(entry) => entry,
+            None => continue
+        };
+
+        let identity_token = entry.get("identitytoken").and_then(|token| token.as_str()).map(|token| token.to_string());
+        let (username, password) = match entry.get("auth").and_then(|auth| auth.as_str()) {
+            Some(auth) => {
+                let decoded = base64::decode(auth)
+                    .map_err(|e| RegistryError { msg: format!("Invalid credentials for [{}] in the docker config: {}", key, e) })?;
+                let decoded = String::from_utf8_lossy(decoded.as_slice()).to_string();
+                match decoded.split_once_compat(':') {
+                    Some((username, password)) => (Some(username.to_string()), Some(password.to_string())),
+                    None => return Err(RegistryError { msg: format!("Invalid credentials for [{}] in the docker config", key) })
+                }
+            },
+            None => (None, None)
+        };
+
+        if username.is_some() || identity_token.is_some() {
+            return Ok(Some(RegistryCredentials {
+                server: key,
+                username,
+                password,
+                identity_token,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+// Runs `docker-credential-<helper> get` which reads the server on stdin and answers with JSON. A helper which doesn't have
+// credentials for the server means the registry is pulled from anonymously.
+fn run_credential_helper(helper: &str, server: &str) -> Result<Option<RegistryCredentials>, RegistryError> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = Command::new(program.as_str())
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| RegistryError { msg: format!("Failed to run credential helper [{}]: {}", program, e) })?;
+
+    child.stdin.take().unwrap().write_all(server.as_bytes())
+        .map_err(|e| RegistryError { msg: format!("Failed to write to credential helper [{}]: {}", program, e) })?;
+    let output = child.wait_with_output()
+        .map_err(|e| RegistryError { msg: format!("Failed to run credential helper [{}]: {}", program, e) })?;
+
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout).to_string() + String::from_utf8_lossy(&output.stderr).as_ref();
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        return Err(RegistryError { msg: format!("Credential helper [{}] failed: {}", program, message.trim()) });
+    }
+
+    let response: Value = serde_json::from_slice(output.stdout.as_slice())
+        .map_err(|e| RegistryError { msg: format!("Invalid response from credential helper [{}]: {}", program, e) })?;
+    let username = response.get("Username").and_then(|username| username.as_str()).unwrap_or("");
+    let secret = response.get("Secret").and_then(|secret| secret.as_str()).map(|secret| secret.to_string());
+
+    // Helpers hand out identity tokens with this placeholder for the user name.
+    Ok(Some(if username == "<token>" {
+        RegistryCredentials { server: server.to_string(), username: None, password: None, identity_token: secret }
+    } else {
+        RegistryCredentials { server: server.to_string(), username: Some(username.to_string()), password: secret, identity_token: None }
+    }))
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{registry_host, stored_credentials};
+
+    #[test]
+    fn registry_is_only_taken_from_names_which_look_like_hosts() {
+        assert_eq!("docker.io", registry_host("alpine:latest"));
+        assert_eq!("docker.io", registry_host("library/alpine"));
+        assert_eq!("ghcr.io", registry_host("ghcr.io/org/image:1.0"));
+        assert_eq!("localhost:5000", registry_host("localhost:5000/image"));
+    }
+
+    #[test]
+    fn stored_credentials_are_decoded() {
+        let config = json!({
+            "auths": {
+                "https://index.docker.io/v1/": { "auth": base64::encode("user:p@ss:word") },
+                "registry.example.com": { "identitytoken": "token" }
+            }
+        });
+
+        let hub = stored_credentials(&config, "docker.io").unwrap().unwrap();
+        assert_eq!(Some("user".to_string()), hub.username);
+        assert_eq!(Some("p@ss:word".to_string()), hub.password);
+
+        let example = stored_credentials(&config, "registry.example.com").unwrap().unwrap();
+        assert_eq!(Some("token".to_string()), example.identity_token);
+
+        assert_eq!(None, stored_credentials(&config, "ghcr.io").unwrap());
+    }
+}
@@ -1,31 +1,141 @@
 use std::fmt::Formatter;
 use std::fmt;
 use std::error::Error;
+use std::path::PathBuf;
 use async_trait::async_trait;
-use crate::config::{Agent, ProjectConfig, ArchiveRule, ShellConfig, PluginSpecification, Step};
+use serde::{Deserialize, Serialize};
+use crate::artifacts::ArtifactManifest;
+use crate::build::BuildOptions;
+use crate::cache::{CacheInfo, CacheMetadata};
+use crate::config::{Agent, ProjectConfig, ArchiveRule, ShellConfig, PluginSpecification, Step, BuildImageRule, PushImageRule, RegisterImageRule};
 
 pub mod docker_runtime;
 pub mod k8s_runtime;
+#[cfg(test)]
+pub mod fake_runtime;
+
+// Variables the runtime sets in agents, the proxy ones only when the module's egress is restricted. A step's secrets
+// can't use these names.
+pub const AGENT_VARIABLES: [&str; 7] = ["JARVIS_AGENT_HOME", "HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy", "NO_PROXY", "no_proxy"];
 
 #[async_trait]
 pub trait BuildRuntime {
     fn connect(&mut self);
 
-    async fn init_for_module(&mut self, module_name: &String, project_config: &ProjectConfig) -> Result<(), BuildRuntimeError>;
+    async fn init_for_module(&mut self, module_name: &String, project_config: &ProjectConfig, build_options: &BuildOptions) -> Result<ModuleReport, BuildRuntimeError>;
 
     async fn create_agent(&mut self, module_name: &String, agent: &Agent, step: Option<&Step>) -> Result<String, BuildRuntimeError>;
 
     async fn execute_command(&mut self, agent_id: &str, shell_config: &ShellConfig, command: &str) -> Result<(), BuildRuntimeError>;
 
-    async fn get_archive(&mut self, agent_id: &str, archive_rule: &ArchiveRule) -> Result<(), BuildRuntimeError>;
+    async fn get_archive(&mut self, agent_id: &str, archive_rule: &ArchiveRule, output_directory: &PathBuf) -> Result<ArtifactManifest, BuildRuntimeError>;
+
+    async fn restore_input(&mut self, agent_id: &str, archive: Vec<u8>, target_path: &str) -> Result<(), BuildRuntimeError>;
+
+    async fn collect_step_report(&mut self, module_name: &String, agent_id: &str) -> Result<StepReport, BuildRuntimeError>;
 
     async fn destroy_agent(&mut self, agent_id: &str) -> Result<(), BuildRuntimeError>;
 
-    async fn tear_down_for_module(&self, module_name: &String) -> Result<(), BuildRuntimeError>;
+    // Remote caches are only updated when the module succeeded, so a broken build can't spread a bad cache.
+    async fn tear_down_for_module(&self, module_name: &String, succeeded: bool) -> Result<(), BuildRuntimeError>;
 
     async fn cleanup_resources(&self) -> Result<(), BuildRuntimeError>;
 
+    async fn list_caches(&self) -> Result<Vec<CacheInfo>, BuildRuntimeError>;
+
+    async fn remove_cache(&self, volume_name: &str) -> Result<(), BuildRuntimeError>;
+
+    async fn export_cache(&self, volume_name: &str, destination: &PathBuf) -> Result<(), BuildRuntimeError>;
+
+    async fn import_cache(&self, volume_name: &str, metadata: &CacheMetadata, archive: &PathBuf) -> Result<(), BuildRuntimeError>;
+
     async fn ensure_plugins_loaded(&mut self, plugins: Vec<&PluginSpecification>) -> Result<(), BuildRuntimeError>;
+
+    async fn build_image(&mut self, module_name: &String, rule: &BuildImageRule) -> Result<BuiltImage, BuildRuntimeError>;
+
+    async fn push_image(&mut self, module_name: &String, rule: &PushImageRule, environment: Option<&str>) -> Result<Vec<PushedImage>, BuildRuntimeError>;
+
+    async fn register_image(&mut self, module_name: &String, agent_id: &str, rule: &RegisterImageRule) -> Result<BuiltImage, BuildRuntimeError>;
+
+    // Removes the build-scoped names of images built or registered during this build, run once the build has finished.
+    async fn remove_build_images(&mut self) -> Result<(), BuildRuntimeError>;
+
+    // Pulls the image as it's currently tagged and returns the digest it resolved to.
+    async fn resolve_image_digest(&mut self, project_config: &ProjectConfig, image: &str) -> Result<String, BuildRuntimeError>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ModuleReport {
+    pub checkouts: Vec<CheckoutReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckoutReport {
+    pub module_name: String,
+
+    pub repository: String,
+
+    pub path: String,
+
+    pub commit: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StepReport {
+    pub egress: Option<Vec<EgressRecord>>,
+
+    pub caches: Vec<CacheReport>,
+}
+
+// An image produced by a `build_image` step or registered after a step, which later agents can run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuiltImage {
+    pub name: String,
+
+    pub id: String,
+
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushedImage {
+    pub reference: String,
+
+    pub digest: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheReport {
+    pub name: String,
+
+    pub key: String,
+
+    pub read_only: bool,
+
+    pub outcome: CacheOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheOutcome {
+    Hit,
+    RemoteHit,
+    PartialHit { restored_key: String },
+    Miss,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EgressRecord {
+    pub host: String,
+
+    pub port: u16,
+
+    pub connections: u32,
+
+    pub denied_connections: u32,
+
+    pub bytes_sent: u64,
+
+    pub bytes_received: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -1,48 +1,148 @@
-use crate::runtime::{BuildRuntime, BuildRuntimeError};
+use crate::runtime::{BuildRuntime, BuildRuntimeError, BuiltImage, ModuleReport, PushedImage, StepReport, CacheReport, CheckoutReport};
 use std::collections::HashMap;
 use bollard::Docker;
 use bollard::volume::{CreateVolumeOptions, RemoveVolumeOptions, ListVolumesOptions};
 use async_trait::async_trait;
 
-use crate::config::{Agent, ProjectConfig, CacheRule, ArchiveRule, ShellConfig, PluginSpecification, Step};
+use crate::config::{Agent, CheckoutRule, BuildImageRule, PushImageRule, RegisterImageRule, ProjectConfig, RegistryConfig, WorkspaceCompression, ArchiveRule, ShellConfig, PluginSpecification, Step};
 use bollard::container::{CreateContainerOptions, Config, StartContainerOptions, UploadToContainerOptions, RemoveContainerOptions, ListContainersOptions, DownloadFromContainerOptions};
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
 use bollard::image::{CreateImageOptions, ListImagesOptions};
 use tokio::stream::StreamExt;
 use std::{io, env};
-use std::io::{Write, Read};
+use std::io::Write;
 use bollard::models::{HostConfig, Mount, MountTypeEnum, PortMap, PortBinding};
 use std::path::PathBuf;
 use std::fs::File;
-use flate2::write::GzEncoder;
-use flate2::Compression;
 use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::container::LogOutput;
 use chrono::Utc;
-use path_absolutize::Absolutize;
-use regex::Regex;
+use self::egress_proxy::EgressProxy;
+use crate::artifacts;
+use crate::artifacts::ArtifactManifest;
+use crate::cache;
+use crate::summary::format_bytes;
+use crate::workspace;
+use crate::cache::{CacheInfo, CacheMetadata, RemoteCache};
+use crate::masking::SecretMasker;
+use crate::secrets;
+use crate::secrets::SecretsProvider;
+use crate::build::{BuildOptions, WorkspaceMode};
+use crate::workspace::WorkspaceFiles;
+use self::secret_delivery::AgentSecrets;
+use self::services::StepServices;
+use self::workspaces::{agent_user, shell_quote};
+use bollard::auth::DockerCredentials;
+use crate::image_lock;
+use crate::image_lock::ImageLock;
+
+mod egress_proxy;
+mod caches;
+mod workspaces;
+mod secret_delivery;
+mod data_destruction;
+mod images;
+mod image_builds;
+mod services;
 
 pub struct DockerRuntime {
     docker: Option<Docker>,
 
     module_components: HashMap<String, Box<ModuleComponents>>,
+
+    secret_masker: SecretMasker,
+
+    // Images pulled by this build, so an `always` pull policy refreshes each image once rather than for every agent.
+    pulled_images: Vec<String>,
+
+    // The ID of each image built so far in this build, by the name agents refer to it with.
+    build_images: HashMap<String, String>,
+
+    // Part of the tag given to every image built or registered during this build, so their names can't collide with
+    // another build's.
+    build_scope: String,
+
+    scoped_image_tags: Vec<String>,
 }
 
 struct ModuleComponents {
     build_data_volume: String,
 
+    reuse_workspace: bool,
+
+    workspace_mode: WorkspaceMode,
+
+    overlay_scratch_volume: Option<String>,
+
+    sensitive_workspace: bool,
+
+    // Cache volumes which are overwritten and removed when the module is torn down.
+    sensitive_caches: Vec<String>,
+
     identifier_base: String,
 
+    project_id: String,
+
     containers: HashMap<String, String>,
 
-    jarvis_directory: PathBuf,
+    // Secrets directories inside the workspace, relative to it.
+    secrets_paths: Vec<String>,
+
+    secrets_provider: Box<dyn SecretsProvider + Send + Sync>,
+
+    // The secret files written into each agent, so they can be removed before the agent is destroyed.
+    delivered_secrets: HashMap<String, Vec<String>>,
+
+    // The variables holding or pointing at each agent's secrets. They're given to the step's command when it runs rather
+    // than to the container, where anyone able to inspect it could read them.
+    secret_environment: HashMap<String, Vec<String>>,
+
+    // The user each agent writing to a bind mounted project runs as, whose files are handed back to the project's owner.
+    workspace_writers: HashMap<String, String>,
+
+    // Services started for each agent, which are stopped along with it.
+    services: HashMap<String, StepServices>,
+
+    project_directory: PathBuf,
+
+    workspace_directory: PathBuf,
+
+    cache_reports: HashMap<String, Vec<CacheReport>>,
+
+    remote_cache: Option<Box<dyn RemoteCache + Send + Sync>>,
+
+    cache_pushes: Vec<(String, CacheMetadata)>,
+
+    egress_proxy: Option<EgressProxy>,
+
+    allowed_hosts: Option<Vec<String>>,
+
+    registries: Vec<RegistryConfig>,
+
+    image_lock: ImageLock,
 }
 
 impl DockerRuntime {
     pub fn new() -> Self {
+        DockerRuntime::with_secret_masker(SecretMasker::new())
+    }
+
+    // Secrets the runtime reads are registered with `secret_masker`, so a masker shared with the build hides them from
+    // the build's output and reports as well as from command output.
+    pub fn with_secret_masker(secret_masker: SecretMasker) -> Self {
         DockerRuntime {
             docker: None,
             module_components: HashMap::new(),
+            secret_masker,
+            pulled_images: vec![],
+            build_images: HashMap::new(),
+            build_scope: thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(12)
+                .collect::<String>()
+                .to_lowercase(),
+            scoped_image_tags: vec![],
         }
     }
 
@@ -93,14 +193,13 @@ impl DockerRuntime {
         }
     }
 
-    async fn pull_image(&self, image: &str) -> Result<(), BuildRuntimeError> {
-        // TODO validate that a tag is provided, otherwise this will pull all tags for a repository.
-
+    async fn pull_image(&self, image: &str, credentials: Option<DockerCredentials>) -> Result<(), BuildRuntimeError> {
         if let Some(ref docker) = self.docker {
+            let image = image_lock::with_default_tag(image);
             let mut pull_results = docker.create_image(Some(CreateImageOptions {
-                from_image: image,
+                from_image: image.as_str(),
                 ..Default::default()
-            }), None, None);
+            }), None, credentials);
 
             print!("{}", ansi_escapes::CursorHide);
             let mut layer_id_line_numbers = HashMap::<String, usize>::new();
@@ -166,7 +265,9 @@ impl DockerRuntime {
                               module_component: &str,
                               name: &str,
                               agent: &Agent,
-                              secrets_config: Vec<(String, String, String)>,
+                              image: &str,
+                              agent_secrets: &AgentSecrets,
+                              cache_mounts: Vec<Mount>,
                               using_plugins: bool
     ) -> Result<String, BuildRuntimeError> {
         let mut environment: Option<Vec<String>> = None;
@@ -181,48 +282,26 @@ impl DockerRuntime {
             environment = Some(vec![format!("{}={}", "JARVIS_AGENT_HOME", "/build/agent/")])
         }
 
+        let egress_proxy = &self.module_components.get(module_component).unwrap().egress_proxy;
+        if let Some(proxy) = egress_proxy {
+            if let Some(env) = &mut environment {
+                env.extend(proxy.environment());
+            }
+        }
+
         if let Some(ref docker) = self.docker {
             let time = Utc::now().to_rfc3339();
             let mut labels = HashMap::new();
             labels.insert("created-by".to_string(), "jarvis".to_string());
             labels.insert("build-time".to_string(), time);
 
-            let data_volume = self.module_components.get(module_component).unwrap().build_data_volume.as_str();
+            let mut mounts = self.workspace_mounts(module_component);
 
-            let mut mounts = vec![Mount {
-                target: Some("/build/workspace".to_string()),
-                source: Some(data_volume.clone().to_string()),
-                typ: Some(MountTypeEnum::VOLUME),
-                ..Default::default()
-            }];
-
-            for secret_config in secrets_config {
-                mounts.push(Mount {
-                    target: Some(secret_config.2.clone()),
-                    source: Some(format!("{}", secret_config.1)),
-                    typ: Some(MountTypeEnum::BIND),
-                    ..Default::default()
-                });
-
-                if let Some(environment) = &mut environment {
-                    environment.push(format!("{}_FILE={}", secret_config.0, secret_config.2));
-                } else {
-                    environment = Some(vec![format!("{}_FILE={}", secret_config.0, secret_config.2)]);
-                }
+            if !agent_secrets.files.is_empty() {
+                mounts.push(DockerRuntime::secrets_mount());
             }
 
-            if let Some(cache_list) = &agent.cache {
-                let cache_volumes = ensure_caches_created(&self, cache_list, self.module_components.get(module_component).unwrap().identifier_base.as_str()).await?;
-
-                for cache in cache_list {
-                    mounts.push(Mount {
-                        target: Some(cache.location.clone()),
-                        source: Some(cache_volumes.get(cache.name.as_str()).unwrap().clone()),
-                        typ: Some(MountTypeEnum::VOLUME),
-                        ..Default::default()
-                    });
-                }
-            }
+            mounts.extend(cache_mounts);
 
             if using_plugins {
                 mounts.push(Mount {
@@ -233,19 +312,8 @@ impl DockerRuntime {
                 });
             }
 
-            let mut user_config = None;
-            let mut privileged = false;
-            if let Some(container) = &agent.container {
-                privileged = container.privileged.unwrap_or(false);
-
-                if let Some(user) = &container.user {
-                    if let Some(group) = &container.group {
-                        user_config = Some(format!("{}:{}", user, group));
-                    } else {
-                        user_config = Some(user.to_string());
-                    }
-                }
-            }
+            let user_config = agent_user(agent);
+            let privileged = agent.container.as_ref().and_then(|container| container.privileged).unwrap_or(false);
 
             let command_config = vec!["/bin/sh", "-c", "tail -f /dev/null"].iter().map(|x| x.to_string()).collect();
 
@@ -265,7 +333,7 @@ impl DockerRuntime {
             };
 
             let container_result = docker.create_container(Some(CreateContainerOptions { name }), Config {
-                image: Some(agent.image.clone()),
+                image: Some(image.to_string()),
                 entrypoint: Some(command_config),
                 cmd: Some(vec![]),
                 env: environment,
@@ -281,6 +349,7 @@ impl DockerRuntime {
                     mounts: Some(mounts),
                     privileged: Some(privileged),
                     port_bindings: port_config.1,
+                    network_mode: egress_proxy.as_ref().map(|proxy| proxy.network_name.clone()),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -308,59 +377,88 @@ impl DockerRuntime {
         }
     }
 
-    async fn upload_project(&self, container_id: &str, project_directory: &PathBuf) -> Result<(), BuildRuntimeError> {
-        if let Some(ref docker) = self.docker {
-            let id: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(30)
-                .collect();
+    async fn upload_project(&self, container_id: &str, project_config: &ProjectConfig) -> Result<(), BuildRuntimeError> {
+        let files = workspace::collect_workspace_files(project_config)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+        println!("Uploading workspace: {} file(s), {}", files.file_count, format_bytes(files.total_size));
 
-            let mut bundle_path = env::temp_dir();
-            bundle_path.push(format!("jarvis-bundle-{}.tgz", id));
-
-            {
-                // TODO exclude .jarvis/secrets directory from the upload to prevent leaking secrets
-                let tar_gz = File::create(&bundle_path).unwrap();
-                let enc = GzEncoder::new(tar_gz, Compression::default());
-                let mut tar = tar::Builder::new(enc);
-                tar.append_dir_all(".", project_directory).unwrap();
-            }
+        self.upload_workspace(container_id, files, project_config, "/build/workspace").await
+    }
 
-            let mut file = File::open(&bundle_path).unwrap();
-            let mut contents = Vec::new();
-            file.read_to_end(&mut contents).unwrap();
+    async fn upload_workspace(&self, container_id: &str, files: WorkspaceFiles, project_config: &ProjectConfig, target_path: &str) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let workspace_config = project_config.build_config.workspace.as_ref();
+            let compression = workspace_config.and_then(|w| w.compression).unwrap_or(WorkspaceCompression::Gzip);
+            let threads = workspace_config.and_then(|w| w.compression_threads).unwrap_or(num_cpus::get());
+
+            // The archive is produced on a blocking thread and streamed into the upload as it is written.
+            let (sender, receiver) = futures::channel::mpsc::channel(16);
+            let archive_task = tokio::task::spawn_blocking(move || {
+                workspace::stream_workspace(files, compression, threads, sender)
+            });
 
             let options = Some(UploadToContainerOptions {
-                path: "/build/workspace",
+                path: target_path,
                 ..Default::default()
             });
 
-            let upload_result = docker.upload_to_container(container_id, options, contents.into()).await;
+            let upload_result = docker.upload_to_container(container_id, options, hyper::Body::wrap_stream(receiver)).await;
 
-            std::fs::remove_file(bundle_path).unwrap();
+            let archive_result = archive_task.await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to package workspace: {}", e) })
+                .and_then(|result| result.map_err(|e| BuildRuntimeError { msg: format!("{}", e) }));
 
-            upload_result.map_err(|e| {
-                BuildRuntimeError { msg: format!("Error uploading build bundle: {}", format_docker_api_error(e)) }
-            })
+            // A failed upload closes the stream, which makes packaging fail too, so the engine's error comes first.
+            match (upload_result, archive_result) {
+                (Err(e), Err(archive_error)) => Err(BuildRuntimeError { msg: format!("Error uploading build bundle: {} ({})", format_docker_api_error(e), archive_error) }),
+                (Err(e), Ok(_)) => Err(BuildRuntimeError { msg: format!("Error uploading build bundle: {}", format_docker_api_error(e)) }),
+                (Ok(_), archive_result) => archive_result
+            }
         } else {
             Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
         }
     }
 
-    async fn get_archive_internal(&mut self, agent_id: &str, archive_rule: &ArchiveRule) -> Result<(), BuildRuntimeError> {
+    async fn get_archive_internal(&mut self, agent_id: &str, archive_rule: &ArchiveRule, output_directory: &PathBuf) -> Result<ArtifactManifest, BuildRuntimeError> {
+        let roots = artifacts::archive_roots(archive_rule)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+
+        let mut downloads = vec![];
+        for root in roots {
+            let id: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .collect();
+
+            let mut download_path = env::temp_dir();
+            download_path.push(format!("jarvis-archive-{}.tar", id));
+
+            let download_result = self.download_path(agent_id, root.as_str(), &download_path).await;
+            downloads.push((root, download_path));
+
+            if let Err(e) = download_result {
+                remove_downloads(&downloads);
+                return Err(BuildRuntimeError { msg: format!("Failed to download archive {}: {}", archive_rule.name, e) });
+            }
+        }
+
+        let manifest = artifacts::package_archive(archive_rule, &downloads, output_directory)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) });
+
+        remove_downloads(&downloads);
+
+        manifest
+    }
+
+    async fn download_path(&self, agent_id: &str, path: &str, destination: &PathBuf) -> Result<(), BuildRuntimeError> {
         if let Some(ref docker) = self.docker {
             let mut download_stream = docker.download_from_container(agent_id, Some(DownloadFromContainerOptions {
-                path: archive_rule.location.clone()
+                path: path.to_string()
             }));
 
-            let output_file_name = match &archive_rule.output {
-                Some(output) => output.clone(),
-                None => format!("{}.tar", archive_rule.name)
-            };
-
-            let mut f = File::create(output_file_name)
+            let mut f = File::create(destination)
                 .map_err(|e| {
-                    BuildRuntimeError { msg: format!("Failed to create file for archive {}, due to {}", archive_rule.name, e) }
+                    BuildRuntimeError { msg: format!("Failed to create file for download of {}, due to {}", path, e) }
                 })?;
 
             while let Some(download_item) = download_stream.next().await {
@@ -373,7 +471,7 @@ impl DockerRuntime {
 
                 f.write_all(&bytes)
                     .map_err(|e| {
-                        BuildRuntimeError { msg: format!("Failed to write to archive {}, due to {}", archive_rule.name, e) }
+                        BuildRuntimeError { msg: format!("Failed to write download of {}, due to {}", path, e) }
                     })?;
             }
 
@@ -383,7 +481,7 @@ impl DockerRuntime {
         }
     }
 
-    async fn execute_command_internal(&mut self, agent_id: &str, shell_config: &ShellConfig, working_directory: &str, command: &str, detach: bool) -> Result<(), BuildRuntimeError> {
+    async fn execute_command_internal(&mut self, agent_id: &str, shell_config: &ShellConfig, working_directory: &str, command: &str, detach: bool, environment: Option<Vec<&str>>) -> Result<(), BuildRuntimeError> {
         if let Some(ref docker) = self.docker {
             let exec_id = docker.create_exec(agent_id, CreateExecOptions {
                 cmd: Some(vec![shell_config.executable.as_str(), "-c", command]),
@@ -392,6 +490,7 @@ impl DockerRuntime {
                 attach_stdin: Some(true),
                 tty: Some(true),
                 working_dir: Some(working_directory),
+                env: environment,
                 ..Default::default()
             }).await
                 .map(|exec| exec.id)
@@ -402,12 +501,13 @@ impl DockerRuntime {
                 detach
             }));
 
+            let mut output = self.secret_masker.stream();
             while let Some(exec_result) = exec.next().await {
                 match exec_result {
                     Ok(result) => {
                         match result {
                             StartExecResults::Attached { log } => {
-                                print!("{}", log);
+                                print!("{}", output.push(format!("{}", log).as_str()));
                             }
                             StartExecResults::Detached => {
                                 // Do nothing
@@ -415,10 +515,12 @@ impl DockerRuntime {
                         }
                     },
                     Err(e) => {
+                        print!("{}", output.finish());
                         return Err(BuildRuntimeError { msg: format!("Error running exec: {}", e) });
                     }
                 }
             }
+            print!("{}", output.finish());
 
             docker.inspect_exec(&exec_id).await
                 .map_err(|e| {
@@ -458,6 +560,63 @@ impl DockerRuntime {
         }
     }
 
+    async fn execute_command_for_output(&self, agent_id: &str, shell_config: &ShellConfig, working_directory: &str, command: &str) -> Result<String, BuildRuntimeError> {
+        self.execute_command_for_output_as(agent_id, shell_config, working_directory, command, None, None).await
+    }
+
+    // Runs as `user` rather than the user the container was created with when one is given, with `environment` added to
+    // the container's environment for this command only.
+    async fn execute_command_for_output_as(&self, agent_id: &str, shell_config: &ShellConfig, working_directory: &str, command: &str, user: Option<&str>, environment: Option<Vec<&str>>) -> Result<String, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let exec_id = docker.create_exec(agent_id, CreateExecOptions {
+                cmd: Some(vec![shell_config.executable.as_str(), "-c", command]),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                working_dir: Some(working_directory),
+                user,
+                env: environment,
+                ..Default::default()
+            }).await
+                .map(|exec| exec.id)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create exec: {}", format_docker_api_error(e)) })
+                ?;
+
+            let mut exec = docker.start_exec(&exec_id, None::<StartExecOptions>);
+
+            let mut output = String::new();
+            let mut errors = self.secret_masker.stream();
+            while let Some(exec_result) = exec.next().await {
+                match exec_result {
+                    Ok(StartExecResults::Attached { log }) => {
+                        match log {
+                            LogOutput::StdOut { .. } | LogOutput::Console { .. } => output.push_str(format!("{}", log).as_str()),
+                            _ => print!("{}", errors.push(format!("{}", log).as_str()))
+                        }
+                    },
+                    Ok(StartExecResults::Detached) => {},
+                    Err(e) => {
+                        print!("{}", errors.finish());
+                        return Err(BuildRuntimeError { msg: format!("Error running exec: {}", e) });
+                    }
+                }
+            }
+            print!("{}", errors.finish());
+
+            let result = docker.inspect_exec(&exec_id).await
+                .map_err(|e| {
+                    BuildRuntimeError { msg: format!("Failed to check command status {}", format_docker_api_error(e)) }
+                })?;
+
+            if result.exit_code != Some(0) {
+                return Err(BuildRuntimeError { msg: format!("Command [{}] has non-zero exit status [{:?}]", self.secret_masker.mask(command), result.exit_code) });
+            }
+
+            Ok(self.secret_masker.mask(output.as_str()))
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
     async fn delete_container(&self, agent_id: &str) -> Result<(), BuildRuntimeError> {
         if let Some(ref docker) = self.docker {
             let options = Some(RemoveContainerOptions {
@@ -615,7 +774,7 @@ impl DockerRuntime {
             let image = "alpine:latest";
             let image_available = self.image_available(image).await?;
             if !image_available {
-                self.pull_image(image).await?;
+                self.pull_image(image, None).await?;
             }
 
             let container_result = docker.create_container(Some(CreateContainerOptions { name: id.clone() }), Config {
@@ -647,7 +806,7 @@ impl DockerRuntime {
                 executable: "/bin/sh".to_string()
             };
 
-            self.execute_command_internal(container.as_str(), &shell_config, "/input", "cp -pR agent-worker agent-plugins bin /plugins", false).await?;
+            self.execute_command_internal(container.as_str(), &shell_config, "/input", "cp -pR agent-worker agent-plugins bin /plugins", false, None).await?;
 
             self.delete_container(container.as_str()).await?;
 
@@ -656,6 +815,85 @@ impl DockerRuntime {
             Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
         }
     }
+
+    async fn populate_workspace(&mut self, module_name: &String, init_agent: &str, data_volume_name: &str, volume_existed: bool, checkouts: Option<&Vec<CheckoutRule>>, project_config: &ProjectConfig, build_options: &BuildOptions) -> Result<Vec<CheckoutReport>, BuildRuntimeError> {
+        if build_options.reuse_workspace {
+            self.sync_workspace(init_agent, data_volume_name, volume_existed, project_config).await?;
+        } else if build_options.workspace_mode == WorkspaceMode::Volume {
+            self.upload_project(init_agent, project_config).await?;
+        }
+
+        match checkouts {
+            Some(checkouts) => self.populate_checkouts(module_name.as_str(), init_agent, checkouts, project_config).await,
+            None => Ok(vec![])
+        }
+    }
+
+    async fn start_agent(&mut self, module_name: &String, name: &str, agent: &Agent, image: &str, step: Option<&Step>, services_network: Option<String>) -> Result<(), BuildRuntimeError> {
+        let secrets = match &step {
+            Some(step) => &step.secrets,
+            None => &None
+        };
+
+        let using_plugins = match &step {
+            Some(step) => step.plugins.is_some(),
+            None => false
+        };
+
+        let environment = step.and_then(|step| step.environment.as_ref()).map(|environment| environment.as_str());
+        let agent_secrets = self.configure_secrets(module_name.as_str(), secrets, environment).await?;
+
+        if let Some(step) = &step {
+            let component = self.module_components.get(module_name).unwrap();
+            if let Some(proxy) = &component.egress_proxy {
+                let proxy_container = proxy.container_id.clone();
+                let allowed_hosts = step.allowed_hosts.clone().or(component.allowed_hosts.clone());
+                self.reconfigure_egress_proxy(proxy_container.as_str(), allowed_hosts.as_ref()).await?;
+            }
+        }
+
+        let (cache_mounts, cache_reports) = match &agent.cache {
+            Some(cache_rules) => self.prepare_caches(module_name.as_str(), cache_rules).await?,
+            None => (vec![], vec![])
+        };
+
+        self.create_container(module_name, name, agent, image, &agent_secrets, cache_mounts, using_plugins).await
+            .map(|x| {
+                let component: &mut Box<ModuleComponents> = self.module_components.get_mut(module_name).unwrap();
+                component.containers.insert(agent.name.clone(), x);
+                component.cache_reports.insert(name.to_string(), cache_reports);
+                ()
+            })?;
+
+        let container_id = self.module_components.get(module_name).unwrap().containers.get(agent.name.as_str()).unwrap().clone();
+        if let Some(network) = &services_network {
+            self.connect_to_services(network.as_str(), container_id.as_str()).await?;
+        }
+        self.start_container(container_id.as_str()).await?;
+        // Secret files are only readable by their owner, so they belong to whoever the agent runs as. That user's files in
+        // a bind mounted project are handed back to the project's owner when the agent is destroyed.
+        let bind_mounted = self.module_components.get(module_name).unwrap().workspace_mode == WorkspaceMode::Bind;
+        let owner = match agent_user(agent) {
+            Some(user) => Some(user),
+            None if bind_mounted || !agent_secrets.files.is_empty() => self.image_user(image).await?,
+            None => None
+        };
+        if bind_mounted {
+            let writer = owner.as_ref().and_then(|owner| owner.split(':').next()).unwrap_or("0").to_string();
+            self.module_components.get_mut(module_name).unwrap().workspace_writers.insert(name.to_string(), writer);
+        }
+        self.deliver_secrets(module_name.as_str(), name, container_id.as_str(), agent_secrets, owner).await?;
+
+        if step.is_some() && step.unwrap().plugins.is_some() {
+            let shell_config = ShellConfig {
+                executable: "/bin/sh".to_string()
+            };
+            println!("Executing agent commant");
+            self.execute_command_internal(container_id.as_str(), &shell_config, "/", "chmod 500 /build/agent/bin/detect_arch.sh && . /build/agent/bin/detect_arch.sh && /build/agent/agent-worker/0.0.0-dev/$ARCH/agent-worker", true, None).await?;
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -664,25 +902,99 @@ impl BuildRuntime for DockerRuntime {
         self.docker = Some(Docker::connect_with_local_defaults().unwrap())
     }
 
-    async fn init_for_module(&mut self, module_name: &String, project_config: &ProjectConfig) -> Result<(), BuildRuntimeError> {
-        let id: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(30)
-            .collect();
-        let data_volume_name = format!("build-data-volume_{}_{}", module_name, id);
+    async fn init_for_module(&mut self, module_name: &String, project_config: &ProjectConfig, build_options: &BuildOptions) -> Result<ModuleReport, BuildRuntimeError> {
+        let identifier_base = format!("{}_{}", project_config.build_config.project_id, module_name);
+        let data_volume_name = if build_options.reuse_workspace {
+            DockerRuntime::reused_workspace_name(identifier_base.as_str())
+        } else {
+            let id: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .collect();
+            format!("build-data-volume_{}_{}", module_name, id)
+        };
         let module_components = ModuleComponents {
-            jarvis_directory: project_config.jarvis_directory.clone(),
+            secrets_paths: workspace::workspace_secrets_paths(project_config)
+                .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?,
+            secrets_provider: secrets::create_provider(project_config)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to configure secrets: {}", e) })?,
+            delivered_secrets: HashMap::new(),
+            secret_environment: HashMap::new(),
+            workspace_writers: HashMap::new(),
+            services: HashMap::new(),
+            project_directory: project_config.project_directory.clone(),
+            workspace_directory: project_config.workspace_directory.clone(),
+            cache_reports: HashMap::new(),
+            remote_cache: cache::create_remote_cache(&project_config.build_config)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to configure remote cache: {}", e) })?,
+            cache_pushes: vec![],
             // TODO rename to workspace volume
             build_data_volume: data_volume_name.clone(),
+            reuse_workspace: build_options.reuse_workspace,
+            workspace_mode: build_options.workspace_mode,
+            overlay_scratch_volume: None,
+            sensitive_workspace: project_config.build_config.workspace.as_ref().and_then(|workspace| workspace.sensitive).unwrap_or(false),
+            sensitive_caches: vec![],
             containers: HashMap::new(),
-            // TODO identify the project more specifically to allow duplicate module names.
-            identifier_base: format!("{}", module_name),
+            identifier_base,
+            project_id: project_config.build_config.project_id.clone(),
+            egress_proxy: None,
+            allowed_hosts: None,
+            registries: project_config.build_config.registries.clone().unwrap_or_default(),
+            image_lock: ImageLock::load(&project_config.jarvis_directory)
+                .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?,
         };
 
         self.module_components.insert(module_name.to_string(), Box::new(module_components));
 
-        self.create_docker_volume(data_volume_name.as_str(), None).await
-            .map(|_| { () })?;
+        let module = project_config.build_config.modules.iter().find(|m| &m.name == module_name);
+        let checkouts = module.and_then(|module| module.checkouts.as_ref());
+        if let Some(module) = module {
+            let restricts_egress = module.allowed_hosts.is_some() || module.steps.iter().any(|step| step.allowed_hosts.is_some());
+
+            if module.monitor_egress.unwrap_or(false) || restricts_egress {
+                let proxy = self.start_egress_proxy(module_name.as_str(), module.allowed_hosts.as_ref()).await?;
+                let component = self.module_components.get_mut(module_name).unwrap();
+                component.egress_proxy = Some(proxy);
+                component.allowed_hosts = module.allowed_hosts.clone();
+            }
+        }
+
+        let mut volume_existed = false;
+        match build_options.workspace_mode {
+            WorkspaceMode::Bind => {
+                if checkouts.is_some() {
+                    return Err(BuildRuntimeError { msg: format!("Module [{}] has checkouts, which can't be written into a bind mounted workspace", module_name) });
+                }
+
+                println!("Mounting workspace from [{}]", project_config.workspace_directory.display());
+                return Ok(ModuleReport::default());
+            },
+            WorkspaceMode::BindReadOnly => {
+                println!("Mounting workspace from [{}] with a discarded overlay", project_config.workspace_directory.display());
+                self.create_overlay_workspace(module_name.as_str(), data_volume_name.as_str(), &project_config.workspace_directory).await?;
+
+                if checkouts.is_none() {
+                    return Ok(ModuleReport::default());
+                }
+            },
+            WorkspaceMode::Volume => {
+                volume_existed = build_options.reuse_workspace && self.workspace_volume_exists(data_volume_name.as_str()).await?;
+            }
+        }
+
+        if build_options.workspace_mode == WorkspaceMode::Volume && !volume_existed {
+            let labels = if build_options.reuse_workspace {
+                let mut labels = HashMap::new();
+                labels.insert("used-for".to_string(), "workspace".to_string());
+                Some(labels)
+            } else {
+                None
+            };
+
+            self.create_docker_volume(data_volume_name.as_str(), labels).await
+                .map(|_| { () })?;
+        }
 
         let init_agent = self.create_agent(module_name, &Agent {
             name: "jarvis-init".to_string(),
@@ -691,11 +1003,22 @@ impl BuildRuntime for DockerRuntime {
             environment: None,
             cache: None,
             container: None,
+            pull_policy: None,
         }, None).await?;
 
-        self.upload_project(init_agent.as_str(), &project_config.project_directory).await?;
-
-        self.delete_container(init_agent.as_str()).await
+        // The init agent goes whether or not the workspace could be populated, the build tears down everything else.
+        let populate_result = self.populate_workspace(module_name, init_agent.as_str(), data_volume_name.as_str(), volume_existed, checkouts, project_config, build_options).await;
+        let delete_result = self.delete_container(init_agent.as_str()).await;
+        match (populate_result, delete_result) {
+            (Ok(checkouts), Ok(_)) => Ok(ModuleReport {
+                checkouts,
+            }),
+            (Err(e), Err(delete_error)) => {
+                println!("Failed to remove [{}]: {}", init_agent, delete_error);
+                Err(e)
+            },
+            (Err(e), Ok(_)) | (Ok(_), Err(e)) => Err(e)
+        }
     }
 
     async fn create_agent(&mut self, module_name: &String, agent: &Agent, step: Option<&Step>) -> Result<String, BuildRuntimeError> {
@@ -705,57 +1028,129 @@ impl BuildRuntime for DockerRuntime {
             .collect();
         let name = format!("jarvis-agent-{}-{}-{}", module_name, agent.name, id);
 
-        if !self.image_available(agent.image.as_str()).await? {
-            self.pull_image(agent.image.as_str()).await?;
-        }
-
-        let secrets = match &step {
-            Some(step) => &step.secrets,
-            None => &None
-        };
+        let image = self.ensure_agent_image(module_name, agent).await?;
 
-        let using_plugins = match &step {
-            Some(step) => step.plugins.is_some(),
-            None => false
+        // Services are ready before the agent exists, so the step never sees them half started.
+        let services_network = match step.and_then(|step| step.services.as_ref()) {
+            Some(services) => Some(self.start_services(module_name.as_str(), name.as_str(), services).await?),
+            None => None
         };
 
-        let secrets_config = configure_secrets(&self.module_components.get(module_name).unwrap().jarvis_directory, secrets)?;
+        // An agent which can't be started is destroyed straight away, it may already hold secrets and services.
+        if let Err(e) = self.start_agent(module_name, name.as_str(), agent, image.as_str(), step, services_network).await {
+            self.destroy_agent(name.as_str()).await
+                .unwrap_or_else(|e| println!("Failed to destroy agent [{}]: {}", name, e));
+            return Err(e);
+        }
 
-        self.create_container(module_name, name.as_str(), agent, secrets_config, using_plugins).await
-            .map(|x| {
-                let component: &mut Box<ModuleComponents> = self.module_components.get_mut(module_name).unwrap();
-                component.containers.insert(agent.name.clone(), x);
-                ()
-            })?;
+        Ok(name)
+    }
 
-        let container_id = self.module_components.get(module_name).unwrap().containers.get(agent.name.as_str()).unwrap().clone();
-        self.start_container(container_id.as_str()).await?;
+    async fn execute_command(&mut self, agent_id: &str, shell_config: &ShellConfig, command: &str) -> Result<(), BuildRuntimeError> {
+        let environment = self.module_components.values()
+            .find_map(|component| component.secret_environment.get(agent_id))
+            .cloned();
+        let environment = environment.as_ref().map(|environment| environment.iter().map(|variable| variable.as_str()).collect());
 
-        if step.is_some() && step.unwrap().plugins.is_some() {
-            let shell_config = ShellConfig {
-                executable: "/bin/sh".to_string()
-            };
-            println!("Executing agent commant");
-            self.execute_command_internal(container_id.as_str(), &shell_config, "/", "chmod 500 /build/agent/bin/detect_arch.sh && . /build/agent/bin/detect_arch.sh && /build/agent/agent-worker/0.0.0-dev/$ARCH/agent-worker", true).await?;
-        }
+        self.execute_command_internal(agent_id, shell_config, "/build/workspace", command, false, environment).await
+    }
 
-        Ok(name.clone())
+    async fn get_archive(&mut self, agent_id: &str, archive_rule: &ArchiveRule, output_directory: &PathBuf) -> Result<ArtifactManifest, BuildRuntimeError> {
+        self.get_archive_internal(agent_id, archive_rule, output_directory).await
     }
 
-    async fn execute_command(&mut self, agent_id: &str, shell_config: &ShellConfig, command: &str) -> Result<(), BuildRuntimeError> {
-        self.execute_command_internal(agent_id, shell_config, "/build/workspace", command, false).await
+    async fn restore_input(&mut self, agent_id: &str, archive: Vec<u8>, target_path: &str) -> Result<(), BuildRuntimeError> {
+        let target_path = if target_path.starts_with('/') {
+            target_path.to_string()
+        } else {
+            format!("{}/{}", artifacts::WORKSPACE_DIRECTORY, target_path.trim_start_matches("./"))
+        };
+
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+        self.execute_command_for_output(agent_id, &shell_config, "/", format!("mkdir -p -- {}", shell_quote(target_path.as_str())).as_str()).await?;
+
+        if let Some(ref docker) = self.docker {
+            docker.upload_to_container(agent_id, Some(UploadToContainerOptions {
+                path: target_path.as_str(),
+                ..Default::default()
+            }), archive.into()).await
+                .map_err(|e| {
+                    BuildRuntimeError { msg: format!("Error restoring input to [{}]: {}", target_path, format_docker_api_error(e)) }
+                })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
     }
 
-    async fn get_archive(&mut self, agent_id: &str, archive_rule: &ArchiveRule) -> Result<(), BuildRuntimeError> {
-        self.get_archive_internal(agent_id, archive_rule).await
+    async fn collect_step_report(&mut self, module_name: &String, agent_id: &str) -> Result<StepReport, BuildRuntimeError> {
+        let caches = self.module_components.get_mut(module_name).unwrap().cache_reports.remove(agent_id)
+            .unwrap_or(vec![]);
+
+        let proxy_container = self.module_components.get(module_name).unwrap().egress_proxy.as_ref()
+            .map(|proxy| proxy.container_id.clone());
+
+        let egress = match proxy_container {
+            Some(proxy_container) => Some(self.read_egress_log(proxy_container.as_str()).await?),
+            None => None
+        };
+
+        Ok(StepReport {
+            egress,
+            caches,
+        })
     }
 
+    // Every part of the agent is removed even when removing another part fails.
     async fn destroy_agent(&mut self, agent_id: &str) -> Result<(), BuildRuntimeError> {
-        self.delete_container(agent_id).await
+        self.remove_secrets(agent_id).await;
+        self.restore_workspace_ownership(agent_id).await;
+
+        let container_result = self.delete_container(agent_id).await;
+        let services_result = self.stop_services(agent_id).await;
+        first_error(vec![container_result, services_result])
     }
 
-    async fn tear_down_for_module(&self, module_name: &String) -> Result<(), BuildRuntimeError> {
-        self.delete_volume(self.module_components.get(module_name).unwrap().build_data_volume.as_str()).await
+    async fn tear_down_for_module(&self, module_name: &String, succeeded: bool) -> Result<(), BuildRuntimeError> {
+        if succeeded {
+            self.push_remote_caches(module_name.as_str()).await;
+        }
+
+        // One failure doesn't stop the rest being cleaned up, sensitive data especially.
+        let mut results = vec![];
+        let module_components = match self.module_components.get(module_name) {
+            Some(module_components) => module_components,
+            // The module failed to initialise before anything was created for it.
+            None => return Ok(())
+        };
+        if let Some(proxy) = &module_components.egress_proxy {
+            results.push(self.stop_egress_proxy(proxy).await);
+        }
+
+        for cache_volume in &module_components.sensitive_caches {
+            results.push(self.destroy_volume(cache_volume.as_str()).await);
+        }
+
+        // Everything the build wrote to an overlay workspace is in the scratch volume, the overlay itself only points at
+        // the project directory.
+        let sensitive = module_components.sensitive_workspace;
+        match module_components.workspace_mode {
+            WorkspaceMode::Bind => {},
+            WorkspaceMode::BindReadOnly => {
+                results.push(self.delete_volume(module_components.build_data_volume.as_str()).await);
+                match &module_components.overlay_scratch_volume {
+                    Some(scratch_volume) if sensitive => results.push(self.destroy_volume(scratch_volume.as_str()).await),
+                    Some(scratch_volume) => results.push(self.delete_volume(scratch_volume.as_str()).await),
+                    None => {}
+                }
+            },
+            WorkspaceMode::Volume if module_components.reuse_workspace => {},
+            WorkspaceMode::Volume if sensitive => results.push(self.destroy_volume(module_components.build_data_volume.as_str()).await),
+            WorkspaceMode::Volume => results.push(self.delete_volume(module_components.build_data_volume.as_str()).await)
+        }
+
+        first_error(results)
     }
 
     async fn cleanup_resources(&self) -> Result<(), BuildRuntimeError> {
@@ -793,6 +1188,22 @@ impl BuildRuntime for DockerRuntime {
         Ok(())
     }
 
+    async fn list_caches(&self) -> Result<Vec<CacheInfo>, BuildRuntimeError> {
+        self.list_cache_volumes().await
+    }
+
+    async fn remove_cache(&self, volume_name: &str) -> Result<(), BuildRuntimeError> {
+        self.delete_volume(volume_name).await
+    }
+
+    async fn export_cache(&self, volume_name: &str, destination: &PathBuf) -> Result<(), BuildRuntimeError> {
+        self.export_cache_volume(volume_name, destination).await
+    }
+
+    async fn import_cache(&self, volume_name: &str, metadata: &CacheMetadata, archive: &PathBuf) -> Result<(), BuildRuntimeError> {
+        self.import_cache_volume(volume_name, metadata, archive).await
+    }
+
     async fn ensure_plugins_loaded(&mut self, _plugins: Vec<&PluginSpecification>) -> Result<(), BuildRuntimeError> {
         // TODO do not unwrap me
         let agent_home = std::env::var("JARVIS_AGENT_HOME").unwrap();
@@ -805,43 +1216,48 @@ impl BuildRuntime for DockerRuntime {
 
         Ok(())
     }
-}
 
-fn configure_secrets(jarvis_directory: &PathBuf, secrets: &Option<Vec<String>>) -> Result<Vec<(String, String, String)>, BuildRuntimeError> {
-    let mut secret_mounts = Vec::<(String, String, String)>::new();
-    if let Some(secrets) = secrets {
-        for secret in secrets {
-            let secret_file = jarvis_directory.join("secrets").join(format!("{}.secret.txt", secret));
+    async fn build_image(&mut self, module_name: &String, rule: &BuildImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        self.build_workspace_image(module_name.as_str(), rule).await
+    }
 
-            if !secret_file.exists() {
-                return Err(BuildRuntimeError { msg: format!("Secret [{}] not found at [{}]", secret, secret_file.to_str().unwrap()) });
-            }
+    async fn push_image(&mut self, module_name: &String, rule: &PushImageRule, environment: Option<&str>) -> Result<Vec<PushedImage>, BuildRuntimeError> {
+        self.push_built_image(module_name.as_str(), rule, environment).await
+    }
 
-            let target_path = secret_file.absolutize().unwrap().to_str().unwrap().to_owned();
-            secret_mounts.push((
-                to_environment_variable_name(secret),
-                target_path,
-                format!("/build/secrets/{}", secret)
-            ));
-        }
+    async fn register_image(&mut self, module_name: &String, agent_id: &str, rule: &RegisterImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        self.register_step_image(module_name.as_str(), agent_id, rule).await
     }
 
-    Ok(secret_mounts)
-}
+    async fn remove_build_images(&mut self) -> Result<(), BuildRuntimeError> {
+        self.remove_scoped_images().await
+    }
 
-fn to_environment_variable_name(source: &str) -> String {
-    let pattern = Regex::new(r"(?P<l>.*)[^a-zA-Z0-9_](?P<r>.*)").unwrap();
+    async fn resolve_image_digest(&mut self, project_config: &ProjectConfig, image: &str) -> Result<String, BuildRuntimeError> {
+        self.resolve_digest(project_config, image).await
+    }
+}
 
-    // Loop required to deal with overlapping matches, would a different regex help?
+fn remove_downloads(downloads: &Vec<(String, PathBuf)>) {
+    for download in downloads {
+        if download.1.exists() {
+            std::fs::remove_file(&download.1).unwrap_or_else(|e| println!("Failed to remove temporary file [{}]: {}", download.1.display(), e));
+        }
+    }
+}
 
-    let mut last = "".to_owned();
-    let mut next = source.to_owned();
-    while last != next {
-        last = next.clone();
-        next = pattern.replace_all(next.as_str(), "${l}_$r").into_owned();
+// The first failure of a clean up which carries on past failures, any others are reported as they'd be lost otherwise.
+fn first_error(results: Vec<Result<(), BuildRuntimeError>>) -> Result<(), BuildRuntimeError> {
+    let mut errors = results.into_iter().filter_map(|result| result.err());
+    let first = errors.next();
+    for e in errors {
+        println!("{}", e);
     }
 
-    next.to_ascii_uppercase()
+    match first {
+        Some(e) => Err(e),
+        None => Ok(())
+    }
 }
 
 fn format_docker_api_error(e: bollard::errors::Error) -> String {
@@ -851,19 +1267,3 @@ fn format_docker_api_error(e: bollard::errors::Error) -> String {
         _ => "Driver error".to_string()
     }
 }
-
-async fn ensure_caches_created(runtime: &DockerRuntime, cache_rules: &Vec<CacheRule>, identifier_base: &str) -> Result<HashMap<String, String>, BuildRuntimeError> {
-    let volumes: HashMap<String, String> = cache_rules.iter().map(|rule| {
-        let cache_volume_name = format!("cache_{}_{}", identifier_base, rule.name);
-        (rule.name.clone(), cache_volume_name)
-    }).collect();
-
-    let mut extra_labels = HashMap::new();
-    extra_labels.insert("used-for".to_string(), "caching".to_string());
-
-    for volume in &volumes {
-        runtime.create_docker_volume(volume.1, Some(extra_labels.clone())).await?;
-    }
-
-    Ok(volumes)
-}
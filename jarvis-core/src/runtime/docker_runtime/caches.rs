@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+
+use std::path::PathBuf;
+
+use bollard::container::{Config, CreateContainerOptions, UploadToContainerOptions};
+use bollard::models::{HostConfig, Mount, MountTypeEnum};
+use bollard::volume::ListVolumesOptions;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::cache;
+use crate::cache::{CacheInfo, CacheMetadata};
+use crate::config::{CacheMode, CacheRule, ShellConfig};
+use crate::runtime::{BuildRuntimeError, CacheOutcome, CacheReport};
+use super::{DockerRuntime, format_docker_api_error};
+
+const CACHE_PROJECT_LABEL: &str = "cache-project";
+const CACHE_MODULE_LABEL: &str = "cache-module";
+const CACHE_NAME_LABEL: &str = "cache-name";
+const CACHE_KEY_LABEL: &str = "cache-key";
+
+struct CacheVolume {
+    name: String,
+
+    key: String,
+
+    build_time: String,
+}
+
+impl DockerRuntime {
+    // Works out which volume backs each cache for the current key. An exact key match is a hit, otherwise the newest volume
+    // whose key starts with one of the restore keys seeds a fresh volume for the new key.
+    pub(super) async fn prepare_caches(&mut self, module_name: &str, cache_rules: &Vec<CacheRule>) -> Result<(Vec<Mount>, Vec<CacheReport>), BuildRuntimeError> {
+        let component = self.module_components.get(module_name).unwrap();
+        let identifier_base = component.identifier_base.clone();
+        let project_id = component.project_id.clone();
+        let workspace_directory = component.workspace_directory.clone();
+
+        let mut mounts = vec![];
+        let mut reports = vec![];
+        for rule in cache_rules {
+            let key = match &rule.key {
+                Some(template) => cache::render_cache_key(template.as_str(), &workspace_directory)
+                    .map_err(|e| BuildRuntimeError { msg: format!("Failed to resolve key for cache [{}]: {}", rule.name, e) })?,
+                None => "".to_string()
+            };
+            let read_only = rule.mode == Some(CacheMode::ReadOnly);
+
+            let cache_labels = vec![
+                (CACHE_PROJECT_LABEL.to_string(), project_id.clone()),
+                (CACHE_MODULE_LABEL.to_string(), module_name.to_string()),
+                (CACHE_NAME_LABEL.to_string(), rule.name.clone()),
+            ];
+            let volume_name = cache::volume_name(identifier_base.as_str(), rule.name.as_str(), key.as_str());
+            let existing = self.find_cache_volumes(&cache_labels).await?;
+
+            let metadata = CacheMetadata {
+                project_id: project_id.clone(),
+                module_name: module_name.to_string(),
+                name: rule.name.clone(),
+                key: key.clone(),
+            };
+
+            let hit = existing.iter().any(|volume| volume.name == volume_name);
+            // The remote cache only saves time, so a build carries on without it when it can't be reached.
+            let remote_hit = !hit && !key.is_empty() && match self.pull_remote_cache(module_name, volume_name.as_str(), &metadata).await {
+                Ok(pulled) => pulled,
+                Err(e) => {
+                    println!("Failed to pull cache [{}], continuing without it: {}", rule.name, e);
+                    false
+                }
+            };
+
+            let restored = if hit || remote_hit {
+                None
+            } else {
+                rule.restore_keys.iter().flatten()
+                    .filter_map(|prefix| {
+                        existing.iter()
+                            .filter(|volume| volume.key.starts_with(prefix.as_str()))
+                            .max_by(|a, b| a.build_time.cmp(&b.build_time))
+                    })
+                    .next()
+            };
+
+            let (outcome, source) = if hit {
+                (CacheOutcome::Hit, Some(volume_name.clone()))
+            } else if remote_hit {
+                (CacheOutcome::RemoteHit, Some(volume_name.clone()))
+            } else if let Some(restored) = restored {
+                let restored_key = restored.key.clone();
+                let restored_volume = restored.name.clone();
+
+                // A read only cache can use the restored volume directly since nothing will be written back to it.
+                if read_only {
+                    (CacheOutcome::PartialHit { restored_key }, Some(restored_volume))
+                } else {
+                    self.create_cache_volume(volume_name.as_str(), &cache_labels, key.as_str()).await?;
+                    self.copy_volume(restored_volume.as_str(), volume_name.as_str()).await?;
+                    (CacheOutcome::PartialHit { restored_key }, Some(volume_name.clone()))
+                }
+            } else if read_only {
+                // Creating an empty volume here would make later builds see a hit for a cache nobody populated.
+                (CacheOutcome::Miss, None)
+            } else {
+                self.create_cache_volume(volume_name.as_str(), &cache_labels, key.as_str()).await?;
+                (CacheOutcome::Miss, Some(volume_name.clone()))
+            };
+
+            // Only content under a new key is worth sending, an unchanged key means the remote already has it.
+            // Sensitive caches never leave the machine.
+            let changed = outcome != CacheOutcome::Hit && outcome != CacheOutcome::RemoteHit;
+            let sensitive = rule.sensitive.unwrap_or(false);
+            let component = self.module_components.get_mut(module_name).unwrap();
+            if changed && !read_only && !sensitive && !key.is_empty() && component.remote_cache.is_some() {
+                if !component.cache_pushes.iter().any(|push| push.0 == volume_name) {
+                    component.cache_pushes.push((volume_name.clone(), metadata));
+                }
+            }
+
+            if let Some(source) = source {
+                if sensitive && !read_only && !component.sensitive_caches.contains(&source) {
+                    component.sensitive_caches.push(source.clone());
+                }
+
+                mounts.push(Mount {
+                    target: Some(rule.location.clone()),
+                    source: Some(source),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    read_only: Some(read_only),
+                    ..Default::default()
+                });
+            }
+
+            reports.push(CacheReport {
+                name: rule.name.clone(),
+                key,
+                read_only,
+                outcome,
+            });
+        }
+
+        let used_volumes = mounts.iter().filter_map(|mount| mount.source.clone()).collect();
+        cache::record_cache_use(&used_volumes)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+
+        Ok((mounts, reports))
+    }
+
+    pub(super) async fn list_cache_volumes(&self) -> Result<Vec<CacheInfo>, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let mut filters = HashMap::new();
+            filters.insert("label", vec!["used-for=caching"]);
+
+            let volumes = docker.list_volumes(Some(ListVolumesOptions { filters })).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to list cache volumes {}", format_docker_api_error(e)) })?
+                .volumes;
+
+            // Sizes are only available from the disk usage report, which can be slow to compute when there are many volumes.
+            let usage = docker.df().await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to get volume disk usage {}", format_docker_api_error(e)) })?;
+            let usage_by_name: HashMap<String, (i64, i64)> = usage.volumes.unwrap_or(vec![]).into_iter()
+                .filter_map(|volume| volume.usage_data.map(|data| (volume.name, (data.size, data.ref_count))))
+                .collect();
+
+            Ok(volumes.into_iter().map(|volume| {
+                let label = |name: &str| volume.labels.get(name).cloned().unwrap_or_default();
+                let usage = usage_by_name.get(&volume.name);
+
+                CacheInfo {
+                    volume_name: volume.name.clone(),
+                    project_id: label(CACHE_PROJECT_LABEL),
+                    module_name: label(CACHE_MODULE_LABEL),
+                    name: label(CACHE_NAME_LABEL),
+                    key: label(CACHE_KEY_LABEL),
+                    // Docker reports -1 when the size hasn't been calculated.
+                    size: usage.filter(|usage| usage.0 >= 0).map(|usage| usage.0 as u64),
+                    created: label("build-time"),
+                    last_used: None,
+                    in_use: usage.map_or(false, |usage| usage.1 > 0),
+                }
+            }).collect())
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn find_cache_volumes(&self, cache_labels: &Vec<(String, String)>) -> Result<Vec<CacheVolume>, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let label_filters: Vec<String> = cache_labels.iter().map(|label| format!("{}={}", label.0, label.1)).collect();
+
+            let mut filters = HashMap::new();
+            let mut label_filter = vec!["used-for=caching"];
+            label_filter.extend(label_filters.iter().map(|label| label.as_str()));
+            filters.insert("label", label_filter);
+
+            docker.list_volumes(Some(ListVolumesOptions { filters })).await
+                .map(|results| {
+                    results.volumes.iter().map(|volume| {
+                        CacheVolume {
+                            name: volume.name.clone(),
+                            key: volume.labels.get(CACHE_KEY_LABEL).cloned().unwrap_or_default(),
+                            build_time: volume.labels.get("build-time").cloned().unwrap_or_default(),
+                        }
+                    }).collect()
+                })
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to list cache volumes {}", format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn create_cache_volume(&self, volume_name: &str, cache_labels: &Vec<(String, String)>, key: &str) -> Result<String, BuildRuntimeError> {
+        let mut extra_labels: HashMap<String, String> = cache_labels.iter().cloned().collect();
+        extra_labels.insert("used-for".to_string(), "caching".to_string());
+        extra_labels.insert(CACHE_KEY_LABEL.to_string(), key.to_string());
+
+        self.create_docker_volume(volume_name, Some(extra_labels)).await
+    }
+
+    async fn copy_volume(&mut self, source_volume: &str, target_volume: &str) -> Result<(), BuildRuntimeError> {
+        let container = self.start_helper_container("cache-transfer", vec![Mount {
+            target: Some("/from".to_string()),
+            source: Some(source_volume.to_string()),
+            typ: Some(MountTypeEnum::VOLUME),
+            read_only: Some(true),
+            ..Default::default()
+        }, Mount {
+            target: Some("/to".to_string()),
+            source: Some(target_volume.to_string()),
+            typ: Some(MountTypeEnum::VOLUME),
+            ..Default::default()
+        }]).await?;
+
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+
+        let copy_result = self.execute_command_for_output(container.as_str(), &shell_config, "/", "cp -a /from/. /to/").await;
+
+        self.delete_container(container.as_str()).await?;
+
+        copy_result.map(|_| ())
+    }
+
+    // Writes a tar of the volume content, with every entry under `cache/`.
+    pub(super) async fn export_cache_volume(&self, volume_name: &str, destination: &PathBuf) -> Result<(), BuildRuntimeError> {
+        let container = self.start_helper_container("cache-transfer", vec![Mount {
+            target: Some("/cache".to_string()),
+            source: Some(volume_name.to_string()),
+            typ: Some(MountTypeEnum::VOLUME),
+            read_only: Some(true),
+            ..Default::default()
+        }]).await?;
+
+        let download_result = self.download_path(container.as_str(), "/cache", destination).await;
+
+        self.delete_container(container.as_str()).await?;
+
+        download_result
+    }
+
+    // Loads a cache archive into the volume for its key, creating the volume if this machine doesn't have it yet.
+    pub(super) async fn import_cache_volume(&self, volume_name: &str, metadata: &CacheMetadata, archive: &PathBuf) -> Result<(), BuildRuntimeError> {
+        let cache_labels = vec![
+            (CACHE_PROJECT_LABEL.to_string(), metadata.project_id.clone()),
+            (CACHE_MODULE_LABEL.to_string(), metadata.module_name.clone()),
+            (CACHE_NAME_LABEL.to_string(), metadata.name.clone()),
+        ];
+        self.create_cache_volume(volume_name, &cache_labels, metadata.key.as_str()).await?;
+
+        let content = std::fs::read(archive)
+            .map_err(|e| BuildRuntimeError { msg: format!("Failed to read [{}]: {}", archive.display(), e) })?;
+
+        let container = self.start_helper_container("cache-transfer", vec![Mount {
+            target: Some("/cache".to_string()),
+            source: Some(volume_name.to_string()),
+            typ: Some(MountTypeEnum::VOLUME),
+            ..Default::default()
+        }]).await?;
+
+        // The archive content is already under `cache/`, and Docker unpacks gzipped archives itself.
+        let upload_result = match self.docker {
+            Some(ref docker) => docker.upload_to_container(container.as_str(), Some(UploadToContainerOptions {
+                path: "/",
+                ..Default::default()
+            }), content.into()).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to import cache into [{}]: {}", volume_name, format_docker_api_error(e)) }),
+            None => Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        };
+
+        self.delete_container(container.as_str()).await?;
+
+        upload_result
+    }
+
+    // Pulls the archive for an exact key from the remote cache, returning `false` when the remote doesn't have it. A volume
+    // which couldn't be filled is removed again so it's never taken for a hit.
+    async fn pull_remote_cache(&self, module_name: &str, volume_name: &str, metadata: &CacheMetadata) -> Result<bool, BuildRuntimeError> {
+        let remote_cache = match &self.module_components.get(module_name).unwrap().remote_cache {
+            Some(remote_cache) => remote_cache,
+            None => return Ok(false)
+        };
+
+        let archive = cache::temp_path("jarvis-cache-pull", "tgz");
+        let pull_result = match remote_cache.pull(metadata.remote_object_name().as_str(), &archive).await {
+            Ok(true) => {
+                let import_result = self.import_cache_volume(volume_name, metadata, &archive).await;
+                if import_result.is_err() {
+                    self.delete_volume(volume_name).await
+                        .unwrap_or_else(|e| println!("Failed to remove partially imported cache [{}]: {}", volume_name, e));
+                }
+                import_result.map(|_| true)
+            },
+            Ok(false) => Ok(false),
+            Err(e) => Err(BuildRuntimeError { msg: format!("{}", e) })
+        };
+
+        if archive.exists() {
+            std::fs::remove_file(&archive).unwrap_or_else(|e| println!("Failed to remove temporary file [{}]: {}", archive.display(), e));
+        }
+
+        pull_result
+    }
+
+    // Sends caches created or changed by this module's steps to the remote cache. Failures only produce a warning because
+    // the build itself has already finished.
+    pub(super) async fn push_remote_caches(&self, module_name: &str) {
+        let component = self.module_components.get(module_name).unwrap();
+        let remote_cache = match &component.remote_cache {
+            Some(remote_cache) => remote_cache,
+            None => return
+        };
+
+        for (volume_name, metadata) in &component.cache_pushes {
+            println!("Pushing cache {} to the remote cache", metadata.name);
+
+            let volume_tar = cache::temp_path("jarvis-cache-push", "tar");
+            let archive = cache::temp_path("jarvis-cache-push", "tgz");
+
+            let push_result = match self.export_cache_volume(volume_name.as_str(), &volume_tar).await {
+                Ok(_) => match cache::pack_cache_archive(metadata, &volume_tar, &archive) {
+                    Ok(_) => remote_cache.push(metadata.remote_object_name().as_str(), &archive).await
+                        .map_err(|e| format!("{}", e)),
+                    Err(e) => Err(format!("{}", e))
+                },
+                Err(e) => Err(format!("{}", e))
+            };
+
+            if let Err(e) = push_result {
+                println!("Failed to push cache [{}]: {}", metadata.name, e);
+            }
+
+            for path in vec![volume_tar, archive] {
+                if path.exists() {
+                    std::fs::remove_file(&path).unwrap_or_else(|e| println!("Failed to remove temporary file [{}]: {}", path.display(), e));
+                }
+            }
+        }
+    }
+
+    // A short lived container for moving data in and out of volumes which aren't attached to any agent.
+    pub(super) async fn start_helper_container(&self, used_for: &str, mounts: Vec<Mount>) -> Result<String, BuildRuntimeError> {
+        self.start_helper_container_on_network(used_for, mounts, None).await
+    }
+
+    pub(super) async fn start_helper_container_on_network(&self, used_for: &str, mounts: Vec<Mount>, network: Option<String>) -> Result<String, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let id: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .collect();
+
+            let mut labels = HashMap::new();
+            labels.insert("created-by".to_string(), "jarvis".to_string());
+            labels.insert("used-for".to_string(), used_for.to_string());
+
+            let command_config = vec!["/bin/sh", "-c", "tail -f /dev/null"].iter().map(|x| x.to_string()).collect();
+
+            let image = "alpine:latest";
+            if !self.image_available(image).await? {
+                self.pull_image(image, None).await?;
+            }
+
+            let container = docker.create_container(Some(CreateContainerOptions { name: id.clone() }), Config {
+                image: Some(image.to_string()),
+                entrypoint: Some(command_config),
+                cmd: Some(vec![]),
+                labels: Some(labels),
+                host_config: Some(HostConfig {
+                    mounts: Some(mounts),
+                    network_mode: network,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }).await
+                .map(|x| x.id)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create helper container: {}", format_docker_api_error(e)) })?;
+
+            if let Err(e) = self.start_container(container.as_str()).await {
+                self.delete_container(container.as_str()).await
+                    .unwrap_or_else(|e| println!("Failed to remove helper container [{}]: {}", container, e));
+                return Err(e);
+            }
+
+            Ok(container)
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+}
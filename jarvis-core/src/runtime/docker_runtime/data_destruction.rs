@@ -0,0 +1,37 @@
+use bollard::models::{Mount, MountTypeEnum};
+
+use crate::config::ShellConfig;
+use crate::runtime::BuildRuntimeError;
+use super::DockerRuntime;
+
+// Overwrites every file with zeros in place, flushes the writes to disk and only then removes everything. The block count
+// is rounded up so the last partial block of each file is covered too.
+const SCRUB_COMMAND: &str = "find /scrub -type f -exec sh -c 'for f; do \
+    dd if=/dev/zero of=\"$f\" bs=65536 count=$(( ($(wc -c < \"$f\") + 65535) / 65536 )) conv=notrunc 2>/dev/null || exit 1; \
+    done' sh {} + \
+    && sync \
+    && rm -rf /scrub/* /scrub/.[!.]* /scrub/..?*";
+
+impl DockerRuntime {
+    // Deletes a volume holding sensitive data after overwriting its contents. This protects against the data being read
+    // back from the volume's backing storage once it's gone, within the limits of the storage itself: copy on write
+    // filesystems and SSDs may still keep the old blocks around.
+    pub(super) async fn destroy_volume(&self, volume_name: &str) -> Result<(), BuildRuntimeError> {
+        println!("Overwriting sensitive volume [{}]", volume_name);
+
+        let helper = self.start_helper_container("scrubbing", vec![Mount {
+            target: Some("/scrub".to_string()),
+            source: Some(volume_name.to_string()),
+            typ: Some(MountTypeEnum::VOLUME),
+            ..Default::default()
+        }]).await?;
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+        let scrubbed = self.execute_command_for_output(helper.as_str(), &shell_config, "/", SCRUB_COMMAND).await;
+        self.delete_container(helper.as_str()).await?;
+        scrubbed.map_err(|e| BuildRuntimeError { msg: format!("Failed to overwrite volume [{}], it has been kept: {}", volume_name, e) })?;
+
+        self.delete_volume(volume_name).await
+    }
+}
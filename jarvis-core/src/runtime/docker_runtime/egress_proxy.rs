@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+
+use bollard::container::{Config, CreateContainerOptions, UploadToContainerOptions};
+use bollard::network::{ConnectNetworkOptions, CreateNetworkOptions};
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use crate::config::ShellConfig;
+use crate::validate::valid_allowed_host;
+use crate::runtime::docker_runtime::{format_docker_api_error, DockerRuntime};
+use crate::runtime::{BuildRuntimeError, EgressRecord};
+
+const PROXY_IMAGE: &str = "ubuntu/squid:latest";
+
+const PROXY_PORT: u16 = 3128;
+
+const ACCESS_LOG: &str = "/var/log/squid/jarvis-access.log";
+
+const CACHE_LOG: &str = "/var/log/squid/cache.log";
+
+// Squid writes this to its cache log each time it finishes loading its configuration.
+const CONFIG_LOADED_MESSAGE: &str = "Accepting HTTP Socket connections";
+
+pub struct EgressProxy {
+    pub container_id: String,
+
+    pub network_name: String,
+
+    pub proxy_address: String,
+}
+
+impl EgressProxy {
+    pub fn environment(&self) -> Vec<String> {
+        let proxy_url = format!("http://{}", self.proxy_address);
+
+        vec![
+            format!("HTTP_PROXY={}", proxy_url),
+            format!("HTTPS_PROXY={}", proxy_url),
+            format!("http_proxy={}", proxy_url),
+            format!("https_proxy={}", proxy_url),
+            "NO_PROXY=localhost,127.0.0.1".to_string(),
+            "no_proxy=localhost,127.0.0.1".to_string(),
+        ]
+    }
+}
+
+impl DockerRuntime {
+    // Agents are attached to an internal network which has no route out of the Docker host. The only way out is through the
+    // proxy container, which is attached to both the internal network and the default bridge, and records every request.
+    pub(super) async fn start_egress_proxy(&self, module_name: &str, allowed_hosts: Option<&Vec<String>>) -> Result<EgressProxy, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let id: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .collect();
+
+            let time = Utc::now().to_rfc3339();
+            let mut labels = HashMap::new();
+            labels.insert("created-by".to_string(), "jarvis".to_string());
+            labels.insert("build-time".to_string(), time);
+            labels.insert("used-for".to_string(), "egress-proxy".to_string());
+
+            if !self.image_available(PROXY_IMAGE).await? {
+                self.pull_image(PROXY_IMAGE, None).await?;
+            }
+
+            let network_name = format!("jarvis-egress_{}_{}", module_name, id);
+            docker.create_network(CreateNetworkOptions {
+                name: network_name.clone(),
+                check_duplicate: true,
+                internal: true,
+                labels: labels.clone(),
+                ..Default::default()
+            }).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create egress network: {}", format_docker_api_error(e)) })?;
+
+            let name = format!("jarvis-egress-proxy-{}-{}", module_name, id);
+            let container_result = docker.create_container(Some(CreateContainerOptions { name: name.clone() }), Config {
+                image: Some(PROXY_IMAGE.to_string()),
+                labels: Some(labels),
+                ..Default::default()
+            }).await
+                .map(|x| {
+                    for warning in x.warnings {
+                        println!("docker container create warning: {}", warning);
+                    }
+                    x.id
+                })
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create egress proxy container: {}", format_docker_api_error(e)) });
+
+            let container_id = match container_result {
+                Ok(container_id) => container_id,
+                Err(e) => {
+                    docker.remove_network(network_name.as_str()).await
+                        .unwrap_or_else(|remove_error| println!("Failed to remove egress network [{}]: {}", network_name, format_docker_api_error(remove_error)));
+                    return Err(e);
+                }
+            };
+
+            let proxy = EgressProxy {
+                container_id,
+                network_name,
+                proxy_address: format!("{}:{}", name, PROXY_PORT),
+            };
+
+            // The module doesn't know about the proxy until it has started, so a proxy which fails to start is removed here.
+            match self.attach_egress_proxy(&proxy, allowed_hosts).await {
+                Ok(_) => Ok(proxy),
+                Err(e) => {
+                    self.stop_egress_proxy(&proxy).await
+                        .unwrap_or_else(|stop_error| println!("Failed to remove egress proxy: {}", stop_error));
+                    Err(e)
+                }
+            }
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn attach_egress_proxy(&self, proxy: &EgressProxy, allowed_hosts: Option<&Vec<String>>) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            docker.connect_network(proxy.network_name.as_str(), ConnectNetworkOptions {
+                container: proxy.container_id.clone(),
+                ..Default::default()
+            }).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to attach egress proxy to network: {}", format_docker_api_error(e)) })?;
+        }
+
+        self.upload_proxy_config(proxy.container_id.as_str(), render_proxy_config(allowed_hosts)?.as_str()).await?;
+        self.start_container(proxy.container_id.as_str()).await
+    }
+
+    // Steps run one at a time so the proxy can be switched to each step's allow list just before the step starts. Squid
+    // reloads its configuration in the background after being signalled, so this waits until it has finished, otherwise
+    // the step could start under the previous step's allow list.
+    pub(super) async fn reconfigure_egress_proxy(&mut self, proxy: &str, allowed_hosts: Option<&Vec<String>>) -> Result<(), BuildRuntimeError> {
+        self.upload_proxy_config(proxy, render_proxy_config(allowed_hosts)?.as_str()).await?;
+
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+
+        let command = format!("loaded() {{ count=$(grep -c '{message}' {log} 2>/dev/null); echo \"${{count:-0}}\"; }}; \
+                               before=$(loaded); squid -k reconfigure || exit 1; \
+                               attempts=0; while [ \"$(loaded)\" -le \"$before\" ]; do \
+                                 attempts=$((attempts + 1)); [ $attempts -gt 100 ] && exit 1; sleep 0.1; \
+                               done", message = CONFIG_LOADED_MESSAGE, log = CACHE_LOG);
+
+        self.execute_command_for_output(proxy, &shell_config, "/", command.as_str()).await
+            .map(|_| ())
+            .map_err(|e| BuildRuntimeError { msg: format!("Egress proxy didn't load the allow list: {}", e) })
+    }
+
+    pub(super) async fn read_egress_log(&mut self, proxy: &str) -> Result<Vec<EgressRecord>, BuildRuntimeError> {
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+
+        // Truncate after reading so that the next step only sees its own traffic.
+        let log = self.execute_command_for_output(proxy, &shell_config, "/", format!("touch {0} && cat {0} && : > {0}", ACCESS_LOG).as_str()).await?;
+
+        Ok(parse_access_log(log.as_str()))
+    }
+
+    pub(super) async fn stop_egress_proxy(&self, proxy: &EgressProxy) -> Result<(), BuildRuntimeError> {
+        self.delete_container(proxy.container_id.as_str()).await?;
+
+        if let Some(ref docker) = self.docker {
+            docker.remove_network(proxy.network_name.as_str()).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to remove egress network [{}]: {}", proxy.network_name, format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn upload_proxy_config(&self, container_id: &str, proxy_config: &str) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let mut tar = tar::Builder::new(Vec::new());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(proxy_config.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, "squid.conf", proxy_config.as_bytes())
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to package egress proxy config: {}", e) })?;
+            let contents = tar.into_inner()
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to package egress proxy config: {}", e) })?;
+
+            docker.upload_to_container(container_id, Some(UploadToContainerOptions {
+                path: "/etc/squid",
+                ..Default::default()
+            }), contents.into()).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to configure egress proxy: {}", format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+}
+
+// Hosts are checked again here, as well as by `jarvis validate`, because they're written straight into the proxy's
+// configuration where anything unexpected could change what it allows.
+fn render_proxy_config(allowed_hosts: Option<&Vec<String>>) -> Result<String, BuildRuntimeError> {
+    let mut lines = vec![
+        format!("http_port {}", PROXY_PORT),
+        "logformat jarvis %ts %Ss/%03>Hs %rm %ru %>st %<st".to_string(),
+        format!("access_log stdio:{} jarvis", ACCESS_LOG),
+        format!("cache_log {}", CACHE_LOG),
+        "cache deny all".to_string(),
+    ];
+
+    match allowed_hosts {
+        Some(allowed_hosts) => {
+            if let Some(host) = allowed_hosts.iter().find(|host| !valid_allowed_host(host.as_str())) {
+                return Err(BuildRuntimeError { msg: format!("[{}] isn't a valid allowed host", host) });
+            }
+
+            if !allowed_hosts.is_empty() {
+                let domains: Vec<String> = allowed_hosts.iter().map(|host| to_squid_domain(host)).collect();
+                lines.push(format!("acl jarvis_allowed dstdomain {}", domains.join(" ")));
+                lines.push("http_access allow jarvis_allowed".to_string());
+            }
+            lines.push("http_access deny all".to_string());
+        },
+        None => lines.push("http_access allow all".to_string())
+    }
+
+    Ok(lines.join("\n") + "\n")
+}
+
+// Squid uses a leading dot to match a domain and all of its subdomains.
+fn to_squid_domain(host: &str) -> String {
+    if host.starts_with("*.") {
+        host[1..].to_string()
+    } else {
+        host.to_string()
+    }
+}
+
+// Parses lines written with the `jarvis` log format, e.g.
+// `1603100000 TCP_TUNNEL/200 CONNECT github.com:443 517 5120`
+pub fn parse_access_log(log: &str) -> Vec<EgressRecord> {
+    let mut records: Vec<EgressRecord> = vec![];
+
+    for line in log.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 6 {
+            continue;
+        }
+
+        let (host, port) = match parse_destination(parts[2], parts[3]) {
+            Some(destination) => destination,
+            None => continue
+        };
+
+        let denied = parts[1].starts_with("TCP_DENIED");
+        let bytes_sent = parts[4].parse::<u64>().unwrap_or(0);
+        let bytes_received = parts[5].parse::<u64>().unwrap_or(0);
+
+        let existing = records.iter_mut().find(|r| r.host == host && r.port == port);
+        let record = match existing {
+            Some(record) => record,
+            None => {
+                records.push(EgressRecord {
+                    host,
+                    port,
+                    connections: 0,
+                    denied_connections: 0,
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                });
+                records.last_mut().unwrap()
+            }
+        };
+
+        record.connections += 1;
+        if denied {
+            record.denied_connections += 1;
+        }
+        record.bytes_sent += bytes_sent;
+        record.bytes_received += bytes_received;
+    }
+
+    records
+}
+
+fn parse_destination(method: &str, url: &str) -> Option<(String, u16)> {
+    let (default_port, authority) = if method == "CONNECT" {
+        (443, url)
+    } else if let Some(index) = url.find("://") {
+        let default_port = if url[..index].eq_ignore_ascii_case("https") { 443 } else { 80 };
+        let rest = &url[index + 3..];
+        (default_port, rest.split('/').next().unwrap_or(rest))
+    } else {
+        return None;
+    };
+
+    match authority.rfind(':') {
+        Some(index) => {
+            let port = authority[index + 1..].parse::<u16>().ok()?;
+            Some((authority[..index].to_string(), port))
+        }
+        None => Some((authority.to_string(), default_port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_access_log, render_proxy_config, to_squid_domain};
+
+    #[test]
+    fn wildcards_match_subdomains() {
+        assert_eq!(".example.com", to_squid_domain("*.example.com"));
+        assert_eq!("github.com", to_squid_domain("github.com"));
+    }
+
+    #[test]
+    fn allow_lists_deny_everything_else() {
+        let config = render_proxy_config(Some(&vec!["github.com".to_string(), "*.npmjs.org".to_string()])).unwrap();
+        assert!(config.contains("acl jarvis_allowed dstdomain github.com .npmjs.org\nhttp_access allow jarvis_allowed\nhttp_access deny all\n"));
+
+        let config = render_proxy_config(Some(&vec![])).unwrap();
+        assert!(!config.contains("http_access allow"));
+        assert!(config.ends_with("http_access deny all\n"));
+
+        let config = render_proxy_config(None).unwrap();
+        assert!(config.ends_with("http_access allow all\n"));
+    }
+
+    #[test]
+    fn hosts_cannot_inject_proxy_config() {
+        assert!(render_proxy_config(Some(&vec!["github.com\nhttp_access allow all".to_string()])).is_err());
+        assert!(render_proxy_config(Some(&vec!["github.com all".to_string()])).is_err());
+        assert!(render_proxy_config(Some(&vec!["*".to_string()])).is_err());
+    }
+
+    #[test]
+    fn aggregates_connections_by_destination() {
+        let log = "1603100000 TCP_TUNNEL/200 CONNECT github.com:443 517 5120\n\
+                   1603100001 TCP_TUNNEL/200 CONNECT github.com:443 100 200\n\
+                   1603100002 TCP_MISS/200 GET http://deb.debian.org/debian/dists 80 4000\n\
+                   1603100003 TCP_DENIED/403 CONNECT evil.example.com:8443 10 0\n";
+
+        let records = parse_access_log(log);
+
+        assert_eq!(3, records.len());
+        assert_eq!("github.com", records[0].host);
+        assert_eq!(443, records[0].port);
+        assert_eq!(2, records[0].connections);
+        assert_eq!(617, records[0].bytes_sent);
+        assert_eq!(5320, records[0].bytes_received);
+        assert_eq!(("deb.debian.org", 80), (records[1].host.as_str(), records[1].port));
+        assert_eq!(1, records[2].denied_connections);
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+
+use bollard::auth::DockerCredentials;
+use bollard::image::{BuildImageOptions, ImportImageOptions, PushImageOptions, RemoveImageOptions, TagImageOptions};
+use futures::channel::mpsc::Sender;
+use futures::executor::block_on;
+use futures::SinkExt;
+use tokio::stream::StreamExt;
+
+use crate::cache;
+use crate::config::{BuildImageRule, PushImageRule, RegisterImageRule};
+use crate::environments::secret_lookup_name;
+use crate::image_lock::image_repository;
+use crate::registry;
+use crate::runtime::{BuildRuntimeError, BuiltImage, PushedImage};
+use super::{DockerRuntime, format_docker_api_error};
+
+impl DockerRuntime {
+    // Builds from the workspace as the earlier steps left it, so generated files and build outputs are part of the context.
+    pub(super) async fn build_workspace_image(&mut self, module_name: &str, rule: &BuildImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        let context = self.download_build_context(module_name, rule).await?;
+        let build_result = self.build_context_image(module_name, rule, &context).await;
+        remove_temporary_file(&context);
+
+        build_result
+    }
+
+    async fn build_context_image(&mut self, module_name: &str, rule: &BuildImageRule, context: &PathBuf) -> Result<BuiltImage, BuildRuntimeError> {
+        // The engine only asks for credentials when a base image needs them, so every configured registry is offered.
+        let mut credentials = HashMap::new();
+        let components = self.module_components.get(module_name).unwrap();
+        for registry_config in &components.registries {
+            if let Some(registry_credentials) = self.registry_credentials(&components.registries, components.secrets_provider.as_ref(), registry_config.host.as_str()).await? {
+                let server = match registry_config.host.as_str() {
+                    "docker.io" => "https://index.docker.io/v1/".to_string(),
+                    host => host.to_string()
+                };
+                credentials.insert(server, registry_credentials);
+            }
+        }
+
+        let mut tags = rule.tags.clone().unwrap_or_default();
+        let id = self.run_image_build(rule, tags.first().map(|tag| tag.as_str()).unwrap_or(""), credentials, context).await?;
+
+        for tag in tags.iter().skip(1) {
+            self.tag_image(id.as_str(), tag.as_str()).await?;
+        }
+
+        tags.push(self.record_build_image(rule.name.as_str(), id.as_str()).await?);
+
+        Ok(BuiltImage {
+            name: rule.name.clone(),
+            id,
+            tags,
+        })
+    }
+
+    // Picks up an image which a step's command left in the engine, either already loaded or as an archive in the workspace.
+    pub(super) async fn register_step_image(&mut self, module_name: &str, agent_id: &str, rule: &RegisterImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        let id = match (&rule.image, &rule.archive) {
+            (Some(image), None) => self.image_id(image.as_str()).await?,
+            (None, Some(archive)) => self.load_image_archive(module_name, agent_id, archive.as_str()).await?,
+            _ => return Err(BuildRuntimeError { msg: format!("Image [{}] must be registered from exactly one of image or archive", rule.name) })
+        };
+
+        let tag = self.record_build_image(rule.name.as_str(), id.as_str()).await?;
+
+        Ok(BuiltImage {
+            name: rule.name.clone(),
+            id,
+            tags: vec![tag],
+        })
+    }
+
+    // Every image made during the build gets a name scoped to the build, so it can be told apart from other builds' images
+    // and removed when the build ends without touching tags anyone else gave it.
+    async fn record_build_image(&mut self, name: &str, id: &str) -> Result<String, BuildRuntimeError> {
+        let tag = format!("jarvis-build/{}:{}", name, self.build_scope);
+        self.tag_image(id, tag.as_str()).await?;
+
+        self.scoped_image_tags.push(tag.clone());
+        self.build_images.insert(name.to_string(), id.to_string());
+
+        Ok(tag)
+    }
+
+    // Removing the build-scoped name only deletes the image when nothing else refers to it.
+    pub(super) async fn remove_scoped_images(&mut self) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let mut failures = vec![];
+            for tag in self.scoped_image_tags.drain(..) {
+                if let Err(e) = docker.remove_image(tag.as_str(), Some(RemoveImageOptions {
+                    force: false,
+                    noprune: false,
+                }), None).await {
+                    failures.push(format!("[{}]: {}", tag, format_docker_api_error(e)));
+                }
+            }
+            self.build_images.clear();
+
+            if failures.is_empty() {
+                Ok(())
+            } else {
+                Err(BuildRuntimeError { msg: format!("Failed to remove build images {}", failures.join(", ")) })
+            }
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn image_id(&self, image: &str) -> Result<String, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            docker.inspect_image(image).await
+                .map(|inspect| inspect.id)
+                .map_err(|e| BuildRuntimeError { msg: format!("Image [{}] isn't in the container engine: {}", image, format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // The archive goes through temporary files rather than memory, image archives are often hundreds of megabytes.
+    async fn load_image_archive(&self, module_name: &str, agent_id: &str, archive: &str) -> Result<String, BuildRuntimeError> {
+        let archive_path = format!("/build/workspace/{}", archive.trim_start_matches('/'));
+        let download = cache::temp_path("jarvis-image-archive", "tar");
+        let download_result = self.download_path(agent_id, archive_path.as_str(), &download).await;
+
+        let image = cache::temp_path("jarvis-image-archive", "tar");
+        let extract_result = download_result.and_then(|_| {
+            File::open(&download)
+                .and_then(|file| File::create(&image).and_then(|output| single_entry(file, output)))
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to read image archive [{}] in module [{}]: {}", archive, module_name, e) })
+        });
+
+        remove_temporary_file(&download);
+
+        let load_result = match extract_result {
+            Ok(_) => self.import_image_file(archive, &image).await,
+            Err(e) => Err(e)
+        };
+
+        remove_temporary_file(&image);
+
+        load_result
+    }
+
+    async fn import_image_file(&self, archive: &str, image: &PathBuf) -> Result<String, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let file = File::open(image)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to read image archive [{}]: {}", archive, e) })?;
+
+            let (sender, receiver) = futures::channel::mpsc::channel(16);
+            tokio::task::spawn_blocking(move || stream_file(file, sender));
+
+            let mut load_results = docker.import_image(ImportImageOptions {
+                quiet: true,
+            }, hyper::Body::wrap_stream(receiver), None);
+
+            let mut loaded = None;
+            while let Some(load_result) = load_results.next().await {
+                let info = load_result
+                    .map_err(|e| BuildRuntimeError { msg: format!("Failed to load image archive [{}]: {}", archive, format_docker_api_error(e)) })?;
+
+                if let Some(error) = info.error {
+                    return Err(BuildRuntimeError { msg: format!("Failed to load image archive [{}]: {}", archive, error) });
+                }
+
+                if let Some(reference) = info.stream.as_ref().and_then(|stream| loaded_image(stream.as_str())) {
+                    loaded = Some(reference);
+                }
+            }
+
+            let reference = loaded.ok_or(BuildRuntimeError { msg: format!("Image archive [{}] didn't contain an image", archive) })?;
+            self.image_id(reference.as_str()).await
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // Writes the context to a temporary file rather than holding it in memory, it can be as big as the whole workspace.
+    async fn download_build_context(&self, module_name: &str, rule: &BuildImageRule) -> Result<PathBuf, BuildRuntimeError> {
+        let context_path = match rule.context.as_ref().map(|context| context.trim_matches('/')) {
+            Some(context) if !context.is_empty() && context != "." => format!("/build/workspace/{}", context),
+            _ => "/build/workspace".to_string()
+        };
+
+        let container = self.start_helper_container("image-build-context", self.workspace_mounts(module_name)).await?;
+
+        let archive = cache::temp_path("jarvis-build-context", "tar");
+        let download_result = self.download_path(container.as_str(), context_path.as_str(), &archive).await;
+        let delete_result = self.delete_container(container.as_str()).await;
+
+        let context = cache::temp_path("jarvis-build-context", "tar");
+        let context_result = download_result.and(delete_result).and_then(|_| {
+            File::open(&archive)
+                .and_then(|file| File::create(&context).and_then(|output| strip_archive_root(file, output)))
+                .map(|_| context.clone())
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to prepare the build context [{}]: {}", context_path, e) })
+        });
+
+        remove_temporary_file(&archive);
+        if context_result.is_err() {
+            remove_temporary_file(&context);
+        }
+
+        context_result
+    }
+
+    async fn run_image_build(&self, rule: &BuildImageRule, tag: &str, credentials: HashMap<String, DockerCredentials>, context: &PathBuf) -> Result<String, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let file = File::open(context)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to read the build context for image [{}]: {}", rule.name, e) })?;
+
+            // The context is read on a blocking thread and streamed to the engine as it is sent. A read error ends the
+            // stream with that error, which fails the build.
+            let (sender, receiver) = futures::channel::mpsc::channel(16);
+            tokio::task::spawn_blocking(move || stream_file(file, sender));
+
+            let mut build_results = docker.build_image(BuildImageOptions {
+                dockerfile: rule.dockerfile.clone().unwrap_or("Dockerfile".to_string()),
+                t: tag.to_string(),
+                target: rule.target.clone().unwrap_or_default(),
+                buildargs: rule.build_args.clone().unwrap_or_default(),
+                cachefrom: rule.cache_from.clone().unwrap_or_default(),
+                rm: true,
+                ..Default::default()
+            }, Some(credentials), Some(hyper::Body::wrap_stream(receiver)));
+
+            let mut image_id = None;
+            while let Some(build_result) = build_results.next().await {
+                let info = build_result
+                    .map_err(|e| BuildRuntimeError { msg: format!("Failed to build image [{}]: {}", rule.name, format_docker_api_error(e)) })?;
+
+                if let Some(error) = info.error {
+                    return Err(BuildRuntimeError { msg: format!("Failed to build image [{}]: {}", rule.name, self.secret_masker.mask(error.as_str())) });
+                }
+
+                if let Some(stream) = info.stream {
+                    // The legacy builder only reports the image through its output.
+                    if stream.starts_with("Successfully built ") {
+                        image_id = Some(stream["Successfully built ".len()..].trim().to_string());
+                    }
+                    print!("{}", self.secret_masker.mask(stream.as_str()));
+                }
+
+                if let Some(id) = info.aux.and_then(|aux| aux.id) {
+                    image_id = Some(id);
+                }
+            }
+
+            image_id.ok_or(BuildRuntimeError { msg: format!("Building image [{}] didn't produce an image", rule.name) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // Tags the build output with each destination and pushes it. Credentials come from the step's secret when it has one,
+    // otherwise from the project's registries or the user's docker configuration. A registry on localhost without
+    // authentication, such as a throwaway `registry:2` container in tests, needs neither.
+    pub(super) async fn push_built_image(&mut self, module_name: &str, rule: &PushImageRule, environment: Option<&str>) -> Result<Vec<PushedImage>, BuildRuntimeError> {
+        let id = self.build_images.get(rule.image.as_str()).cloned()
+            .ok_or(BuildRuntimeError { msg: format!("Image [{}] hasn't been built by an earlier step", rule.image) })?;
+
+        let mut pushed = vec![];
+        for destination in &rule.to {
+            let host = registry::registry_host(destination.as_str());
+            let components = self.module_components.get(module_name).unwrap();
+            let credentials = match &rule.secret {
+                Some(secret) => Some(self.secret_credentials(components.secrets_provider.as_ref(), secret_lookup_name(environment, secret.as_str()).as_str(), host.as_str()).await?),
+                None => self.registry_credentials(&components.registries, components.secrets_provider.as_ref(), host.as_str()).await?
+            };
+
+            self.tag_image(id.as_str(), destination.as_str()).await?;
+            let digest = self.push_tag(destination.as_str(), credentials).await?;
+
+            pushed.push(PushedImage {
+                reference: destination.clone(),
+                digest,
+            });
+        }
+
+        Ok(pushed)
+    }
+
+    async fn push_tag(&self, reference: &str, credentials: Option<DockerCredentials>) -> Result<String, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            println!("Pushing {}", reference);
+            let (repository, tag) = split_tag(reference);
+            let mut push_results = docker.push_image(repository, Some(PushImageOptions {
+                tag,
+            }), credentials);
+
+            let mut digest = None;
+            while let Some(push_result) = push_results.next().await {
+                let info = push_result
+                    .map_err(|e| BuildRuntimeError { msg: format!("Failed to push [{}]: {}", reference, format_docker_api_error(e)) })?;
+
+                if let Some(error) = info.error {
+                    return Err(BuildRuntimeError { msg: format!("Failed to push [{}]: {}", reference, self.secret_masker.mask(error.as_str())) });
+                }
+
+                if let Some(status) = info.status {
+                    if let Some(pushed) = pushed_digest(status.as_str()) {
+                        digest = Some(pushed);
+                    }
+                }
+            }
+
+            digest.ok_or(BuildRuntimeError { msg: format!("Pushing [{}] didn't report a digest", reference) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn tag_image(&self, id: &str, tag: &str) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let (repository, version) = split_tag(tag);
+
+            docker.tag_image(id, Some(TagImageOptions {
+                repo: repository,
+                tag: version,
+            })).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to tag image as [{}]: {}", tag, format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+}
+
+// A reference without a tag means `latest`, as it does for the docker CLI.
+fn split_tag(reference: &str) -> (&str, &str) {
+    let repository = image_repository(reference);
+    match &reference[repository.len()..] {
+        "" => (repository, "latest"),
+        tag => (repository, tag.trim_start_matches(':'))
+    }
+}
+
+// The engine reports what it pushed as `<tag>: digest: <digest> size: <size>`.
+fn pushed_digest(status: &str) -> Option<String> {
+    let start = status.find("digest: ")? + "digest: ".len();
+    status[start..].split_whitespace().next().map(|digest| digest.to_string())
+}
+
+// The engine reports a loaded image as `Loaded image: <tag>`, or `Loaded image ID: <id>` when the archive has no tags.
+fn loaded_image(stream: &str) -> Option<String> {
+    let stream = stream.trim();
+    for prefix in &["Loaded image ID: ", "Loaded image: "] {
+        if stream.starts_with(prefix) {
+            return Some(stream[prefix.len()..].trim().to_string());
+        }
+    }
+    None
+}
+
+// A download of a single file is an archive holding just that file.
+fn single_entry<R: io::Read, W: io::Write>(archive: R, mut output: W) -> Result<W, io::Error> {
+    let mut archive = tar::Archive::new(archive);
+    let mut entry = archive.entries()?.next()
+        .unwrap_or(Err(io::Error::new(io::ErrorKind::NotFound, "the archive is empty")))?;
+
+    io::copy(&mut entry, &mut output)?;
+    Ok(output)
+}
+
+// Downloads from a container put everything under the name of the directory which was asked for, the engine wants the
+// context at the root of the archive.
+fn strip_archive_root<R: io::Read, W: io::Write>(archive: R, output: W) -> Result<W, io::Error> {
+    let mut source = tar::Archive::new(archive);
+    let mut builder = tar::Builder::new(output);
+
+    for entry in source.entries()? {
+        let mut entry = entry?;
+        let path: PathBuf = entry.path()?.components().skip(1).collect();
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let mut header = entry.header().clone();
+        if let Some(link_name) = entry.link_name()? {
+            if header.entry_type().is_hard_link() {
+                let link_name: PathBuf = link_name.components().skip(1).collect();
+                header.set_link_name(link_name)?;
+            }
+        }
+
+        builder.append_data(&mut header, path, &mut entry)?;
+    }
+
+    builder.into_inner()
+}
+
+fn stream_file(mut file: File, mut sender: Sender<Result<Vec<u8>, io::Error>>) {
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(read) => {
+                // The engine stopped reading, the build has already failed.
+                if block_on(sender.send(Ok(buffer[..read].to_vec()))).is_err() {
+                    return;
+                }
+            },
+            Err(e) => {
+                block_on(sender.send(Err(e))).unwrap_or(());
+                return;
+            }
+        }
+    }
+}
+
+fn remove_temporary_file(path: &PathBuf) {
+    if path.exists() {
+        std::fs::remove_file(path).unwrap_or_else(|e| println!("Failed to remove temporary file [{}]: {}", path.display(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::image::RemoveImageOptions;
+
+    use crate::runtime::BuildRuntime;
+    use crate::runtime::docker_runtime::DockerRuntime;
+    use super::{loaded_image, pushed_digest, single_entry, split_tag, strip_archive_root};
+
+    #[test]
+    fn push_references_default_to_latest() {
+        assert_eq!(("localhost:5000/app", "latest"), split_tag("localhost:5000/app"));
+        assert_eq!(("ghcr.io/org/app", "1.0"), split_tag("ghcr.io/org/app:1.0"));
+
+        assert_eq!(Some("sha256:abc".to_string()), pushed_digest("1.0: digest: sha256:abc size: 1573"));
+        assert_eq!(None, pushed_digest("Pushed"));
+    }
+
+    #[test]
+    fn loaded_images_are_read_from_the_load_output() {
+        assert_eq!(Some("app:test".to_string()), loaded_image("Loaded image: app:test\n"));
+        assert_eq!(Some("sha256:abc".to_string()), loaded_image("Loaded image ID: sha256:abc\n"));
+        assert_eq!(None, loaded_image("Loading layer"));
+    }
+
+    #[test]
+    fn context_is_moved_to_the_archive_root() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "app/src/main.py", "pass".as_bytes()).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let stripped = strip_archive_root(archive.as_slice(), vec![]).unwrap();
+
+        let mut archive = tar::Archive::new(stripped.as_slice());
+        let paths: Vec<String> = archive.entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(vec!["src/main.py".to_string()], paths);
+    }
+
+    #[test]
+    fn downloaded_files_are_unwrapped() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "app.tar", "image".as_bytes()).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        assert_eq!(b"image".to_vec(), single_entry(archive.as_slice(), vec![]).unwrap());
+        assert!(single_entry(tar::Builder::new(vec![]).into_inner().unwrap().as_slice(), vec![]).is_err());
+    }
+
+    // Needs a container engine and a registry without authentication, set JARVIS_TEST_REGISTRY to the address of one
+    // started with `docker run -d -p 5000:5000 registry:2`.
+    #[tokio::test]
+    #[ignore]
+    async fn pushes_report_the_digest_the_registry_stores() {
+        let registry = std::env::var("JARVIS_TEST_REGISTRY").expect("JARVIS_TEST_REGISTRY must be set");
+        let mut runtime = DockerRuntime::new();
+        runtime.connect();
+
+        runtime.pull_image("busybox:latest", None).await.unwrap();
+        let id = runtime.image_id("busybox:latest").await.unwrap();
+
+        let reference = format!("{}/jarvis-push-test:{}", registry, chrono::Utc::now().timestamp_nanos());
+        runtime.tag_image(id.as_str(), reference.as_str()).await.unwrap();
+        let push_result = runtime.push_tag(reference.as_str(), None).await;
+        runtime.docker.as_ref().unwrap().remove_image(reference.as_str(), None::<RemoveImageOptions>, None).await.unwrap();
+        let digest = push_result.unwrap();
+
+        let (repository, tag) = split_tag(reference.as_str());
+        let manifest = reqwest::Client::new()
+            .get(format!("http://{}/v2/{}/manifests/{}", registry, &repository[registry.len() + 1..], tag).as_str())
+            .header("Accept", "application/vnd.docker.distribution.manifest.v2+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.oci.image.manifest.v1+json, application/vnd.oci.image.index.v1+json")
+            .send()
+            .await
+            .unwrap();
+
+        assert!(manifest.status().is_success());
+        assert_eq!(Some(digest.as_str()), manifest.headers().get("Docker-Content-Digest").and_then(|value| value.to_str().ok()));
+    }
+}
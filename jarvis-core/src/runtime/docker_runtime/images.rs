@@ -0,0 +1,140 @@
+use bollard::auth::DockerCredentials;
+
+use crate::config::{Agent, ProjectConfig, PullPolicy, RegistryConfig};
+use crate::image_lock::image_repository;
+use crate::registry;
+use crate::runtime::BuildRuntimeError;
+use crate::secrets;
+use crate::secrets::{extract_secret_key, SecretsProvider};
+use super::{DockerRuntime, format_docker_api_error};
+
+impl DockerRuntime {
+    // Makes sure the agent's image is present according to its pull policy and returns the reference to run it by. Images
+    // in the project's lock are run by their digest, which never changes, so they're only pulled when missing. Images built
+    // earlier in the build are run by their ID.
+    pub(super) async fn ensure_agent_image(&mut self, module_name: &str, agent: &Agent) -> Result<String, BuildRuntimeError> {
+        if let Some(name) = agent.built_image() {
+            return self.build_images.get(name).cloned()
+                .ok_or(BuildRuntimeError { msg: format!("Agent [{}] runs image [{}], which hasn't been built by an earlier step", agent.name, name) });
+        }
+
+        let components = self.module_components.get(module_name).unwrap();
+        let pinned = components.image_lock.pinned_reference(agent.image.as_str());
+        let image = pinned.clone().unwrap_or(agent.image.clone());
+
+        let pull = match agent.pull_policy.unwrap_or(PullPolicy::IfNotPresent) {
+            PullPolicy::Always if pinned.is_none() => !self.pulled_images.iter().any(|pulled| pulled == &image),
+            PullPolicy::Always | PullPolicy::IfNotPresent => !self.image_available(image.as_str()).await?,
+            PullPolicy::Never => {
+                if !self.image_available(image.as_str()).await? {
+                    return Err(BuildRuntimeError { msg: format!("Image [{}] for agent [{}] isn't present and its pull policy is never", image, agent.name) });
+                }
+                false
+            }
+        };
+
+        if pull {
+            let components = self.module_components.get(module_name).unwrap();
+            let host = registry::registry_host(image.as_str());
+            let credentials = self.registry_credentials(&components.registries, components.secrets_provider.as_ref(), host.as_str()).await?;
+            self.pull_image(image.as_str(), credentials).await?;
+            self.pulled_images.push(image.clone());
+        }
+
+        Ok(image)
+    }
+
+    // Always pulls, so the digest is the one the tag points at now rather than whatever was pulled last.
+    pub(super) async fn resolve_digest(&mut self, project_config: &ProjectConfig, image: &str) -> Result<String, BuildRuntimeError> {
+        let registries = project_config.build_config.registries.clone().unwrap_or_default();
+        let secrets_provider = secrets::create_provider(project_config)
+            .map_err(|e| BuildRuntimeError { msg: format!("Failed to configure secrets: {}", e) })?;
+
+        let host = registry::registry_host(image);
+        let credentials = self.registry_credentials(&registries, secrets_provider.as_ref(), host.as_str()).await?;
+        self.pull_image(image, credentials).await?;
+
+        if let Some(ref docker) = self.docker {
+            let inspect = docker.inspect_image(image).await
+                .map_err(|e| BuildRuntimeError { msg: format_docker_api_error(e) })?;
+
+            // Docker Hub images are recorded without the registry or the `library/` prefix for official images.
+            let repository = normalise_repository(image_repository(image));
+            inspect.repo_digests.into_iter().flatten()
+                .find_map(|repo_digest| {
+                    let mut parts = repo_digest.splitn(2, '@');
+                    match (parts.next(), parts.next()) {
+                        (Some(name), Some(digest)) if normalise_repository(name) == repository => Some(digest.to_string()),
+                        _ => None
+                    }
+                })
+                .ok_or(BuildRuntimeError { msg: format!("Image [{}] has no digest from its registry", image) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // The user an image runs as when the agent doesn't configure one, none means root.
+    pub(super) async fn image_user(&self, image: &str) -> Result<Option<String>, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let inspect = docker.inspect_image(image).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to inspect image [{}]: {}", image, format_docker_api_error(e)) })?;
+
+            Ok(inspect.config.and_then(|config| config.user).filter(|user| !user.is_empty()))
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // Credentials from a registry's secret take priority over the user's docker configuration, so a project can pull with a
+    // dedicated account wherever it's built.
+    pub(super) async fn registry_credentials(&self, registries: &Vec<RegistryConfig>, secrets_provider: &(dyn SecretsProvider + Send + Sync), host: &str) -> Result<Option<DockerCredentials>, BuildRuntimeError> {
+        if let Some(registry_config) = registries.iter().find(|registry_config| registry_config.host == host) {
+            return self.secret_credentials(secrets_provider, registry_config.secret.as_str(), host).await.map(Some);
+        }
+
+        let credentials = registry::docker_config_credentials(host)
+            .map_err(|e| BuildRuntimeError { msg: format!("Failed to get credentials for registry [{}]: {}", host, e) })?;
+
+        Ok(credentials.map(|credentials| {
+            for secret in credentials.password.iter().chain(credentials.identity_token.iter()) {
+                self.secret_masker.register(secret.as_bytes());
+            }
+
+            DockerCredentials {
+                username: credentials.username,
+                password: credentials.password,
+                identitytoken: credentials.identity_token,
+                serveraddress: Some(credentials.server),
+                ..Default::default()
+            }
+        }))
+    }
+
+    // Reads registry credentials from a secret with `username` and `password` keys.
+    pub(super) async fn secret_credentials(&self, secrets_provider: &(dyn SecretsProvider + Send + Sync), secret: &str, host: &str) -> Result<DockerCredentials, BuildRuntimeError> {
+        let value = secrets_provider.get_secret(secret).await
+            .map_err(|e| BuildRuntimeError { msg: format!("Failed to get credentials for registry [{}]: {}", host, e) })?;
+
+        let mut keys = vec![];
+        for key in &["username", "password"] {
+            let key_value = extract_secret_key(secret, value.as_slice(), key)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to get credentials for registry [{}]: {}", host, e) })?;
+            keys.push(String::from_utf8_lossy(key_value.as_slice()).to_string());
+        }
+        self.secret_masker.register(value.as_slice());
+        self.secret_masker.register(keys[1].as_bytes());
+
+        Ok(DockerCredentials {
+            username: Some(keys[0].clone()),
+            password: Some(keys[1].clone()),
+            serveraddress: Some(host.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+fn normalise_repository(repository: &str) -> &str {
+    let repository = repository.trim_start_matches("docker.io/");
+    repository.trim_start_matches("library/")
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use bollard::models::{Mount, MountTmpfsOptions, MountTypeEnum};
+
+use crate::config::{SecretRule, ShellConfig};
+use crate::environments::secret_lookup_name;
+use crate::runtime::BuildRuntimeError;
+use crate::secrets::{extract_secret_key, secret_targets, SECRETS_DIRECTORY};
+use super::DockerRuntime;
+use super::workspaces::shell_quote;
+
+// Secrets are small, the limit only stops a runaway step from filling the host's memory through the secrets directory.
+const SECRETS_TMPFS_SIZE: i64 = 16 * 1024 * 1024;
+
+// What an agent needs to see a step's secrets: the files to write into its tmpfs and the variables to give the step's
+// command, which point at the files or hold values themselves.
+#[derive(Default)]
+pub(super) struct AgentSecrets {
+    pub(super) files: Vec<SecretFile>,
+
+    pub(super) environment: Vec<String>,
+}
+
+pub(super) struct SecretFile {
+    path: String,
+
+    // Where the value is written, `path` is a link to it when the two differ.
+    storage_path: String,
+
+    value: Vec<u8>,
+}
+
+impl DockerRuntime {
+    // Fetches each secret from the module's provider, from the step's environment when it has one. Values are only held
+    // in memory until they've been written into the agent, they never touch the host's disk.
+    pub(super) async fn configure_secrets(&mut self, module_name: &str, secrets: &Option<Vec<SecretRule>>, environment: Option<&str>) -> Result<AgentSecrets, BuildRuntimeError> {
+        let secrets = match secrets {
+            Some(secrets) => secrets,
+            None => return Ok(AgentSecrets::default())
+        };
+
+        let mut values: HashMap<String, Vec<u8>> = HashMap::new();
+        for rule in secrets {
+            let value = self.module_components.get(module_name).unwrap().secrets_provider.get_secret(secret_lookup_name(environment, rule.name()).as_str()).await
+                .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+            self.secret_masker.register(value.as_slice());
+            values.insert(rule.name().to_string(), value);
+        }
+
+        let mut agent_secrets = AgentSecrets::default();
+        for target in secret_targets(secrets) {
+            let value = match &target.key {
+                Some(key) => {
+                    let value = extract_secret_key(target.secret.as_str(), values[&target.secret].as_slice(), key.as_str())
+                        .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+                    self.secret_masker.register(value.as_slice());
+                    value
+                },
+                None => values[&target.secret].clone()
+            };
+
+            if target.environment {
+                let text = String::from_utf8(value.clone()).ok().filter(|text| !text.contains('\0'))
+                    .ok_or(BuildRuntimeError { msg: format!("Secret [{}] isn't text and can't be put in variable [{}]", target.secret, target.variable) })?;
+                agent_secrets.environment.push(format!("{}={}", target.variable, text));
+            }
+
+            if let (Some(path), Some(storage_path)) = (&target.path, &target.storage_path) {
+                agent_secrets.environment.push(format!("{}={}", target.file_variable(), path));
+                agent_secrets.files.push(SecretFile {
+                    path: path.clone(),
+                    storage_path: storage_path.clone(),
+                    value,
+                });
+            }
+        }
+
+        Ok(agent_secrets)
+    }
+
+    // An in-memory filesystem for the secrets, so nothing written there outlives the container.
+    pub(super) fn secrets_mount() -> Mount {
+        Mount {
+            target: Some(SECRETS_DIRECTORY.to_string()),
+            typ: Some(MountTypeEnum::TMPFS),
+            tmpfs_options: Some(MountTmpfsOptions {
+                size_bytes: Some(SECRETS_TMPFS_SIZE),
+                mode: Some(0o755),
+            }),
+            ..Default::default()
+        }
+    }
+
+    // Writes the secrets into the agent's tmpfs. The archive API can't be used because it writes underneath tmpfs mounts,
+    // into the container's own filesystem, so each value is passed to an exec through its environment instead. Files are
+    // only readable by `owner`, the user the agent runs as whether configured or taken from its image, or by root when
+    // there is none. The variables are kept for the
+    // step's command, commands run by plugins don't see them.
+    pub(super) async fn deliver_secrets(&mut self, module_name: &str, agent_name: &str, container_id: &str, agent_secrets: AgentSecrets, owner: Option<String>) -> Result<(), BuildRuntimeError> {
+        let component = self.module_components.get_mut(module_name).unwrap();
+        if !agent_secrets.environment.is_empty() {
+            component.secret_environment.insert(agent_name.to_string(), agent_secrets.environment);
+        }
+
+        let secret_files = agent_secrets.files;
+        if secret_files.is_empty() {
+            return Ok(());
+        }
+
+        let delivered = secret_files.iter()
+            .flat_map(|secret_file| vec![secret_file.path.clone(), secret_file.storage_path.clone()])
+            .collect::<Vec<String>>();
+        component.delivered_secrets.insert(agent_name.to_string(), delivered);
+
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+        for secret_file in &secret_files {
+            let value = format!("JARVIS_SECRET_VALUE={}", base64::encode(secret_file.value.as_slice()));
+            let storage_path = shell_quote(secret_file.storage_path.as_str());
+            let mut command = format!("umask 022 && mkdir -p \"$(dirname {0})\" && (umask 377 && printf '%s' \"$JARVIS_SECRET_VALUE\" | base64 -d > {0})", storage_path);
+            if let Some(owner) = &owner {
+                command.push_str(format!(" && chown {} {}", shell_quote(owner.as_str()), storage_path).as_str());
+            }
+            if secret_file.path != secret_file.storage_path {
+                let path = shell_quote(secret_file.path.as_str());
+                command.push_str(format!(" && mkdir -p \"$(dirname {})\" && ln -sf {} {}", path, storage_path, path).as_str());
+            }
+
+            self.execute_command_for_output_as(container_id, &shell_config, "/", command.as_str(), Some("0"), Some(vec![value.as_str()])).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to deliver secret [{}]: {}", secret_file.path, e) })?;
+        }
+
+        Ok(())
+    }
+
+    // Runs before the agent is destroyed so the secrets are gone even if removing the container fails. Removing the
+    // container discards the tmpfs anyway, so a failure here is only reported.
+    pub(super) async fn remove_secrets(&mut self, agent_name: &str) {
+        for component in self.module_components.values_mut() {
+            component.secret_environment.remove(agent_name);
+        }
+
+        let paths = self.module_components.values_mut()
+            .find_map(|component| component.delivered_secrets.remove(agent_name));
+
+        if let Some(paths) = paths {
+            let shell_config = ShellConfig {
+                executable: "/bin/sh".to_string()
+            };
+            let quoted: Vec<String> = paths.iter().map(|path| shell_quote(path.as_str())).collect();
+
+            self.execute_command_for_output_as(agent_name, &shell_config, "/", format!("rm -f {}", quoted.join(" ")).as_str(), Some("0"), None).await
+                .map(|_| ())
+                .unwrap_or_else(|e| println!("Failed to remove secrets from [{}]: {}", agent_name, e));
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
+use bollard::models::{EndpointSettings, HostConfig};
+use bollard::network::{ConnectNetworkOptions, CreateNetworkOptions};
+use chrono::Utc;
+
+use crate::config::{ServiceRule, ShellConfig, BUILD_IMAGE_PREFIX};
+use crate::registry;
+use crate::runtime::BuildRuntimeError;
+use super::{DockerRuntime, first_error, format_docker_api_error};
+
+const DEFAULT_READY_TIMEOUT: u64 = 60;
+
+pub(super) struct StepServices {
+    network_name: String,
+
+    containers: Vec<String>,
+}
+
+impl DockerRuntime {
+    // Services share an internal network with the agent, where each is reachable by its name. Nothing on the network has
+    // a route out of the Docker host, so a service can't be used to get around the egress rules.
+    pub(super) async fn start_services(&mut self, module_name: &str, agent_name: &str, services: &Vec<ServiceRule>) -> Result<String, BuildRuntimeError> {
+        let network_name = format!("jarvis-services_{}", agent_name);
+        self.create_services_network(network_name.as_str()).await?;
+        self.module_components.get_mut(module_name).unwrap().services.insert(agent_name.to_string(), StepServices {
+            network_name: network_name.clone(),
+            containers: vec![],
+        });
+
+        for service in services {
+            if let Err(e) = self.start_service(module_name, agent_name, network_name.as_str(), service).await {
+                self.stop_services(agent_name).await
+                    .unwrap_or_else(|e| println!("Failed to stop services for [{}]: {}", agent_name, e));
+                return Err(e);
+            }
+        }
+
+        Ok(network_name)
+    }
+
+    pub(super) async fn connect_to_services(&self, network_name: &str, container_id: &str) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            docker.connect_network(network_name, ConnectNetworkOptions {
+                container: container_id.to_string(),
+                ..Default::default()
+            }).await
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to attach agent to services network: {}", format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    pub(super) async fn stop_services(&mut self, agent_name: &str) -> Result<(), BuildRuntimeError> {
+        let services = self.module_components.values_mut()
+            .find_map(|component| component.services.remove(agent_name));
+
+        // Every service is removed even when one of them can't be, the network only goes once they all have.
+        let mut results = vec![];
+        if let Some(services) = services {
+            for container in &services.containers {
+                results.push(self.delete_container(container.as_str()).await);
+            }
+
+            if let Some(ref docker) = self.docker {
+                results.push(docker.remove_network(services.network_name.as_str()).await
+                    .map_err(|e| BuildRuntimeError { msg: format!("Failed to remove services network [{}]: {}", services.network_name, format_docker_api_error(e)) }));
+            }
+        }
+
+        first_error(results)
+    }
+
+    async fn create_services_network(&self, network_name: &str) -> Result<(), BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            docker.create_network(CreateNetworkOptions {
+                name: network_name.to_string(),
+                check_duplicate: true,
+                internal: true,
+                labels: service_labels(),
+                ..Default::default()
+            }).await
+                .map(|_| ())
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create services network: {}", format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    async fn start_service(&mut self, module_name: &str, agent_name: &str, network_name: &str, service: &ServiceRule) -> Result<(), BuildRuntimeError> {
+        let image = self.ensure_service_image(module_name, service).await?;
+
+        let container_id = if let Some(ref docker) = self.docker {
+            let mut endpoints = HashMap::new();
+            endpoints.insert(network_name.to_string(), EndpointSettings {
+                aliases: Some(vec![service.name.clone()]),
+                ..Default::default()
+            });
+
+            docker.create_container(Some(CreateContainerOptions { name: format!("jarvis-service-{}-{}", service.name, agent_name) }), Config {
+                image: Some(image),
+                entrypoint: service.entrypoint.clone(),
+                cmd: service.command.clone(),
+                env: service.environment.as_ref().map(|environment| environment.iter().map(|(key, value)| format!("{}={}", key, value)).collect()),
+                labels: Some(service_labels()),
+                host_config: Some(HostConfig {
+                    network_mode: Some(network_name.to_string()),
+                    ..Default::default()
+                }),
+                networking_config: Some(NetworkingConfig {
+                    endpoints_config: endpoints,
+                }),
+                ..Default::default()
+            }).await
+                .map(|x| x.id)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create service [{}]: {}", service.name, format_docker_api_error(e)) })?
+        } else {
+            return Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() });
+        };
+
+        if let Some(services) = self.module_components.get_mut(module_name).unwrap().services.get_mut(agent_name) {
+            services.containers.push(container_id.clone());
+        }
+
+        self.start_container(container_id.as_str()).await?;
+        self.wait_for_service(network_name, container_id.as_str(), service).await
+    }
+
+    async fn ensure_service_image(&self, module_name: &str, service: &ServiceRule) -> Result<String, BuildRuntimeError> {
+        if service.image.starts_with(BUILD_IMAGE_PREFIX) {
+            let name = &service.image[BUILD_IMAGE_PREFIX.len()..];
+            return self.build_images.get(name).cloned()
+                .ok_or(BuildRuntimeError { msg: format!("Service [{}] runs image [{}], which hasn't been built by an earlier step", service.name, name) });
+        }
+
+        if !self.image_available(service.image.as_str()).await? {
+            let components = self.module_components.get(module_name).unwrap();
+            let host = registry::registry_host(service.image.as_str());
+            let credentials = self.registry_credentials(&components.registries, components.secrets_provider.as_ref(), host.as_str()).await?;
+            self.pull_image(service.image.as_str(), credentials).await?;
+        }
+
+        Ok(service.image.clone())
+    }
+
+    // Polls once a second until every check passes. Ports are checked from a helper on the services network because the
+    // service's own image may not have the tools to check itself.
+    async fn wait_for_service(&self, network_name: &str, container_id: &str, service: &ServiceRule) -> Result<(), BuildRuntimeError> {
+        let check = match &service.ready {
+            Some(check) => check,
+            None => return Ok(())
+        };
+
+        let checker = match check.port {
+            Some(_) => Some(self.start_helper_container_on_network("service-readiness", vec![], Some(network_name.to_string())).await?),
+            None => None
+        };
+
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+        let timeout = check.timeout.unwrap_or(DEFAULT_READY_TIMEOUT);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+
+        let result = loop {
+            let port_ready = match (&checker, check.port) {
+                (Some(checker), Some(port)) => self.execute_command_for_output(checker.as_str(), &shell_config, "/", format!("nc -z -w 1 {} {}", service.name, port).as_str()).await.is_ok(),
+                _ => true
+            };
+            let command_ready = port_ready && match &check.command {
+                Some(command) => self.execute_command_for_output(container_id, &shell_config, "/", command.as_str()).await.is_ok(),
+                None => true
+            };
+
+            if port_ready && command_ready {
+                break Ok(());
+            }
+            if Instant::now() >= deadline {
+                break Err(BuildRuntimeError { msg: format!("Service [{}] wasn't ready after {} seconds", service.name, timeout) });
+            }
+
+            tokio::time::delay_for(Duration::from_secs(1)).await;
+        };
+
+        // Whether the service is ready matters more than whether the checker could be removed.
+        let delete_result = match &checker {
+            Some(checker) => self.delete_container(checker.as_str()).await,
+            None => Ok(())
+        };
+
+        first_error(vec![result, delete_result])
+    }
+}
+
+fn service_labels() -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    labels.insert("created-by".to_string(), "jarvis".to_string());
+    labels.insert("build-time".to_string(), Utc::now().to_rfc3339());
+    labels.insert("used-for".to_string(), "service".to_string());
+    labels
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use bollard::models::{Mount, MountTypeEnum};
+use bollard::volume::{CreateVolumeOptions, ListVolumesOptions};
+use chrono::Utc;
+use path_absolutize::Absolutize;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::build::WorkspaceMode;
+use crate::config::{Agent, CheckoutRule, ProjectConfig, ShellConfig};
+use crate::git;
+use crate::git::GitSource;
+use crate::runtime::{BuildRuntimeError, CheckoutReport};
+use crate::summary::format_bytes;
+use crate::validate;
+use crate::workspace;
+use crate::workspace::SyncState;
+use super::{DockerRuntime, format_docker_api_error};
+
+// Keeps the command line for each removal well below the argument length limits of the shell in the agent.
+const DELETE_BATCH_SIZE: usize = 100;
+
+impl DockerRuntime {
+    // Reused workspaces are named after the project and module so that the next build finds the same volume again.
+    pub(super) fn reused_workspace_name(identifier_base: &str) -> String {
+        format!("workspace_{}", identifier_base).chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' { c } else { '-' })
+            .collect()
+    }
+
+    pub(super) async fn workspace_volume_exists(&self, volume_name: &str) -> Result<bool, BuildRuntimeError> {
+        if let Some(ref docker) = self.docker {
+            let mut filters = HashMap::new();
+            filters.insert("name", vec![volume_name]);
+            filters.insert("label", vec!["used-for=workspace"]);
+
+            // The name filter matches on substrings, so the result still has to be checked for the exact name.
+            docker.list_volumes(Some(ListVolumesOptions { filters })).await
+                .map(|results| results.volumes.iter().any(|volume| volume.name == volume_name))
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to list workspace volumes {}", format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // Brings a reused workspace volume up to date with the project directory. Files are compared by content hash against
+    // what the volume holds now, so only changed files are uploaded, files a build changed are reset and files which have
+    // gone are removed. The state recorded by the last sync only saves hashing unchanged project files again.
+    pub(super) async fn sync_workspace(&mut self, container_id: &str, volume_name: &str, volume_existed: bool, project_config: &ProjectConfig) -> Result<(), BuildRuntimeError> {
+        let files = workspace::collect_workspace_files(project_config)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+
+        // Without the volume the recorded state describes content which no longer exists.
+        let previous = if volume_existed {
+            workspace::load_sync_state(volume_name)
+                .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?
+        } else {
+            SyncState::default()
+        };
+
+        let current = workspace::hash_workspace(&files, &previous)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+
+        // Steps in earlier builds may have changed files in the volume, so it's compared as it is now.
+        let in_volume = if volume_existed {
+            let listing = self.execute_command_for_output(container_id, &shell_config, "/build/workspace", workspace::VOLUME_LISTING_COMMAND).await?;
+            workspace::volume_sync_state(listing.as_str(), &previous, &current)
+        } else {
+            SyncState::default()
+        };
+
+        let plan = workspace::plan_sync(files, &in_volume, &current);
+        println!("Syncing workspace: {} changed file(s), {}, {} removed path(s)",
+                 plan.changed.file_count, format_bytes(plan.changed.total_size), plan.deleted.len());
+
+        for batch in plan.deleted.chunks(DELETE_BATCH_SIZE) {
+            let paths: Vec<String> = batch.iter().map(|path| shell_quote(path)).collect();
+            self.execute_command_for_output(container_id, &shell_config, "/build/workspace", format!("rm -rf -- {}", paths.join(" ")).as_str()).await?;
+        }
+
+        if !plan.changed.entries.is_empty() {
+            self.upload_workspace(container_id, plan.changed, project_config, "/build/workspace").await?;
+        }
+
+        workspace::save_sync_state(volume_name, &current)
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })
+    }
+
+    // Clones each additional repository on the host and uploads it below the workspace through `container_id`.
+    pub(super) async fn populate_checkouts(&mut self, module_name: &str, container_id: &str, checkouts: &Vec<CheckoutRule>, project_config: &ProjectConfig) -> Result<Vec<CheckoutReport>, BuildRuntimeError> {
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+
+        let mut reports = vec![];
+        for checkout in checkouts {
+            let id: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .collect();
+            let checkout_directory = std::env::temp_dir().join(format!("jarvis-checkout-{}", id));
+
+            let source = GitSource {
+                repository: checkout.repository.clone(),
+                reference: checkout.reference.clone(),
+                depth: checkout.depth,
+                submodules: checkout.submodules.unwrap_or(false),
+                sparse_paths: checkout.sparse.clone().unwrap_or(vec![]),
+            };
+            let result = self.upload_checkout(container_id, &source, &checkout_directory, checkout.path.as_str(), &shell_config, project_config).await;
+
+            if checkout_directory.exists() {
+                std::fs::remove_dir_all(&checkout_directory)
+                    .unwrap_or_else(|e| println!("Failed to remove checkout [{}]: {}", checkout_directory.display(), e));
+            }
+
+            // Repository URLs can carry credentials, and the report ends up in the stored build record.
+            let commit = result?;
+            let repository = self.secret_masker.mask(checkout.repository.as_str());
+            println!("Checked out [{}] at [{}] into [{}]", repository, commit, checkout.path);
+            reports.push(CheckoutReport {
+                module_name: module_name.to_string(),
+                repository,
+                path: checkout.path.clone(),
+                commit,
+            });
+        }
+
+        Ok(reports)
+    }
+
+    async fn upload_checkout(&mut self, container_id: &str, source: &GitSource, checkout_directory: &PathBuf, path: &str, shell_config: &ShellConfig, project_config: &ProjectConfig) -> Result<String, BuildRuntimeError> {
+        let commit = git::checkout(source, checkout_directory)
+            .map_err(|e| BuildRuntimeError { msg: format!("Failed to check out [{}]: {}", source.repository, e) })?;
+
+        let files = workspace::collect_directory_files(checkout_directory, false, vec![])
+            .map_err(|e| BuildRuntimeError { msg: format!("{}", e) })?;
+
+        // Whatever a reused workspace has at the path is from an earlier checkout, files which have gone from the
+        // repository since would otherwise be left behind.
+        let target_path = checkout_target(path)?;
+        self.execute_command_for_output(container_id, shell_config, "/", format!("rm -rf -- {0} && mkdir -p -- {0}", shell_quote(target_path.as_str())).as_str()).await?;
+        self.upload_workspace(container_id, files, project_config, target_path.as_str()).await?;
+
+        Ok(commit)
+    }
+
+    pub(super) fn workspace_mounts(&self, module_name: &str) -> Vec<Mount> {
+        let component = self.module_components.get(module_name).unwrap();
+
+        let source = match component.workspace_mode {
+            WorkspaceMode::Bind => absolute_path(&component.workspace_directory),
+            WorkspaceMode::Volume | WorkspaceMode::BindReadOnly => component.build_data_volume.clone()
+        };
+        let typ = match component.workspace_mode {
+            WorkspaceMode::Bind => MountTypeEnum::BIND,
+            WorkspaceMode::Volume | WorkspaceMode::BindReadOnly => MountTypeEnum::VOLUME
+        };
+
+        let mut mounts = vec![Mount {
+            target: Some("/build/workspace".to_string()),
+            source: Some(source),
+            typ: Some(typ),
+            ..Default::default()
+        }];
+
+        // Secrets are never uploaded with the workspace, so they're hidden behind an empty directory when the project is
+        // mounted directly.
+        if component.workspace_mode != WorkspaceMode::Volume {
+            for secrets_path in &component.secrets_paths {
+                mounts.push(Mount {
+                    target: Some(format!("/build/workspace/{}", secrets_path)),
+                    typ: Some(MountTypeEnum::TMPFS),
+                    ..Default::default()
+                });
+            }
+        }
+
+        mounts
+    }
+
+    // Docker's local volume driver can mount an overlay, which keeps the project directory untouched while the build
+    // writes into an upper layer. The upper layer lives in a scratch volume so the daemon can clean it up, whichever user
+    // the build wrote files as. Both layers are paths on the daemon's host, so this needs a local Linux daemon.
+    pub(super) async fn create_overlay_workspace(&mut self, module_name: &str, volume_name: &str, project_directory: &PathBuf) -> Result<(), BuildRuntimeError> {
+        if !local_linux_daemon() {
+            return Err(BuildRuntimeError { msg: "The bind-ro workspace mode needs a Docker daemon running locally on Linux, use the volume mode instead".to_string() });
+        }
+        let lower_directory = overlay_lower_directory(project_directory)?;
+
+        let scratch_volume = format!("{}-scratch", volume_name);
+        let mut labels = HashMap::new();
+        labels.insert("used-for".to_string(), "workspace-overlay".to_string());
+        self.create_docker_volume(scratch_volume.as_str(), Some(labels)).await?;
+        self.module_components.get_mut(module_name).unwrap().overlay_scratch_volume = Some(scratch_volume.clone());
+
+        let helper = self.start_helper_container("workspace-overlay", vec![Mount {
+            target: Some("/scratch".to_string()),
+            source: Some(scratch_volume.clone()),
+            typ: Some(MountTypeEnum::VOLUME),
+            ..Default::default()
+        }]).await?;
+        let shell_config = ShellConfig {
+            executable: "/bin/sh".to_string()
+        };
+        let prepared = self.execute_command_for_output(helper.as_str(), &shell_config, "/", "mkdir -p /scratch/upper /scratch/work").await;
+        self.delete_container(helper.as_str()).await?;
+        prepared?;
+
+        if let Some(ref docker) = self.docker {
+            let scratch_path = docker.inspect_volume(scratch_volume.as_str()).await
+                .map(|volume| volume.mountpoint)
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to inspect volume [{}]: {}", scratch_volume, format_docker_api_error(e)) })?;
+
+            let overlay_options = format!("lowerdir={},upperdir={}/upper,workdir={}/work", lower_directory, scratch_path, scratch_path);
+            let mut driver_opts = HashMap::new();
+            driver_opts.insert("type", "overlay");
+            driver_opts.insert("device", "overlay");
+            driver_opts.insert("o", overlay_options.as_str());
+
+            let time = Utc::now().to_rfc3339();
+            let mut labels = HashMap::new();
+            labels.insert("created-by", "jarvis");
+            labels.insert("build-time", time.as_str());
+            labels.insert("used-for", "workspace");
+
+            docker.create_volume(CreateVolumeOptions {
+                name: volume_name,
+                driver: "local",
+                driver_opts,
+                labels,
+            }).await
+                .map(|_| ())
+                .map_err(|e| BuildRuntimeError { msg: format!("Failed to create overlay workspace [{}]: {}", volume_name, format_docker_api_error(e)) })
+        } else {
+            Err(BuildRuntimeError { msg: "Runtime has not been initialised".to_string() })
+        }
+    }
+
+    // Anything written to a bind mounted project directory keeps the owner it was written as. Whatever the agent's user
+    // owns there is handed back to the owner of the project directory before the agent goes, so the host's user can still
+    // change and remove the build's outputs. A failure is only reported, the step has already run.
+    pub(super) async fn restore_workspace_ownership(&mut self, agent_name: &str) {
+        let writer = self.module_components.values_mut()
+            .find_map(|component| component.workspace_writers.remove(agent_name)
+                .map(|writer| (writer, host_owner(&component.workspace_directory))));
+
+        if let Some((writer, Some((uid, gid)))) = writer {
+            let shell_config = ShellConfig {
+                executable: "/bin/sh".to_string()
+            };
+
+            self.execute_command_for_output_as(agent_name, &shell_config, "/", ownership_command(writer.as_str(), uid, gid).as_str(), Some("0"), None).await
+                .map(|_| ())
+                .unwrap_or_else(|e| println!("Failed to hand files written by [{}] back to the owner of the project: {}", agent_name, e));
+        }
+    }
+}
+
+// Other mounts below the workspace, caches and the mask over the secrets directory, are left alone.
+fn ownership_command(writer: &str, uid: u32, gid: u32) -> String {
+    format!("find /build/workspace -xdev -user {} \\( ! -user {} -o ! -group {} \\) -exec chown -h {}:{} {{}} +", shell_quote(writer), uid, gid, uid, gid)
+}
+
+// Checked again here rather than trusting validation, since the workspace below the path is removed.
+fn checkout_target(path: &str) -> Result<String, BuildRuntimeError> {
+    if !validate::inside_workspace(path) {
+        return Err(BuildRuntimeError { msg: format!("Checkout path [{}] must be inside the workspace", path) });
+    }
+
+    Ok(format!("/build/workspace/{}", path.trim_start_matches("./").trim_end_matches('/')))
+}
+
+// The user an agent runs as when its configuration names one, otherwise the image decides.
+pub(super) fn agent_user(agent: &Agent) -> Option<String> {
+    let container = agent.container.as_ref();
+    let user = container.and_then(|container| container.user.as_ref())?;
+    match container.and_then(|container| container.group.as_ref()) {
+        Some(group) => Some(format!("{}:{}", user, group)),
+        None => Some(user.to_string())
+    }
+}
+
+// Overlay options are separated by commas and lower directories by colons, neither can be escaped.
+fn overlay_lower_directory(project_directory: &PathBuf) -> Result<String, BuildRuntimeError> {
+    let path = absolute_path(project_directory);
+    if path.contains(',') || path.contains(':') {
+        return Err(BuildRuntimeError { msg: format!("The project directory [{}] can't be used as an overlay, its path contains [,] or [:]", path) });
+    }
+
+    Ok(path)
+}
+
+// The overlay is mounted by the daemon from paths on the daemon's host, so it only works when that is this machine.
+fn local_linux_daemon() -> bool {
+    let local_socket = std::env::var("DOCKER_HOST")
+        .map_or(true, |host| host.is_empty() || host.starts_with("unix://"));
+    cfg!(target_os = "linux") && local_socket
+}
+
+fn absolute_path(path: &PathBuf) -> String {
+    path.absolutize()
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or(path.to_string_lossy().to_string())
+}
+
+#[cfg(unix)]
+fn host_owner(path: &PathBuf) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::metadata(path).ok().map(|metadata| (metadata.uid(), metadata.gid()))
+}
+
+// Docker Desktop maps file ownership on its own, so nothing needs handing back elsewhere.
+#[cfg(not(unix))]
+fn host_owner(_path: &PathBuf) -> Option<(u32, u32)> {
+    None
+}
+
+pub(super) fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config::{Agent, ContainerConfiguration};
+    use super::{agent_user, checkout_target, overlay_lower_directory, ownership_command, shell_quote};
+
+    fn agent(user: Option<&str>, group: Option<&str>) -> Agent {
+        Agent {
+            name: "builder".to_string(),
+            default: None,
+            image: "alpine:latest".to_string(),
+            environment: None,
+            cache: None,
+            container: Some(ContainerConfiguration {
+                user: user.map(|user| user.to_string()),
+                group: group.map(|group| group.to_string()),
+                privileged: None,
+            }),
+            pull_policy: None,
+        }
+    }
+
+    #[test]
+    fn agents_run_as_the_configured_user() {
+        assert_eq!(Some("builder:staff".to_string()), agent_user(&agent(Some("builder"), Some("staff"))));
+        assert_eq!(Some("builder".to_string()), agent_user(&agent(Some("builder"), None)));
+        assert_eq!(None, agent_user(&agent(None, Some("staff"))));
+        assert_eq!(None, agent_user(&Agent { container: None, ..agent(None, None) }));
+    }
+
+    #[test]
+    fn only_files_the_agent_wrote_are_handed_back() {
+        assert_eq!(
+            "find /build/workspace -xdev -user 'builder' \\( ! -user 1000 -o ! -group 100 \\) -exec chown -h 1000:100 {} +",
+            ownership_command("builder", 1000, 100)
+        );
+    }
+
+    #[test]
+    fn overlay_options_cannot_be_injected_through_the_project_path() {
+        assert!(overlay_lower_directory(&PathBuf::from("/home/user/project")).is_ok());
+        assert!(overlay_lower_directory(&PathBuf::from("/home/user/project,upperdir=/etc")).is_err());
+        assert!(overlay_lower_directory(&PathBuf::from("/home/user/a:/home/user/b")).is_err());
+    }
+
+    #[test]
+    fn checkouts_are_never_written_over_the_workspace() {
+        assert_eq!("/build/workspace/vendor/lib", checkout_target("./vendor/lib/").unwrap());
+        assert!(checkout_target(".").is_err());
+        assert!(checkout_target("vendor/../..").is_err());
+        assert!(checkout_target("/build/workspace").is_err());
+    }
+
+    #[test]
+    fn quoted_paths_stay_a_single_argument() {
+        assert_eq!("'/build/workspace/dist'", shell_quote("/build/workspace/dist"));
+        assert_eq!("'/tmp/x'\\''; rm -rf / #'", shell_quote("/tmp/x'; rm -rf / #"));
+    }
+}
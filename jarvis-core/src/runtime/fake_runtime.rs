@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::artifacts::ArtifactManifest;
+use crate::build::BuildOptions;
+use crate::cache::{CacheInfo, CacheMetadata};
+use crate::config::{Agent, ProjectConfig, ArchiveRule, ShellConfig, PluginSpecification, Step, BuildImageRule, PushImageRule, RegisterImageRule};
+use crate::runtime::{BuildRuntime, BuildRuntimeError, BuiltImage, ModuleReport, PushedImage, StepReport};
+
+// Records the calls the build makes on its agents, failing the ones named in `failing`.
+#[derive(Default)]
+pub struct FakeRuntime {
+    pub calls: Arc<Mutex<Vec<String>>>,
+
+    pub failing: Vec<&'static str>,
+}
+
+impl FakeRuntime {
+    fn call(&self, name: &str) -> Result<(), BuildRuntimeError> {
+        self.calls.lock().unwrap().push(name.to_string());
+        if self.failing.contains(&name) {
+            return Err(BuildRuntimeError { msg: format!("[{}] failed", name) });
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BuildRuntime for FakeRuntime {
+    fn connect(&mut self) {}
+
+    async fn init_for_module(&mut self, _module_name: &String, _project_config: &ProjectConfig, _build_options: &BuildOptions) -> Result<ModuleReport, BuildRuntimeError> {
+        self.call("init_for_module").map(|_| ModuleReport::default())
+    }
+
+    async fn create_agent(&mut self, _module_name: &String, agent: &Agent, _step: Option<&Step>) -> Result<String, BuildRuntimeError> {
+        self.call("create_agent").map(|_| agent.name.clone())
+    }
+
+    async fn execute_command(&mut self, _agent_id: &str, _shell_config: &ShellConfig, _command: &str) -> Result<(), BuildRuntimeError> {
+        self.call("execute_command")
+    }
+
+    async fn get_archive(&mut self, _agent_id: &str, archive_rule: &ArchiveRule, _output_directory: &PathBuf) -> Result<ArtifactManifest, BuildRuntimeError> {
+        self.call("get_archive").map(|_| ArtifactManifest {
+            name: archive_rule.name.clone(),
+            output: format!("{}.tar", archive_rule.name),
+            files: vec![],
+        })
+    }
+
+    async fn restore_input(&mut self, _agent_id: &str, _archive: Vec<u8>, _target_path: &str) -> Result<(), BuildRuntimeError> {
+        self.call("restore_input")
+    }
+
+    async fn collect_step_report(&mut self, _module_name: &String, _agent_id: &str) -> Result<StepReport, BuildRuntimeError> {
+        self.call("collect_step_report").map(|_| StepReport::default())
+    }
+
+    async fn destroy_agent(&mut self, _agent_id: &str) -> Result<(), BuildRuntimeError> {
+        self.call("destroy_agent")
+    }
+
+    async fn tear_down_for_module(&self, _module_name: &String, _succeeded: bool) -> Result<(), BuildRuntimeError> {
+        self.call("tear_down_for_module")
+    }
+
+    async fn cleanup_resources(&self) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn list_caches(&self) -> Result<Vec<CacheInfo>, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn remove_cache(&self, _volume_name: &str) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn export_cache(&self, _volume_name: &str, _destination: &PathBuf) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn import_cache(&self, _volume_name: &str, _metadata: &CacheMetadata, _archive: &PathBuf) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn ensure_plugins_loaded(&mut self, _plugins: Vec<&PluginSpecification>) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn build_image(&mut self, _module_name: &String, _rule: &BuildImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn push_image(&mut self, _module_name: &String, _rule: &PushImageRule, _environment: Option<&str>) -> Result<Vec<PushedImage>, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn register_image(&mut self, _module_name: &String, _agent_id: &str, _rule: &RegisterImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn remove_build_images(&mut self) -> Result<(), BuildRuntimeError> {
+        self.call("remove_build_images")
+    }
+
+    async fn resolve_image_digest(&mut self, _project_config: &ProjectConfig, _image: &str) -> Result<String, BuildRuntimeError> {
+        unimplemented!()
+    }
+}
@@ -1,6 +1,10 @@
-use crate::runtime::{BuildRuntime, BuildRuntimeError};
+use crate::runtime::{BuildRuntime, BuildRuntimeError, BuiltImage, ModuleReport, PushedImage, StepReport};
+use std::path::PathBuf;
 use async_trait::async_trait;
-use crate::config::{Agent, ProjectConfig, ArchiveRule, ShellConfig, PluginSpecification, Step};
+use crate::artifacts::ArtifactManifest;
+use crate::build::BuildOptions;
+use crate::cache::{CacheInfo, CacheMetadata};
+use crate::config::{Agent, ProjectConfig, ArchiveRule, ShellConfig, PluginSpecification, Step, BuildImageRule, PushImageRule, RegisterImageRule};
 
 pub struct KubernetesRuntime {
 
@@ -12,7 +16,7 @@ impl BuildRuntime for KubernetesRuntime {
         unimplemented!()
     }
 
-    async fn init_for_module(&mut self, _module_name: &String, _project_config: &ProjectConfig) -> Result<(), BuildRuntimeError> {
+    async fn init_for_module(&mut self, _module_name: &String, _project_config: &ProjectConfig, _build_options: &BuildOptions) -> Result<ModuleReport, BuildRuntimeError> {
         unimplemented!()
     }
 
@@ -24,7 +28,15 @@ impl BuildRuntime for KubernetesRuntime {
         unimplemented!()
     }
 
-    async fn get_archive(&mut self, _agent_id: &str, _archive_rule: &ArchiveRule) -> Result<(), BuildRuntimeError> {
+    async fn get_archive(&mut self, _agent_id: &str, _archive_rule: &ArchiveRule, _output_directory: &PathBuf) -> Result<ArtifactManifest, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn restore_input(&mut self, _agent_id: &str, _archive: Vec<u8>, _target_path: &str) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn collect_step_report(&mut self, _module_name: &String, _agent_id: &str) -> Result<StepReport, BuildRuntimeError> {
         unimplemented!()
     }
 
@@ -32,7 +44,7 @@ impl BuildRuntime for KubernetesRuntime {
         unimplemented!()
     }
 
-    async fn tear_down_for_module(&self, _module_name: &String) -> Result<(), BuildRuntimeError> {
+    async fn tear_down_for_module(&self, _module_name: &String, _succeeded: bool) -> Result<(), BuildRuntimeError> {
         unimplemented!()
     }
 
@@ -40,7 +52,43 @@ impl BuildRuntime for KubernetesRuntime {
         unimplemented!()
     }
 
+    async fn list_caches(&self) -> Result<Vec<CacheInfo>, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn remove_cache(&self, _volume_name: &str) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn export_cache(&self, _volume_name: &str, _destination: &PathBuf) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn import_cache(&self, _volume_name: &str, _metadata: &CacheMetadata, _archive: &PathBuf) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
     async fn ensure_plugins_loaded(&mut self, _plugins: Vec<&PluginSpecification>) -> Result<(), BuildRuntimeError> {
         unimplemented!()
     }
+
+    async fn build_image(&mut self, _module_name: &String, _rule: &BuildImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn push_image(&mut self, _module_name: &String, _rule: &PushImageRule, _environment: Option<&str>) -> Result<Vec<PushedImage>, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn register_image(&mut self, _module_name: &String, _agent_id: &str, _rule: &RegisterImageRule) -> Result<BuiltImage, BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn remove_build_images(&mut self) -> Result<(), BuildRuntimeError> {
+        unimplemented!()
+    }
+
+    async fn resolve_image_digest(&mut self, _project_config: &ProjectConfig, _image: &str) -> Result<String, BuildRuntimeError> {
+        unimplemented!()
+    }
 }
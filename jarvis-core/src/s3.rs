@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+
+use chrono::Utc;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use regex::Regex;
+use reqwest::{Method, StatusCode, Url};
+
+// A minimal client for S3 compatible object stores such as MinIO. Requests are signed with AWS Signature Version 4 and use
+// path style addressing, which is what most self hosted stores expect.
+pub struct S3Client {
+    endpoint: String,
+
+    bucket: String,
+
+    region: String,
+
+    access_key: String,
+
+    secret_key: String,
+
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Clone)]
+pub struct S3Error {
+    msg: String
+}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "s3 error: {}", self.msg)
+    }
+}
+
+impl Error for S3Error {}
+
+impl S3Client {
+    pub fn new(endpoint: &str, bucket: &str, region: Option<&String>) -> Result<Self, S3Error> {
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID")
+            .map_err(|_| S3Error { msg: "AWS_ACCESS_KEY_ID must be set to use an S3 compatible store".to_string() })?;
+        let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+            .map_err(|_| S3Error { msg: "AWS_SECRET_ACCESS_KEY must be set to use an S3 compatible store".to_string() })?;
+
+        Ok(S3Client {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            region: region.cloned().unwrap_or("us-east-1".to_string()),
+            access_key,
+            secret_key,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    pub async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<(), S3Error> {
+        self.send(Method::PUT, key, vec![], Some(body)).await
+            .map(|_| ())
+    }
+
+    pub async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>, S3Error> {
+        self.send(Method::GET, key, vec![], None).await
+    }
+
+    pub async fn head_object(&self, key: &str) -> Result<bool, S3Error> {
+        self.send(Method::HEAD, key, vec![], None).await
+            .map(|response| response.is_some())
+    }
+
+    pub async fn delete_object(&self, key: &str) -> Result<(), S3Error> {
+        self.send(Method::DELETE, key, vec![], None).await
+            .map(|_| ())
+    }
+
+    pub async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, S3Error> {
+        let key_pattern = Regex::new(r"<Key>([^<]*)</Key>").unwrap();
+        let token_pattern = Regex::new(r"<NextContinuationToken>([^<]*)</NextContinuationToken>").unwrap();
+
+        let mut keys = vec![];
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("list-type".to_string(), "2".to_string()),
+                ("prefix".to_string(), prefix.to_string()),
+            ];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token".to_string(), token.clone()));
+            }
+
+            let body = self.send(Method::GET, "", query, None).await?
+                .unwrap_or(vec![]);
+            let body = String::from_utf8_lossy(body.as_slice());
+
+            for key in key_pattern.captures_iter(&body) {
+                keys.push(unescape_xml(&key[1]));
+            }
+
+            continuation_token = token_pattern.captures(&body).map(|c| unescape_xml(&c[1]));
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    // Returns `None` when the object doesn't exist.
+    async fn send(&self, method: Method, key: &str, query: Vec<(String, String)>, body: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, S3Error> {
+        let path = if key.is_empty() {
+            format!("/{}", self.bucket)
+        } else {
+            format!("/{}/{}", self.bucket, key)
+        };
+        let canonical_uri = uri_encode(path.as_str(), false);
+
+        let mut sorted_query: Vec<(String, String)> = query.iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        sorted_query.sort();
+        let canonical_query = sorted_query.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let url_string = if canonical_query.is_empty() {
+            format!("{}{}", self.endpoint, canonical_uri)
+        } else {
+            format!("{}{}?{}", self.endpoint, canonical_uri, canonical_query)
+        };
+        let url = Url::parse(url_string.as_str())
+            .map_err(|e| S3Error { msg: format!("Invalid endpoint [{}]: {}", self.endpoint, e) })?;
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+            None => url.host_str().unwrap_or("").to_string()
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = "UNSIGNED-PAYLOAD";
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!("{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+                                        method.as_str(), canonical_uri, canonical_query, host, payload_hash, amz_date, signed_headers, payload_hash);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+
+        let signing_key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        let signing_key = hmac_sha256(signing_key.as_slice(), self.region.as_bytes());
+        let signing_key = hmac_sha256(signing_key.as_slice(), b"s3");
+        let signing_key = hmac_sha256(signing_key.as_slice(), b"aws4_request");
+        let signature = to_hex(hmac_sha256(signing_key.as_slice(), string_to_sign.as_bytes()).as_slice());
+
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                                    self.access_key, scope, signed_headers, signature);
+
+        let mut request = self.client.request(method.clone(), url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", authorization);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let response = request.send().await
+            .map_err(|e| S3Error { msg: format!("Request to [{}] failed: {}", path, e) })?;
+
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let bytes = response.bytes().await
+            .map_err(|e| S3Error { msg: format!("Failed to read response from [{}]: {}", path, e) })?;
+
+        if !status.is_success() {
+            return Err(S3Error { msg: format!("{} [{}] failed with status [{}]: {}", method, path, status, String::from_utf8_lossy(&bytes)) });
+        }
+
+        Ok(Some(bytes.to_vec()))
+    }
+}
+
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(format!("%{:02X}", byte).as_str()),
+        }
+    }
+
+    encoded
+}
+
+fn unescape_xml(input: &str) -> String {
+    input.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
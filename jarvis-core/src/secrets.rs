@@ -0,0 +1,277 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use regex::Regex;
+
+use crate::config::{get_user_config, ProjectConfig, SecretExposure, SecretRule, SecretsConfig};
+use crate::secrets::encrypted_provider::EncryptedFileSecretsProvider;
+use crate::secrets::env_provider::EnvSecretsProvider;
+use crate::secrets::file_provider::FileSecretsProvider;
+use crate::secrets::vault_provider::VaultSecretsProvider;
+
+pub mod file_provider;
+pub mod env_provider;
+pub mod encrypted_provider;
+pub mod vault_provider;
+pub mod local_store;
+
+pub const SECRETS_DIRECTORY: &str = "/build/secrets";
+
+// Looks up secret values by the name steps refer to them with. Providers only read secrets, how they get into the agent
+// is up to the runtime.
+#[async_trait]
+pub trait SecretsProvider {
+    async fn get_secret(&self, name: &str) -> Result<Vec<u8>, SecretsError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SecretsError {
+    msg: String
+}
+
+impl fmt::Display for SecretsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "secrets error: {}", self.msg)
+    }
+}
+
+impl Error for SecretsError {}
+
+// The project's choice of provider wins over the user's, and secret files in the project are used when neither says.
+fn secrets_config(project_config: &ProjectConfig) -> Result<Option<SecretsConfig>, SecretsError> {
+    match &project_config.build_config.secrets {
+        Some(secrets_config) => Ok(Some(secrets_config.clone())),
+        None => get_user_config()
+            .map(|user_config| user_config.secrets)
+            .map_err(|e| SecretsError { msg: format!("{}", e) })
+    }
+}
+
+fn default_directory(project_config: &ProjectConfig) -> PathBuf {
+    project_config.jarvis_directory.join("secrets")
+}
+
+fn resolve_directory(project_config: &ProjectConfig, directory: &Option<String>) -> PathBuf {
+    match directory {
+        Some(directory) => project_config.project_directory.join(directory),
+        None => default_directory(project_config)
+    }
+}
+
+// Every directory secret files may be kept in, which are never uploaded with the workspace or visible to agents. The
+// default directory is included whichever provider is used, it may still hold secrets from before the project chose one.
+pub fn secrets_directories(project_config: &ProjectConfig) -> Result<Vec<PathBuf>, SecretsError> {
+    let mut directories = vec![default_directory(project_config)];
+    match secrets_config(project_config)? {
+        Some(SecretsConfig::File { directory }) | Some(SecretsConfig::EncryptedFile { directory, .. }) => {
+            let directory = resolve_directory(project_config, &directory);
+            if !directories.contains(&directory) {
+                directories.push(directory);
+            }
+        },
+        _ => {}
+    }
+
+    Ok(directories)
+}
+
+pub fn create_provider(project_config: &ProjectConfig) -> Result<Box<dyn SecretsProvider + Send + Sync>, SecretsError> {
+    let directory = |directory: &Option<String>| resolve_directory(project_config, directory);
+
+    match secrets_config(project_config)? {
+        Some(SecretsConfig::File { directory: secrets_directory }) => {
+            Ok(Box::new(FileSecretsProvider::new(directory(&secrets_directory))))
+        },
+        Some(SecretsConfig::Env { prefix }) => {
+            Ok(Box::new(EnvSecretsProvider::new(prefix)))
+        },
+        Some(SecretsConfig::EncryptedFile { directory: secrets_directory, key_file }) => {
+            let key_file = match key_file {
+                Some(key_file) => PathBuf::from(key_file),
+                None => encrypted_provider::default_key_file(project_config.build_config.project_id.as_str())?
+            };
+            let secrets_directory = directory(&secrets_directory);
+            local_store::recover_rotation(secrets_directory.clone(), key_file.clone())?;
+
+            Ok(Box::new(EncryptedFileSecretsProvider::new(secrets_directory, key_file)))
+        },
+        Some(SecretsConfig::Vault { address, mount, path, field, token_env }) => {
+            let token_env = token_env.unwrap_or("VAULT_TOKEN".to_string());
+            let token = std::env::var(token_env.as_str())
+                .map_err(|_| SecretsError { msg: format!("[{}] must be set to read secrets from Vault", token_env) })?;
+
+            Ok(Box::new(VaultSecretsProvider::new(address.as_str(), token, mount, path, field)))
+        },
+        None => {
+            let key_file = encrypted_provider::default_key_file(project_config.build_config.project_id.as_str())?;
+            local_store::recover_rotation(default_directory(project_config), key_file.clone())?;
+
+            Ok(Box::new(EncryptedFileSecretsProvider::new(default_directory(project_config), key_file).with_plain_text_fallback()))
+        }
+    }
+}
+
+pub fn to_environment_variable_name(source: &str) -> String {
+    let pattern = Regex::new(r"(?P<l>.*)[^a-zA-Z0-9_](?P<r>.*)").unwrap();
+
+    // Loop required to deal with overlapping matches, would a different regex help?
+
+    let mut last = "".to_owned();
+    let mut next = source.to_owned();
+    while last != next {
+        last = next.clone();
+        next = pattern.replace_all(next.as_str(), "${l}_$r").into_owned();
+    }
+
+    next.to_ascii_uppercase()
+}
+
+// One place a step finds a secret, or one key of a structured secret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretTarget {
+    pub secret: String,
+
+    pub key: Option<String>,
+
+    pub variable: String,
+
+    // Whether `variable` holds the value itself.
+    pub environment: bool,
+
+    // Where the step reads the value from, announced in `<variable>_FILE`.
+    pub path: Option<String>,
+
+    // Where the value is actually written, which differs from `path` when that is outside the secrets directory.
+    pub storage_path: Option<String>,
+}
+
+impl SecretTarget {
+    pub fn file_variable(&self) -> String {
+        format!("{}_FILE", self.variable)
+    }
+
+    // Every variable the target sets, for spotting collisions.
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables = vec![];
+        if self.environment {
+            variables.push(self.variable.clone());
+        }
+        if self.path.is_some() {
+            variables.push(self.file_variable());
+        }
+        variables
+    }
+}
+
+pub fn secret_targets(rules: &Vec<SecretRule>) -> Vec<SecretTarget> {
+    let mut targets = vec![];
+    for rule in rules {
+        match rule {
+            SecretRule::Name(name) => targets.push(secret_target(name, None, Some(SecretExposure::File), &None, &None)),
+            SecretRule::Detailed(details) => {
+                let whole_secret = match (&details.keys, details.expose_as) {
+                    (_, Some(expose_as)) => Some(expose_as),
+                    (Some(_), None) => None,
+                    (None, None) => Some(SecretExposure::File)
+                };
+                if whole_secret.is_some() {
+                    targets.push(secret_target(details.name.as_str(), None, whole_secret, &details.path, &details.variable));
+                }
+
+                for key_rule in details.keys.iter().flatten() {
+                    let expose_as = key_rule.expose_as.or(Some(SecretExposure::File));
+                    targets.push(secret_target(details.name.as_str(), Some(key_rule.key.as_str()), expose_as, &key_rule.path, &key_rule.variable));
+                }
+            }
+        }
+    }
+
+    targets
+}
+
+fn secret_target(secret: &str, key: Option<&str>, expose_as: Option<SecretExposure>, path: &Option<String>, variable: &Option<String>) -> SecretTarget {
+    let default_name = match key {
+        Some(key) => format!("{}.{}", secret, key),
+        None => secret.to_string()
+    };
+    let default_path = format!("{}/{}", SECRETS_DIRECTORY, default_name);
+
+    let (path, storage_path) = match path {
+        Some(path) if path.starts_with(format!("{}/", SECRETS_DIRECTORY).as_str()) => (path.clone(), path.clone()),
+        Some(path) if path.starts_with('/') => (path.clone(), default_path),
+        Some(path) => {
+            let path = format!("{}/{}", SECRETS_DIRECTORY, path.trim_start_matches("./"));
+            (path.clone(), path)
+        },
+        None => (default_path.clone(), default_path)
+    };
+
+    let file = expose_as != Some(SecretExposure::Env);
+    SecretTarget {
+        secret: secret.to_string(),
+        key: key.map(|key| key.to_string()),
+        variable: variable.clone().unwrap_or(to_environment_variable_name(default_name.as_str())),
+        environment: expose_as != Some(SecretExposure::File),
+        path: if file { Some(path) } else { None },
+        storage_path: if file { Some(storage_path) } else { None },
+    }
+}
+
+// Structured secrets are JSON or YAML objects, YAML being a superset of JSON both are read the same way. Values which
+// aren't strings are exposed as JSON.
+pub fn extract_secret_key(secret: &str, value: &[u8], key: &str) -> Result<Vec<u8>, SecretsError> {
+    let document: serde_yaml::Value = serde_yaml::from_slice(value)
+        .map_err(|e| SecretsError { msg: format!("Secret [{}] is not a JSON or YAML object: {}", secret, e) })?;
+
+    match document.get(key) {
+        Some(serde_yaml::Value::String(value)) => Ok(value.as_bytes().to_vec()),
+        Some(serde_yaml::Value::Null) | None => Err(SecretsError { msg: format!("Secret [{}] has no [{}] key", secret, key) }),
+        Some(value) => serde_json::to_vec(value)
+            .map_err(|e| SecretsError { msg: format!("Key [{}] of secret [{}] can't be exposed: {}", key, secret, e) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{SecretDetails, SecretExposure, SecretKeyRule, SecretRule};
+    use super::{extract_secret_key, secret_targets};
+
+    #[test]
+    fn names_and_paths_default_from_the_secret() {
+        let targets = secret_targets(&vec![
+            SecretRule::Name("npm-token".to_string()),
+            SecretRule::Detailed(SecretDetails {
+                name: "registry".to_string(),
+                expose_as: None,
+                path: None,
+                variable: None,
+                keys: Some(vec![
+                    SecretKeyRule { key: "username".to_string(), expose_as: Some(SecretExposure::Env), path: None, variable: Some("REGISTRY_USER".to_string()) },
+                    SecretKeyRule { key: "password".to_string(), expose_as: Some(SecretExposure::Both), path: Some("/root/.registry-password".to_string()), variable: None },
+                ]),
+            }),
+        ]);
+
+        assert_eq!(3, targets.len());
+        assert_eq!(vec!["NPM_TOKEN_FILE".to_string()], targets[0].variables());
+        assert_eq!(Some("/build/secrets/npm-token".to_string()), targets[0].path);
+
+        assert_eq!(vec!["REGISTRY_USER".to_string()], targets[1].variables());
+        assert_eq!(None, targets[1].path);
+
+        assert_eq!(vec!["REGISTRY_PASSWORD".to_string(), "REGISTRY_PASSWORD_FILE".to_string()], targets[2].variables());
+        assert_eq!(Some("/root/.registry-password".to_string()), targets[2].path);
+        assert_eq!(Some("/build/secrets/registry.password".to_string()), targets[2].storage_path);
+    }
+
+    #[test]
+    fn keys_are_read_from_json_and_yaml() {
+        assert_eq!(b"ci".to_vec(), extract_secret_key("registry", br#"{"username": "ci", "password": "s3cr3t"}"#, "username").unwrap());
+        assert_eq!(b"s3cr3t".to_vec(), extract_secret_key("registry", b"username: ci\npassword: s3cr3t\n", "password").unwrap());
+        assert_eq!(br#"["a","b"]"#.to_vec(), extract_secret_key("registry", br#"{"scopes": ["a", "b"]}"#, "scopes").unwrap());
+        assert!(extract_secret_key("registry", b"username: ci", "password").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use rand::{thread_rng, Rng};
+
+use crate::config::user_config_directory;
+use crate::secrets::{SecretsError, SecretsProvider};
+use crate::secrets::file_provider::FileSecretsProvider;
+
+pub const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+// Reads `<name>.secret.enc` files encrypted with AES-256-GCM, so secrets can be kept next to the project without being
+// readable by anyone who doesn't have the key. Each file holds the nonce, then the tag, then the ciphertext, and the
+// secret's name is authenticated with it so files can't be swapped around.
+pub struct EncryptedFileSecretsProvider {
+    directory: PathBuf,
+
+    key_file: PathBuf,
+
+    plain_text_fallback: bool,
+}
+
+impl EncryptedFileSecretsProvider {
+    pub fn new(directory: PathBuf, key_file: PathBuf) -> Self {
+        EncryptedFileSecretsProvider {
+            directory,
+            key_file,
+            plain_text_fallback: false,
+        }
+    }
+
+    // Also reads `<name>.secret.txt` files for secrets which haven't been encrypted yet.
+    pub fn with_plain_text_fallback(mut self) -> Self {
+        self.plain_text_fallback = true;
+        self
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for EncryptedFileSecretsProvider {
+    async fn get_secret(&self, name: &str) -> Result<Vec<u8>, SecretsError> {
+        let secret_file = self.directory.join(format!("{}.secret.enc", name));
+        if !secret_file.exists() && self.plain_text_fallback {
+            return FileSecretsProvider::new(self.directory.clone()).get_secret(name).await;
+        }
+        if !secret_file.exists() {
+            return Err(SecretsError { msg: format!("Secret [{}] not found at [{}]", name, secret_file.display()) });
+        }
+
+        let key = read_key(&self.key_file)?;
+        let content = tokio::fs::read(&secret_file).await
+            .map_err(|e| SecretsError { msg: format!("Failed to read secret [{}] from [{}]: {}", name, secret_file.display(), e) })?;
+
+        decrypt_secret(&key, name, content.as_slice())
+    }
+}
+
+// Each project has its own key so that rotating it doesn't affect any other project.
+pub fn default_key_file(project_id: &str) -> Result<PathBuf, SecretsError> {
+    let file_name: String = project_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+
+    user_config_directory()
+        .map(|directory| directory.join("keys").join(format!("{}.key", file_name)))
+        .map_err(|e| SecretsError { msg: format!("{}", e) })
+}
+
+pub fn generate_key() -> Vec<u8> {
+    let mut key = vec![0u8; KEY_LENGTH];
+    thread_rng().fill(key.as_mut_slice());
+    key
+}
+
+// Writes a new key file, which must not exist yet.
+pub fn write_key(key_file: &PathBuf, key: &[u8]) -> Result<(), SecretsError> {
+    let content: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+
+    std::fs::create_dir_all(key_file.parent().unwrap())
+        .and_then(|_| create_private_file(key_file))
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| SecretsError { msg: format!("Failed to write secrets key [{}]: {}", key_file.display(), e) })
+}
+
+// Created with its final permissions, so the key is never readable by anyone else, not even for a moment.
+#[cfg(unix)]
+fn create_private_file(path: &PathBuf) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &PathBuf) -> std::io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+// Keys are stored hex encoded so the file can be inspected and copied around as text.
+pub fn read_key(key_file: &PathBuf) -> Result<Vec<u8>, SecretsError> {
+    let content = std::fs::read_to_string(key_file)
+        .map_err(|e| SecretsError { msg: format!("Failed to read secrets key [{}]: {}", key_file.display(), e) })?;
+    let content = content.trim();
+
+    let key = (0..content.len()).step_by(2)
+        .map(|i| content.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>();
+
+    match key {
+        Some(key) if key.len() == KEY_LENGTH => Ok(key),
+        _ => Err(SecretsError { msg: format!("Secrets key [{}] is not a hex encoded {} byte key", key_file.display(), KEY_LENGTH) })
+    }
+}
+
+pub fn encrypt_secret(key: &[u8], name: &str, plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    thread_rng().fill(&mut nonce);
+
+    let mut ciphertext = vec![0u8; plaintext.len()];
+    let mut tag = [0u8; TAG_LENGTH];
+    AesGcm::new(KeySize::KeySize256, key, &nonce, name.as_bytes())
+        .encrypt(plaintext, ciphertext.as_mut_slice(), &mut tag);
+
+    let mut content = Vec::with_capacity(NONCE_LENGTH + TAG_LENGTH + ciphertext.len());
+    content.extend_from_slice(&nonce);
+    content.extend_from_slice(&tag);
+    content.extend(ciphertext);
+    content
+}
+
+pub fn decrypt_secret(key: &[u8], name: &str, content: &[u8]) -> Result<Vec<u8>, SecretsError> {
+    if content.len() < NONCE_LENGTH + TAG_LENGTH {
+        return Err(SecretsError { msg: format!("Secret [{}] is not a valid encrypted secret", name) });
+    }
+
+    let (nonce, rest) = content.split_at(NONCE_LENGTH);
+    let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+
+    let mut plaintext = vec![0u8; ciphertext.len()];
+    if !AesGcm::new(KeySize::KeySize256, key, nonce, name.as_bytes()).decrypt(ciphertext, plaintext.as_mut_slice(), tag) {
+        return Err(SecretsError { msg: format!("Secret [{}] could not be decrypted, it may have been encrypted with a different key", name) });
+    }
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::temp_path;
+    use super::{decrypt_secret, encrypt_secret, generate_key, KEY_LENGTH, read_key, write_key};
+
+    #[test]
+    fn keys_are_only_readable_by_their_owner() {
+        let key_file = temp_path("jarvis-key-test", "key");
+        let key = generate_key();
+        write_key(&key_file, key.as_slice()).unwrap();
+
+        assert_eq!(key, read_key(&key_file).unwrap());
+        assert!(write_key(&key_file, generate_key().as_slice()).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o600, std::fs::metadata(&key_file).unwrap().permissions().mode() & 0o777);
+        }
+
+        std::fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn secrets_round_trip_and_are_bound_to_their_name() {
+        let key = [7u8; KEY_LENGTH];
+        let encrypted = encrypt_secret(&key, "npm-token", b"s3cr3t");
+
+        assert_eq!(b"s3cr3t".to_vec(), decrypt_secret(&key, "npm-token", encrypted.as_slice()).unwrap());
+        assert!(decrypt_secret(&key, "other-token", encrypted.as_slice()).is_err());
+        assert!(decrypt_secret(&[8u8; KEY_LENGTH], "npm-token", encrypted.as_slice()).is_err());
+    }
+}
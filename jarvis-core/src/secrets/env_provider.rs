@@ -0,0 +1,75 @@
+use std::ffi::OsString;
+
+use async_trait::async_trait;
+
+use crate::secrets::{SecretsError, SecretsProvider, to_environment_variable_name};
+
+const DEFAULT_PREFIX: &str = "JARVIS_SECRET_";
+
+// Reads secrets from the environment Jarvis runs in, which suits CI systems that already inject credentials that way.
+pub struct EnvSecretsProvider {
+    prefix: String,
+}
+
+impl EnvSecretsProvider {
+    pub fn new(prefix: Option<String>) -> Self {
+        EnvSecretsProvider {
+            prefix: prefix.unwrap_or(DEFAULT_PREFIX.to_string()),
+        }
+    }
+
+    fn variable_name(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, to_environment_variable_name(name))
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for EnvSecretsProvider {
+    async fn get_secret(&self, name: &str) -> Result<Vec<u8>, SecretsError> {
+        let variable_name = self.variable_name(name);
+
+        let value = std::env::var_os(variable_name.as_str())
+            .ok_or(SecretsError { msg: format!("Secret [{}] not found, [{}] is not set", name, variable_name) })?;
+
+        value_bytes(value)
+            .map_err(|_| SecretsError { msg: format!("Secret [{}] can't be read, [{}] is not valid unicode", name, variable_name) })
+    }
+}
+
+// Variables hold arbitrary bytes on unix, so secrets which aren't text are passed through untouched.
+#[cfg(unix)]
+fn value_bytes(value: OsString) -> Result<Vec<u8>, OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(value.into_vec())
+}
+
+#[cfg(not(unix))]
+fn value_bytes(value: OsString) -> Result<Vec<u8>, OsString> {
+    value.into_string().map(String::into_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::SecretsProvider;
+    use super::EnvSecretsProvider;
+
+    #[test]
+    fn variable_names_are_prefixed_and_upper_case() {
+        assert_eq!("JARVIS_SECRET_NPM_TOKEN", EnvSecretsProvider::new(None).variable_name("npm-token"));
+        assert_eq!("CI_DB_PASSWORD", EnvSecretsProvider::new(Some("CI_".to_string())).variable_name("db.password"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn values_which_are_not_text_are_read_untouched() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let value = vec![0xde, 0xad, 0xbe, 0xef, 0xff];
+        std::env::set_var("JARVIS_TEST_ENV_PROVIDER_BINARY", OsString::from_vec(value.clone()));
+
+        let secret = EnvSecretsProvider::new(Some("JARVIS_TEST_ENV_PROVIDER_".to_string())).get_secret("binary").await;
+
+        assert_eq!(value, secret.unwrap());
+    }
+}
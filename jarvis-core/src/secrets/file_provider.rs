@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use crate::secrets::{SecretsError, SecretsProvider};
+
+// Reads plain text `<name>.secret.txt` files, which is how secrets were always provided to Jarvis.
+pub struct FileSecretsProvider {
+    directory: PathBuf,
+}
+
+impl FileSecretsProvider {
+    pub fn new(directory: PathBuf) -> Self {
+        FileSecretsProvider {
+            directory,
+        }
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for FileSecretsProvider {
+    async fn get_secret(&self, name: &str) -> Result<Vec<u8>, SecretsError> {
+        let secret_file = self.directory.join(format!("{}.secret.txt", name));
+        if !secret_file.exists() {
+            return Err(SecretsError { msg: format!("Secret [{}] not found at [{}]", name, secret_file.display()) });
+        }
+
+        tokio::fs::read(&secret_file).await
+            .map_err(|e| SecretsError { msg: format!("Failed to read secret [{}] from [{}]: {}", name, secret_file.display(), e) })
+    }
+}
@@ -0,0 +1,428 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::{get_project_config, get_user_config, SecretsConfig};
+use crate::environments::secret_lookup_name;
+use crate::secrets::encrypted_provider::{decrypt_secret, default_key_file, encrypt_secret, generate_key, read_key, write_key};
+use crate::secrets::SecretsError;
+
+const ENCRYPTED_EXTENSION: &str = ".secret.enc";
+const PLAIN_TEXT_EXTENSION: &str = ".secret.txt";
+
+#[derive(Debug, Clone)]
+pub struct SecretInfo {
+    pub name: String,
+
+    pub environment: Option<String>,
+
+    pub encrypted: bool,
+}
+
+// Where a project keeps its encrypted secrets and the key which protects them.
+struct LocalSecrets {
+    directory: PathBuf,
+
+    key_file: PathBuf,
+}
+
+impl LocalSecrets {
+    fn for_project(project_path: PathBuf) -> Result<Self, SecretsError> {
+        let local_secrets = LocalSecrets::configured_for(project_path)?;
+        local_secrets.recover_rotation()?;
+        Ok(local_secrets)
+    }
+
+    fn configured_for(project_path: PathBuf) -> Result<Self, SecretsError> {
+        let project_config = get_project_config(project_path)
+            .map_err(|e| SecretsError { msg: format!("{}", e) })?;
+
+        let secrets_config = match &project_config.build_config.secrets {
+            Some(secrets_config) => Some(secrets_config.clone()),
+            None => get_user_config()
+                .map_err(|e| SecretsError { msg: format!("{}", e) })?
+                .secrets
+        };
+
+        let default_directory = project_config.jarvis_directory.join("secrets");
+        let project_id = project_config.build_config.project_id.as_str();
+        match secrets_config {
+            Some(SecretsConfig::EncryptedFile { directory, key_file }) => Ok(LocalSecrets {
+                directory: directory.map_or(default_directory, |directory| project_config.project_directory.join(directory)),
+                key_file: match key_file {
+                    Some(key_file) => PathBuf::from(key_file),
+                    None => default_key_file(project_id)?
+                },
+            }),
+            None => Ok(LocalSecrets {
+                directory: default_directory,
+                key_file: default_key_file(project_id)?,
+            }),
+            Some(SecretsConfig::File { .. }) => Err(SecretsError { msg: "The project reads plain text secret files, configure the encrypted-file secrets provider to manage encrypted secrets".to_string() }),
+            Some(SecretsConfig::Env { .. }) => Err(SecretsError { msg: "The project reads secrets from the environment, there are no local secrets to manage".to_string() }),
+            Some(SecretsConfig::Vault { .. }) => Err(SecretsError { msg: "The project reads secrets from Vault, manage them there instead".to_string() }),
+        }
+    }
+
+    fn staging_directory(&self) -> PathBuf {
+        sibling(&self.directory, "rotating")
+    }
+
+    fn previous_directory(&self) -> PathBuf {
+        sibling(&self.directory, "previous")
+    }
+
+    fn new_key_file(&self) -> PathBuf {
+        self.key_file.with_extension("key.new")
+    }
+
+    // Once the secrets have been moved aside a rotation is finished, before that nothing has changed and whatever was
+    // staged is thrown away.
+    fn recover_rotation(&self) -> Result<(), SecretsError> {
+        if self.previous_directory().exists() {
+            return self.finish_rotation();
+        }
+
+        remove_path(&self.staging_directory())?;
+        remove_path(&self.new_key_file())
+    }
+
+    fn finish_rotation(&self) -> Result<(), SecretsError> {
+        let staging_directory = self.staging_directory();
+        if staging_directory.exists() {
+            rename(&staging_directory, &self.directory)?;
+        }
+
+        let new_key_file = self.new_key_file();
+        if new_key_file.exists() {
+            rename(&new_key_file, &self.key_file)?;
+        }
+
+        remove_path(&self.previous_directory())
+    }
+
+    fn encrypted_file(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{}{}", name, ENCRYPTED_EXTENSION))
+    }
+
+    fn plain_text_file(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{}{}", name, PLAIN_TEXT_EXTENSION))
+    }
+
+    fn encrypted_names(&self) -> Result<Vec<String>, SecretsError> {
+        Ok(self.list()?.into_iter()
+            .filter(|secret| secret.encrypted)
+            .map(|secret| secret_lookup_name(secret.environment.as_deref(), secret.name.as_str()))
+            .collect())
+    }
+
+    // Secrets belonging to an environment are kept in a directory named after it.
+    fn list(&self) -> Result<Vec<SecretInfo>, SecretsError> {
+        let mut secrets = self.list_directory(&self.directory, None)?;
+        for entry in read_directory(&self.directory)? {
+            if entry.is_dir() {
+                let environment = entry.file_name().unwrap().to_string_lossy().to_string();
+                secrets.extend(self.list_directory(&entry, Some(environment))?);
+            }
+        }
+
+        secrets.sort_by(|a, b| (&a.environment, &a.name).cmp(&(&b.environment, &b.name)));
+        Ok(secrets)
+    }
+
+    fn list_directory(&self, directory: &PathBuf, environment: Option<String>) -> Result<Vec<SecretInfo>, SecretsError> {
+        let mut secrets = vec![];
+        for entry in read_directory(directory)? {
+            let file_name = entry.file_name().unwrap().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_suffix(ENCRYPTED_EXTENSION) {
+                secrets.push(SecretInfo { name: name.to_string(), environment: environment.clone(), encrypted: true });
+            } else if let Some(name) = file_name.strip_suffix(PLAIN_TEXT_EXTENSION) {
+                secrets.push(SecretInfo { name: name.to_string(), environment: environment.clone(), encrypted: false });
+            }
+        }
+
+        Ok(secrets)
+    }
+}
+
+fn read_directory(directory: &PathBuf) -> Result<Vec<PathBuf>, SecretsError> {
+    if !directory.exists() {
+        return Ok(vec![]);
+    }
+
+    fs::read_dir(directory)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .map_err(|e| SecretsError { msg: format!("Failed to read [{}]: {}", directory.display(), e) })
+}
+
+fn sibling(directory: &PathBuf, suffix: &str) -> PathBuf {
+    let name = directory.file_name().map_or("secrets".to_string(), |name| name.to_string_lossy().to_string());
+    directory.with_file_name(format!("{}.{}", name, suffix))
+}
+
+fn rename(from: &PathBuf, to: &PathBuf) -> Result<(), SecretsError> {
+    fs::rename(from, to)
+        .map_err(|e| SecretsError { msg: format!("Failed to move [{}] to [{}]: {}", from.display(), to.display(), e) })
+}
+
+fn remove_path(path: &PathBuf) -> Result<(), SecretsError> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        Ok(())
+    };
+
+    result.map_err(|e| SecretsError { msg: format!("Failed to remove [{}]: {}", path.display(), e) })
+}
+
+// Copies the secrets in `directory` into `staging`, re-encrypting each with the new key. Directories below the secrets
+// directory hold the secrets of an environment.
+fn stage_secrets(directory: &PathBuf, staging: &PathBuf, environment: Option<&str>, old_key: &[u8], new_key: &[u8]) -> Result<usize, SecretsError> {
+    fs::create_dir_all(staging)
+        .map_err(|e| SecretsError { msg: format!("Failed to create [{}]: {}", staging.display(), e) })?;
+
+    let mut count = 0;
+    for entry in read_directory(directory)? {
+        let file_name = entry.file_name().unwrap().to_string_lossy().to_string();
+        let target = staging.join(file_name.as_str());
+
+        if entry.is_dir() {
+            count += stage_secrets(&entry, &target, Some(environment.unwrap_or(file_name.as_str())), old_key, new_key)?;
+        } else if let Some(name) = file_name.strip_suffix(ENCRYPTED_EXTENSION) {
+            let name = secret_lookup_name(environment, name);
+            let content = fs::read(&entry)
+                .map_err(|e| SecretsError { msg: format!("Failed to read [{}]: {}", entry.display(), e) })?;
+            let value = decrypt_secret(old_key, name.as_str(), content.as_slice())?;
+            fs::write(&target, encrypt_secret(new_key, name.as_str(), value.as_slice()))
+                .map_err(|e| SecretsError { msg: format!("Failed to write [{}]: {}", target.display(), e) })?;
+            count += 1;
+        } else {
+            fs::copy(&entry, &target)
+                .map_err(|e| SecretsError { msg: format!("Failed to copy [{}]: {}", entry.display(), e) })?;
+        }
+    }
+
+    Ok(count)
+}
+
+fn validate_name(kind: &str, name: &str) -> Result<(), SecretsError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') || name.starts_with('.') {
+        return Err(SecretsError { msg: format!("Invalid {} name [{}], use letters, digits, '-', '_' and '.'", kind, name) });
+    }
+
+    Ok(())
+}
+
+// Encrypts `value` into the project's secrets directory, replacing any plain text file for the same secret. The key is
+// created the first time a secret is added, in which case its location is returned.
+pub fn add_secret(project_path: PathBuf, environment: Option<&str>, name: &str, value: &[u8]) -> Result<Option<PathBuf>, SecretsError> {
+    validate_name("secret", name)?;
+    if let Some(environment) = environment {
+        validate_name("environment", environment)?;
+    }
+    let name = secret_lookup_name(environment, name);
+    let name = name.as_str();
+
+    let local_secrets = LocalSecrets::for_project(project_path)?;
+
+    let created_key = if local_secrets.key_file.exists() {
+        None
+    } else {
+        write_key(&local_secrets.key_file, generate_key().as_slice())?;
+        Some(local_secrets.key_file.clone())
+    };
+    let key = read_key(&local_secrets.key_file)?;
+
+    let secret_file = local_secrets.encrypted_file(name);
+    fs::create_dir_all(secret_file.parent().unwrap())
+        .and_then(|_| fs::write(&secret_file, encrypt_secret(key.as_slice(), name, value)))
+        .map_err(|e| SecretsError { msg: format!("Failed to write [{}]: {}", secret_file.display(), e) })?;
+
+    let plain_text_file = local_secrets.plain_text_file(name);
+    if plain_text_file.exists() {
+        fs::remove_file(&plain_text_file)
+            .map_err(|e| SecretsError { msg: format!("Failed to remove [{}]: {}", plain_text_file.display(), e) })?;
+    }
+
+    Ok(created_key)
+}
+
+// Builds read secrets without going through the commands above, so they finish or roll back an interrupted rotation
+// the same way before reading anything.
+pub fn recover_rotation(directory: PathBuf, key_file: PathBuf) -> Result<(), SecretsError> {
+    LocalSecrets { directory, key_file }.recover_rotation()
+}
+
+pub fn list_secrets(project_path: PathBuf) -> Result<Vec<SecretInfo>, SecretsError> {
+    LocalSecrets::for_project(project_path)?.list()
+}
+
+pub fn remove_secret(project_path: PathBuf, environment: Option<&str>, name: &str) -> Result<(), SecretsError> {
+    validate_name("secret", name)?;
+    if let Some(environment) = environment {
+        validate_name("environment", environment)?;
+    }
+
+    let local_secrets = LocalSecrets::for_project(project_path)?;
+    let name = secret_lookup_name(environment, name);
+    let name = name.as_str();
+
+    let files: Vec<PathBuf> = vec![local_secrets.encrypted_file(name), local_secrets.plain_text_file(name)].into_iter()
+        .filter(|file| file.exists())
+        .collect();
+    if files.is_empty() {
+        return Err(SecretsError { msg: format!("Secret [{}] not found in [{}]", name, local_secrets.directory.display()) });
+    }
+
+    for file in files {
+        fs::remove_file(&file)
+            .map_err(|e| SecretsError { msg: format!("Failed to remove [{}]: {}", file.display(), e) })?;
+    }
+
+    Ok(())
+}
+
+// Re-encrypts every secret with a new key. Everything is written to a staging directory and a new key file first, then
+// the secrets are moved aside, which is the point where the rotation counts as done, and the staged copies and new key
+// take their place. An interruption is rolled back or finished the next time the secrets are used, so the secrets are
+// never left encrypted with a mix of keys.
+pub fn rotate_key(project_path: PathBuf) -> Result<usize, SecretsError> {
+    let local_secrets = LocalSecrets::for_project(project_path)?;
+    let old_key = read_key(&local_secrets.key_file)?;
+    let new_key = generate_key();
+
+    let staged = write_key(&local_secrets.new_key_file(), new_key.as_slice())
+        .and_then(|_| stage_secrets(&local_secrets.directory, &local_secrets.staging_directory(), None, old_key.as_slice(), new_key.as_slice()));
+    let count = match staged {
+        Ok(count) => count,
+        Err(e) => {
+            local_secrets.recover_rotation()?;
+            return Err(e);
+        }
+    };
+
+    fs::create_dir_all(&local_secrets.directory)
+        .map_err(|e| SecretsError { msg: format!("Failed to create [{}]: {}", local_secrets.directory.display(), e) })?;
+    rename(&local_secrets.directory, &local_secrets.previous_directory())?;
+    local_secrets.finish_rotation()?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::cache::temp_path;
+    use crate::config::get_project_config;
+    use crate::secrets::create_provider;
+    use crate::secrets::encrypted_provider::{decrypt_secret, generate_key, read_key, write_key};
+    use super::{add_secret, list_secrets, LocalSecrets, remove_secret, rotate_key, stage_secrets};
+
+    fn create_project() -> PathBuf {
+        let project = temp_path("jarvis-secrets-test", "project");
+        let key_file = project.join("keys").join("project.key");
+        fs::create_dir_all(project.join(".jarvis")).unwrap();
+        fs::write(project.join(".jarvis/build.yaml"), format!("api_version: 0.1\nproject_id: test\nsecrets:\n  type: encrypted-file\n  key_file: {}\nmodules: []\n", key_file.display())).unwrap();
+        project
+    }
+
+    fn read_secret(project: &PathBuf, name: &str) -> Vec<u8> {
+        let local_secrets = LocalSecrets::for_project(project.clone()).unwrap();
+        let key = read_key(&local_secrets.key_file).unwrap();
+        decrypt_secret(key.as_slice(), name, fs::read(local_secrets.encrypted_file(name)).unwrap().as_slice()).unwrap()
+    }
+
+    fn names(project: &PathBuf) -> Vec<(Option<String>, String)> {
+        list_secrets(project.clone()).unwrap().into_iter()
+            .map(|secret| (secret.environment, secret.name))
+            .collect()
+    }
+
+    #[test]
+    fn secrets_are_added_listed_and_removed() {
+        let project = create_project();
+
+        assert!(add_secret(project.clone(), None, "npm-token", b"s3cr3t").unwrap().is_some());
+        assert!(add_secret(project.clone(), Some("prod"), "db-password", b"hunter22").unwrap().is_none());
+        assert_eq!(vec![(None, "npm-token".to_string()), (Some("prod".to_string()), "db-password".to_string())], names(&project));
+        assert_eq!(b"hunter22".to_vec(), read_secret(&project, "prod/db-password"));
+
+        assert!(add_secret(project.clone(), None, "../npm-token", b"s3cr3t").is_err());
+        assert!(remove_secret(project.clone(), None, "../.jarvis/build").is_err());
+        assert!(remove_secret(project.clone(), Some(".."), "npm-token").is_err());
+        assert!(remove_secret(project.clone(), None, "missing").is_err());
+
+        remove_secret(project.clone(), Some("prod"), "db-password").unwrap();
+        assert_eq!(vec![(None, "npm-token".to_string())], names(&project));
+
+        fs::remove_dir_all(project).unwrap();
+    }
+
+    #[test]
+    fn rotation_re_encrypts_every_secret() {
+        let project = create_project();
+        add_secret(project.clone(), None, "npm-token", b"s3cr3t").unwrap();
+        add_secret(project.clone(), Some("prod"), "db-password", b"hunter22").unwrap();
+        let local_secrets = LocalSecrets::for_project(project.clone()).unwrap();
+        let old_key = read_key(&local_secrets.key_file).unwrap();
+
+        assert_eq!(2, rotate_key(project.clone()).unwrap());
+
+        assert_ne!(old_key, read_key(&local_secrets.key_file).unwrap());
+        assert_eq!(b"s3cr3t".to_vec(), read_secret(&project, "npm-token"));
+        assert_eq!(b"hunter22".to_vec(), read_secret(&project, "prod/db-password"));
+        assert!(!local_secrets.staging_directory().exists() && !local_secrets.previous_directory().exists() && !local_secrets.new_key_file().exists());
+
+        fs::remove_dir_all(project).unwrap();
+    }
+
+    #[test]
+    fn interrupted_rotations_never_mix_keys() {
+        let project = create_project();
+        add_secret(project.clone(), None, "npm-token", b"s3cr3t").unwrap();
+        let local_secrets = LocalSecrets::for_project(project.clone()).unwrap();
+        let old_key = read_key(&local_secrets.key_file).unwrap();
+
+        // Interrupted while staging, the old key and secrets stay.
+        fs::write(local_secrets.new_key_file(), "partial").unwrap();
+        fs::create_dir_all(local_secrets.staging_directory()).unwrap();
+        assert_eq!(b"s3cr3t".to_vec(), read_secret(&project, "npm-token"));
+        assert_eq!(old_key, read_key(&local_secrets.key_file).unwrap());
+        assert!(!local_secrets.staging_directory().exists() && !local_secrets.new_key_file().exists());
+
+        // Interrupted once the secrets were moved aside, the staged secrets and new key replace them.
+        let new_key = generate_key();
+        write_key(&local_secrets.new_key_file(), new_key.as_slice()).unwrap();
+        stage_secrets(&local_secrets.directory, &local_secrets.staging_directory(), None, old_key.as_slice(), new_key.as_slice()).unwrap();
+        fs::rename(&local_secrets.directory, local_secrets.previous_directory()).unwrap();
+        assert_eq!(b"s3cr3t".to_vec(), read_secret(&project, "npm-token"));
+        assert_eq!(new_key, read_key(&local_secrets.key_file).unwrap());
+        assert!(!local_secrets.previous_directory().exists());
+
+        fs::remove_dir_all(project).unwrap();
+    }
+
+    #[tokio::test]
+    async fn builds_finish_interrupted_rotations() {
+        let project = create_project();
+        add_secret(project.clone(), None, "npm-token", b"s3cr3t").unwrap();
+        let local_secrets = LocalSecrets::configured_for(project.clone()).unwrap();
+        let old_key = read_key(&local_secrets.key_file).unwrap();
+
+        let new_key = generate_key();
+        write_key(&local_secrets.new_key_file(), new_key.as_slice()).unwrap();
+        stage_secrets(&local_secrets.directory, &local_secrets.staging_directory(), None, old_key.as_slice(), new_key.as_slice()).unwrap();
+        fs::rename(&local_secrets.directory, local_secrets.previous_directory()).unwrap();
+
+        let provider = create_provider(&get_project_config(project.clone()).unwrap()).unwrap();
+
+        assert_eq!(b"s3cr3t".to_vec(), provider.get_secret("npm-token").await.unwrap());
+        assert_eq!(new_key, read_key(&local_secrets.key_file).unwrap());
+        assert!(!local_secrets.previous_directory().exists() && !local_secrets.staging_directory().exists());
+
+        fs::remove_dir_all(project).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use crate::secrets::{SecretsError, SecretsProvider};
+
+const DEFAULT_MOUNT: &str = "secret";
+const DEFAULT_FIELD: &str = "value";
+
+// Reads secrets from the KV version 2 engine of a HashiCorp Vault compatible server. Each secret is stored at
+// `<path>/<name>` and its value is read from one field of the stored data.
+pub struct VaultSecretsProvider {
+    address: String,
+
+    token: String,
+
+    mount: String,
+
+    path: Option<String>,
+
+    field: String,
+
+    client: reqwest::Client,
+}
+
+impl VaultSecretsProvider {
+    pub fn new(address: &str, token: String, mount: Option<String>, path: Option<String>, field: Option<String>) -> Self {
+        VaultSecretsProvider {
+            address: address.trim_end_matches('/').to_string(),
+            token,
+            mount: mount.unwrap_or(DEFAULT_MOUNT.to_string()).trim_matches('/').to_string(),
+            path: path.map(|path| path.trim_matches('/').to_string()).filter(|path| !path.is_empty()),
+            field: field.unwrap_or(DEFAULT_FIELD.to_string()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn secret_url(&self, name: &str) -> String {
+        match &self.path {
+            Some(path) => format!("{}/v1/{}/data/{}/{}", self.address, self.mount, path, name),
+            None => format!("{}/v1/{}/data/{}", self.address, self.mount, name)
+        }
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for VaultSecretsProvider {
+    async fn get_secret(&self, name: &str) -> Result<Vec<u8>, SecretsError> {
+        let response = self.client.get(self.secret_url(name).as_str())
+            .header("X-Vault-Token", self.token.as_str())
+            .send().await
+            .map_err(|e| SecretsError { msg: format!("Request for secret [{}] failed: {}", name, e) })?;
+
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Err(SecretsError { msg: format!("Secret [{}] not found in Vault", name) });
+        }
+        if !status.is_success() {
+            return Err(SecretsError { msg: format!("Request for secret [{}] failed with status [{}]", name, status) });
+        }
+
+        let body: Value = response.json().await
+            .map_err(|e| SecretsError { msg: format!("Invalid response for secret [{}]: {}", name, e) })?;
+
+        // Structured values are passed on as JSON so that nothing stored in the field is lost.
+        match body.pointer(format!("/data/data/{}", self.field).as_str()) {
+            Some(Value::String(value)) => Ok(value.as_bytes().to_vec()),
+            Some(value) => Ok(value.to_string().into_bytes()),
+            None => Err(SecretsError { msg: format!("Secret [{}] has no [{}] field", name, self.field) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::secrets::SecretsProvider;
+    use super::VaultSecretsProvider;
+
+    // A stand-in for a Vault server which knows a single secret and checks the token it is sent.
+    fn start_stand_in_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let parts: Vec<&str> = request_line.split_whitespace().collect();
+
+                let mut authorised = false;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if header.to_ascii_lowercase().starts_with("x-vault-token:") && header[14..].trim() == "test-token" {
+                        authorised = true;
+                    }
+                }
+
+                let response = if !authorised {
+                    "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                } else if parts[1] == "/v1/secret/data/ci/npm-token" {
+                    let body = r#"{"data":{"data":{"value":"s3cr3t"},"metadata":{"version":1}}}"#;
+                    format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn reads_secrets_from_a_stand_in_server() {
+        let address = start_stand_in_server();
+
+        let provider = VaultSecretsProvider::new(address.as_str(), "test-token".to_string(), None, Some("ci".to_string()), None);
+        assert_eq!(b"s3cr3t".to_vec(), provider.get_secret("npm-token").await.unwrap());
+        assert!(provider.get_secret("missing").await.is_err());
+
+        let unauthorised = VaultSecretsProvider::new(address.as_str(), "wrong-token".to_string(), None, Some("ci".to_string()), None);
+        assert!(unauthorised.get_secret("npm-token").await.is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::OutputFormatter;
+use crate::runtime::{BuiltImage, CacheOutcome, CheckoutReport, PushedImage, StepReport};
+use crate::artifacts::ArtifactManifest;
+
+pub struct BuildSummary {
+    pub build_id: String,
+
+    pub source_commit: Option<String>,
+
+    pub checkouts: Vec<CheckoutReport>,
+
+    pub steps: Vec<StepSummary>,
+}
+
+pub struct StepSummary {
+    pub module_name: String,
+
+    pub step_name: String,
+
+    pub succeeded: bool,
+
+    pub report: StepReport,
+
+    pub artifacts: Vec<ArtifactManifest>,
+
+    pub images: Vec<BuiltImage>,
+
+    pub pushed: Vec<PushedImage>,
+}
+
+impl BuildSummary {
+    pub fn new(build_id: String) -> Self {
+        BuildSummary {
+            build_id,
+            source_commit: None,
+            checkouts: vec![],
+            steps: vec![],
+        }
+    }
+
+    pub fn print(&self, output_formatter: &dyn OutputFormatter) {
+        if self.steps.is_empty() {
+            return;
+        }
+
+        output_formatter.print(format!("Build summary for [{}]", self.build_id));
+        if let Some(commit) = &self.source_commit {
+            output_formatter.background(format!("Source commit [{}]", commit));
+        }
+        for checkout in &self.checkouts {
+            output_formatter.background(format!("[{}] checkout {} at [{}] in {}", checkout.module_name, checkout.repository, checkout.commit, checkout.path));
+        }
+
+        for step in &self.steps {
+            let status = if step.succeeded { "succeeded" } else { "failed" };
+            output_formatter.print(format!("[{}] {}: {}", step.module_name, step.step_name, status));
+
+            for artifact in &step.artifacts {
+                output_formatter.background(format!("  archive {} - {} file(s), {} -> {}",
+                                                    artifact.name,
+                                                    artifact.files.len(),
+                                                    format_bytes(artifact.total_size()),
+                                                    artifact.output));
+            }
+
+            for image in &step.images {
+                if image.tags.is_empty() {
+                    output_formatter.background(format!("  image {} [{}]", image.name, image.id));
+                } else {
+                    output_formatter.background(format!("  image {} [{}] tagged {}", image.name, image.id, image.tags.join(", ")));
+                }
+            }
+
+            for pushed in &step.pushed {
+                output_formatter.background(format!("  pushed {} [{}]", pushed.reference, pushed.digest));
+            }
+
+            for cache in &step.report.caches {
+                let outcome = match &cache.outcome {
+                    CacheOutcome::Hit => "hit".to_string(),
+                    CacheOutcome::RemoteHit => "hit, pulled from the remote cache".to_string(),
+                    CacheOutcome::PartialHit { restored_key } => format!("partial hit, restored from {}", restored_key),
+                    CacheOutcome::Miss => "miss".to_string(),
+                };
+                let mode = if cache.read_only { " (read-only)" } else { "" };
+
+                if cache.key.is_empty() {
+                    output_formatter.background(format!("  cache {}{} - {}", cache.name, mode, outcome));
+                } else {
+                    output_formatter.background(format!("  cache {} [{}]{} - {}", cache.name, cache.key, mode, outcome));
+                }
+            }
+
+            if let Some(egress) = &step.report.egress {
+                if egress.is_empty() {
+                    output_formatter.background("  no remote hosts contacted".to_string());
+                }
+
+                for record in egress {
+                    let mut line = format!("  {}:{} - {} connection(s), {} sent, {} received",
+                                           record.host,
+                                           record.port,
+                                           record.connections,
+                                           format_bytes(record.bytes_sent),
+                                           format_bytes(record.bytes_received));
+                    if record.denied_connections > 0 {
+                        line.push_str(format!(", {} denied", record.denied_connections).as_str());
+                    }
+                    output_formatter.background(line);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
@@ -2,7 +2,14 @@ use std::fmt;
 use crate::config;
 use std::error::Error;
 use std::fmt::Formatter;
-use crate::config::ProjectConfig;
+use std::collections::HashMap;
+use std::path::Component;
+use regex::Regex;
+use crate::config::{Agent, Module, ProjectConfig, Step, BUILD_IMAGE_PREFIX};
+use crate::cache;
+use crate::secrets;
+use crate::environments;
+use crate::runtime::AGENT_VARIABLES;
 
 #[derive(Debug, Clone)]
 pub struct ValidationError {
@@ -28,16 +35,519 @@ pub fn validate_project(project_path: std::path::PathBuf) -> Result<ValidationMe
 
     match project_config {
         Err(e) => Err(ValidationError { msg: format!("Could not load project config: {}", e).to_string() }),
-        Ok(project_config) => Ok(validate_project_config(project_config))
+        Ok(project_config) => Ok(validate_project_config(&project_config))
     }
 }
 
-fn validate_project_config(project_config: ProjectConfig) -> ValidationMessages {
+// Also run before every build, so a configuration with errors never gets as far as running anything.
+pub fn validate_project_config(project_config: &ProjectConfig) -> ValidationMessages {
     let mut messages = ValidationMessages { errors: vec![], warnings: vec![] };
 
     if project_config.build_config.modules.is_empty() {
         messages.warnings.push("No build modules defined".to_string());
     }
 
+    messages.errors.extend(environments::check_step_environments(&project_config.build_config));
+
+    // Modules and their steps run in order, so an image can only be used once an earlier step has built or registered it.
+    let mut built_images: Vec<&str> = vec![];
+    for module in &project_config.build_config.modules {
+        for checkout in module.checkouts.iter().flatten() {
+            if !inside_workspace(checkout.path.as_str()) {
+                messages.errors.push(format!("Checkout of [{}] in module [{}] must have a path inside the workspace, but was [{}]", checkout.repository, module.name, checkout.path));
+            }
+        }
+
+        let step_hosts = module.steps.iter().flat_map(|step| step.allowed_hosts.iter().flatten());
+        for host in module.allowed_hosts.iter().flatten().chain(step_hosts) {
+            if !valid_allowed_host(host.as_str()) {
+                messages.errors.push(format!("Allowed host [{}] in module [{}] must be a host name, an IP address or `*.` followed by a domain", host, module.name));
+            }
+        }
+
+        let mut checked_agents: Vec<&str> = vec![];
+        for step in &module.steps {
+            validate_step_secrets(module, step, &mut messages);
+            validate_build_image(module, step, &mut messages);
+            validate_registered_images(module, step, &mut messages);
+            validate_services(module, step, &built_images, &mut messages);
+
+            if let Some(rule) = &step.push_image {
+                if !built_images.iter().any(|built| built == &rule.image.as_str()) {
+                    messages.errors.push(format!("Step [{}] in module [{}] pushes image [{}], but no step builds or registers it", step.name, module.name, rule.image));
+                }
+                if rule.to.is_empty() {
+                    messages.errors.push(format!("Step [{}] in module [{}] pushes image [{}] without saying where to", step.name, module.name, rule.image));
+                }
+                for destination in rule.to.iter().filter(|destination| destination.contains('@')) {
+                    messages.errors.push(format!("Step [{}] in module [{}] can't push to [{}], push destinations are tags rather than digests", step.name, module.name, destination));
+                }
+            }
+
+            // Agents are started for the steps which run commands, so the image must exist by the first of those.
+            if let (Some(_), Some(agent)) = (&step.command, step_agent(module, step)) {
+                if !checked_agents.contains(&agent.name.as_str()) {
+                    checked_agents.push(agent.name.as_str());
+                    if let Some(name) = agent.built_image() {
+                        if !built_images.iter().any(|built| built == &name) {
+                            messages.errors.push(format!("Agent [{}] in module [{}] runs image [{}], but no step before [{}] builds or registers it", agent.name, module.name, name, step.name));
+                        }
+                    }
+                }
+            }
+
+            let names = step.build_image.iter().map(|rule| &rule.name)
+                .chain(step.register_images.iter().flatten().map(|rule| &rule.name));
+            for name in names {
+                if built_images.contains(&name.as_str()) {
+                    messages.errors.push(format!("Image [{}] is built or registered by more than one step", name));
+                }
+                built_images.push(name.as_str());
+            }
+        }
+
+        for agent in module.agents.iter().flatten() {
+            if let Some(name) = agent.built_image() {
+                if !checked_agents.contains(&agent.name.as_str()) && !built_images.iter().any(|built| built == &name) {
+                    messages.errors.push(format!("Agent [{}] in module [{}] runs image [{}], but no step builds or registers it", agent.name, module.name, name));
+                }
+            }
+
+            for cache_rule in agent.cache.iter().flatten() {
+                match &cache_rule.key {
+                    Some(key) => {
+                        if let Err(e) = cache::render_cache_key(key.as_str(), &project_config.project_directory) {
+                            messages.errors.push(format!("Cache [{}] on agent [{}] has an invalid key: {}", cache_rule.name, agent.name, e));
+                        }
+                    },
+                    None => {
+                        if cache_rule.restore_keys.is_some() {
+                            messages.warnings.push(format!("Cache [{}] on agent [{}] has restore keys but no key, so they will never be used", cache_rule.name, agent.name));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     messages
 }
+
+// A relative path to somewhere below the workspace, which can't be the workspace itself.
+pub fn inside_workspace(path: &str) -> bool {
+    let components = std::path::Path::new(path).components();
+    !path.trim().is_empty()
+        && components.clone().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        && components.clone().any(|c| matches!(c, Component::Normal(_)))
+}
+
+// An entry in an egress allow list, `*.example.com` allows the domain and all of its subdomains.
+pub fn valid_allowed_host(host: &str) -> bool {
+    let host = if host.starts_with("*.") { &host[2..] } else { host };
+    host.parse::<std::net::IpAddr>().is_ok() || (host.contains(|c: char| c.is_ascii_alphanumeric()) && hostname_validator::is_valid(host))
+}
+
+// Built and registered images are tagged with their name, which is kept as it is so that two names can't end up as the
+// same tag.
+fn valid_image_name(name: &str) -> bool {
+    Regex::new(r"^[a-z0-9]+([._-][a-z0-9]+)*$").unwrap().is_match(name)
+}
+
+fn validate_build_image(module: &Module, step: &Step, messages: &mut ValidationMessages) {
+    let rule = match (&step.command, &step.build_image, &step.push_image) {
+        (Some(_), None, None) | (None, None, Some(_)) => return,
+        (None, Some(rule), None) => rule,
+        _ => {
+            messages.errors.push(format!("Step [{}] in module [{}] must have exactly one of command, build_image or push_image", step.name, module.name));
+            return;
+        }
+    };
+
+    if !valid_image_name(rule.name.as_str()) {
+        messages.errors.push(format!("Image [{}] built by step [{}] must be a lowercase name without the [{}] prefix", rule.name, step.name, BUILD_IMAGE_PREFIX));
+    }
+
+    for path in rule.context.iter().chain(rule.dockerfile.iter()) {
+        let path = std::path::Path::new(path.as_str());
+        if path.is_absolute() || path.components().any(|c| c == std::path::Component::ParentDir) {
+            messages.errors.push(format!("Step [{}] in module [{}] must build from inside the workspace, but used [{}]", step.name, module.name, path.display()));
+        }
+    }
+
+    if step.secrets.is_some() || step.inputs.is_some() || step.archives.is_some() {
+        messages.warnings.push(format!("Step [{}] in module [{}] builds an image, its secrets, inputs and archives are ignored", step.name, module.name));
+    }
+}
+
+fn validate_registered_images(module: &Module, step: &Step, messages: &mut ValidationMessages) {
+    let rules = match &step.register_images {
+        Some(rules) => rules,
+        None => return
+    };
+
+    if step.command.is_none() {
+        messages.errors.push(format!("Step [{}] in module [{}] registers images, which needs a command to produce them", step.name, module.name));
+    }
+
+    for rule in rules {
+        if !valid_image_name(rule.name.as_str()) {
+            messages.errors.push(format!("Image [{}] registered by step [{}] must be a lowercase name without the [{}] prefix", rule.name, step.name, BUILD_IMAGE_PREFIX));
+        }
+
+        match (&rule.image, &rule.archive) {
+            (Some(_), None) => {},
+            (None, Some(archive)) => {
+                let path = std::path::Path::new(archive.as_str());
+                if archive.trim().is_empty() || path.is_absolute() || path.components().any(|c| c == std::path::Component::ParentDir) {
+                    messages.errors.push(format!("Step [{}] in module [{}] must register image archives from inside the workspace, but used [{}]", step.name, module.name, archive));
+                }
+            },
+            _ => messages.errors.push(format!("Image [{}] registered by step [{}] must have exactly one of image or archive", rule.name, step.name))
+        }
+    }
+}
+
+// Services are reached by their name, so each must be a unique host name.
+fn validate_services(module: &Module, step: &Step, built_images: &Vec<&str>, messages: &mut ValidationMessages) {
+    let services = match &step.services {
+        Some(services) => services,
+        None => return
+    };
+
+    if step.command.is_none() {
+        messages.errors.push(format!("Step [{}] in module [{}] has services, which need a command to use them", step.name, module.name));
+    }
+
+    let host_pattern = Regex::new(r"^[a-z0-9]([a-z0-9-]*[a-z0-9])?$").unwrap();
+    let mut names = vec![];
+    for service in services {
+        if !host_pattern.is_match(service.name.as_str()) {
+            messages.errors.push(format!("Service [{}] of step [{}] in module [{}] must have a name which is a valid host name", service.name, step.name, module.name));
+        }
+        if names.contains(&service.name.as_str()) {
+            messages.errors.push(format!("Step [{}] in module [{}] has more than one service named [{}]", step.name, module.name, service.name));
+        }
+        names.push(service.name.as_str());
+
+        if service.image.starts_with(BUILD_IMAGE_PREFIX) {
+            let name = &service.image[BUILD_IMAGE_PREFIX.len()..];
+            if !built_images.iter().any(|built| built == &name) {
+                messages.errors.push(format!("Service [{}] of step [{}] runs image [{}], but no step builds or registers it", service.name, step.name, name));
+            }
+        }
+
+        if let Some(check) = &service.ready {
+            if check.port.is_none() && check.command.is_none() {
+                messages.errors.push(format!("Service [{}] of step [{}] has a readiness check without a port or a command", service.name, step.name));
+            }
+        }
+    }
+}
+
+// The agent a step runs on, the module's default agent unless the step names one.
+fn step_agent<'a>(module: &'a Module, step: &Step) -> Option<&'a Agent> {
+    module.agents.iter().flatten()
+        .find(|agent| match &step.agent {
+            Some(name) => &agent.name == name,
+            None => agent.default.unwrap_or(false)
+        })
+}
+
+// Variables and files generated for a step's secrets must not overwrite each other, the agent's own environment or the
+// variables jarvis sets in every agent.
+fn validate_step_secrets(module: &Module, step: &Step, messages: &mut ValidationMessages) {
+    let rules = match &step.secrets {
+        Some(rules) => rules,
+        None => return
+    };
+
+    let agent = step_agent(module, step);
+    let mut variables: HashMap<String, String> = HashMap::new();
+    for variable in AGENT_VARIABLES.iter() {
+        variables.insert(variable.to_string(), "jarvis itself".to_string());
+    }
+    if let Some(agent) = agent {
+        for variable in agent.environment.iter().flat_map(|environment| environment.keys()) {
+            variables.insert(variable.clone(), format!("the environment of agent [{}]", agent.name));
+        }
+    }
+    let mut paths: HashMap<String, String> = HashMap::new();
+
+    let variable_pattern = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    for target in secrets::secret_targets(rules) {
+        let source = match &target.key {
+            Some(key) => format!("key [{}] of secret [{}]", key, target.secret),
+            None => format!("secret [{}]", target.secret)
+        };
+
+        if !variable_pattern.is_match(target.variable.as_str()) {
+            messages.errors.push(format!("Step [{}] in module [{}] exposes {} as [{}], which isn't a valid variable name", step.name, module.name, source, target.variable));
+        }
+
+        for variable in target.variables() {
+            match variables.get(&variable) {
+                Some(existing) => messages.errors.push(format!("Step [{}] in module [{}] sets variable [{}] for both {} and {}", step.name, module.name, variable, existing, source)),
+                None => { variables.insert(variable, source.clone()); }
+            }
+        }
+
+        for path in target.path.iter().chain(target.storage_path.iter().filter(|storage_path| Some(*storage_path) != target.path.as_ref())) {
+            if path.split('/').any(|component| component == "..") {
+                messages.errors.push(format!("Step [{}] in module [{}] writes {} to [{}], which must not contain '..'", step.name, module.name, source, path));
+            }
+
+            match paths.get(path) {
+                Some(existing) => messages.errors.push(format!("Step [{}] in module [{}] writes both {} and {} to [{}]", step.name, module.name, existing, source, path)),
+                None => { paths.insert(path.clone(), source.clone()); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config::{BuildConfig, ProjectConfig};
+    use super::{inside_workspace, validate_project_config};
+
+    fn project(build_yaml: &str) -> ProjectConfig {
+        let build_config: BuildConfig = serde_yaml::from_str(build_yaml).unwrap();
+        ProjectConfig {
+            project_directory: PathBuf::from("project"),
+            workspace_directory: PathBuf::from("project"),
+            jarvis_directory: PathBuf::from("project/.jarvis"),
+            build_config,
+        }
+    }
+
+    fn errors(build_yaml: &str) -> Vec<String> {
+        validate_project_config(&project(build_yaml)).errors
+    }
+
+    #[test]
+    fn checkouts_stay_below_the_workspace() {
+        for path in vec!["vendor/lib", "./vendor", "vendor/"] {
+            assert!(inside_workspace(path), "{}", path);
+        }
+        for path in vec!["", " ", ".", "./", "..", "vendor/../..", "/etc", "/build/workspace/vendor"] {
+            assert!(!inside_workspace(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn checkouts_outside_the_workspace_are_errors() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    checkouts:
+      - repository: https://example.com/lib.git
+        path: ../lib
+    steps: []
+");
+
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("[../lib]"));
+    }
+
+    #[test]
+    fn secrets_cannot_replace_the_variables_jarvis_sets() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    agents:
+      - name: alpine
+        default: true
+        image: alpine:latest
+    steps:
+      - name: build
+        command: make
+        secrets:
+          - name: proxy
+            as: env
+            variable: HTTPS_PROXY
+          - name: home
+            as: env
+            variable: JARVIS_AGENT_HOME
+          - name: token
+            as: env
+            variable: TOKEN
+");
+
+        assert_eq!(2, errors.len());
+        assert!(errors[0].contains("[HTTPS_PROXY]"));
+        assert!(errors[1].contains("[JARVIS_AGENT_HOME]"));
+    }
+
+    #[test]
+    fn steps_build_images_instead_of_running_commands() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    steps:
+      - name: build
+        command: make
+        build_image:
+          name: app
+");
+
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("exactly one of command, build_image or push_image"));
+    }
+
+    #[test]
+    fn built_images_have_lowercase_names() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    steps:
+      - name: app
+        build_image:
+          name: app
+      - name: upper
+        build_image:
+          name: App
+      - name: space
+        build_image:
+          name: my app
+      - name: prefixed
+        build_image:
+          name: build://web
+");
+
+        assert_eq!(3, errors.len());
+        assert!(errors[0].contains("[App]"));
+        assert!(errors[1].contains("[my app]"));
+        assert!(errors[2].contains("[build://web]"));
+    }
+
+    #[test]
+    fn agents_only_run_build_outputs_named_with_the_prefix() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    agents:
+      - name: registry-image
+        image: build:latest
+      - name: built
+        image: build://app
+      - name: missing
+        image: build://other
+    steps:
+      - name: build
+        build_image:
+          name: app
+");
+
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("[other]"));
+    }
+
+    #[test]
+    fn registered_images_come_from_a_command() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    steps:
+      - name: load
+        command: docker load -i app.tar
+        register_images:
+          - name: app
+            archive: app.tar
+          - name: App
+            image: app:latest
+          - name: outside
+            archive: ../app.tar
+          - name: both
+            image: app:latest
+            archive: app.tar
+");
+
+        assert_eq!(3, errors.len());
+        assert!(errors[0].contains("[App]"));
+        assert!(errors[1].contains("[../app.tar]"));
+        assert!(errors[2].contains("[both]"));
+    }
+
+    #[test]
+    fn services_are_reachable_by_name_and_run_known_images() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: app
+    steps:
+      - name: build
+        build_image:
+          name: app
+      - name: test
+        command: make test
+        services:
+          - name: app
+            image: build://app
+          - name: db
+            image: postgres:13
+            ready:
+              timeout: 30
+          - name: db
+            image: postgres:13
+          - name: Cache_1
+            image: build://cache
+");
+
+        assert_eq!(4, errors.len());
+        assert!(errors[0].contains("readiness check"));
+        assert!(errors[1].contains("more than one service named [db]"));
+        assert!(errors[2].contains("[Cache_1]"));
+        assert!(errors[3].contains("[cache]"));
+    }
+
+    #[test]
+    fn images_are_used_after_the_steps_which_build_them() {
+        let errors = errors("api_version: 0.1
+project_id: test
+modules:
+  - name: first
+    agents:
+      - name: builder
+        image: build://builder
+        default: true
+    steps:
+      - name: publish
+        push_image:
+          image: app
+          to:
+            - localhost:5000/app:latest
+      - name: test
+        command: make test
+        services:
+          - name: app
+            image: build://app
+      - name: builder
+        build_image:
+          name: builder
+      - name: package
+        command: make package
+  - name: second
+    steps:
+      - name: app
+        build_image:
+          name: app
+      - name: publish
+        push_image:
+          image: app
+          to:
+            - localhost:5000/app:latest
+");
+
+        assert_eq!(3, errors.len());
+        assert!(errors[0].contains("pushes image [app]"));
+        assert!(errors[1].contains("Service [app]"));
+        assert!(errors[2].contains("no step before [test]"));
+    }
+}
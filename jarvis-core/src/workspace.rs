@@ -0,0 +1,726 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, sync_channel, SyncSender};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use crypto::digest::Digest;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use futures::channel::mpsc::Sender;
+use futures::executor::block_on;
+use futures::SinkExt;
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::artifacts::HashingReader;
+use crate::config::{jarvis_home_directory, ProjectConfig, WorkspaceCompression};
+use crate::secrets;
+use crate::validate::inside_workspace;
+use crate::summary::format_bytes;
+
+const IGNORE_FILE_NAME: &str = ".jarvisignore";
+
+// Each block is compressed independently, large enough that the extra gzip headers don't matter.
+const COMPRESSION_BLOCK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct WorkspaceError {
+    msg: String
+}
+
+impl fmt::Display for WorkspaceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "workspace error: {}", self.msg)
+    }
+}
+
+impl Error for WorkspaceError {}
+
+pub struct WorkspaceEntry {
+    // Relative to the project directory, using `/` as the separator.
+    pub relative_path: String,
+
+    pub path: PathBuf,
+
+    pub is_dir: bool,
+
+    pub size: u64,
+
+    // In nanoseconds, so a file rewritten within the same second as the last sync still looks modified.
+    pub modified: u64,
+}
+
+pub struct WorkspaceFiles {
+    pub entries: Vec<WorkspaceEntry>,
+
+    pub file_count: usize,
+
+    pub total_size: u64,
+}
+
+// Finds everything which should be uploaded into the workspace. Paths matched by `.jarvisignore` files are skipped, as
+// are `.gitignore` matches when the project opts in. Secrets directories are never included whatever the ignore files say.
+pub fn collect_workspace_files(project_config: &ProjectConfig) -> Result<WorkspaceFiles, WorkspaceError> {
+    let excluded = workspace_secrets_paths(project_config)?.iter()
+        .map(|path| project_config.workspace_directory.join(path))
+        .collect();
+
+    collect_directory_files(&project_config.workspace_directory, respect_gitignore(project_config), excluded)
+}
+
+// The secrets directories which are inside the workspace, relative to it.
+pub fn workspace_secrets_paths(project_config: &ProjectConfig) -> Result<Vec<String>, WorkspaceError> {
+    let directories = secrets::secrets_directories(project_config)
+        .map_err(|e| WorkspaceError { msg: format!("{}", e) })?;
+
+    Ok(directories.iter()
+        .filter_map(|directory| directory.strip_prefix(&project_config.project_directory).ok())
+        .map(|directory| directory.to_string_lossy().replace('\\', "/"))
+        .filter(|directory| inside_workspace(directory.as_str()))
+        .collect())
+}
+
+fn respect_gitignore(project_config: &ProjectConfig) -> bool {
+    project_config.build_config.workspace.as_ref()
+        .and_then(|workspace| workspace.respect_gitignore)
+        .unwrap_or(false)
+}
+
+// Collects everything under `project_directory` which isn't ignored, leaving out `excluded` and whatever is below them.
+pub fn collect_directory_files(project_directory: &PathBuf, respect_gitignore: bool, excluded: Vec<PathBuf>) -> Result<WorkspaceFiles, WorkspaceError> {
+    let walker = WalkBuilder::new(project_directory)
+        .hidden(false)
+        .parents(false)
+        .ignore(false)
+        .git_ignore(respect_gitignore)
+        .git_global(false)
+        .git_exclude(false)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .filter_entry(move |entry| !excluded.iter().any(|excluded| entry.path().starts_with(excluded)))
+        .build();
+
+    let mut entries = vec![];
+    let mut file_count = 0;
+    let mut total_size = 0;
+    for result in walker {
+        let entry = result
+            .map_err(|e| WorkspaceError { msg: format!("Failed to read project directory: {}", e) })?;
+
+        let relative_path = match entry.path().strip_prefix(project_directory) {
+            Ok(relative_path) if relative_path.as_os_str().is_empty() => continue,
+            Ok(relative_path) => relative_path.to_string_lossy().replace('\\', "/"),
+            Err(_) => continue
+        };
+
+        let metadata = entry.path().symlink_metadata()
+            .map_err(|e| WorkspaceError { msg: format!("Failed to read [{}]: {}", entry.path().display(), e) })?;
+
+        if !metadata.is_dir() {
+            file_count += 1;
+            total_size += metadata.len();
+        }
+
+        entries.push(WorkspaceEntry {
+            relative_path,
+            path: entry.path().to_path_buf(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |modified| modified.as_nanos() as u64),
+        });
+    }
+
+    Ok(WorkspaceFiles {
+        entries,
+        file_count,
+        total_size,
+    })
+}
+
+// Links are kept as links rather than being replaced by their targets, which may be outside the project.
+pub fn write_tar<W: Write>(files: &WorkspaceFiles, writer: W) -> Result<W, WorkspaceError> {
+    let mut tar = tar::Builder::new(writer);
+    tar.follow_symlinks(false);
+
+    for entry in &files.entries {
+        let result = if entry.is_dir {
+            tar.append_dir(entry.relative_path.as_str(), &entry.path)
+        } else {
+            tar.append_path_with_name(&entry.path, entry.relative_path.as_str())
+        };
+
+        result.map_err(|e| WorkspaceError { msg: format!("Failed to add [{}] to the workspace: {}", entry.relative_path, e) })?;
+    }
+
+    tar.into_inner()
+        .map_err(|e| WorkspaceError { msg: format!("Failed to finish workspace archive: {}", e) })
+}
+
+// What was uploaded by the last sync of a reused workspace. Content hashes decide whether a file changed, the size and
+// modification time only let unchanged files skip being hashed again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    pub entries: HashMap<String, SyncEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub is_dir: bool,
+
+    pub size: u64,
+
+    pub modified: u64,
+
+    pub sha256: String,
+}
+
+pub struct SyncPlan {
+    pub changed: WorkspaceFiles,
+
+    pub deleted: Vec<String>,
+}
+
+pub fn load_sync_state(workspace_name: &str) -> Result<SyncState, WorkspaceError> {
+    let path = sync_state_path(workspace_name)?;
+    if !path.exists() {
+        return Ok(SyncState::default());
+    }
+
+    let content = fs::read_to_string(&path)
+        .map_err(|e| WorkspaceError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+    serde_json::from_str(content.as_str())
+        .map_err(|e| WorkspaceError { msg: format!("Invalid workspace sync state [{}]: {}", path.display(), e) })
+}
+
+pub fn save_sync_state(workspace_name: &str, state: &SyncState) -> Result<(), WorkspaceError> {
+    let path = sync_state_path(workspace_name)?;
+    fs::create_dir_all(path.parent().unwrap())
+        .map_err(|e| WorkspaceError { msg: format!("Failed to create [{}]: {}", path.display(), e) })?;
+
+    let content = serde_json::to_string(state)
+        .map_err(|e| WorkspaceError { msg: format!("Failed to serialize workspace sync state: {}", e) })?;
+    fs::write(&path, content)
+        .map_err(|e| WorkspaceError { msg: format!("Failed to write [{}]: {}", path.display(), e) })
+}
+
+fn sync_state_path(workspace_name: &str) -> Result<PathBuf, WorkspaceError> {
+    jarvis_home_directory()
+        .map(|home| home.join("workspaces").join(format!("{}.json", workspace_name)))
+        .map_err(|e| WorkspaceError { msg: format!("{}", e) })
+}
+
+pub fn hash_workspace(files: &WorkspaceFiles, previous: &SyncState) -> Result<SyncState, WorkspaceError> {
+    let mut entries = HashMap::new();
+    for entry in &files.entries {
+        let sha256 = match previous.entries.get(&entry.relative_path) {
+            _ if entry.is_dir => "".to_string(),
+            Some(last) if !last.is_dir && last.size == entry.size && last.modified == entry.modified => last.sha256.clone(),
+            _ => hash_file(&entry.path)?
+        };
+
+        entries.insert(entry.relative_path.clone(), SyncEntry {
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified: entry.modified,
+            sha256,
+        });
+    }
+
+    Ok(SyncState {
+        entries,
+    })
+}
+
+fn hash_file(path: &PathBuf) -> Result<String, WorkspaceError> {
+    // Links are hashed by their target path, which is what ends up in the archive.
+    if path.symlink_metadata().map_or(false, |metadata| metadata.file_type().is_symlink()) {
+        let target = fs::read_link(path)
+            .map_err(|e| WorkspaceError { msg: format!("Failed to read [{}]: {}", path.display(), e) })?;
+        let target = target.to_string_lossy();
+        let mut reader = HashingReader::new(target.as_bytes());
+        io::copy(&mut reader, &mut io::sink())
+            .map_err(|e| WorkspaceError { msg: format!("Failed to hash [{}]: {}", path.display(), e) })?;
+        return Ok(reader.hasher.result_str());
+    }
+
+    let file = File::open(path)
+        .map_err(|e| WorkspaceError { msg: format!("Failed to open [{}]: {}", path.display(), e) })?;
+
+    let mut reader = HashingReader::new(file);
+    io::copy(&mut reader, &mut io::sink())
+        .map_err(|e| WorkspaceError { msg: format!("Failed to hash [{}]: {}", path.display(), e) })?;
+
+    Ok(reader.hasher.result_str())
+}
+
+// Lists every path in a workspace volume along with the content hash of each file, for `volume_sync_state`. Links are
+// hashed by their target, the same as `hash_file` does.
+pub const VOLUME_LISTING_COMMAND: &str = r#"find . -mindepth 1 -type d | sed 's/^/d - /'
+find . -mindepth 1 -type f -exec sha256sum {} + | sed 's/^\([0-9a-f]*\)  /f \1 /'
+find . -mindepth 1 -type l | while IFS= read -r link; do
+  printf 'l %s %s\n' "$(printf '%s' "$(readlink "$link")" | sha256sum | cut -d ' ' -f 1)" "$link"
+done"#;
+
+// What a reused volume actually holds, rather than what the last sync left in it, so files which build steps changed are
+// found and reset. Only paths which came from the project are kept, anything a build created itself is left alone.
+pub fn volume_sync_state(listing: &str, previous: &SyncState, current: &SyncState) -> SyncState {
+    let entries = listing.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let (kind, sha256, path) = (parts.next()?, parts.next()?, parts.next()?);
+            let path = path.trim_start_matches("./").to_string();
+            if !previous.entries.contains_key(&path) && !current.entries.contains_key(&path) {
+                return None;
+            }
+
+            let is_dir = kind == "d";
+            Some((path, SyncEntry {
+                is_dir,
+                size: 0,
+                modified: 0,
+                sha256: if is_dir { "".to_string() } else { sha256.to_string() },
+            }))
+        })
+        .collect();
+
+    SyncState {
+        entries,
+    }
+}
+
+// Works out what has to be sent to bring a workspace synced to `previous` up to date with `current`. Deleted paths are
+// trimmed to the topmost deleted directory since removing that takes everything below it too.
+pub fn plan_sync(files: WorkspaceFiles, previous: &SyncState, current: &SyncState) -> SyncPlan {
+    let changed_entries: Vec<WorkspaceEntry> = files.entries.into_iter()
+        .filter(|entry| {
+            let last = previous.entries.get(&entry.relative_path);
+            let now = current.entries.get(&entry.relative_path);
+            match (last, now) {
+                (Some(last), Some(now)) => last.is_dir != now.is_dir || last.sha256 != now.sha256,
+                _ => true
+            }
+        })
+        .collect();
+
+    // A path that turned from a file into a directory or back has to be removed before the new one can be unpacked.
+    let mut deleted: Vec<String> = previous.entries.iter()
+        .filter(|(path, last)| current.entries.get(*path).map_or(true, |now| now.is_dir != last.is_dir))
+        .map(|(path, _)| path.clone())
+        .collect();
+    deleted.sort();
+    let mut trimmed: Vec<String> = vec![];
+    for path in deleted {
+        if !trimmed.iter().any(|parent| path.starts_with(format!("{}/", parent).as_str())) {
+            trimmed.push(path);
+        }
+    }
+
+    SyncPlan {
+        changed: WorkspaceFiles {
+            file_count: changed_entries.iter().filter(|entry| !entry.is_dir).count(),
+            total_size: changed_entries.iter().filter(|entry| !entry.is_dir).map(|entry| entry.size).sum(),
+            entries: changed_entries,
+        },
+        deleted: trimmed,
+    }
+}
+
+// Writes the workspace archive into `sender` as it is produced, so the upload can start before the archive is complete and
+// the archive is never held on disk or in memory as a whole. Run this on a blocking thread while the receiver is consumed.
+pub fn stream_workspace(files: WorkspaceFiles, compression: WorkspaceCompression, threads: usize, sender: Sender<Result<Vec<u8>, io::Error>>) -> Result<(), WorkspaceError> {
+    let mut error_sender = sender.clone();
+    let channel_writer = ChannelWriter { sender };
+
+    let result = match compression {
+        WorkspaceCompression::None => {
+            // Tar writes many small headers, batch them up rather than sending each one through the channel.
+            let buffered_writer = io::BufWriter::with_capacity(COMPRESSION_BLOCK_SIZE, channel_writer);
+            write_tar(&files, ProgressWriter::new(buffered_writer, files.total_size))
+                .and_then(|writer| writer.finish())
+                .and_then(|buffered_writer| buffered_writer.into_inner()
+                    .map_err(|e| WorkspaceError { msg: format!("Failed to send workspace: {}", e) }))
+                .map(|_| ())
+        },
+        WorkspaceCompression::Gzip => {
+            let progress_writer = ProgressWriter::new(ParallelGzEncoder::new(channel_writer, threads), files.total_size);
+            write_tar(&files, progress_writer)
+                .and_then(|writer| writer.finish())
+                .and_then(|encoder| encoder.finish())
+                .map(|_| ())
+        },
+    };
+
+    // Make sure the upload fails too rather than sending a truncated archive.
+    if let Err(e) = &result {
+        block_on(error_sender.send(Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))))).unwrap_or(());
+    }
+
+    result
+}
+
+struct ChannelWriter {
+    sender: Sender<Result<Vec<u8>, io::Error>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on(self.sender.send(Ok(buf.to_vec())))
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reports how much of the uncompressed workspace has been sent. The tar headers are counted too, so the total is capped.
+struct ProgressWriter<W: Write> {
+    inner: W,
+
+    total: u64,
+
+    written: u64,
+
+    last_percent: u64,
+}
+
+impl<W: Write> ProgressWriter<W> {
+    fn new(inner: W, total: u64) -> Self {
+        ProgressWriter {
+            inner,
+            total,
+            written: 0,
+            last_percent: 0,
+        }
+    }
+
+    fn finish(self) -> Result<W, WorkspaceError> {
+        println!("\rUploading workspace: 100% ({})", format_bytes(self.total));
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+
+        let percent = if self.total == 0 { 100 } else { (self.written * 100 / self.total).min(100) };
+        if percent != self.last_percent {
+            self.last_percent = percent;
+            print!("\rUploading workspace: {}% ({} of {})", percent, format_bytes(self.written.min(self.total)), format_bytes(self.total));
+            io::stdout().flush().unwrap_or(());
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Compresses blocks on a fixed set of threads, writing each as its own gzip member. Concatenated members are a valid gzip
+// stream which Docker unpacks like any other. Blocks are handed to the workers in turn through bounded channels and
+// collected in the same order, so at most one block per worker is held in memory and the output keeps its order.
+struct ParallelGzEncoder<W: Write> {
+    inner: W,
+
+    block: Vec<u8>,
+
+    workers: Vec<CompressionWorker>,
+
+    next_submit: usize,
+
+    next_collect: usize,
+
+    in_flight: usize,
+}
+
+struct CompressionWorker {
+    blocks: Option<SyncSender<Vec<u8>>>,
+
+    compressed: Receiver<io::Result<Vec<u8>>>,
+
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    fn new(inner: W, threads: usize) -> Self {
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let (block_sender, block_receiver) = sync_channel::<Vec<u8>>(1);
+                let (compressed_sender, compressed_receiver) = sync_channel(1);
+                let handle = thread::spawn(move || {
+                    for block in block_receiver {
+                        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                        let compressed = encoder.write_all(block.as_slice()).and_then(|_| encoder.finish());
+                        if compressed_sender.send(compressed).is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                CompressionWorker {
+                    blocks: Some(block_sender),
+                    compressed: compressed_receiver,
+                    handle: Some(handle),
+                }
+            })
+            .collect();
+
+        ParallelGzEncoder {
+            inner,
+            block: Vec::with_capacity(COMPRESSION_BLOCK_SIZE),
+            workers,
+            next_submit: 0,
+            next_collect: 0,
+            in_flight: 0,
+        }
+    }
+
+    fn submit(&mut self, block: Vec<u8>) -> io::Result<()> {
+        if self.in_flight == self.workers.len() {
+            self.collect()?;
+        }
+
+        let sent = self.workers[self.next_submit].blocks.as_ref()
+            .map_or(false, |sender| sender.send(block).is_ok());
+        if !sent {
+            return Err(io::Error::new(io::ErrorKind::Other, "Compression thread failed"));
+        }
+
+        self.next_submit = (self.next_submit + 1) % self.workers.len();
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    fn collect(&mut self) -> io::Result<()> {
+        let compressed = self.workers[self.next_collect].compressed.recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Compression thread failed"))??;
+        self.inner.write_all(compressed.as_slice())?;
+
+        self.next_collect = (self.next_collect + 1) % self.workers.len();
+        self.in_flight -= 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, WorkspaceError> {
+        let mut result = Ok(());
+        if !self.block.is_empty() {
+            let block = std::mem::replace(&mut self.block, vec![]);
+            result = self.submit(block);
+        }
+        while result.is_ok() && self.in_flight > 0 {
+            result = self.collect();
+        }
+
+        for worker in &mut self.workers {
+            worker.blocks.take();
+            if let Some(handle) = worker.handle.take() {
+                handle.join().unwrap_or(());
+            }
+        }
+
+        result.map_err(|e| WorkspaceError { msg: format!("Failed to compress workspace: {}", e) })?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let space = COMPRESSION_BLOCK_SIZE - self.block.len();
+        let taken = space.min(buf.len());
+        self.block.extend_from_slice(&buf[..taken]);
+
+        if self.block.len() == COMPRESSION_BLOCK_SIZE {
+            let block = std::mem::replace(&mut self.block, Vec::with_capacity(COMPRESSION_BLOCK_SIZE));
+            self.submit(block)?;
+        }
+
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+
+    use flate2::read::MultiGzDecoder;
+
+    use crate::cache::temp_path;
+    use crate::config::get_project_config;
+    use super::{collect_workspace_files, COMPRESSION_BLOCK_SIZE, ParallelGzEncoder, plan_sync, SyncEntry, SyncState, volume_sync_state, WorkspaceEntry, WorkspaceFiles, write_tar};
+
+    fn create_project(respect_gitignore: bool) -> PathBuf {
+        let project = temp_path("jarvis-workspace-test", "project");
+        let files = vec![
+            (".jarvis/build.yaml", format!("api_version: 0.1\nproject_id: test\nworkspace:\n  respect_gitignore: {}\nmodules: []\n", respect_gitignore)),
+            (".jarvis/secrets/npm-token.secret", "s3cr3t".to_string()),
+            (".jarvis/secrets/prod/db-password.secret", "s3cr3t".to_string()),
+            // An ignore file can't bring the secrets back.
+            (".jarvisignore", "*.log\nnode_modules/\n!.jarvis/secrets\n!.jarvis/secrets/**\n".to_string()),
+            (".gitignore", "target/\n".to_string()),
+            ("src/main.rs", "fn main() {}".to_string()),
+            ("src/.jarvisignore", "generated.rs\n".to_string()),
+            ("src/generated.rs", "".to_string()),
+            ("build.log", "".to_string()),
+            ("node_modules/left-pad/index.js", "".to_string()),
+            ("target/debug/app", "".to_string()),
+        ];
+        for (path, content) in files {
+            let path = project.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        project
+    }
+
+    fn uploaded_paths(project: &PathBuf) -> Vec<String> {
+        let project_config = get_project_config(project.clone()).unwrap();
+        let files = collect_workspace_files(&project_config).unwrap();
+
+        let archive = write_tar(&files, vec![]).unwrap();
+        let mut archive = tar::Archive::new(archive.as_slice());
+        archive.entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().trim_end_matches('/').to_string())
+            .collect()
+    }
+
+    #[test]
+    fn secrets_and_ignored_paths_are_never_uploaded() {
+        let project = create_project(false);
+        let paths = uploaded_paths(&project);
+
+        assert!(paths.contains(&"src/main.rs".to_string()));
+        assert!(paths.contains(&".jarvis/build.yaml".to_string()));
+        assert!(paths.contains(&"target/debug/app".to_string()));
+        assert!(!paths.iter().any(|path| path.starts_with(".jarvis/secrets")));
+        assert!(!paths.contains(&"build.log".to_string()));
+        assert!(!paths.iter().any(|path| path.starts_with("node_modules")));
+        assert!(!paths.contains(&"src/generated.rs".to_string()));
+
+        std::fs::remove_dir_all(project).unwrap();
+    }
+
+    #[test]
+    fn gitignore_is_only_honoured_when_enabled() {
+        let project = create_project(true);
+        let paths = uploaded_paths(&project);
+
+        assert!(!paths.iter().any(|path| path.starts_with("target")));
+        assert!(!paths.iter().any(|path| path.starts_with(".jarvis/secrets")));
+        assert!(paths.contains(&"src/main.rs".to_string()));
+
+        std::fs::remove_dir_all(project).unwrap();
+    }
+
+    #[test]
+    fn configured_secrets_directories_are_never_uploaded() {
+        let project = create_project(false);
+        std::fs::write(
+            project.join(".jarvis/build.yaml"),
+            "api_version: 0.1\nproject_id: test\nsecrets:\n  type: file\n  directory: ci-secrets\nmodules: []\n"
+        ).unwrap();
+        std::fs::create_dir_all(project.join("ci-secrets/prod")).unwrap();
+        std::fs::write(project.join("ci-secrets/prod/db-password.secret.txt"), "s3cr3t").unwrap();
+
+        let paths = uploaded_paths(&project);
+
+        assert!(!paths.iter().any(|path| path.starts_with("ci-secrets")));
+        assert!(!paths.iter().any(|path| path.starts_with(".jarvis/secrets")));
+        assert!(paths.contains(&"src/main.rs".to_string()));
+
+        std::fs::remove_dir_all(project).unwrap();
+    }
+
+    fn state(entries: Vec<(&str, bool, &str)>) -> SyncState {
+        SyncState {
+            entries: entries.into_iter()
+                .map(|(path, is_dir, sha256)| (path.to_string(), SyncEntry { is_dir, size: 1, modified: 1, sha256: sha256.to_string() }))
+                .collect(),
+        }
+    }
+
+    fn files(paths: Vec<(&str, bool)>) -> WorkspaceFiles {
+        WorkspaceFiles {
+            entries: paths.into_iter()
+                .map(|(path, is_dir)| WorkspaceEntry {
+                    relative_path: path.to_string(),
+                    path: PathBuf::from(path),
+                    is_dir,
+                    size: 1,
+                    modified: 1,
+                })
+                .collect(),
+            file_count: 0,
+            total_size: 0,
+        }
+    }
+
+    #[test]
+    fn parallel_compression_round_trips() {
+        let content: Vec<u8> = (0..COMPRESSION_BLOCK_SIZE * 5 + 123).map(|i| (i % 251) as u8).collect();
+
+        for threads in vec![1, 2, 8] {
+            let mut encoder = ParallelGzEncoder::new(Vec::new(), threads);
+            encoder.write_all(content.as_slice()).unwrap();
+            let compressed = encoder.finish().unwrap();
+
+            let mut decompressed = vec![];
+            MultiGzDecoder::new(compressed.as_slice()).read_to_end(&mut decompressed).unwrap();
+
+            assert_eq!(content, decompressed);
+        }
+    }
+
+    #[test]
+    fn files_changed_inside_the_volume_are_reset() {
+        let previous = state(vec![("src", true, ""), ("src/main.rs", false, "a"), ("src/old.rs", false, "b")]);
+        let current = state(vec![("src", true, ""), ("src/main.rs", false, "a"), ("src/lib.rs", false, "c")]);
+
+        // A step edited main.rs and built target/app, lib.rs is new in the project.
+        let listing = "d - ./src\nf edited ./src/main.rs\nf b ./src/old.rs\nd - ./target\nf x ./target/app\n";
+        let in_volume = volume_sync_state(listing, &previous, &current);
+        assert!(!in_volume.entries.contains_key("target/app"));
+
+        let plan = plan_sync(files(vec![("src", true), ("src/main.rs", false), ("src/lib.rs", false)]), &in_volume, &current);
+
+        let changed: Vec<String> = plan.changed.entries.iter().map(|entry| entry.relative_path.clone()).collect();
+        assert_eq!(vec!["src/main.rs", "src/lib.rs"], changed);
+        assert_eq!(vec!["src/old.rs"], plan.deleted);
+    }
+
+    #[test]
+    fn sync_plan_contains_changed_files_and_topmost_deletions() {
+        let previous = state(vec![("src", true, ""), ("src/main.rs", false, "a"), ("src/lib.rs", false, "b"),
+                                  ("old", true, ""), ("old/file", false, "c"), ("target", false, "d")]);
+        let current = state(vec![("src", true, ""), ("src/main.rs", false, "a"), ("src/lib.rs", false, "changed"),
+                                 ("src/new.rs", false, "e"), ("target", true, "")]);
+
+        let plan = plan_sync(files(vec![("src", true), ("src/main.rs", false), ("src/lib.rs", false),
+                                        ("src/new.rs", false), ("target", true)]), &previous, &current);
+
+        let changed: Vec<String> = plan.changed.entries.iter().map(|entry| entry.relative_path.clone()).collect();
+        assert_eq!(vec!["src/lib.rs", "src/new.rs", "target"], changed);
+        assert_eq!(2, plan.changed.file_count);
+        assert_eq!(vec!["old", "target"], plan.deleted);
+    }
+}